@@ -0,0 +1,107 @@
+use anyhow::{anyhow, Error};
+use openssl::sha::sha256;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use super::sigv4::{self, Credentials};
+use crate::config::ArtifactDestinationConfig;
+
+/// Files of the guest to upload once the command of a job exited
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ArtifactsRequest {
+    /// Name of the bucket in the configuration
+    pub destination: String,
+    /// Absolute paths of the files in the guest, uploaded under their name
+    pub paths: Vec<String>,
+}
+
+/// File of the guest uploaded once the command of a job exited
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Artifact {
+    /// Path of the file in the guest
+    pub path: String,
+    pub url: String,
+    pub size: u64,
+}
+
+/// Name a file of the guest is uploaded under, refusing the paths which do
+/// not name a file
+pub fn file_name(path: &str) -> Result<&str, Error> {
+    match path.rsplit('/').next() {
+        Some(name) if !name.is_empty() && name != "." && name != ".." => Ok(name),
+        _ => Err(anyhow!("{} does not name a file", path)),
+    }
+}
+
+/// Upload `content` to `key` of the bucket of `destination`, under its
+/// prefix, returning the URL of the object.
+pub async fn upload(
+    client: &reqwest::Client,
+    destination: &ArtifactDestinationConfig,
+    key: &str,
+    content: Vec<u8>,
+) -> Result<String, Error> {
+    let endpoint = reqwest::Url::parse(&destination.endpoint)?;
+    let host = match (endpoint.host_str(), endpoint.port()) {
+        (Some(host), Some(port)) => format!("{}:{}", host, port),
+        (Some(host), None) => host.to_string(),
+        (None, _) => return Err(anyhow!("invalid endpoint {}", endpoint)),
+    };
+    let path = format!("{}/{}{}", destination.bucket, destination.prefix, key)
+        .split('/')
+        .map(sigv4::encode_segment)
+        .collect::<Vec<_>>()
+        .join("/");
+    let path = format!("/{}", path);
+    let url = format!("{}://{}{}", endpoint.scheme(), host, path);
+
+    let credentials = Credentials::resolve(
+        destination.access_key_id.as_ref(),
+        destination.secret_access_key.as_ref(),
+    )?;
+    let amz_date = sigv4::amz_date(OffsetDateTime::now_utc());
+
+    // Header names are sorted, as signing requires
+    let mut headers = vec![
+        ("host", host),
+        ("x-amz-content-sha256", hex::encode(sha256(&content))),
+        ("x-amz-date", amz_date.clone()),
+    ];
+    if let Some(token) = &credentials.session_token {
+        headers.push(("x-amz-security-token", token.clone()));
+    }
+
+    let authorization = sigv4::sign(
+        &credentials,
+        &destination.region,
+        "s3",
+        &amz_date,
+        &sigv4::Request {
+            method: "PUT",
+            path: &path,
+            headers: &headers,
+            body: &content,
+        },
+    )?;
+
+    let mut request = client.put(&url).header("Authorization", authorization);
+    for (name, value) in headers.iter().filter(|(name, _)| *name != "host") {
+        request = request.header(*name, value);
+    }
+
+    let response = request.body(content).send().await?;
+    let status = response.status();
+    if !status.is_success() {
+        let text = response.text().await.unwrap_or_default();
+        return Err(anyhow!(
+            "upload to {} failed with {}: {}",
+            url,
+            status,
+            text
+        ));
+    }
+
+    Ok(url)
+}
@@ -0,0 +1,408 @@
+use std::{fmt::Display, ops::Deref};
+
+use actix_web::{
+    dev::{Payload, ServiceRequest},
+    error::InternalError,
+    http::{header, Method, StatusCode},
+    web, FromRequest, HttpMessage, HttpRequest, HttpResponse,
+};
+use anyhow::{anyhow, Result};
+use futures::future::LocalBoxFuture;
+use tracing::debug;
+
+use crate::{
+    api::{
+        error::ApiError,
+        service::{LambdoApiService, LambdoApiServiceTrait},
+    },
+    config::{AuthConfig, TokenConfig, TokenScope},
+    vm_manager::Error,
+};
+
+/// Paths anyone may reach, for probes not to need a token
+const PUBLIC_PATHS: [&str; 1] = ["/readyz"];
+
+/// Token a request was authenticated with, in the extensions of the request
+#[derive(Debug, Clone)]
+pub struct Principal {
+    pub name: String,
+    pub namespace: Option<String>,
+    /// Whether the token sees the VMs of every namespace
+    pub all_namespaces: bool,
+}
+
+/// Id of the VM of a `/vms/{id}` or `/destroy/{id}` path, which is not found
+/// unless it is in the namespace the caller is restricted to, if any.
+#[derive(Debug)]
+pub struct VmId(String);
+
+impl VmId {
+    pub fn into_inner(self) -> String {
+        self.0
+    }
+}
+
+impl Deref for VmId {
+    type Target = String;
+
+    fn deref(&self) -> &String {
+        &self.0
+    }
+}
+
+impl Display for VmId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl FromRequest for VmId {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(request: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let id = request.match_info().get("id").map(str::to_string);
+        let scope = super::caller(request).scope().map(str::to_string);
+        let service = request.app_data::<web::Data<LambdoApiService>>().cloned();
+
+        Box::pin(async move {
+            let id = id.ok_or_else(|| ApiError::from(Error::VmNotFound))?;
+            let (Some(scope), Some(service)) = (scope, service) else {
+                return Ok(VmId(id));
+            };
+            // Missing VMs are left to the route, which knows how to answer
+            match service.vm(&id).await {
+                Ok(vm) if vm.namespace != scope => Err(ApiError::from(Error::VmNotFound).into()),
+                _ => Ok(VmId(id)),
+            }
+        })
+    }
+}
+
+/// Tokens requests are authenticated with
+pub struct Tokens(Vec<TokenConfig>);
+
+impl Tokens {
+    /// Tokens of the configuration, along with the ones of its token file
+    pub fn load(config: &AuthConfig) -> Result<Self> {
+        let mut tokens = config.tokens.clone();
+        if let Some(path) = &config.token_file {
+            let content = std::fs::read_to_string(path)
+                .map_err(|e| anyhow!("cannot read token file {}: {}", path, e))?;
+            let from_file: Vec<TokenConfig> = serde_yaml::from_str(&content)
+                .map_err(|e| anyhow!("cannot parse token file {}: {}", path, e))?;
+            tokens.extend(from_file);
+        }
+        if let Some(token) = tokens.iter().find(|token| token.token.is_empty()) {
+            return Err(anyhow!("token {} is empty", token.name));
+        }
+        Ok(Tokens(tokens))
+    }
+
+    pub fn find(&self, value: &str) -> Option<&TokenConfig> {
+        // Compared in constant time, not to leak how much of a token matched
+        self.0.iter().find(|token| {
+            token.token.len() == value.len()
+                && openssl::memcmp::eq(token.token.as_bytes(), value.as_bytes())
+        })
+    }
+}
+
+/// Scope a token needs to send `method` to `path`, none to read
+fn required_scope(method: &Method, path: &str) -> Option<TokenScope> {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match segments.as_slice() {
+        ["admin", ..] | ["images", ..] => Some(TokenScope::Admin),
+        ["functions"] if method == Method::POST => Some(TokenScope::Admin),
+        ["vms", "adopt"] => Some(TokenScope::Admin),
+        ["namespaces", _, "quota"] if method == Method::PUT => Some(TokenScope::Admin),
+        ["destroy", _] | ["vms", _, "undo-destroy"] | ["vms", _, "pause"] => Some(TokenScope::Stop),
+        ["stacks", _] if method == Method::DELETE => Some(TokenScope::Stop),
+        _ if method == Method::GET || method == Method::HEAD => None,
+        _ => Some(TokenScope::Start),
+    }
+}
+
+/// Check the bearer token of `request` and that it allows the request,
+/// adding its principal to the extensions of the request.
+pub fn authenticate(tokens: &Tokens, request: &ServiceRequest) -> Result<(), actix_web::Error> {
+    // Decoded like the path requests are routed on, for `/%61dmin` to be
+    // checked as `/admin` is
+    let path = request.match_info().as_str();
+    if PUBLIC_PATHS.contains(&path) {
+        return Ok(());
+    }
+
+    let token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .and_then(|value| tokens.find(value.trim()))
+        .ok_or_else(|| {
+            let error = ApiError::new(
+                StatusCode::UNAUTHORIZED,
+                "unauthenticated",
+                "missing or invalid bearer token",
+            );
+            let response = HttpResponse::Unauthorized()
+                .insert_header((header::WWW_AUTHENTICATE, "Bearer"))
+                .json(&error);
+            InternalError::from_response("unauthenticated", response)
+        })?;
+
+    if let Some(scope) = required_scope(request.method(), path) {
+        if !allows(token, scope) {
+            debug!(
+                "Token {} lacks scope {:?} for {} {}",
+                token.name,
+                scope,
+                request.method(),
+                path
+            );
+            let message = format!(
+                "token {} lacks the {} scope",
+                token.name,
+                format!("{:?}", scope).to_lowercase()
+            );
+            return Err(ApiError::new(StatusCode::FORBIDDEN, "missing_scope", message).into());
+        }
+    }
+
+    request.extensions_mut().insert(Principal::from(token));
+    Ok(())
+}
+
+/// Whether `token` was granted `scope`, which the admin scope includes
+pub fn allows(token: &TokenConfig, scope: TokenScope) -> bool {
+    token
+        .scopes
+        .iter()
+        .any(|granted| *granted == scope || *granted == TokenScope::Admin)
+}
+
+impl From<&TokenConfig> for Principal {
+    fn from(token: &TokenConfig) -> Self {
+        Principal {
+            name: token.name.clone(),
+            namespace: token.namespace.clone(),
+            all_namespaces: token.scopes.contains(&TokenScope::Admin),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    fn token(name: &str, scopes: &[TokenScope]) -> TokenConfig {
+        TokenConfig {
+            name: name.to_string(),
+            token: format!("{}-secret", name),
+            scopes: scopes.to_vec(),
+            namespace: Some(format!("{}-namespace", name)),
+        }
+    }
+
+    fn tokens() -> Tokens {
+        Tokens(vec![
+            token("reader", &[]),
+            token("starter", &[TokenScope::Start]),
+            token("stopper", &[TokenScope::Stop]),
+            token("admin", &[TokenScope::Admin]),
+        ])
+    }
+
+    /// Status `authenticate` answers a request to `path` with `bearer`, 200
+    /// if it lets it through
+    fn status(method: Method, path: &str, bearer: Option<&str>) -> StatusCode {
+        let mut request = TestRequest::default().method(method).uri(path);
+        if let Some(bearer) = bearer {
+            request = request.insert_header((header::AUTHORIZATION, format!("Bearer {}", bearer)));
+        }
+        match authenticate(&tokens(), &request.to_srv_request()) {
+            Ok(()) => StatusCode::OK,
+            Err(e) => e.as_response_error().status_code(),
+        }
+    }
+
+    #[test]
+    fn requires_admin_for_admin_and_images() {
+        for (method, path) in [
+            (Method::GET, "/admin/support-bundle"),
+            (Method::POST, "/admin/cordon"),
+            (Method::POST, "/images/samples"),
+            (Method::POST, "/images/promote"),
+            (Method::POST, "/functions"),
+            (Method::POST, "/vms/adopt"),
+            (Method::PUT, "/namespaces/team/quota"),
+        ] {
+            assert_eq!(
+                required_scope(&method, path),
+                Some(TokenScope::Admin),
+                "{} {}",
+                method,
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn requires_stop_to_destroy_and_pause() {
+        for (method, path) in [
+            (Method::DELETE, "/destroy/vm"),
+            (Method::POST, "/vms/vm/undo-destroy"),
+            (Method::POST, "/vms/vm/pause"),
+            (Method::DELETE, "/stacks/web"),
+        ] {
+            assert_eq!(
+                required_scope(&method, path),
+                Some(TokenScope::Stop),
+                "{} {}",
+                method,
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn requires_start_to_change_anything_else() {
+        for (method, path) in [
+            (Method::POST, "/start"),
+            (Method::POST, "/spawn"),
+            (Method::POST, "/jobs"),
+            (Method::POST, "/functions/hello/invoke"),
+            (Method::POST, "/vms/vm/resume"),
+            (Method::PATCH, "/vms/vm/ports"),
+            (Method::POST, "/stacks"),
+            (Method::POST, "/volumes"),
+            (Method::DELETE, "/volumes/data"),
+        ] {
+            assert_eq!(
+                required_scope(&method, path),
+                Some(TokenScope::Start),
+                "{} {}",
+                method,
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn requires_nothing_to_read() {
+        for (method, path) in [
+            (Method::GET, "/vms"),
+            (Method::GET, "/vms/vm"),
+            (Method::HEAD, "/vms/vm"),
+            (Method::GET, "/jobs/job"),
+            (Method::GET, "/stacks/web"),
+            (Method::GET, "/events"),
+            (Method::GET, "/namespaces/team/quota"),
+        ] {
+            assert_eq!(required_scope(&method, path), None, "{} {}", method, path);
+        }
+    }
+
+    #[test]
+    fn admin_allows_every_scope() {
+        let admin = token("admin", &[TokenScope::Admin]);
+        for scope in [TokenScope::Start, TokenScope::Stop, TokenScope::Admin] {
+            assert!(allows(&admin, scope));
+        }
+
+        let starter = token("starter", &[TokenScope::Start]);
+        assert!(allows(&starter, TokenScope::Start));
+        assert!(!allows(&starter, TokenScope::Stop));
+        assert!(!allows(&starter, TokenScope::Admin));
+    }
+
+    #[test]
+    fn lets_anyone_reach_public_paths() {
+        for path in PUBLIC_PATHS {
+            assert_eq!(status(Method::GET, path, None), StatusCode::OK);
+            assert_eq!(status(Method::GET, path, Some("wrong")), StatusCode::OK);
+        }
+    }
+
+    #[test]
+    fn refuses_missing_and_wrong_bearers() {
+        assert_eq!(status(Method::GET, "/vms", None), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            status(Method::GET, "/vms", Some("wrong")),
+            StatusCode::UNAUTHORIZED
+        );
+        // A known token of another scheme is not a bearer
+        let request = TestRequest::get()
+            .uri("/vms")
+            .insert_header((header::AUTHORIZATION, "Basic reader-secret"))
+            .to_srv_request();
+        let error = authenticate(&tokens(), &request).unwrap_err();
+        assert_eq!(
+            error.as_response_error().status_code(),
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[test]
+    fn refuses_tokens_lacking_the_scope() {
+        assert_eq!(
+            status(Method::POST, "/start", Some("reader-secret")),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status(Method::DELETE, "/destroy/vm", Some("starter-secret")),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status(Method::GET, "/admin/support-bundle", Some("stopper-secret")),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status(Method::GET, "/vms", Some("reader-secret")),
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(Method::POST, "/start", Some("starter-secret")),
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(Method::DELETE, "/destroy/vm", Some("admin-secret")),
+            StatusCode::OK
+        );
+    }
+
+    #[test]
+    fn checks_the_decoded_path() {
+        for (method, path) in [
+            (Method::POST, "/%61dmin/cordon"),
+            (Method::GET, "/%61dmin/support-bundle"),
+            (Method::POST, "/%69mages/promote"),
+            (Method::DELETE, "/%64estroy/vm"),
+        ] {
+            assert_eq!(
+                status(method.clone(), path, Some("starter-secret")),
+                StatusCode::FORBIDDEN,
+                "{} {}",
+                method,
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn adds_the_principal_of_the_token() {
+        let request = TestRequest::post()
+            .uri("/start")
+            .insert_header((header::AUTHORIZATION, "Bearer  starter-secret "))
+            .to_srv_request();
+        authenticate(&tokens(), &request).unwrap();
+
+        let extensions = request.extensions();
+        let principal = extensions.get::<Principal>().unwrap();
+        assert_eq!(principal.name, "starter");
+        assert_eq!(principal.namespace.as_deref(), Some("starter-namespace"));
+        assert!(!principal.all_namespaces);
+    }
+}
@@ -0,0 +1,87 @@
+use std::{fs::OpenOptions, io, path::Path, sync::Mutex};
+
+use actix_web::HttpRequest;
+use tracing::{Span, Subscriber};
+use tracing_subscriber::{
+    filter::dynamic_filter_fn, fmt::format::FmtSpan, layer::Filter, registry::LookupSpan, Layer,
+};
+use uuid::Uuid;
+
+use crate::config::DebugConfig;
+
+/// Header asking for a request to be debugged
+pub const DEBUG_HEADER: &str = "x-lambdo-debug";
+/// Header carrying the admin token, for the request to be debugged
+pub const ADMIN_TOKEN_HEADER: &str = "x-lambdo-admin-token";
+/// Header of the response giving the id of the request in the debug log
+pub const DEBUG_ID_HEADER: &str = "x-lambdo-debug-id";
+
+/// Name of the span everything a debugged request does happens in
+const DEBUG_SPAN: &str = "lambdo_debug";
+
+/// Whether a request is to be debugged
+pub enum Debugging {
+    Off,
+    On,
+    /// Asked for without the admin token, or while debug mode is disabled
+    Denied,
+}
+
+pub fn debugging(config: Option<&DebugConfig>, request: &HttpRequest) -> Debugging {
+    let header = |name: &str| {
+        request
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+    if !header(DEBUG_HEADER).is_some_and(|value| value.eq_ignore_ascii_case("true")) {
+        return Debugging::Off;
+    }
+
+    match config {
+        Some(config) if header(ADMIN_TOKEN_HEADER) == Some(config.admin_token.as_str()) => {
+            Debugging::On
+        }
+        _ => Debugging::Denied,
+    }
+}
+
+/// New span to run a debugged request in, along with its id.
+pub fn span() -> (String, Span) {
+    let id = Uuid::new_v4().to_string();
+    let span = tracing::info_span!(DEBUG_SPAN, request = %id);
+    (id, span)
+}
+
+/// Layer writing every trace of the debugged requests to the debug log,
+/// whatever the log level, along with the time spent in each of their spans.
+pub fn layer<S>(config: &DebugConfig) -> io::Result<impl Layer<S>>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    if let Some(folder) = Path::new(&config.log_file).parent() {
+        std::fs::create_dir_all(folder)?;
+    }
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&config.log_file)?;
+
+    Ok(tracing_subscriber::fmt::layer()
+        .with_ansi(false)
+        .with_span_events(FmtSpan::CLOSE)
+        .with_writer(Mutex::new(file))
+        .with_filter(in_debug_span()))
+}
+
+fn in_debug_span<S>() -> impl Filter<S>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    dynamic_filter_fn(|metadata, cx| {
+        metadata.name() == DEBUG_SPAN
+            || cx
+                .lookup_current()
+                .is_some_and(|span| span.scope().any(|span| span.name() == DEBUG_SPAN))
+    })
+}
@@ -0,0 +1,163 @@
+use std::fmt::Display;
+
+use actix_web::{http::StatusCode, HttpResponse, HttpResponseBuilder, ResponseError};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::vm_manager::Error;
+
+/// Error a route answers with, as a `{"code", "message", "details"}` JSON
+/// body
+#[derive(Debug, Serialize)]
+pub struct ApiError {
+    #[serde(skip)]
+    pub status: StatusCode,
+    /// Identifier of the error, which clients can match on unlike the message
+    pub code: &'static str,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<Value>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        ApiError {
+            status,
+            code,
+            message: message.into(),
+            details: None,
+        }
+    }
+
+    pub fn with_details(mut self, details: Value) -> Self {
+        self.details = Some(details);
+        self
+    }
+
+    /// The same error, answered with `status` where the error means
+    /// something else than usual
+    pub fn with_status(mut self, status: StatusCode) -> Self {
+        self.status = status;
+        self
+    }
+}
+
+impl Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponseBuilder::new(self.status).json(self)
+    }
+}
+
+impl From<Error> for ApiError {
+    fn from(error: Error) -> Self {
+        let (status, code, details) = match &error {
+            Error::VmNotFound => (StatusCode::NOT_FOUND, "vm_not_found", None),
+            Error::VolumeNotFound => (StatusCode::NOT_FOUND, "volume_not_found", None),
+            Error::SnapshotNotFound => (StatusCode::NOT_FOUND, "snapshot_not_found", None),
+            Error::ShareLinkNotFound => (StatusCode::NOT_FOUND, "share_link_not_found", None),
+            Error::StackNotFound => (StatusCode::NOT_FOUND, "stack_not_found", None),
+            Error::ServiceNotFound => (StatusCode::NOT_FOUND, "service_not_found", None),
+            Error::FunctionNotFound => (StatusCode::NOT_FOUND, "function_not_found", None),
+            Error::JobNotFound => (StatusCode::NOT_FOUND, "job_not_found", None),
+            // Starting VMs for an unknown tenant is not allowed
+            Error::TenantNotFound => (StatusCode::FORBIDDEN, "tenant_not_found", None),
+            Error::VmAlreadyExists => (StatusCode::CONFLICT, "vm_already_exists", None),
+            Error::VolumeAlreadyExists => (StatusCode::CONFLICT, "volume_already_exists", None),
+            Error::SnapshotAlreadyExists => (StatusCode::CONFLICT, "snapshot_already_exists", None),
+            Error::StackAlreadyExists => (StatusCode::CONFLICT, "stack_already_exists", None),
+            Error::FunctionAlreadyExists => (StatusCode::CONFLICT, "function_already_exists", None),
+            Error::VolumeInUse(vm_id) => (
+                StatusCode::CONFLICT,
+                "volume_in_use",
+                Some(json!({ "vmId": vm_id })),
+            ),
+            Error::VolumeBusy(name) => (
+                StatusCode::CONFLICT,
+                "volume_busy",
+                Some(json!({ "volume": name })),
+            ),
+            Error::PortInUse(port) => (
+                StatusCode::CONFLICT,
+                "port_in_use",
+                Some(json!({ "port": port })),
+            ),
+            Error::PortNotMapped(port) => (
+                StatusCode::BAD_REQUEST,
+                "port_not_mapped",
+                Some(json!({ "port": port })),
+            ),
+            Error::IdempotencyKeyInUse => (StatusCode::CONFLICT, "idempotency_key_in_use", None),
+            Error::IdempotencyKeyReused => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "idempotency_key_reused",
+                None,
+            ),
+            Error::VmAlreadyEnded => (StatusCode::CONFLICT, "vm_already_ended", None),
+            Error::DestroyNotScheduled => (StatusCode::CONFLICT, "destroy_not_scheduled", None),
+            Error::DestroyScheduled => (StatusCode::CONFLICT, "destroy_scheduled", None),
+            Error::VmNotRunning => (StatusCode::CONFLICT, "vm_not_running", None),
+            Error::VmNotPaused => (StatusCode::CONFLICT, "vm_not_paused", None),
+            Error::InvalidOptions(_) => (StatusCode::BAD_REQUEST, "invalid_options", None),
+            Error::ImageError(_) => (StatusCode::UNPROCESSABLE_ENTITY, "bad_image", None),
+            Error::PromotionRejected(_) => {
+                (StatusCode::UNPROCESSABLE_ENTITY, "promotion_rejected", None)
+            }
+            Error::HookFailed(_) => (StatusCode::UNPROCESSABLE_ENTITY, "hook_failed", None),
+            Error::FeatureDisabled(feature) => (
+                StatusCode::FORBIDDEN,
+                "feature_disabled",
+                Some(json!({ "feature": feature })),
+            ),
+            Error::AdmissionDenied(_) => (StatusCode::FORBIDDEN, "admission_denied", None),
+            Error::QuotaExceeded(violation) => (
+                StatusCode::FORBIDDEN,
+                "quota_exceeded",
+                Some(json!(violation)),
+            ),
+            Error::InsufficientCapacity(_) => {
+                (StatusCode::TOO_MANY_REQUESTS, "insufficient_capacity", None)
+            }
+            Error::BudgetExceeded(_) => (StatusCode::TOO_MANY_REQUESTS, "budget_exceeded", None),
+            Error::NoIPAvailable => (StatusCode::INSUFFICIENT_STORAGE, "no_ip_available", None),
+            Error::BootTimeout(failure) => (
+                StatusCode::GATEWAY_TIMEOUT,
+                "boot_timeout",
+                Some(json!({ "reason": failure })),
+            ),
+            Error::ServiceUnhealthy(service, failure) => (
+                StatusCode::GATEWAY_TIMEOUT,
+                "service_unhealthy",
+                Some(json!({ "service": service, "reason": failure })),
+            ),
+            Error::AgentUnavailable(_) => (StatusCode::BAD_GATEWAY, "agent_unavailable", None),
+            Error::ShuttingDown => (StatusCode::SERVICE_UNAVAILABLE, "shutting_down", None),
+            Error::Cordoned => (StatusCode::SERVICE_UNAVAILABLE, "cordoned", None),
+            Error::PolicyUnavailable(_) => {
+                (StatusCode::SERVICE_UNAVAILABLE, "policy_unavailable", None)
+            }
+            Error::VmmNew(_) | Error::VmmConfigure(_) | Error::VmmRun(_) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, "vmm_error", None)
+            }
+            Error::NetSetupError(_) => (StatusCode::INTERNAL_SERVER_ERROR, "network_error", None),
+            Error::VolumeError(_) => (StatusCode::INTERNAL_SERVER_ERROR, "volume_error", None),
+            Error::Other(_) => (StatusCode::INTERNAL_SERVER_ERROR, "internal_error", None),
+        };
+
+        ApiError {
+            status,
+            code,
+            message: error.to_string(),
+            details,
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use serde::Serialize;
+
+use crate::vm_manager::{image_manager::ImageSource, Check};
+
+/// What `/start` would do with a request, and why
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StartExplanation {
+    /// Whether every check passed
+    pub would_start: bool,
+    pub warm_pool: WarmPoolDecision,
+    pub images: Vec<ImageChoice>,
+    /// Host to guest port mappings, with the automatically allocated host
+    /// ports
+    pub port_mapping: Vec<(u16, u16)>,
+    pub bridge: Option<String>,
+    /// Kernel command line, with the defaults of the images and of the
+    /// configuration applied
+    pub boot_args: Option<String>,
+    pub vcpu_count: Option<u8>,
+    pub mem_size_mib: Option<u32>,
+    pub read_only_rootfs: bool,
+    pub checks: Vec<Check>,
+}
+
+impl StartExplanation {
+    /// Explanation of a request refused before anything was planned
+    pub fn refused(checks: Vec<Check>) -> Self {
+        StartExplanation {
+            would_start: false,
+            warm_pool: WarmPoolDecision::default(),
+            images: Vec::new(),
+            port_mapping: Vec::new(),
+            bridge: None,
+            boot_args: None,
+            vcpu_count: None,
+            mem_size_mib: None,
+            read_only_rootfs: false,
+            checks,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageChoice {
+    /// What the image is used as: kernel, initrd or disk
+    pub role: &'static str,
+    #[serde(flatten)]
+    pub source: ImageSource,
+}
+
+/// Whether the VM would be a pre-booted one
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WarmPoolDecision {
+    /// Pool matching the request
+    pub pool: Option<String>,
+    /// Idle VMs in the pool
+    pub idle: usize,
+    /// Whether a VM would be claimed from the pool instead of booted
+    pub claimed: bool,
+    pub reason: String,
+}
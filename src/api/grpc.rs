@@ -0,0 +1,274 @@
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+
+use anyhow::{anyhow, Result};
+use tonic::{transport::Server, Request, Response, Status};
+use tracing::{debug, error, info};
+
+use crate::{
+    api::{
+        auth::{self, Principal, Tokens},
+        policy::Caller,
+        service::LambdoApiServiceTrait,
+    },
+    config::{GrpcConfig, TokenScope},
+    vm_manager::{
+        image_manager::ImageManifest,
+        state::{VMDetails, VMStatus},
+        Error, SimpleSpawn, VMOptionsDTO,
+    },
+};
+
+use proto::lambdo_server::{Lambdo, LambdoServer};
+
+pub mod proto {
+    tonic::include_proto!("lambdo.v1");
+}
+
+/// The gRPC API, answering with the same service as the web API
+pub struct GrpcApi<S> {
+    service: Arc<S>,
+    /// Tokens requests are authenticated with, none to not check them
+    tokens: Option<Arc<Tokens>>,
+}
+
+impl<S: LambdoApiServiceTrait + 'static> GrpcApi<S> {
+    pub fn new(service: Arc<S>, tokens: Option<Arc<Tokens>>) -> Self {
+        GrpcApi { service, tokens }
+    }
+
+    /// Who sent `request`, once its bearer token is checked to have `scope`
+    // Status is what tonic answers calls with
+    #[allow(clippy::result_large_err)]
+    fn caller<T>(&self, request: &Request<T>, scope: Option<TokenScope>) -> Result<Caller, Status> {
+        let principal = match &self.tokens {
+            Some(tokens) => {
+                let token = request
+                    .metadata()
+                    .get("authorization")
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.strip_prefix("Bearer "))
+                    .and_then(|value| tokens.find(value.trim()))
+                    .ok_or_else(|| Status::unauthenticated("missing or invalid bearer token"))?;
+                if let Some(scope) = scope.filter(|scope| !auth::allows(token, *scope)) {
+                    debug!(
+                        "Token {} lacks scope {:?} for a gRPC call",
+                        token.name, scope
+                    );
+                    return Err(Status::permission_denied(format!(
+                        "token {} lacks the {} scope",
+                        token.name,
+                        format!("{:?}", scope).to_lowercase()
+                    )));
+                }
+                Some(Principal::from(token))
+            }
+            None => None,
+        };
+
+        Ok(Caller {
+            address: request
+                .remote_addr()
+                .map(|address| address.ip().to_string()),
+            token: principal.as_ref().map(|principal| principal.name.clone()),
+            namespace: principal
+                .as_ref()
+                .and_then(|principal| principal.namespace.clone()),
+            // Everything is visible to everyone without authentication
+            all_namespaces: principal.is_none_or(|principal| principal.all_namespaces),
+            ..Default::default()
+        })
+    }
+
+    /// Response to a call that started `started`, along with the network of
+    /// the VM
+    async fn start_response(&self, started: (String, HashMap<u16, u16>)) -> proto::StartResponse {
+        let (id, port_mapping) = started;
+        let network = self.service.network(&id).await.ok();
+        let mut port_mapping: Vec<(u16, u16)> = port_mapping.into_iter().collect();
+        port_mapping.sort();
+
+        proto::StartResponse {
+            id,
+            port_mapping: port_mapping.into_iter().map(port).collect(),
+            ip: network.as_ref().and_then(|network| network.ip.clone()),
+            ip6: network.as_ref().and_then(|network| network.ip6.clone()),
+            bridge: network.as_ref().map(|network| network.bridge.clone()),
+            gateway: network.map(|network| network.gateway),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl<S: LambdoApiServiceTrait + 'static> Lambdo for GrpcApi<S> {
+    async fn start(
+        &self,
+        request: Request<proto::StartRequest>,
+    ) -> Result<Response<proto::StartResponse>, Status> {
+        let caller = self.caller(&request, Some(TokenScope::Start))?;
+        let options: VMOptionsDTO = serde_json::from_str(&request.get_ref().options_json)
+            .map_err(|e| Status::invalid_argument(format!("invalid VM options: {}", e)))?;
+        debug!("Received gRPC VM Start request: {:?}", options);
+
+        let started = self.service.start(options, caller).await.map_err(|e| {
+            error!("Error while starting VM: {:?}", e);
+            status(e)
+        })?;
+        info!("VM started with id: {}", started.0);
+        Ok(Response::new(self.start_response(started).await))
+    }
+
+    async fn spawn(
+        &self,
+        request: Request<proto::SpawnRequest>,
+    ) -> Result<Response<proto::StartResponse>, Status> {
+        let caller = self.caller(&request, Some(TokenScope::Start))?;
+        let request = request.into_inner();
+        let rootfs = request
+            .rootfs
+            .ok_or_else(|| Status::invalid_argument("no rootfs"))?;
+        if let Some(port) = request
+            .requested_ports
+            .iter()
+            .find(|port| u16::try_from(**port).is_err())
+        {
+            return Err(Status::invalid_argument(format!("invalid port {}", port)));
+        }
+        let requested_ports = request
+            .requested_ports
+            .iter()
+            .map(|port| *port as u16)
+            .collect();
+        let spawn = SimpleSpawn {
+            rootfs: ImageManifest {
+                id: rootfs.id,
+                location: rootfs.location,
+                sha256: rootfs.sha256,
+            },
+            requested_ports,
+            tenant: request.tenant,
+            ttl_seconds: request.ttl_seconds,
+        };
+        debug!("Received gRPC VM Spawn request: {:?}", spawn);
+
+        let started = self
+            .service
+            .simple_spawn(spawn, caller)
+            .await
+            .map_err(|e| {
+                error!("Error while starting VM: {:?}", e);
+                status(e)
+            })?;
+        info!("VM started with id: {}", started.0);
+        Ok(Response::new(self.start_response(started).await))
+    }
+
+    async fn stop(
+        &self,
+        request: Request<proto::StopRequest>,
+    ) -> Result<Response<proto::StopResponse>, Status> {
+        let caller = self.caller(&request, Some(TokenScope::Stop))?;
+        let id = &request.get_ref().id;
+        debug!("Received gRPC VM Stop request for id: {}", id);
+
+        // VMs of other namespaces are not found, as on the web API
+        let vm = self.service.vm(id).await.map_err(status)?;
+        if caller.scope().is_some_and(|scope| scope != vm.namespace) {
+            return Err(status(Error::VmNotFound));
+        }
+        let destroy_at = self.service.stop(id).await.map_err(status)?;
+        Ok(Response::new(proto::StopResponse { destroy_at }))
+    }
+
+    async fn list_vms(
+        &self,
+        request: Request<proto::ListVmsRequest>,
+    ) -> Result<Response<proto::ListVmsResponse>, Status> {
+        let caller = self.caller(&request, None)?;
+        let vms = self.service.list_vms(&caller).await;
+        Ok(Response::new(proto::ListVmsResponse {
+            vms: vms.into_iter().map(vm).collect(),
+        }))
+    }
+}
+
+/// Serve the gRPC API until its server fails.
+pub async fn serve<S: LambdoApiServiceTrait + 'static>(
+    config: &GrpcConfig,
+    service: Arc<S>,
+    tokens: Option<Arc<Tokens>>,
+) -> Result<()> {
+    let address: SocketAddr = format!("{}:{}", config.host, config.port)
+        .parse()
+        .map_err(|e| {
+            anyhow!(
+                "invalid gRPC address {}:{}: {}",
+                config.host,
+                config.port,
+                e
+            )
+        })?;
+    info!("Starting gRPC server on {}", address);
+    Server::builder()
+        .add_service(LambdoServer::new(GrpcApi::new(service, tokens)))
+        .serve(address)
+        .await
+        .map_err(|e| anyhow!("gRPC server on {} failed: {}", address, e))
+}
+
+/// Status of a call that failed with `error`, with the code closest to the
+/// status the web API answers
+fn status(error: Error) -> Status {
+    match error {
+        Error::VmNotFound => Status::not_found(error.to_string()),
+        Error::InvalidOptions(reason) => Status::invalid_argument(reason),
+        Error::TenantNotFound | Error::FeatureDisabled(_) => {
+            Status::permission_denied(error.to_string())
+        }
+        Error::AdmissionDenied(reason) => Status::permission_denied(reason),
+        Error::QuotaExceeded(violation) => {
+            Status::permission_denied(serde_json::json!(violation).to_string())
+        }
+        Error::InsufficientCapacity(reason) | Error::BudgetExceeded(reason) => {
+            Status::resource_exhausted(reason)
+        }
+        Error::BootTimeout(failure) => {
+            Status::deadline_exceeded(format!("BOOT_TIMEOUT: {}", failure))
+        }
+        Error::PolicyUnavailable(reason) => Status::unavailable(reason),
+        Error::NoIPAvailable | Error::ShuttingDown | Error::Cordoned => {
+            Status::unavailable(error.to_string())
+        }
+        Error::HookFailed(reason) => Status::failed_precondition(reason),
+        _ => Status::internal(error.to_string()),
+    }
+}
+
+fn port((host, guest): (u16, u16)) -> proto::PortMapping {
+    proto::PortMapping {
+        host: host.into(),
+        guest: guest.into(),
+    }
+}
+
+fn vm(vm: VMDetails) -> proto::Vm {
+    let status = match vm.status {
+        VMStatus::Pending => proto::VmStatus::Pending,
+        VMStatus::Running => proto::VmStatus::Running,
+        VMStatus::Paused => proto::VmStatus::Paused,
+        VMStatus::CrashLoopBackOff => proto::VmStatus::CrashLoopBackOff,
+        VMStatus::Exited => proto::VmStatus::Exited,
+        VMStatus::Terminated => proto::VmStatus::Terminated,
+    };
+    proto::Vm {
+        id: vm.id,
+        status: status.into(),
+        ip: vm.ip,
+        port_mapping: vm.port_mapping.into_iter().map(port).collect(),
+        started_at: vm.started_at,
+        expires_at: vm.expires_at,
+        tenant: vm.tenant,
+        namespace: vm.namespace,
+        vcpu_count: vm.vcpu_count.map(u32::from),
+        mem_size_mib: vm.mem_size_mib,
+    }
+}
@@ -0,0 +1,711 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+use crate::{
+    api::artifacts::{Artifact, ArtifactsRequest},
+    config::JobsConfig,
+    vm_manager::{host_memory_mib, now_ms, state::DEFAULT_NAMESPACE, Error, VMOptionsDTO},
+};
+
+/// Command to run to completion in a VM of its own
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobRequest {
+    /// VM the command runs in, destroyed once it exits
+    pub vm: VMOptionsDTO,
+    /// Command run by the guest agent
+    pub command: Vec<String>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// Seconds the command may run, the most the configuration allows if
+    /// unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+    /// Number of replicas of the command, run as an array job whose items
+    /// each get their index in `LAMBDO_JOB_INDEX`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub count: Option<u32>,
+    /// Most items of an array job running at once, all of them if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parallelism: Option<u32>,
+    /// Times the command is run again after it failed or could not be run,
+    /// for each item of an array job
+    #[serde(default)]
+    pub retries: u32,
+    /// Jobs of a higher priority are run first, whatever their tenant
+    #[serde(default)]
+    pub priority: i32,
+    /// Files the guest agent hands over once the command exited, uploaded
+    /// by lambdo for the guest not to need credentials
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub artifacts: Option<ArtifactsRequest>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    /// Waiting for its VM to be started
+    Queued,
+    Running,
+    /// The command exited with 0
+    Succeeded,
+    /// The command exited with another code
+    Failed,
+    /// The command could not be run, for the reason in `error`
+    Errored,
+}
+
+impl JobStatus {
+    pub fn is_finished(self) -> bool {
+        matches!(
+            self,
+            JobStatus::Succeeded | JobStatus::Failed | JobStatus::Errored
+        )
+    }
+}
+
+/// Environment variables telling the items of an array job apart
+pub const JOB_INDEX_ENV: &str = "LAMBDO_JOB_INDEX";
+pub const JOB_COUNT_ENV: &str = "LAMBDO_JOB_COUNT";
+
+/// Run of a command to completion, retried until it succeeds or runs out of
+/// attempts
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobRun {
+    pub status: JobStatus,
+    /// VM the command runs or last ran in
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vm_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stdout: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stderr: Option<String>,
+    /// Why the command could not be run
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Files uploaded once the command exited
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub artifacts: Vec<Artifact>,
+    /// Times the command was run, retries included
+    #[serde(default)]
+    pub attempts: u32,
+    /// Position in the queue of the jobs waiting to be run, from 1, of the
+    /// first queued item for array jobs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queue_position: Option<u32>,
+    /// When the first VM started to be booted, in ms since epoch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<u64>,
+}
+
+impl JobRun {
+    fn queued() -> Self {
+        JobRun {
+            status: JobStatus::Queued,
+            vm_id: None,
+            exit_code: None,
+            stdout: None,
+            stderr: None,
+            error: None,
+            artifacts: Vec::new(),
+            attempts: 0,
+            queue_position: None,
+            started_at: None,
+            finished_at: None,
+        }
+    }
+
+    /// Start an attempt in the VM `vm_id`.
+    pub fn attempt(&mut self, vm_id: Option<String>) {
+        self.status = JobStatus::Running;
+        self.started_at.get_or_insert_with(now_ms);
+        self.attempts += 1;
+        self.vm_id = vm_id;
+    }
+
+    /// Keep the exit code, the output and the uploaded files of the last
+    /// attempt, or why it could not be run.
+    pub fn finish(
+        &mut self,
+        result: &Result<(i32, String, String), Error>,
+        artifacts: Vec<Artifact>,
+    ) {
+        self.artifacts = artifacts;
+        match result {
+            Ok((exit_code, stdout, stderr)) => {
+                self.status = match exit_code {
+                    0 => JobStatus::Succeeded,
+                    _ => JobStatus::Failed,
+                };
+                self.exit_code = Some(*exit_code);
+                self.stdout = Some(stdout.clone());
+                self.stderr = Some(stderr.clone());
+                self.error = None;
+            }
+            Err(e) => {
+                self.status = JobStatus::Errored;
+                self.exit_code = None;
+                self.stdout = None;
+                self.stderr = None;
+                self.error = Some(e.to_string());
+            }
+        }
+        self.finished_at = Some(now_ms());
+    }
+}
+
+/// Replica of the command of an array job
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobItem {
+    /// Index of the item, in `LAMBDO_JOB_INDEX`
+    pub index: u32,
+    #[serde(flatten)]
+    pub run: JobRun,
+}
+
+/// Number of items of an array job by status
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobSummary {
+    pub queued: u32,
+    pub running: u32,
+    pub succeeded: u32,
+    pub failed: u32,
+    pub errored: u32,
+}
+
+impl JobSummary {
+    fn of(items: &[JobItem]) -> Self {
+        let mut summary = JobSummary::default();
+        for item in items {
+            *match item.run.status {
+                JobStatus::Queued => &mut summary.queued,
+                JobStatus::Running => &mut summary.running,
+                JobStatus::Succeeded => &mut summary.succeeded,
+                JobStatus::Failed => &mut summary.failed,
+                JobStatus::Errored => &mut summary.errored,
+            } += 1;
+        }
+        summary
+    }
+
+    /// Status of an array job whose items all finished: failed if a command
+    /// of an item failed, errored if one could not be run.
+    fn status(&self) -> JobStatus {
+        if self.failed > 0 {
+            JobStatus::Failed
+        } else if self.errored > 0 {
+            JobStatus::Errored
+        } else {
+            JobStatus::Succeeded
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Job {
+    pub id: String,
+    /// Namespace of the caller that submitted the job, where its VMs run
+    pub namespace: String,
+    pub command: Vec<String>,
+    /// When the job was submitted, in ms since epoch
+    pub created_at: u64,
+    /// Run of the command, or of the whole array for array jobs
+    #[serde(flatten)]
+    pub run: JobRun,
+    /// Items of an array job, by index
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub items: Vec<JobItem>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub summary: Option<JobSummary>,
+}
+
+impl Job {
+    /// Apply `change` to the item `index` of an array job, finishing the job
+    /// along with its last item.
+    pub fn update_item(&mut self, index: u32, change: impl FnOnce(&mut JobRun)) {
+        let Some(item) = self.items.iter_mut().find(|item| item.index == index) else {
+            return;
+        };
+        change(&mut item.run);
+
+        let summary = JobSummary::of(&self.items);
+        if self.items.iter().all(|item| item.run.status.is_finished()) {
+            self.run.status = summary.status();
+            self.run.error = match summary.errored {
+                0 => None,
+                errored => Some(format!(
+                    "{} of {} items could not be run",
+                    errored,
+                    self.items.len()
+                )),
+            };
+            self.run.finished_at = Some(now_ms());
+        }
+        self.summary = Some(summary);
+    }
+}
+
+/// How long the scheduler waits for capacity to be freed before starting
+/// a VM again, once a start was refused for lack of it
+const CAPACITY_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// What the scheduler knows of a job with items to run
+struct Scheduling {
+    request: Arc<JobRequest>,
+    priority: i32,
+    tenant: String,
+    /// vCPUs and MiB of memory of the VM of each item
+    resources: (u64, u64),
+    parallelism: u32,
+    /// Items of the job being run
+    running: u32,
+}
+
+/// Item of a job waiting to be run, or run again
+#[derive(Debug, Clone)]
+struct Pending {
+    job_id: String,
+    index: Option<u32>,
+    /// Order of submission, the earliest first among equals
+    seq: u64,
+    /// Times the command of the item was already run
+    attempts: u32,
+}
+
+/// Item of a job handed out by the scheduler, to be given back through
+/// `requeue` or `done`
+pub struct Dispatched {
+    pub job_id: String,
+    pub index: Option<u32>,
+    pub request: Arc<JobRequest>,
+    /// Times the command of the item was already run
+    pub attempts: u32,
+    pending: Pending,
+}
+
+struct Inner {
+    jobs: HashMap<String, Job>,
+    scheduling: HashMap<String, Scheduling>,
+    queue: Vec<Pending>,
+    /// vCPUs and MiB of memory of the running items, by tenant
+    usage: HashMap<String, (u64, u64)>,
+    next_seq: u64,
+    /// Until when no item is handed out, the host lacking capacity
+    blocked_until: Option<Instant>,
+}
+
+/// Jobs submitted to lambdo, kept in memory until `retention` seconds after
+/// they finished, along with the scheduler of their items.
+///
+/// Items are handed out by priority, then to the tenant with the smallest
+/// dominant share of the host among the running items, weighted by tenant,
+/// then in order of submission. Once a VM cannot be started for lack of
+/// capacity, nothing is handed out for a while, lower priority items not
+/// getting ahead of the ones waiting.
+pub struct JobStore {
+    retention: u64,
+    weights: HashMap<String, f64>,
+    /// vCPUs and MiB of memory of the host
+    capacity: (u64, u64),
+    inner: Mutex<Inner>,
+    changed: Notify,
+}
+
+impl JobStore {
+    pub fn new(config: &JobsConfig) -> Self {
+        let cpus = std::thread::available_parallelism().map_or(1, |n| n.get()) as u64;
+        let memory = host_memory_mib().unwrap_or(1).max(1);
+        JobStore {
+            retention: config.retention,
+            weights: config.weights.clone(),
+            capacity: (cpus, memory),
+            inner: Mutex::new(Inner {
+                jobs: HashMap::new(),
+                scheduling: HashMap::new(),
+                queue: Vec::new(),
+                usage: HashMap::new(),
+                next_seq: 0,
+                blocked_until: None,
+            }),
+            changed: Notify::new(),
+        }
+    }
+
+    /// Queue `request`, whose VMs each have `resources` vCPUs and MiB of
+    /// memory, returning the job it is run as.
+    pub fn submit(&self, request: JobRequest, resources: (u64, u64)) -> Job {
+        let items: Vec<JobItem> = (0..request.count.unwrap_or(0))
+            .map(|index| JobItem {
+                index,
+                run: JobRun::queued(),
+            })
+            .collect();
+        let job = Job {
+            id: Uuid::new_v4().to_string(),
+            namespace: request
+                .vm
+                .namespace
+                .clone()
+                .unwrap_or_else(|| DEFAULT_NAMESPACE.to_string()),
+            command: request.command.clone(),
+            created_at: now_ms(),
+            run: JobRun::queued(),
+            summary: request.count.map(|_| JobSummary::of(&items)),
+            items,
+        };
+
+        let mut inner = self.inner.lock().unwrap();
+        let expired_before = now_ms().saturating_sub(self.retention.saturating_mul(1000));
+        inner
+            .jobs
+            .retain(|_, job| job.run.finished_at.is_none_or(|at| at >= expired_before));
+        inner.jobs.insert(job.id.clone(), job.clone());
+
+        let indexes: Vec<Option<u32>> = match request.count {
+            Some(count) => (0..count).map(Some).collect(),
+            None => vec![None],
+        };
+        for index in indexes {
+            let seq = inner.next_seq;
+            inner.next_seq += 1;
+            inner.queue.push(Pending {
+                job_id: job.id.clone(),
+                index,
+                seq,
+                attempts: 0,
+            });
+        }
+        inner.scheduling.insert(
+            job.id.clone(),
+            Scheduling {
+                priority: request.priority,
+                tenant: request.vm.tenant.clone().unwrap_or_default(),
+                resources,
+                parallelism: request.parallelism.unwrap_or(u32::MAX).max(1),
+                running: 0,
+                request: Arc::new(request),
+            },
+        );
+        drop(inner);
+        self.changed.notify_one();
+
+        job
+    }
+
+    /// Wait for the next item to run, marking its job as running.
+    pub async fn next(&self) -> Dispatched {
+        loop {
+            if let Some(dispatched) = self.pick() {
+                return dispatched;
+            }
+            // Woken up when something changes, and for the capacity freed by
+            // the VMs of lambdo outside of jobs
+            let _ = tokio::time::timeout(CAPACITY_RETRY_INTERVAL, self.changed.notified()).await;
+        }
+    }
+
+    fn pick(&self) -> Option<Dispatched> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner
+            .blocked_until
+            .is_some_and(|until| Instant::now() < until)
+        {
+            return None;
+        }
+        inner.blocked_until = None;
+
+        let position = {
+            let order = self.order(&inner);
+            order.into_iter().find(|&position| {
+                let pending = &inner.queue[position];
+                inner
+                    .scheduling
+                    .get(&pending.job_id)
+                    .is_some_and(|job| job.running < job.parallelism)
+            })?
+        };
+        let pending = inner.queue.remove(position);
+        let scheduling = inner.scheduling.get_mut(&pending.job_id)?;
+        scheduling.running += 1;
+        let (request, tenant, resources) = (
+            scheduling.request.clone(),
+            scheduling.tenant.clone(),
+            scheduling.resources,
+        );
+        let usage = inner.usage.entry(tenant).or_default();
+        usage.0 += resources.0;
+        usage.1 += resources.1;
+        if let Some(job) = inner.jobs.get_mut(&pending.job_id) {
+            if job.run.status == JobStatus::Queued {
+                job.run.status = JobStatus::Running;
+                job.run.started_at.get_or_insert_with(now_ms);
+            }
+        }
+
+        Some(Dispatched {
+            job_id: pending.job_id.clone(),
+            index: pending.index,
+            request,
+            attempts: pending.attempts,
+            pending,
+        })
+    }
+
+    /// Positions in the queue, in the order the items are handed out.
+    fn order(&self, inner: &Inner) -> Vec<usize> {
+        let share = |tenant: &str| {
+            let (vcpus, memory) = inner.usage.get(tenant).copied().unwrap_or_default();
+            let dominant =
+                (vcpus as f64 / self.capacity.0 as f64).max(memory as f64 / self.capacity.1 as f64);
+            dominant
+                / self
+                    .weights
+                    .get(tenant)
+                    .copied()
+                    .unwrap_or(1.0)
+                    .max(f64::EPSILON)
+        };
+        let mut order: Vec<(usize, i32, f64, u64)> = inner
+            .queue
+            .iter()
+            .enumerate()
+            .filter_map(|(position, pending)| {
+                let job = inner.scheduling.get(&pending.job_id)?;
+                Some((position, job.priority, share(&job.tenant), pending.seq))
+            })
+            .collect();
+        order.sort_by(|a, b| b.1.cmp(&a.1).then(a.2.total_cmp(&b.2)).then(a.3.cmp(&b.3)));
+        order.into_iter().map(|(position, ..)| position).collect()
+    }
+
+    /// Give back an item that could not be run, to be handed out again in
+    /// its place, after a while if the host lacks capacity, and otherwise as
+    /// a retry.
+    pub fn requeue(&self, dispatched: Dispatched, lacks_capacity: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        self.release(&mut inner, &dispatched.job_id);
+        let mut pending = dispatched.pending;
+        if lacks_capacity {
+            inner.blocked_until = Some(Instant::now() + CAPACITY_RETRY_INTERVAL);
+        } else {
+            pending.attempts += 1;
+        }
+        inner.queue.push(pending);
+        drop(inner);
+        self.changed.notify_one();
+    }
+
+    /// Give back an item that finished.
+    pub fn done(&self, dispatched: Dispatched) {
+        let mut inner = self.inner.lock().unwrap();
+        self.release(&mut inner, &dispatched.job_id);
+        let job_id = &dispatched.job_id;
+        let idle = inner
+            .scheduling
+            .get(job_id)
+            .is_some_and(|job| job.running == 0);
+        if idle && !inner.queue.iter().any(|pending| &pending.job_id == job_id) {
+            inner.scheduling.remove(job_id);
+        }
+        drop(inner);
+        self.changed.notify_one();
+    }
+
+    fn release(&self, inner: &mut Inner, job_id: &str) {
+        let Some(job) = inner.scheduling.get_mut(job_id) else {
+            return;
+        };
+        job.running = job.running.saturating_sub(1);
+        let (tenant, resources) = (job.tenant.clone(), job.resources);
+        if let Some(usage) = inner.usage.get_mut(&tenant) {
+            usage.0 = usage.0.saturating_sub(resources.0);
+            usage.1 = usage.1.saturating_sub(resources.1);
+        }
+    }
+
+    /// Job `id`, along with the positions of its queued items in the order
+    /// they will be handed out, from 1.
+    pub fn get(&self, id: &str) -> Option<Job> {
+        let inner = self.inner.lock().unwrap();
+        let mut job = inner.jobs.get(id).cloned()?;
+        for (rank, position) in self.order(&inner).into_iter().enumerate() {
+            let pending = &inner.queue[position];
+            if pending.job_id != id {
+                continue;
+            }
+            let queue_position = Some(rank as u32 + 1);
+            match pending.index {
+                Some(index) => {
+                    if let Some(item) = job.items.iter_mut().find(|item| item.index == index) {
+                        item.run.queue_position = queue_position;
+                    }
+                    job.run.queue_position.get_or_insert(rank as u32 + 1);
+                }
+                None => job.run.queue_position = queue_position,
+            }
+        }
+        Some(job)
+    }
+
+    /// Apply `change` to the job `id`, returning it as changed.
+    pub fn update(&self, id: &str, change: impl FnOnce(&mut Job)) -> Option<Job> {
+        let mut inner = self.inner.lock().unwrap();
+        let job = inner.jobs.get_mut(id)?;
+        change(job);
+        Some(job.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Store of a host with 8 vCPUs and 8 GiB of memory
+    fn store(weights: &[(&str, f64)]) -> JobStore {
+        let mut config: JobsConfig = serde_yaml::from_str("{}").unwrap();
+        config.weights = weights
+            .iter()
+            .map(|(tenant, weight)| (tenant.to_string(), *weight))
+            .collect();
+        let mut store = JobStore::new(&config);
+        store.capacity = (8, 8192);
+        store
+    }
+
+    fn request(tenant: &str, priority: i32) -> JobRequest {
+        serde_json::from_value(serde_json::json!({
+            "vm": {
+                "boot": { "kernel": { "id": "vmlinux", "location": "vmlinux" } },
+                "disks": [],
+                "network": {},
+                "tenant": tenant,
+            },
+            "command": ["true"],
+            "priority": priority,
+        }))
+        .unwrap()
+    }
+
+    /// Submit a job of `tenant` whose VMs take a quarter of the host
+    fn submit(store: &JobStore, tenant: &str, priority: i32) -> String {
+        store.submit(request(tenant, priority), (2, 2048)).id
+    }
+
+    fn next_job(store: &JobStore) -> Option<String> {
+        store.pick().map(|dispatched| dispatched.job_id)
+    }
+
+    #[test]
+    fn hands_out_higher_priorities_first() {
+        let store = store(&[]);
+        let low = submit(&store, "a", 0);
+        let high = submit(&store, "b", 5);
+
+        assert_eq!(next_job(&store), Some(high));
+        assert_eq!(next_job(&store), Some(low));
+        assert_eq!(next_job(&store), None);
+    }
+
+    #[test]
+    fn hands_out_to_the_tenant_with_the_smallest_dominant_share() {
+        let store = store(&[]);
+        let first = submit(&store, "a", 0);
+        let second = submit(&store, "a", 0);
+        let other = submit(&store, "b", 0);
+
+        assert_eq!(next_job(&store), Some(first));
+        // Ahead of the earlier job of a tenant already running one
+        assert_eq!(next_job(&store), Some(other));
+        assert_eq!(next_job(&store), Some(second));
+    }
+
+    #[test]
+    fn weighs_the_shares_of_tenants() {
+        let store = store(&[("a", 4.0)]);
+        let a = submit(&store, "a", 0);
+        let b = submit(&store, "b", 0);
+        let later_b = submit(&store, "b", 0);
+        let later_a = submit(&store, "a", 0);
+        assert_eq!(next_job(&store), Some(a));
+        assert_eq!(next_job(&store), Some(b));
+
+        // Both run a quarter of the host, which weighs less for a
+        assert_eq!(next_job(&store), Some(later_a));
+        assert_eq!(next_job(&store), Some(later_b));
+    }
+
+    #[test]
+    fn runs_at_most_parallelism_items_of_a_job() {
+        let store = store(&[]);
+        let mut request = request("a", 0);
+        request.count = Some(3);
+        request.parallelism = Some(2);
+        store.submit(request, (1, 512));
+
+        let first = store.pick().unwrap();
+        let second = store.pick().unwrap();
+        assert_eq!((first.index, second.index), (Some(0), Some(1)));
+        assert!(store.pick().is_none());
+
+        store.done(first);
+        assert_eq!(store.pick().unwrap().index, Some(2));
+    }
+
+    #[test]
+    fn backs_off_once_capacity_lacks() {
+        let store = store(&[]);
+        let id = submit(&store, "a", 0);
+
+        let dispatched = store.pick().unwrap();
+        store.requeue(dispatched, true);
+        assert!(store.pick().is_none());
+
+        // Not counted as an attempt
+        store.inner.lock().unwrap().blocked_until = None;
+        let dispatched = store.pick().unwrap();
+        assert_eq!(
+            (dispatched.job_id.as_str(), dispatched.attempts),
+            (id.as_str(), 0)
+        );
+
+        store.requeue(dispatched, false);
+        assert_eq!(store.pick().unwrap().attempts, 1);
+    }
+
+    #[test]
+    fn reports_the_positions_in_the_queue() {
+        let store = store(&[]);
+        let mut array = request("a", 0);
+        array.count = Some(2);
+        let array = store.submit(array, (1, 512)).id;
+        let urgent = submit(&store, "b", 5);
+
+        assert_eq!(store.get(&urgent).unwrap().run.queue_position, Some(1));
+        let job = store.get(&array).unwrap();
+        assert_eq!(job.run.queue_position, Some(2));
+        let positions: Vec<Option<u32>> = job
+            .items
+            .iter()
+            .map(|item| item.run.queue_position)
+            .collect();
+        assert_eq!(positions, vec![Some(2), Some(3)]);
+
+        assert_eq!(next_job(&store), Some(urgent));
+        assert_eq!(store.get(&array).unwrap().run.queue_position, Some(1));
+    }
+}
@@ -1,39 +1,246 @@
+pub mod artifacts;
+pub mod auth;
+pub mod debug;
+pub mod error;
+pub mod explain;
+pub mod grpc;
+pub mod jobs;
+pub mod plugins;
+pub mod policy;
+pub mod queue;
+pub mod selftest;
 pub mod service;
+pub mod sigv4;
+pub mod stacks;
+pub mod support_bundle;
+pub mod tls;
 
-use actix_web::{delete, http::StatusCode, post, web, HttpResponseBuilder, Responder};
+use actix_web::{
+    delete, get,
+    http::{header, StatusCode},
+    patch, post, put,
+    web::{self, Bytes},
+    Either, HttpMessage, HttpRequest, HttpResponse, HttpResponseBuilder, Responder, ResponseError,
+};
+use futures::{stream, Future, Stream};
+use openssl::sha::sha256;
 use serde::{Deserialize, Serialize};
-use tracing::{debug, error, info};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{debug, error, info, Instrument};
 
 use crate::{
-    api::service::{LambdoApiService, LambdoApiServiceTrait},
-    vm_manager::{Error, SimpleSpawn, VMOptionsDTO},
+    api::{
+        auth::VmId,
+        error::ApiError,
+        jobs::JobRequest,
+        policy::Caller,
+        service::{LambdoApiService, LambdoApiServiceTrait},
+        stacks::StackDTO,
+    },
+    config::NamespaceConfig,
+    vm_manager::{
+        agent::AgentRequest,
+        events::Event,
+        idempotency::StartedVm,
+        image_manager::promotion::PromotionDTO,
+        volume_manager::{CloneVolumeDTO, CreateSnapshotDTO, CreateVolumeDTO},
+        AdoptOptions, Error, ExportOptions, FunctionDTO, PortsUpdateDTO, RunRequest, ShareLinkDTO,
+        SimpleSpawn, VMManagerTrait, VMOptionsDTO,
+    },
+};
+
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::Infallible,
+    mem::take,
+    sync::Arc,
+    time::Duration,
 };
 
-use std::{collections::HashMap, error::Error as STDError};
+/// Interval of the comments keeping idle event streams open
+const SSE_KEEP_ALIVE: Duration = Duration::from_secs(15);
+/// Header of the start requests that start at most one VM however many
+/// times they are sent
+const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+/// Header of the responses answering a request with a known idempotency key
+const IDEMPOTENT_REPLAYED_HEADER: &str = "idempotent-replayed";
 
 #[derive(Serialize, Deserialize)]
 pub struct StartResponse {
     pub id: String,
     pub port_mapping: Vec<(u16, u16)>,
+    /// Address of the guest, which the host reaches directly on the bridge
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip6: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bridge: Option<String>,
+    /// Address of the bridge, the gateway of the guest
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gateway: Option<String>,
 }
 
 impl From<(String, HashMap<u16, u16>)> for StartResponse {
     fn from(value: (String, HashMap<u16, u16>)) -> Self {
         let (id, port_mapping) = value;
         let port_mapping = port_mapping.into_iter().collect();
-        StartResponse { id, port_mapping }
+        StartResponse {
+            id,
+            port_mapping,
+            ip: None,
+            ip6: None,
+            bridge: None,
+            gateway: None,
+        }
+    }
+}
+
+/// Response to a request that started `started`, along with the network of
+/// the VM
+async fn start_response(
+    service: &LambdoApiService,
+    started: (String, HashMap<u16, u16>),
+) -> StartResponse {
+    let network = service.network(&started.0).await.ok();
+    let mut response = StartResponse::from(started);
+    if let Some(network) = network {
+        response.ip = network.ip;
+        response.ip6 = network.ip6;
+        response.bridge = Some(network.bridge);
+        response.gateway = Some(network.gateway);
+    }
+    response
+}
+
+/// Start a VM with `start`, unless a request with the same idempotency key
+/// already did, in which case the VM it started is returned along with
+/// `true`
+async fn idempotent(
+    service: &LambdoApiService,
+    request: &HttpRequest,
+    body: &impl Serialize,
+    start: impl Future<Output = Result<StartedVm, Error>>,
+) -> (Result<StartedVm, Error>, bool) {
+    let Some(key) = request.headers().get(IDEMPOTENCY_KEY_HEADER) else {
+        return (start.await, false);
+    };
+    let key = match key.to_str() {
+        Ok(key) if !key.is_empty() && key.len() <= 255 => key,
+        _ => {
+            let reason = "idempotency key must be 1 to 255 visible ASCII characters";
+            return (Err(Error::InvalidOptions(reason.to_string())), false);
+        }
+    };
+    let namespace = caller(request).namespace().to_string();
+    // Keys of maps are sorted once in a value, for equal bodies to match
+    let body = serde_json::to_value(body).unwrap_or_default();
+    let fingerprint = hex::encode(sha256(format!("{} {}", request.path(), body).as_bytes()));
+
+    match service
+        .vm_manager
+        .claim_idempotency_key(&namespace, key, &fingerprint)
+        .await
+    {
+        Ok(Some(started)) => {
+            info!("Answering idempotency key {} with VM {}", key, started.0);
+            return (Ok(started), true);
+        }
+        Ok(None) => {}
+        Err(e) => return (Err(e), false),
+    }
+    let claimed = ClaimedKey {
+        vm_manager: service.vm_manager.clone(),
+        namespace,
+        key: key.to_string(),
+        settled: false,
+    };
+    let result = start.await;
+    claimed.settle(result.as_ref().ok().cloned()).await;
+    (result, false)
+}
+
+/// Idempotency key claimed by a request, released if the request is dropped
+/// before settling it, as when its client goes away
+struct ClaimedKey {
+    vm_manager: Arc<dyn VMManagerTrait>,
+    namespace: String,
+    key: String,
+    settled: bool,
+}
+
+impl ClaimedKey {
+    async fn settle(mut self, started: Option<StartedVm>) {
+        self.vm_manager
+            .settle_idempotency_key(&self.namespace, &self.key, started)
+            .await;
+        self.settled = true;
+    }
+}
+
+impl Drop for ClaimedKey {
+    fn drop(&mut self) {
+        if self.settled {
+            return;
+        }
+        let vm_manager = self.vm_manager.clone();
+        let (namespace, key) = (take(&mut self.namespace), take(&mut self.key));
+        tokio::spawn(async move {
+            debug!("Releasing idempotency key {} of a dropped request", key);
+            vm_manager
+                .settle_idempotency_key(&namespace, &key, None)
+                .await;
+        });
+    }
+}
+
+/// Who sent `request`, as far as the HTTP server knows
+fn caller(request: &HttpRequest) -> Caller {
+    let principal = request.extensions().get::<auth::Principal>().cloned();
+    Caller {
+        address: request.peer_addr().map(|address| address.ip().to_string()),
+        token: principal.as_ref().map(|principal| principal.name.clone()),
+        namespace: principal
+            .as_ref()
+            .and_then(|principal| principal.namespace.clone()),
+        // Everything is visible to everyone without authentication
+        all_namespaces: principal.is_none_or(|principal| principal.all_namespaces),
+        ..Default::default()
     }
 }
 
 #[post("/start")]
 pub async fn start_route(
+    request: HttpRequest,
     vm_options: web::Json<VMOptionsDTO>,
     api_service: web::Data<LambdoApiService>,
-) -> Result<impl Responder, Box<dyn STDError>> {
+) -> Result<impl Responder, ApiError> {
     debug!("Received HTTP VM Start request body: {:?}", vm_options);
 
     let service = api_service.get_ref();
-    let result = service.start(vm_options.into_inner()).await;
+    let vm_options = vm_options.into_inner();
+    let start = service.start(vm_options.clone(), caller(&request));
+    let ((result, replayed), debug_id) =
+        match debug::debugging(service.config.api.debug.as_ref(), &request) {
+            debug::Debugging::Off => (
+                idempotent(service, &request, &vm_options, start).await,
+                None,
+            ),
+            debug::Debugging::On => {
+                let (id, span) = debug::span();
+                info!("Debugging VM start request {}", id);
+                let result =
+                    idempotent(service, &request, &vm_options, start.instrument(span)).await;
+                (result, Some(id))
+            }
+            debug::Debugging::Denied => {
+                return Err(ApiError::new(
+                    StatusCode::FORBIDDEN,
+                    "debug_denied",
+                    "debugging requests requires the admin token",
+                ));
+            }
+        };
 
     if let Ok(result) = result.as_ref() {
         info!("VM started with id: {}", result.0);
@@ -41,20 +248,48 @@ pub async fn start_route(
         error!("Error while starting VM: {:?}", result);
     }
 
-    let response = result?;
+    let mut response = match result {
+        Ok(response) => HttpResponseBuilder::new(StatusCode::OK)
+            .insert_header((IDEMPOTENT_REPLAYED_HEADER, replayed.to_string()))
+            .json(start_response(service, response).await),
+        Err(e) => ApiError::from(e).error_response(),
+    };
+    // Made of a UUID, which is a valid header value
+    if let Some(Ok(id)) = debug_id.map(|id| header::HeaderValue::from_str(&id)) {
+        response
+            .headers_mut()
+            .insert(header::HeaderName::from_static(debug::DEBUG_ID_HEADER), id);
+    }
+    Ok(response)
+}
 
-    Ok(web::Json(StartResponse::from(response)))
+#[get("/explain/start")]
+pub async fn explain_start_route(
+    http_request: HttpRequest,
+    request: web::Json<VMOptionsDTO>,
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    debug!("Received HTTP start explain request body: {:?}", request);
+
+    let explanation = api_service
+        .get_ref()
+        .explain_start(request.into_inner(), &caller(&http_request))
+        .await;
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(explanation))
 }
 
 #[post("/spawn")]
 pub async fn simple_spawn_route(
+    request: HttpRequest,
     vm_options: web::Json<SimpleSpawn>,
     api_service: web::Data<LambdoApiService>,
-) -> Result<impl Responder, Box<dyn STDError>> {
+) -> Result<impl Responder, ApiError> {
     debug!("Received HTTP VM Start request body: {:?}", vm_options);
 
     let service = api_service.get_ref();
-    let result = service.simple_spawn(vm_options.into_inner()).await;
+    let vm_options = vm_options.into_inner();
+    let start = service.simple_spawn(vm_options.clone(), caller(&request));
+    let (result, replayed) = idempotent(service, &request, &vm_options, start).await;
 
     if let Ok(result) = result.as_ref() {
         info!("VM started with id: {}", result.0);
@@ -62,25 +297,991 @@ pub async fn simple_spawn_route(
         error!("Error while starting VM: {:?}", result);
     }
 
-    let response = result?;
+    Ok(HttpResponseBuilder::new(StatusCode::OK)
+        .insert_header((IDEMPOTENT_REPLAYED_HEADER, replayed.to_string()))
+        .json(start_response(service, result?).await))
+}
+
+#[post("/run")]
+pub async fn run_route(
+    http_request: HttpRequest,
+    request: web::Json<RunRequest>,
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    debug!(
+        "Received HTTP run request for language: {}",
+        request.language
+    );
+
+    let result = api_service
+        .get_ref()
+        .run(request.into_inner(), caller(&http_request))
+        .await
+        .inspect_err(|e| error!("Error while running code: {:?}", e))?;
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(result))
+}
+
+#[post("/jobs")]
+pub async fn submit_job_route(
+    request: HttpRequest,
+    job: web::Json<JobRequest>,
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    debug!("Received HTTP job request: {:?}", job);
+
+    let caller = caller(&request);
+    let job = api_service
+        .get_ref()
+        .submit_job(job.into_inner(), caller)
+        .await
+        .inspect_err(|e| error!("Error while submitting job: {:?}", e))?;
+    Ok(HttpResponseBuilder::new(StatusCode::ACCEPTED).json(job))
+}
+
+#[get("/jobs/{id}")]
+pub async fn job_route(
+    request: HttpRequest,
+    id: web::Path<String>,
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    debug!("Received HTTP job status request for id: {}", id);
+
+    let job = api_service
+        .get_ref()
+        .job(&id.into_inner(), &caller(&request))
+        .await?;
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(job))
+}
+
+#[post("/functions")]
+pub async fn register_function_route(
+    request: web::Json<FunctionDTO>,
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    debug!("Received HTTP function registration: {:?}", request);
+
+    api_service
+        .get_ref()
+        .register_function(request.into_inner())
+        .await?;
+    Ok(HttpResponseBuilder::new(StatusCode::CREATED).finish())
+}
+
+#[post("/functions/{name}/invoke")]
+pub async fn invoke_function_route(
+    request: HttpRequest,
+    name: web::Path<String>,
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    debug!("Received HTTP invocation of function: {}", name);
 
-    Ok(web::Json(StartResponse::from(response)))
+    let response = api_service
+        .get_ref()
+        .invoke_function(&name, caller(&request))
+        .await
+        .inspect_err(|e| error!("Error while invoking function {}: {:?}", name, e))?;
+    Ok(HttpResponseBuilder::new(StatusCode::OK)
+        .json(start_response(api_service.get_ref(), response).await))
 }
 
 #[delete("/destroy/{id}")]
 pub async fn stop_route(
-    id: web::Path<String>,
+    id: VmId,
     api_service: web::Data<LambdoApiService>,
-) -> Result<impl Responder, Box<dyn STDError>> {
+) -> Result<impl Responder, ApiError> {
     debug!("Received HTTP VM Stop request for id: {}", id);
 
     let service = api_service.get_ref();
 
-    match service.stop(&id.into_inner()).await {
-        Ok(_) => Ok(HttpResponseBuilder::new(StatusCode::NO_CONTENT)),
-        Err(e) => match e {
-            Error::VmNotFound => Ok(HttpResponseBuilder::new(StatusCode::NOT_FOUND)),
-            _ => Err(e.into()),
-        },
+    match service.stop(&id.into_inner()).await? {
+        None => Ok(HttpResponseBuilder::new(StatusCode::NO_CONTENT).finish()),
+        Some(destroy_at) => Ok(HttpResponseBuilder::new(StatusCode::ACCEPTED)
+            .json(serde_json::json!({ "destroy_at": destroy_at }))),
+    }
+}
+
+#[post("/vms/{id}/undo-destroy")]
+pub async fn undo_destroy_route(
+    id: VmId,
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    debug!("Received HTTP VM undo destroy request for id: {}", id);
+
+    let service = api_service.get_ref();
+
+    service.undo_stop(&id.into_inner()).await?;
+    Ok(HttpResponseBuilder::new(StatusCode::NO_CONTENT))
+}
+
+#[post("/vms/{id}/pause")]
+pub async fn pause_route(
+    id: VmId,
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    debug!("Received HTTP VM pause request for id: {}", id);
+
+    let service = api_service.get_ref();
+
+    service.pause(&id.into_inner()).await?;
+    Ok(HttpResponseBuilder::new(StatusCode::NO_CONTENT))
+}
+
+#[post("/vms/{id}/resume")]
+pub async fn resume_route(
+    id: VmId,
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    debug!("Received HTTP VM resume request for id: {}", id);
+
+    let service = api_service.get_ref();
+
+    service.resume(&id.into_inner()).await?;
+    Ok(HttpResponseBuilder::new(StatusCode::NO_CONTENT))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct EventsQuery {
+    /// Only return events with a greater id
+    #[serde(default)]
+    pub since: u64,
+    /// Only return events of this type
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+}
+
+#[get("/events")]
+pub async fn events_route(
+    request: HttpRequest,
+    query: web::Query<EventsQuery>,
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    debug!("Received HTTP events request: {:?}", query);
+
+    let query = query.into_inner();
+    let scope = caller(&request).scope().map(str::to_string);
+    let accepts_sse = request
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/event-stream"));
+    if accepts_sse {
+        // Reconnecting clients resume after the last event they received
+        let since = request
+            .headers()
+            .get("Last-Event-ID")
+            .and_then(|id| id.to_str().ok()?.parse().ok())
+            .unwrap_or(query.since);
+        return Ok(Either::Right(
+            HttpResponse::Ok()
+                .content_type("text/event-stream")
+                .insert_header((header::CACHE_CONTROL, "no-cache"))
+                // Compression would hold events back until a block is full
+                .insert_header((header::CONTENT_ENCODING, "identity"))
+                .streaming(event_stream(api_service, since, query.kind, scope)),
+        ));
+    }
+
+    let service = api_service.get_ref();
+    let mut events = service.events(query.since, query.kind).await;
+    events.retain(|event| event.is_visible_in(scope.as_deref()));
+
+    if !service.config.api.network.stream_responses {
+        return Ok(Either::Left(Either::Left(web::Json(events))));
+    }
+
+    Ok(Either::Left(Either::Right(
+        HttpResponse::Ok()
+            .content_type("application/json")
+            .streaming(json_array_stream(events)),
+    )))
+}
+
+struct EventStreamState {
+    service: web::Data<LambdoApiService>,
+    receiver: broadcast::Receiver<Event>,
+    backlog: VecDeque<Event>,
+    last_id: u64,
+    kind: Option<String>,
+    /// Namespace the subscriber is restricted to, if it is
+    scope: Option<String>,
+}
+
+/// Server-sent events for the stored events newer than `since`, followed by
+/// the events recorded from now on, of the namespace `scope` if set.
+fn event_stream(
+    service: web::Data<LambdoApiService>,
+    since: u64,
+    kind: Option<String>,
+    scope: Option<String>,
+) -> impl Stream<Item = Result<Bytes, Infallible>> {
+    // Subscribing before reading the stored events leaves no gap between
+    // them, duplicates are skipped by id
+    let receiver = service.subscribe_events();
+    let state = EventStreamState {
+        service,
+        receiver,
+        backlog: VecDeque::new(),
+        last_id: since,
+        kind,
+        scope,
+    };
+
+    stream::unfold((state, true), |(mut state, first)| async move {
+        if first {
+            let events = state
+                .service
+                .events(state.last_id, state.kind.clone())
+                .await;
+            state.backlog = events.into();
+        }
+
+        loop {
+            let event = match state.backlog.pop_front() {
+                Some(event) => event,
+                None => match tokio::time::timeout(SSE_KEEP_ALIVE, state.receiver.recv()).await {
+                    Ok(Ok(event)) => event,
+                    Ok(Err(RecvError::Lagged(_))) => {
+                        // Catch up from the store
+                        let events = state
+                            .service
+                            .events(state.last_id, state.kind.clone())
+                            .await;
+                        state.backlog = events.into();
+                        continue;
+                    }
+                    Ok(Err(RecvError::Closed)) => return None,
+                    Err(_) => {
+                        return Some((Ok(Bytes::from_static(b": keep-alive\n\n")), (state, false)))
+                    }
+                },
+            };
+
+            if event.id <= state.last_id
+                || state.kind.as_ref().is_some_and(|kind| *kind != event.kind)
+            {
+                continue;
+            }
+            state.last_id = event.id;
+            if !event.is_visible_in(state.scope.as_deref()) {
+                continue;
+            }
+
+            let data = serde_json::to_string(&event).unwrap_or_default();
+            let message = format!(
+                "id: {}\nevent: {}\ndata: {}\n\n",
+                event.id, event.kind, data
+            );
+            return Some((Ok(Bytes::from(message)), (state, false)));
+        }
+    })
+}
+
+/// Serialize `items` as a JSON array, one element at a time, so that large
+/// lists are sent as they are encoded rather than in a single buffer.
+fn json_array_stream<T: Serialize>(
+    items: Vec<T>,
+) -> impl Stream<Item = Result<Bytes, serde_json::Error>> {
+    let len = items.len();
+    let elements = items.into_iter().enumerate().map(move |(i, item)| {
+        let mut chunk = if i == 0 { b"[".to_vec() } else { Vec::new() };
+        serde_json::to_writer(&mut chunk, &item)?;
+        chunk.push(if i + 1 == len { b']' } else { b',' });
+        Ok(Bytes::from(chunk))
+    });
+
+    let empty = (len == 0).then(|| Ok(Bytes::from_static(b"[]")));
+    stream::iter(elements.chain(empty))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FeatureStatus {
+    /// Whether the feature is enabled in the configuration
+    pub enabled: bool,
+    /// Whether the feature is implemented by this build
+    pub available: bool,
+}
+
+#[get("/capabilities")]
+pub async fn capabilities_route(
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    debug!("Received HTTP capabilities request");
+
+    let features = api_service.get_ref().capabilities().await;
+
+    Ok(web::Json(serde_json::json!({ "features": features })))
+}
+
+#[post("/images/samples")]
+pub async fn fetch_samples_route(
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    debug!("Received HTTP sample images fetch request");
+
+    let images = api_service
+        .get_ref()
+        .fetch_samples()
+        .await
+        .inspect_err(|e| error!("Error while fetching sample images: {:?}", e))?;
+    Ok(HttpResponseBuilder::new(StatusCode::CREATED).json(images))
+}
+
+#[post("/images/promote")]
+pub async fn promote_image_route(
+    request: web::Json<PromotionDTO>,
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    debug!("Received HTTP image promotion request: {:?}", request);
+
+    let promotion = api_service
+        .get_ref()
+        .promote_image(request.into_inner())
+        .await
+        .inspect_err(|e| error!("Error while promoting image: {:?}", e))?;
+    Ok(HttpResponseBuilder::new(StatusCode::CREATED).json(promotion))
+}
+
+#[post("/admin/verify-images")]
+pub async fn verify_images_route(
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    debug!("Received HTTP image audit request");
+
+    let report = api_service.get_ref().verify_images().await?;
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(report))
+}
+
+#[get("/admin/support-bundle")]
+pub async fn support_bundle_route(
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    debug!("Received HTTP support bundle request");
+
+    let service = api_service.get_ref();
+    let bundle = service.support_bundle().await?;
+
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    Ok(HttpResponseBuilder::new(StatusCode::OK)
+        .content_type("application/gzip")
+        // Already compressed
+        .insert_header((header::CONTENT_ENCODING, "identity"))
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            format!(
+                "attachment; filename=\"lambdo-support-{}.tar.gz\"",
+                timestamp
+            ),
+        ))
+        .body(bundle))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CordonRequest {
+    pub reason: Option<String>,
+}
+
+#[post("/admin/cordon")]
+pub async fn cordon_route(
+    request: Option<web::Json<CordonRequest>>,
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    debug!("Received HTTP cordon request: {:?}", request);
+
+    let reason = request.and_then(|request| request.into_inner().reason);
+    let cordon = api_service.get_ref().cordon(reason).await;
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(cordon))
+}
+
+#[delete("/admin/cordon")]
+pub async fn uncordon_route(
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    debug!("Received HTTP uncordon request");
+
+    if api_service.get_ref().uncordon().await {
+        Ok(HttpResponseBuilder::new(StatusCode::NO_CONTENT).finish())
+    } else {
+        Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "not_cordoned",
+            "Node is not cordoned",
+        ))
+    }
+}
+
+#[post("/admin/selftest")]
+pub async fn selftest_route(
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    debug!("Received HTTP self test request");
+
+    let report = api_service.get_ref().selftest().await;
+    let code = match report.passed {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    Ok(HttpResponseBuilder::new(code).json(report))
+}
+
+#[get("/readyz")]
+pub async fn readyz_route(
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    let status = api_service.get_ref().node_status().await;
+    let code = match status.ready {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    Ok(HttpResponseBuilder::new(code).json(status))
+}
+
+#[post("/vms/adopt")]
+pub async fn adopt_route(
+    request: HttpRequest,
+    options: web::Json<AdoptOptions>,
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    debug!("Received HTTP VM adopt request body: {:?}", options);
+
+    let service = api_service.get_ref();
+
+    let response = service
+        .adopt(options.into_inner(), &caller(&request))
+        .await
+        .inspect_err(|e| error!("Error while adopting VM: {:?}", e))?;
+    info!("VM adopted with id: {}", response.0);
+    Ok(HttpResponseBuilder::new(StatusCode::CREATED).json(start_response(service, response).await))
+}
+
+#[get("/vms")]
+pub async fn list_vms_route(
+    request: HttpRequest,
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    debug!("Received HTTP VM list request");
+
+    let vms = api_service.get_ref().list_vms(&caller(&request)).await;
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(vms))
+}
+
+#[get("/vms/{id}")]
+pub async fn vm_route(
+    id: VmId,
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    debug!("Received HTTP VM request for id: {}", id);
+
+    let service = api_service.get_ref();
+
+    let vm = service.vm(&id.into_inner()).await?;
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(vm))
+}
+
+#[get("/tenants/{tenant}/usage")]
+pub async fn tenant_usage_route(
+    request: HttpRequest,
+    tenant: web::Path<String>,
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    debug!("Received HTTP usage request for tenant: {}", tenant);
+
+    let service = api_service.get_ref();
+
+    match service
+        .tenant_usage(&tenant.into_inner(), &caller(&request))
+        .await
+    {
+        Ok(usage) => Ok(HttpResponseBuilder::new(StatusCode::OK).json(usage)),
+        // The tenant looked up is the resource here, rather than a reason to
+        // refuse a request
+        Err(e @ Error::TenantNotFound) => Err(ApiError::from(e).with_status(StatusCode::NOT_FOUND)),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[get("/namespaces/{namespace}/quota")]
+pub async fn namespace_quota_route(
+    request: HttpRequest,
+    namespace: web::Path<String>,
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    debug!("Received HTTP quota request for namespace: {}", namespace);
+
+    // Tokens restricted to a namespace only see the quota of theirs
+    let namespace = namespace.into_inner();
+    if caller(&request)
+        .scope()
+        .is_some_and(|scope| scope != namespace)
+    {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "namespace_not_found",
+            "Namespace not found",
+        ));
+    }
+
+    let quota = api_service.get_ref().namespace_quota(&namespace).await;
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(quota))
+}
+
+#[put("/namespaces/{namespace}/quota")]
+pub async fn set_namespace_quota_route(
+    namespace: web::Path<String>,
+    quota: web::Json<NamespaceConfig>,
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    debug!(
+        "Received HTTP quota update for namespace {}: {:?}",
+        namespace, quota
+    );
+
+    let quota = api_service
+        .get_ref()
+        .set_namespace_quota(&namespace.into_inner(), quota.into_inner())
+        .await;
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(quota))
+}
+
+#[get("/vms/{id}/firewall")]
+pub async fn firewall_route(
+    id: VmId,
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    debug!("Received HTTP VM firewall request for id: {}", id);
+
+    let service = api_service.get_ref();
+
+    let rules = service.firewall_rules(&id.into_inner()).await?;
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(rules))
+}
+
+#[get("/vms/{id}/network")]
+pub async fn network_route(
+    id: VmId,
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    debug!("Received HTTP VM network request for id: {}", id);
+
+    let service = api_service.get_ref();
+
+    let network = service.network(&id.into_inner()).await?;
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(network))
+}
+
+#[get("/vms/{id}/ports")]
+pub async fn ports_route(
+    id: VmId,
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    debug!("Received HTTP VM ports request for id: {}", id);
+
+    let service = api_service.get_ref();
+
+    let ports = service.ports(&id.into_inner()).await?;
+    let port_mapping: Vec<(u16, u16)> = ports.into_iter().collect();
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(port_mapping))
+}
+
+#[post("/vms/{id}/ssh")]
+pub async fn ssh_route(
+    id: VmId,
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    debug!("Received HTTP SSH access request for VM {}", id);
+
+    let access = api_service
+        .get_ref()
+        .open_ssh(&id)
+        .await
+        .inspect_err(|e| error!("Error while opening SSH access: {:?}", e))?;
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(access))
+}
+
+#[post("/vms/{id}/share-links")]
+pub async fn create_share_link_route(
+    id: VmId,
+    request: web::Json<ShareLinkDTO>,
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    debug!(
+        "Received HTTP share link request for VM {}: {:?}",
+        id, request
+    );
+
+    let link = api_service
+        .get_ref()
+        .create_share_link(&id, request.into_inner())
+        .await
+        .inspect_err(|e| error!("Error while creating share link: {:?}", e))?;
+    Ok(HttpResponseBuilder::new(StatusCode::CREATED).json(link))
+}
+
+#[get("/vms/{id}/share-links")]
+pub async fn share_links_route(
+    id: VmId,
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    debug!("Received HTTP share links request for VM {}", id);
+
+    let links = api_service.get_ref().share_links(&id).await?;
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(links))
+}
+
+#[delete("/vms/{id}/share-links/{link}")]
+pub async fn revoke_share_link_route(
+    id: VmId,
+    path: web::Path<(String, String)>,
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    let (_, link) = path.into_inner();
+    debug!(
+        "Received HTTP revocation of share link {} of VM {}",
+        link, id
+    );
+
+    api_service.get_ref().revoke_share_link(&id, &link).await?;
+    Ok(HttpResponseBuilder::new(StatusCode::NO_CONTENT).finish())
+}
+
+#[post("/stacks")]
+pub async fn create_stack_route(
+    http_request: HttpRequest,
+    request: web::Json<StackDTO>,
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    debug!("Received HTTP stack request: {:?}", request);
+
+    let stack = api_service
+        .get_ref()
+        .create_stack(request.into_inner(), caller(&http_request))
+        .await
+        .inspect_err(|e| error!("Error while creating stack: {:?}", e))?;
+    Ok(HttpResponseBuilder::new(StatusCode::CREATED).json(stack))
+}
+
+#[get("/stacks")]
+pub async fn list_stacks_route(
+    http_request: HttpRequest,
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    debug!("Received HTTP stack list request");
+
+    let stacks = api_service.get_ref().stacks(&caller(&http_request)).await;
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(stacks))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct StackQuery {
+    /// Namespace of the stack, for callers seeing every namespace
+    pub namespace: Option<String>,
+}
+
+#[get("/stacks/{name}")]
+pub async fn stack_route(
+    http_request: HttpRequest,
+    name: web::Path<String>,
+    query: web::Query<StackQuery>,
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    debug!("Received HTTP request for stack {}", name);
+
+    let caller = caller(&http_request).within(query.into_inner().namespace);
+    let stack = api_service.get_ref().stack(&name, &caller).await?;
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(stack))
+}
+
+#[get("/discovery/{stack}/{service}")]
+pub async fn discovery_route(
+    http_request: HttpRequest,
+    path: web::Path<(String, String)>,
+    query: web::Query<StackQuery>,
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    let (stack, service) = path.into_inner();
+    debug!(
+        "Received HTTP discovery request for service {} of stack {}",
+        service, stack
+    );
+
+    let caller = caller(&http_request).within(query.into_inner().namespace);
+    let endpoints = api_service
+        .get_ref()
+        .discover(&stack, &service, &caller)
+        .await?;
+    // Members come and go with the lifecycle of their VMs
+    Ok(HttpResponseBuilder::new(StatusCode::OK)
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .json(endpoints))
+}
+
+#[delete("/stacks/{name}")]
+pub async fn destroy_stack_route(
+    http_request: HttpRequest,
+    name: web::Path<String>,
+    query: web::Query<StackQuery>,
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    debug!("Received HTTP destroy request for stack {}", name);
+
+    let caller = caller(&http_request).within(query.into_inner().namespace);
+    api_service.get_ref().destroy_stack(&name, &caller).await?;
+    Ok(HttpResponseBuilder::new(StatusCode::NO_CONTENT).finish())
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RollbackQuery {
+    /// Name of the snapshot to roll back to
+    pub snapshot: String,
+}
+
+#[post("/vms/{id}/rollback")]
+pub async fn rollback_route(
+    id: VmId,
+    query: web::Query<RollbackQuery>,
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    debug!("Received HTTP rollback request for VM {}: {:?}", id, query);
+
+    match api_service.get_ref().rollback(&id, &query.snapshot).await {
+        Ok(()) => Ok(HttpResponseBuilder::new(StatusCode::NO_CONTENT).finish()),
+        Err(e) => {
+            error!("Error while rolling back VM: {:?}", e);
+            match e {
+                // The VM cannot be rolled back in its current state
+                Error::InvalidOptions(_) => {
+                    Err(ApiError::from(e).with_status(StatusCode::CONFLICT))
+                }
+                _ => Err(e.into()),
+            }
+        }
     }
 }
+
+#[post("/vms/{id}/agent")]
+pub async fn agent_route(
+    id: VmId,
+    request: web::Json<AgentRequest>,
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    debug!("Received HTTP agent request for VM {}: {:?}", id, request);
+
+    let response = api_service
+        .get_ref()
+        .call_agent(&id, request.into_inner())
+        .await
+        .inspect_err(|e| error!("Error while calling the agent of VM {}: {:?}", id, e))?;
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(response))
+}
+
+#[get("/ports/{host_port}")]
+pub async fn port_owner_route(
+    request: HttpRequest,
+    host_port: web::Path<u16>,
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    debug!("Received HTTP port lookup request for port: {}", host_port);
+
+    let service = api_service.get_ref();
+
+    let host_port = host_port.into_inner();
+    // Ports of VMs of other namespaces are not mapped, as far as the caller
+    // knows
+    match service.port_owner(host_port, &caller(&request)).await {
+        Some(owner) => Ok(HttpResponseBuilder::new(StatusCode::OK).json(owner)),
+        None => Err(Error::PortNotMapped(host_port).into()),
+    }
+}
+
+#[patch("/vms/{id}/ports")]
+pub async fn update_ports_route(
+    id: VmId,
+    update: web::Json<PortsUpdateDTO>,
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    debug!(
+        "Received HTTP VM ports update request for id {}: {:?}",
+        id, update
+    );
+
+    let service = api_service.get_ref();
+
+    let ports = service
+        .update_ports(&id.into_inner(), update.into_inner())
+        .await
+        .inspect_err(|e| error!("Error while updating VM ports: {:?}", e))?;
+    let port_mapping: Vec<(u16, u16)> = ports.into_iter().collect();
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(port_mapping))
+}
+
+#[post("/vms/{id}/export")]
+pub async fn export_route(
+    id: VmId,
+    options: web::Json<ExportOptions>,
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    debug!(
+        "Received HTTP VM export request for id {}: {:?}",
+        id, options
+    );
+
+    let service = api_service.get_ref();
+
+    let result = service
+        .export(&id.into_inner(), options.into_inner())
+        .await
+        .inspect_err(|e| error!("Error while exporting VM: {:?}", e))?;
+    info!("VM exported to {}", result.path.display());
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(result))
+}
+
+#[get("/volumes")]
+pub async fn list_volumes_route(
+    request: HttpRequest,
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    debug!("Received HTTP volume list request");
+
+    Ok(web::Json(
+        api_service.get_ref().list_volumes(&caller(&request)).await,
+    ))
+}
+
+#[post("/volumes")]
+pub async fn create_volume_route(
+    http_request: HttpRequest,
+    request: web::Json<CreateVolumeDTO>,
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    debug!("Received HTTP volume create request body: {:?}", request);
+
+    let service = api_service.get_ref();
+
+    let volume = service
+        .create_volume(request.into_inner(), &caller(&http_request))
+        .await
+        .inspect_err(|e| error!("Error while creating volume: {:?}", e))?;
+    info!("Volume {} created", volume.name);
+    Ok(HttpResponseBuilder::new(StatusCode::CREATED).json(volume))
+}
+
+#[get("/volumes/{name}")]
+pub async fn get_volume_route(
+    request: HttpRequest,
+    name: web::Path<String>,
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    debug!("Received HTTP volume get request for name: {}", name);
+
+    let service = api_service.get_ref();
+
+    let volume = service
+        .get_volume(&name.into_inner(), &caller(&request))
+        .await?;
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(volume))
+}
+
+#[delete("/volumes/{name}")]
+pub async fn delete_volume_route(
+    request: HttpRequest,
+    name: web::Path<String>,
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    debug!("Received HTTP volume delete request for name: {}", name);
+
+    let service = api_service.get_ref();
+
+    service
+        .delete_volume(&name.into_inner(), &caller(&request))
+        .await?;
+    Ok(HttpResponseBuilder::new(StatusCode::NO_CONTENT))
+}
+
+#[get("/volumes/{name}/snapshots")]
+pub async fn list_snapshots_route(
+    request: HttpRequest,
+    name: web::Path<String>,
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    debug!("Received HTTP snapshot list request for volume: {}", name);
+
+    let service = api_service.get_ref();
+
+    let volume = service
+        .get_volume(&name.into_inner(), &caller(&request))
+        .await?;
+    Ok(HttpResponseBuilder::new(StatusCode::OK).json(volume.snapshots))
+}
+
+#[post("/volumes/{name}/snapshots")]
+pub async fn create_snapshot_route(
+    http_request: HttpRequest,
+    name: web::Path<String>,
+    request: web::Json<CreateSnapshotDTO>,
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    debug!(
+        "Received HTTP snapshot create request for volume {}: {:?}",
+        name, request
+    );
+
+    let service = api_service.get_ref();
+
+    let snapshot = service
+        .create_snapshot(
+            &name.into_inner(),
+            request.into_inner(),
+            &caller(&http_request),
+        )
+        .await
+        .inspect_err(|e| error!("Error while creating snapshot: {:?}", e))?;
+    Ok(HttpResponseBuilder::new(StatusCode::CREATED).json(snapshot))
+}
+
+#[delete("/volumes/{name}/snapshots/{snapshot}")]
+pub async fn delete_snapshot_route(
+    request: HttpRequest,
+    path: web::Path<(String, String)>,
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    let (name, snapshot) = path.into_inner();
+    debug!(
+        "Received HTTP snapshot delete request for volume {}: {}",
+        name, snapshot
+    );
+
+    let service = api_service.get_ref();
+
+    service
+        .delete_snapshot(&name, &snapshot, &caller(&request))
+        .await?;
+    Ok(HttpResponseBuilder::new(StatusCode::NO_CONTENT))
+}
+
+#[post("/volumes/{name}/clone")]
+pub async fn clone_volume_route(
+    http_request: HttpRequest,
+    name: web::Path<String>,
+    request: web::Json<CloneVolumeDTO>,
+    api_service: web::Data<LambdoApiService>,
+) -> Result<impl Responder, ApiError> {
+    debug!(
+        "Received HTTP volume clone request for volume {}: {:?}",
+        name, request
+    );
+
+    let service = api_service.get_ref();
+
+    let volume = service
+        .clone_volume(
+            &name.into_inner(),
+            request.into_inner(),
+            &caller(&http_request),
+        )
+        .await
+        .inspect_err(|e| error!("Error while cloning volume: {:?}", e))?;
+    info!("Volume {} created", volume.name);
+    Ok(HttpResponseBuilder::new(StatusCode::CREATED).json(volume))
+}
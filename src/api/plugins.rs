@@ -0,0 +1,137 @@
+use anyhow::{anyhow, Result};
+use tracing::{debug, info};
+use wasmtime::{
+    component::{Component, Linker, ResourceTable},
+    Config, Engine, Store,
+};
+use wasmtime_wasi::{WasiCtx, WasiCtxView, WasiView};
+
+use crate::config::PluginConfig;
+
+wasmtime::component::bindgen!({
+    world: "plugin",
+    path: "wit/plugin.wit",
+});
+
+use exports::lambdo::plugin::admission::Verdict;
+
+/// What the plugins decided about a request
+#[derive(Debug)]
+pub enum Admission {
+    /// The request, as mutated by the plugins, as JSON
+    Allowed(String),
+    Denied {
+        plugin: String,
+        reason: String,
+    },
+}
+
+struct LoadedPlugin {
+    path: String,
+    fuel: u64,
+    component: Component,
+}
+
+/// State of an instance of a plugin, which gets no environment, arguments,
+/// files nor network
+struct PluginState {
+    wasi: WasiCtx,
+    table: ResourceTable,
+}
+
+impl WasiView for PluginState {
+    fn ctx(&mut self) -> WasiCtxView<'_> {
+        WasiCtxView {
+            ctx: &mut self.wasi,
+            table: &mut self.table,
+        }
+    }
+}
+
+/// Runs the WASM plugins the requests to start VMs go through.
+///
+/// Each request gets fresh instances of the plugins, which keep nothing
+/// from one request to the next.
+pub struct PluginHost {
+    engine: Engine,
+    linker: Linker<PluginState>,
+    plugins: Vec<LoadedPlugin>,
+}
+
+impl PluginHost {
+    /// Compile the plugins of `configs`, failing on the first that cannot
+    /// be loaded.
+    pub fn new(configs: &[PluginConfig]) -> Result<Self> {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config).map_err(|e| anyhow!("cannot create engine: {}", e))?;
+        let mut linker = Linker::new(&engine);
+        wasmtime_wasi::p2::add_to_linker_sync(&mut linker)
+            .map_err(|e| anyhow!("cannot link WASI: {}", e))?;
+
+        let plugins = configs
+            .iter()
+            .map(|config| {
+                let component = Component::from_file(&engine, &config.path)
+                    .map_err(|e| anyhow!("cannot load plugin {}: {}", config.path, e))?;
+                info!("Loaded plugin {}", config.path);
+                Ok(LoadedPlugin {
+                    path: config.path.clone(),
+                    fuel: config.fuel,
+                    component,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(PluginHost {
+            engine,
+            linker,
+            plugins,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.plugins.is_empty()
+    }
+
+    /// Pass `request` through the plugins in order, each one seeing it as
+    /// mutated by the previous ones, until one denies it.
+    ///
+    /// Blocks for as long as the plugins run, within their fuel.
+    pub fn admit(&self, mut request: String) -> Result<Admission> {
+        for plugin in &self.plugins {
+            let verdict = self
+                .call(plugin, &request)
+                .map_err(|e| anyhow!("plugin {} failed: {}", plugin.path, e))?;
+            match verdict {
+                Verdict::Allow => debug!("Plugin {} allowed the request", plugin.path),
+                Verdict::Mutate(mutated) => {
+                    debug!("Plugin {} mutated the request", plugin.path);
+                    request = mutated;
+                }
+                Verdict::Deny(reason) => {
+                    return Ok(Admission::Denied {
+                        plugin: plugin.path.clone(),
+                        reason,
+                    })
+                }
+            }
+        }
+
+        Ok(Admission::Allowed(request))
+    }
+
+    fn call(&self, plugin: &LoadedPlugin, request: &str) -> wasmtime::Result<Verdict> {
+        let state = PluginState {
+            wasi: WasiCtx::builder().build(),
+            table: ResourceTable::new(),
+        };
+        let mut store = Store::new(&self.engine, state);
+        store.set_fuel(plugin.fuel)?;
+
+        let instance = Plugin::instantiate(&mut store, &plugin.component, &self.linker)?;
+        instance
+            .lambdo_plugin_admission()
+            .call_admit(&mut store, request)
+    }
+}
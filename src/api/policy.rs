@@ -0,0 +1,101 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{
+    config::PolicyConfig,
+    vm_manager::{state::DEFAULT_NAMESPACE, VMOptionsDTO},
+};
+
+/// Who sent a request, as far as lambdo knows
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Caller {
+    /// Address of the HTTP client
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+    /// Name of the token the request was authenticated with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    /// Queue the request was taken from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queue: Option<String>,
+    /// Namespace of the token of the caller, the VMs it starts going there
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    /// Whether the caller sees the VMs of every namespace, not only its own
+    #[serde(skip)]
+    pub all_namespaces: bool,
+}
+
+impl Caller {
+    /// Namespace the VMs started by the caller go to
+    pub fn namespace(&self) -> &str {
+        self.namespace.as_deref().unwrap_or(DEFAULT_NAMESPACE)
+    }
+
+    /// Namespace the caller is restricted to, if it is
+    pub fn scope(&self) -> Option<&str> {
+        (!self.all_namespaces).then(|| self.namespace())
+    }
+
+    /// The caller restricted to `namespace`, if it sees every namespace and
+    /// picked one
+    pub fn within(self, namespace: Option<String>) -> Self {
+        match namespace {
+            Some(namespace) if self.all_namespaces => Caller {
+                namespace: Some(namespace),
+                all_namespaces: false,
+                ..self
+            },
+            _ => self,
+        }
+    }
+}
+
+/// Ask the policy engine why `request` of `caller` should be denied,
+/// returning no reason if it is allowed.
+///
+/// `config.url` is the OPA document of the denial messages, queried with
+/// `{"request", "caller"}` as input. An undefined document fails, for a
+/// missing policy not to allow everything.
+pub async fn evaluate(
+    config: &PolicyConfig,
+    request: &VMOptionsDTO,
+    caller: &Caller,
+) -> Result<Vec<String>> {
+    let input = serde_json::json!({
+        "input": {
+            "request": request,
+            "caller": caller,
+        }
+    });
+    let response = reqwest::Client::new()
+        .post(&config.url)
+        .timeout(Duration::from_secs(config.timeout))
+        .header("content-type", "application/json")
+        .body(input.to_string())
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        return Err(anyhow!("policy engine answered {}", response.status()));
+    }
+
+    let body: Value = serde_json::from_slice(&response.bytes().await?)
+        .map_err(|e| anyhow!("unexpected answer of the policy engine: {}", e))?;
+    let result = body
+        .get("result")
+        .ok_or_else(|| anyhow!("policy {} is undefined", config.url))?;
+    let reasons = result
+        .as_array()
+        .ok_or_else(|| anyhow!("policy {} is not a set of messages", config.url))?;
+    Ok(reasons
+        .iter()
+        .map(|reason| match reason {
+            Value::String(reason) => reason.clone(),
+            reason => reason.to_string(),
+        })
+        .collect())
+}
@@ -0,0 +1,175 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use anyhow::Error;
+use serde::Deserialize;
+use tokio::sync::{broadcast::error::RecvError, Mutex, OwnedSemaphorePermit, Semaphore};
+use tracing::{debug, error, info, warn};
+
+use super::{
+    policy::Caller,
+    service::{LambdoApiService, LambdoApiServiceTrait},
+};
+use crate::{
+    config::{QueueConfig, QueueKind},
+    vm_manager::{SimpleSpawn, VMOptionsDTO},
+};
+
+pub mod nats;
+pub mod sqs;
+
+/// Delay before receiving again after the queue failed
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Request carried by a queue message, with the body of the matching HTTP
+/// request
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum QueueRequest {
+    Start(Box<VMOptionsDTO>),
+    Spawn(SimpleSpawn),
+}
+
+#[derive(Debug)]
+pub struct QueueMessage {
+    pub id: String,
+    pub body: Vec<u8>,
+    /// Handle the message is acknowledged with
+    pub handle: String,
+}
+
+/// Message queue lambdo takes its spawn requests from.
+#[async_trait::async_trait]
+pub trait Queue: Send {
+    /// Name of the queue, for logging
+    fn name(&self) -> String;
+    /// Wait for the next message.
+    async fn receive(&mut self) -> Result<QueueMessage, Error>;
+    /// The request was run, remove the message from the queue.
+    async fn ack(&mut self, message: &QueueMessage) -> Result<(), Error>;
+    /// The request failed, deliver the message again later.
+    async fn nack(&mut self, message: &QueueMessage) -> Result<(), Error>;
+    /// The message is not a valid request, never deliver it again.
+    async fn reject(&mut self, message: &QueueMessage) -> Result<(), Error>;
+}
+
+/// Start consuming the configured queue.
+pub fn start(service: Arc<LambdoApiService>, config: &QueueConfig) {
+    let queue: Box<dyn Queue> = match &config.kind {
+        QueueKind::Nats(nats) => Box::new(nats::NatsQueue::new(nats.clone())),
+        QueueKind::Sqs(sqs) => Box::new(sqs::SqsQueue::new(sqs.clone())),
+    };
+    info!("consuming spawn requests from {}", queue.name());
+    tokio::spawn(consume(service, queue, config.concurrency));
+}
+
+/// Run the requests of `queue`, keeping at most `concurrency` of the VMs they
+/// started alive at once.
+///
+/// Requests are run one at a time, in the order they are received: the next
+/// message is only received once the VM of the previous one started, or
+/// failed to. `concurrency` bounds the VMs alive, not the starts in flight.
+async fn consume(service: Arc<LambdoApiService>, mut queue: Box<dyn Queue>, concurrency: usize) {
+    let slots = Arc::new(Semaphore::new(concurrency.max(1)));
+    let running: Arc<Mutex<HashMap<String, OwnedSemaphorePermit>>> = Arc::default();
+    tokio::spawn(release_slots(service.clone(), running.clone()));
+
+    loop {
+        // The semaphore is never closed
+        let slot = slots.clone().acquire_owned().await.unwrap();
+
+        let message = match queue.receive().await {
+            Ok(message) => message,
+            Err(e) => {
+                error!("cannot receive from {}: {}", queue.name(), e);
+                tokio::time::sleep(RETRY_DELAY).await;
+                continue;
+            }
+        };
+        debug!("received message {} from {}", message.id, queue.name());
+
+        let request = match serde_json::from_slice::<QueueRequest>(&message.body) {
+            Ok(request) => request,
+            Err(e) => {
+                warn!("rejecting invalid message {}: {}", message.id, e);
+                if let Err(e) = queue.reject(&message).await {
+                    error!("cannot reject message {}: {}", message.id, e);
+                }
+                continue;
+            }
+        };
+
+        let result = match request {
+            QueueRequest::Start(request) => {
+                let caller = Caller {
+                    queue: Some(queue.name()),
+                    ..Default::default()
+                };
+                service.start(*request, caller).await
+            }
+            QueueRequest::Spawn(request) => {
+                let caller = Caller {
+                    queue: Some(queue.name()),
+                    ..Default::default()
+                };
+                service.simple_spawn(request, caller).await
+            }
+        };
+
+        let acknowledged = match result {
+            Ok((id, _)) => {
+                info!("started VM {} for message {}", id, message.id);
+                service.events.record(
+                    "queue.started",
+                    Some(&id),
+                    serde_json::json!({ "queue": queue.name(), "message": message.id }),
+                );
+                running.lock().await.insert(id.clone(), slot);
+                // The VM may have been destroyed before its slot was tracked
+                if service.vm(&id).await.is_err() {
+                    running.lock().await.remove(&id);
+                }
+                queue.ack(&message).await
+            }
+            // Denied again on every delivery
+            Err(e @ crate::vm_manager::Error::AdmissionDenied(_)) => {
+                warn!("rejecting denied message {}: {}", message.id, e);
+                queue.reject(&message).await
+            }
+            Err(e) => {
+                error!("cannot run message {}: {}", message.id, e);
+                queue.nack(&message).await
+            }
+        };
+        if let Err(e) = acknowledged {
+            error!("cannot acknowledge message {}: {}", message.id, e);
+        }
+    }
+}
+
+/// Free the slot of the VMs started from the queue once they are destroyed.
+async fn release_slots(
+    service: Arc<LambdoApiService>,
+    running: Arc<Mutex<HashMap<String, OwnedSemaphorePermit>>>,
+) {
+    let mut events = service.events.subscribe();
+    loop {
+        match events.recv().await {
+            Ok(event) if event.kind == "vm.destroyed" => {
+                if let Some(id) = event.vm_id {
+                    running.lock().await.remove(&id);
+                }
+            }
+            Ok(_) => {}
+            Err(RecvError::Lagged(_)) => {
+                // Some destructions were missed, look for the VMs still there
+                let ids: Vec<String> = running.lock().await.keys().cloned().collect();
+                for id in ids {
+                    if service.vm(&id).await.is_err() {
+                        running.lock().await.remove(&id);
+                    }
+                }
+            }
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
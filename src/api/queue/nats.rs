@@ -0,0 +1,126 @@
+use anyhow::{anyhow, Error};
+
+use super::{Queue, QueueMessage};
+use crate::{
+    config::NatsQueueConfig,
+    nats::{NatsConnection, NatsMessage},
+};
+
+/// Nanoseconds a pull request waits for a message before it is renewed
+const PULL_EXPIRES: u64 = 30_000_000_000;
+
+/// Fetches requests one at a time from a durable JetStream pull consumer.
+pub struct NatsQueue {
+    config: NatsQueueConfig,
+    connection: Option<NatsConnection>,
+    inbox: String,
+}
+
+impl NatsQueue {
+    pub fn new(config: NatsQueueConfig) -> Self {
+        NatsQueue {
+            config,
+            connection: None,
+            inbox: format!("_INBOX.lambdo.{}", uuid::Uuid::new_v4().simple()),
+        }
+    }
+
+    async fn connection(&mut self) -> Result<&mut NatsConnection, Error> {
+        if self.connection.is_none() {
+            let mut connection = NatsConnection::connect(&self.config.server).await?;
+            connection.subscribe(&self.inbox, 1).await?;
+            self.connection = Some(connection);
+        }
+        Ok(self.connection.as_mut().unwrap())
+    }
+
+    async fn pull(&mut self) -> Result<NatsMessage, Error> {
+        let subject = format!(
+            "$JS.API.CONSUMER.MSG.NEXT.{}.{}",
+            self.config.stream, self.config.consumer
+        );
+        let request = serde_json::json!({ "batch": 1, "expires": PULL_EXPIRES }).to_string();
+        let inbox = self.inbox.clone();
+
+        let connection = self.connection().await?;
+        connection
+            .publish(&subject, Some(&inbox), request.as_bytes())
+            .await?;
+        loop {
+            let message = connection.next_message().await?;
+            match message.status() {
+                None => return Ok(message),
+                // Idle heartbeat
+                Some(100) => continue,
+                // No message before the request expired, or no responders
+                Some(404) | Some(408) | Some(503) => {
+                    connection
+                        .publish(&subject, Some(&inbox), request.as_bytes())
+                        .await?
+                }
+                Some(_) => {
+                    return Err(anyhow!(
+                        "JetStream answered {}",
+                        message.headers.unwrap_or_default().trim()
+                    ))
+                }
+            }
+        }
+    }
+
+    async fn reply(&mut self, message: &QueueMessage, payload: &[u8]) -> Result<(), Error> {
+        let result = match self.connection.as_mut() {
+            Some(connection) => connection.publish(&message.handle, None, payload).await,
+            None => Err(anyhow!("connection to the NATS server was lost")),
+        };
+        if result.is_err() {
+            self.connection = None;
+        }
+        result
+    }
+}
+
+#[async_trait::async_trait]
+impl Queue for NatsQueue {
+    fn name(&self) -> String {
+        format!(
+            "JetStream consumer {}.{}",
+            self.config.stream, self.config.consumer
+        )
+    }
+
+    async fn receive(&mut self) -> Result<QueueMessage, Error> {
+        let message = match self.pull().await {
+            Ok(message) => message,
+            Err(e) => {
+                self.connection = None;
+                return Err(e);
+            }
+        };
+
+        // Acknowledgements go to $JS.ACK.<stream>.<consumer>.<delivered>.<stream sequence>...
+        let handle = message.reply.ok_or(anyhow!(
+            "message on {} cannot be acknowledged",
+            message.subject
+        ))?;
+        let id = handle.split('.').nth(5).unwrap_or(&handle).to_string();
+
+        Ok(QueueMessage {
+            id,
+            body: message.payload,
+            handle,
+        })
+    }
+
+    async fn ack(&mut self, message: &QueueMessage) -> Result<(), Error> {
+        self.reply(message, b"+ACK").await
+    }
+
+    async fn nack(&mut self, message: &QueueMessage) -> Result<(), Error> {
+        self.reply(message, b"-NAK").await
+    }
+
+    async fn reject(&mut self, message: &QueueMessage) -> Result<(), Error> {
+        self.reply(message, b"+TERM").await
+    }
+}
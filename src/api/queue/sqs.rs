@@ -0,0 +1,157 @@
+use anyhow::{anyhow, Error};
+use serde_json::{json, Value};
+use time::OffsetDateTime;
+
+use super::{Queue, QueueMessage};
+use crate::{
+    api::sigv4::{self, Credentials},
+    config::SqsQueueConfig,
+};
+
+/// Seconds a receive call waits for a message
+const WAIT_TIME: u64 = 20;
+/// Seconds before a failed message is delivered again
+const RETRY_VISIBILITY_TIMEOUT: u64 = 30;
+
+/// Receives requests from an SQS queue, through its JSON API signed with
+/// AWS Signature Version 4.
+pub struct SqsQueue {
+    config: SqsQueueConfig,
+    client: reqwest::Client,
+}
+
+impl SqsQueue {
+    pub fn new(config: SqsQueueConfig) -> Self {
+        SqsQueue {
+            config,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    fn credentials(&self) -> Result<Credentials, Error> {
+        Credentials::resolve(
+            self.config.access_key_id.as_ref(),
+            self.config.secret_access_key.as_ref(),
+        )
+    }
+
+    /// Call `action` of the SQS API with the JSON `body`.
+    async fn call(&self, action: &str, body: Value) -> Result<Value, Error> {
+        let url = reqwest::Url::parse(&self.config.queue_url)?;
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => return Err(anyhow!("invalid queue URL {}", url)),
+        };
+        let endpoint = format!("{}://{}/", url.scheme(), host);
+
+        let body = body.to_string();
+        let credentials = self.credentials()?;
+        let amz_date = sigv4::amz_date(OffsetDateTime::now_utc());
+
+        // Header names are sorted, as signing requires
+        let mut headers = vec![
+            ("content-type", "application/x-amz-json-1.0".to_string()),
+            ("host", host),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        if let Some(token) = &credentials.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        headers.push(("x-amz-target", format!("AmazonSQS.{}", action)));
+
+        let authorization = sigv4::sign(
+            &credentials,
+            &self.config.region,
+            "sqs",
+            &amz_date,
+            &sigv4::Request {
+                method: "POST",
+                path: "/",
+                headers: &headers,
+                body: body.as_bytes(),
+            },
+        )?;
+
+        let mut request = self
+            .client
+            .post(&endpoint)
+            .header("Authorization", authorization);
+        for (name, value) in headers.iter().filter(|(name, _)| *name != "host") {
+            request = request.header(*name, value);
+        }
+
+        let response = request.body(body).send().await?;
+        let status = response.status();
+        let text = response.text().await?;
+        if !status.is_success() {
+            return Err(anyhow!("SQS {} failed with {}: {}", action, status, text));
+        }
+
+        Ok(serde_json::from_str(&text).unwrap_or(Value::Null))
+    }
+}
+
+#[async_trait::async_trait]
+impl Queue for SqsQueue {
+    fn name(&self) -> String {
+        format!("SQS queue {}", self.config.queue_url)
+    }
+
+    async fn receive(&mut self) -> Result<QueueMessage, Error> {
+        loop {
+            let response = self
+                .call(
+                    "ReceiveMessage",
+                    json!({
+                        "QueueUrl": self.config.queue_url,
+                        "MaxNumberOfMessages": 1,
+                        "WaitTimeSeconds": WAIT_TIME,
+                    }),
+                )
+                .await?;
+
+            let Some(message) = response["Messages"].get(0) else {
+                continue;
+            };
+            let field = |name: &str| {
+                message[name]
+                    .as_str()
+                    .map(str::to_string)
+                    .ok_or(anyhow!("SQS message without {}", name))
+            };
+
+            return Ok(QueueMessage {
+                id: field("MessageId")?,
+                body: field("Body")?.into_bytes(),
+                handle: field("ReceiptHandle")?,
+            });
+        }
+    }
+
+    async fn ack(&mut self, message: &QueueMessage) -> Result<(), Error> {
+        self.call(
+            "DeleteMessage",
+            json!({ "QueueUrl": self.config.queue_url, "ReceiptHandle": message.handle }),
+        )
+        .await
+        .map(|_| ())
+    }
+
+    async fn nack(&mut self, message: &QueueMessage) -> Result<(), Error> {
+        self.call(
+            "ChangeMessageVisibility",
+            json!({
+                "QueueUrl": self.config.queue_url,
+                "ReceiptHandle": message.handle,
+                "VisibilityTimeout": RETRY_VISIBILITY_TIMEOUT,
+            }),
+        )
+        .await
+        .map(|_| ())
+    }
+
+    async fn reject(&mut self, message: &QueueMessage) -> Result<(), Error> {
+        self.ack(message).await
+    }
+}
@@ -0,0 +1,81 @@
+use std::{future::Future, time::Instant};
+
+use serde::Serialize;
+
+use crate::vm_manager::Error;
+
+/// What the command of the self test prints, for its output to be checked
+pub const MARKER: &str = "lambdo-selftest-ok";
+
+/// How one step of the self test went
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SelftestStep {
+    pub name: String,
+    pub passed: bool,
+    pub duration_ms: u64,
+    pub detail: String,
+}
+
+/// How the self test went, the steps after the first failing one being
+/// skipped, apart from stopping the VM
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SelftestReport {
+    pub passed: bool,
+    pub duration_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vm_id: Option<String>,
+    pub steps: Vec<SelftestStep>,
+    #[serde(skip)]
+    started: Instant,
+}
+
+impl SelftestReport {
+    pub fn start() -> Self {
+        SelftestReport {
+            passed: true,
+            duration_ms: 0,
+            vm_id: None,
+            steps: Vec::new(),
+            started: Instant::now(),
+        }
+    }
+
+    /// Run the step `name`, recording how it went, and return what it gave
+    /// if it passed.
+    pub async fn step<T>(
+        &mut self,
+        name: &str,
+        step: impl Future<Output = Result<(T, String), Error>>,
+    ) -> Option<T> {
+        let started = Instant::now();
+        let result = step.await;
+        let (passed, detail, value) = match result {
+            Ok((value, detail)) => (true, detail, Some(value)),
+            Err(e) => (false, e.to_string(), None),
+        };
+
+        self.passed &= passed;
+        self.steps.push(SelftestStep {
+            name: name.to_string(),
+            passed,
+            duration_ms: started.elapsed().as_millis() as u64,
+            detail,
+        });
+        value
+    }
+
+    /// Name of the first step that failed
+    pub fn failed_step(&self) -> Option<&str> {
+        self.steps
+            .iter()
+            .find(|step| !step.passed)
+            .map(|step| step.name.as_str())
+    }
+
+    pub fn finish(mut self) -> Self {
+        self.duration_ms = self.started.elapsed().as_millis() as u64;
+        self
+    }
+}
@@ -1,183 +1,2889 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
 
 use crate::{
-    config::LambdoConfig,
+    api::{
+        artifacts::{self, Artifact, ArtifactsRequest},
+        explain::{ImageChoice, StartExplanation, WarmPoolDecision},
+        jobs::{
+            Dispatched, Job, JobRequest, JobRun, JobStatus, JobStore, JOB_COUNT_ENV, JOB_INDEX_ENV,
+        },
+        plugins::{Admission, PluginHost},
+        policy::{self, Caller},
+        selftest::{self, SelftestReport},
+        stacks::{self, ServiceEndpoints, Stack, StackDTO},
+        support_bundle::{self, BundleFiles},
+        FeatureStatus,
+    },
+    config::{ImageManagerStrategy, LambdoConfig, NamespaceConfig, RuntimeConfig, FEATURES},
     vm_manager::{
-        image_manager::{Image, ImageManager, ImageManifest},
-        state::LambdoStateRef,
-        BootOptions, DiskOptions, NetworkOptions, SimpleSpawn, VMManager, VMManagerTrait,
-        VMOptions, VMOptionsDTO,
+        agent::{AgentRequest, AgentResponse},
+        cpu_accounting::TenantUsage,
+        events::{Event, EventStore},
+        image_manager::{
+            audit::{self, AuditReport, CorruptedImage},
+            promotion::{self, Promotion, PromotionDTO},
+            samples, Image, ImageManager, ImageManifest, ImageOrigin,
+        },
+        ingress_proxy::ShareLink,
+        node::{Cordon, NodeStatus},
+        now_ms,
+        port_allocator::{PortAllocator, PortOwner},
+        ssh::{self, SshAccess},
+        state::{
+            function_vm_key, LambdoStateRef, NamespaceQuota, NamespaceUsage, StackMember,
+            VMDetails, VMStatus, DEFAULT_NAMESPACE,
+        },
+        volume_manager::{
+            CloneVolumeDTO, CreateSnapshotDTO, CreateVolumeDTO, Snapshot, Volume, VolumeManager,
+        },
+        warm_pool::{self, WarmPool},
+        AdoptOptions, BootOptions, BootOptionsDTO, Check, DiskOptions, DiskOptionsDTO,
+        ExportOptions, ExportResult, FirewallRule, FunctionDTO, NetworkOptions, PortsUpdateDTO,
+        RunRequest, RunResult, ShareLinkDTO, SimpleSpawn, VMManager, VMManagerTrait, VMNetwork,
+        VMOptions, VMOptionsDTO, VolumeAttachmentDTO, DEFAULT_BOOT_ARGS, DEFAULT_MEM_SIZE_MIB,
+        DEFAULT_VCPU_COUNT,
     },
 };
 use mockall::automock;
+use tokio::sync::broadcast;
+use tracing::{debug, error, info, trace_span, warn, Instrument};
+use uuid::Uuid;
 
 pub use crate::vm_manager::Error;
 
+/// How often the functions are checked for VMs to scale to zero
+const FUNCTION_IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// How often the agent of a VM that just booted is checked
+const AGENT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Seconds a VM running code outlives the time it is given, after which it
+/// is destroyed even if the request was dropped
+const RUN_TTL_MARGIN: u64 = 30;
+
+/// Features which are not implemented yet, and cannot be enabled
+const UNAVAILABLE_FEATURES: [&str; 0] = [];
+
+/// Compile the WASM plugins of the configuration, if the `wasm` feature is
+/// enabled.
+fn load_plugins(config: &LambdoConfig) -> Result<PluginHost, Error> {
+    let plugins = &config.api.plugins;
+    if !config.feature_enabled("wasm") {
+        if !plugins.is_empty() {
+            warn!("The wasm feature is disabled, ignoring the plugins");
+        }
+        return PluginHost::new(&[]).map_err(Error::Other);
+    }
+    PluginHost::new(plugins).map_err(Error::Other)
+}
+
+/// Run the items of the queued jobs as the scheduler hands them out.
+pub async fn run_jobs(service: Arc<LambdoApiService>) {
+    loop {
+        let item = service.jobs.next().await;
+        let service = service.clone();
+        tokio::spawn(async move { service.run_item(item).await });
+    }
+}
+
+/// Stop a VM and release its volumes.
+async fn destroy(
+    vm_manager: &dyn VMManagerTrait,
+    volume_manager: &VolumeManager,
+    id: &str,
+) -> Result<(), Error> {
+    let result = vm_manager.stop_vm(id).await;
+
+    // The VM is forgotten even when stopping it fails
+    if !matches!(result, Err(Error::VmNotFound)) {
+        volume_manager.detach_all(id).await?;
+    }
+
+    result
+}
+
+/// Release the volumes of the VMs destroyed by the VM manager once their
+/// time to live expired.
+///
+/// The VM manager keeps the expired VMs until they are taken, `vm.expired`
+/// only telling when to take them, so none is missed when events are.
+async fn release_expired(
+    mut events: broadcast::Receiver<Event>,
+    vm_manager: Arc<dyn VMManagerTrait>,
+    volume_manager: Arc<VolumeManager>,
+) {
+    loop {
+        match events.recv().await {
+            Ok(event) if event.kind == "vm.expired" => {}
+            Ok(_) => continue,
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                debug!("missed {} events, looking for expired VMs", missed);
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        }
+
+        for id in vm_manager.take_expired_vms().await {
+            if let Err(e) = volume_manager.detach_all(&id).await {
+                error!("Error while releasing volumes of VM {}: {:?}", id, e);
+            }
+        }
+    }
+}
+
+/// Audit the image store every `interval`.
+pub async fn audit_images(service: Arc<LambdoApiService>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    // The first tick completes right away, leave the images alone at startup
+    ticker.tick().await;
+
+    loop {
+        ticker.tick().await;
+        if let Err(e) = service.verify_images().await {
+            error!("Error while auditing images: {:?}", e);
+        }
+    }
+}
+
+/// Destroy the VMs of the functions idle for longer than their idle timeout,
+/// checking every few seconds.
+///
+/// A VM taken out of its function is no longer handed out, and is drained of
+/// the requests the ingress proxy forwards to it before being destroyed.
+pub async fn scale_functions_to_zero(service: Arc<LambdoApiService>) {
+    let mut ticker = tokio::time::interval(FUNCTION_IDLE_CHECK_INTERVAL);
+    let drain_timeout = Duration::from_secs(service.config.api.ingress_proxy.drain_timeout);
+
+    loop {
+        ticker.tick().await;
+        for (name, id) in service.vm_manager.take_idle_function_vms().await {
+            let service = service.clone();
+            tokio::spawn(async move {
+                info!("Function {} is idle, draining VM {}", name, id);
+                service.vm_manager.drain_vm(&id, drain_timeout).await;
+                if let Err(e) = destroy(&*service.vm_manager, &service.volume_manager, &id).await {
+                    error!(
+                        "Error while destroying VM {} of function {}: {:?}",
+                        id, name, e
+                    );
+                }
+            });
+        }
+    }
+}
+
 #[automock]
 #[async_trait::async_trait]
 pub trait LambdoApiServiceTrait: Send + Sync {
-    async fn start(&self, request: VMOptionsDTO) -> Result<(String, HashMap<u16, u16>), Error>;
-    async fn stop(&self, id: &str) -> Result<(), Error>;
+    /// Start a VM for `caller`, once the plugins and the policy engine
+    /// admitted the request
+    async fn start(
+        &self,
+        request: VMOptionsDTO,
+        caller: Caller,
+    ) -> Result<(String, HashMap<u16, u16>), Error>;
+    /// Start the services of a stack in the order of their dependencies,
+    /// destroying the ones already started if one fails or does not become
+    /// ready, and create its ingress. Services share the bridge of their
+    /// tenant, each being told the addresses of the ones started before it
+    /// on its kernel command line
+    async fn create_stack(&self, request: StackDTO, caller: Caller) -> Result<Stack, Error>;
+    /// Stacks of the namespace of `caller`, or of every namespace if it sees
+    /// them all
+    async fn stacks(&self, caller: &Caller) -> Vec<Stack>;
+    /// Stack `name` of the namespace of `caller`, or of the only namespace
+    /// with a stack of that name if it sees them all
+    async fn stack(&self, name: &str, caller: &Caller) -> Result<Stack, Error>;
+    /// Where the running VMs of `service` of the stack `stack` can be
+    /// reached, looked up as `stack` does
+    async fn discover(
+        &self,
+        stack: &str,
+        service: &str,
+        caller: &Caller,
+    ) -> Result<ServiceEndpoints, Error>;
+    /// Destroy every service of the stack `name`, looked up as `stack` does,
+    /// right away
+    async fn destroy_stack(&self, name: &str, caller: &Caller) -> Result<(), Error>;
+    /// Queue a job for `caller`, once the plugins and the policy engine
+    /// admitted its VM
+    async fn submit_job(&self, request: JobRequest, caller: Caller) -> Result<Job, Error>;
+    /// Job `id`, if it was submitted in the namespace of `caller` or the
+    /// caller sees them all
+    async fn job(&self, id: &str, caller: &Caller) -> Result<Job, Error>;
+    /// Destroy a VM, or schedule its destruction if a grace period is
+    /// configured, returning when it will be destroyed.
+    async fn stop(&self, id: &str) -> Result<Option<u64>, Error>;
+    async fn undo_stop(&self, id: &str) -> Result<(), Error>;
+    async fn pause(&self, id: &str) -> Result<(), Error>;
+    async fn resume(&self, id: &str) -> Result<(), Error>;
+    async fn export(&self, id: &str, options: ExportOptions) -> Result<ExportResult, Error>;
+    /// Boot a VM again from one of its periodic snapshots
+    async fn rollback(&self, id: &str, snapshot: &str) -> Result<(), Error>;
+    /// Send a request to the agent running in the guest of a VM
+    async fn call_agent(&self, id: &str, request: AgentRequest) -> Result<AgentResponse, Error>;
+    async fn adopt(
+        &self,
+        options: AdoptOptions,
+        caller: &Caller,
+    ) -> Result<(String, HashMap<u16, u16>), Error>;
+    async fn vm(&self, id: &str) -> Result<VMDetails, Error>;
+    /// VMs of the namespace of `caller`, or every VM if it sees them all
+    async fn list_vms(&self, caller: &Caller) -> Vec<VMDetails>;
+    /// Usage of `tenant`, which callers restricted to a namespace only see
+    /// for the tenant of the same name
+    async fn tenant_usage(&self, tenant: &str, caller: &Caller) -> Result<TenantUsage, Error>;
+    /// Quota of a namespace along with what its VMs use
+    async fn namespace_quota(&self, namespace: &str) -> NamespaceQuota;
+    async fn set_namespace_quota(&self, namespace: &str, quota: NamespaceConfig) -> NamespaceQuota;
+    async fn ports(&self, id: &str) -> Result<HashMap<u16, u16>, Error>;
+    /// VM forwarding `host_port`, if it is one `caller` sees
+    async fn port_owner(&self, host_port: u16, caller: &Caller) -> Option<PortOwner>;
+    async fn create_share_link(&self, id: &str, request: ShareLinkDTO) -> Result<ShareLink, Error>;
+    async fn share_links(&self, id: &str) -> Result<Vec<ShareLink>, Error>;
+    async fn revoke_share_link(&self, id: &str, link_id: &str) -> Result<(), Error>;
+    async fn firewall_rules(&self, id: &str) -> Result<Vec<FirewallRule>, Error>;
+    /// Network of a VM, along with every firewall rule applied for it
+    async fn network(&self, id: &str) -> Result<VMNetwork, Error>;
+    async fn update_ports(
+        &self,
+        id: &str,
+        update: PortsUpdateDTO,
+    ) -> Result<HashMap<u16, u16>, Error>;
+    /// What starting a VM with `request` would do, without starting it
+    async fn explain_start(&self, request: VMOptionsDTO, caller: &Caller) -> StartExplanation;
+    /// Forward a host port to the SSH port of a VM, returning how to log in
+    async fn open_ssh(&self, id: &str) -> Result<SshAccess, Error>;
 
     async fn simple_spawn(
         &self,
         request: SimpleSpawn,
+        caller: Caller,
+    ) -> Result<(String, HashMap<u16, u16>), Error>;
+    /// Run code in a one-off VM of the runtime of its language
+    async fn run(&self, request: RunRequest, caller: Caller) -> Result<RunResult, Error>;
+    async fn register_function(&self, request: FunctionDTO) -> Result<(), Error>;
+    /// Boot a VM for a function in the namespace of `caller`, or hand out
+    /// the one already serving it there
+    async fn invoke_function(
+        &self,
+        name: &str,
+        caller: Caller,
     ) -> Result<(String, HashMap<u16, u16>), Error>;
+
+    async fn events(&self, since: u64, kind: Option<String>) -> Vec<Event>;
+    fn subscribe_events(&self) -> broadcast::Receiver<Event>;
+    async fn capabilities(&self) -> HashMap<String, FeatureStatus>;
+    async fn fetch_samples(&self) -> Result<Vec<Image>, Error>;
+    /// Copy an image from the staging store to the image store once its
+    /// digest and signature are checked, recording the promotion
+    async fn promote_image(&self, request: PromotionDTO) -> Result<Promotion, Error>;
+    /// Hash the stored images against their recorded digests, quarantining
+    /// the corrupted ones and downloading them again when possible
+    async fn verify_images(&self) -> Result<AuditReport, Error>;
+    /// Stop the VMs, or leave them running, before lambdo exits
+    async fn shutdown(&self);
+    /// Stop starting VMs on this node until uncordoned, without draining it
+    async fn cordon(&self, reason: Option<String>) -> Cordon;
+    async fn uncordon(&self) -> bool;
+    /// Whether this node is cordoned and the conditions of its host
+    async fn node_status(&self) -> NodeStatus;
+    /// Boot a VM, wait for its agent, run a command in it and destroy it,
+    /// reporting how each step went
+    async fn selftest(&self) -> SelftestReport;
+    /// Gzipped tarball of the sanitized configuration, state, recent events
+    /// and versions of this node, to attach to bug reports
+    async fn support_bundle(&self) -> Result<Vec<u8>, Error>;
+
+    /// Volumes `caller` sees, the ones of its namespace if it is restricted
+    /// to it
+    async fn list_volumes(&self, caller: &Caller) -> Vec<Volume>;
+    async fn get_volume(&self, name: &str, caller: &Caller) -> Result<Volume, Error>;
+    /// Create a volume in the namespace of `caller`
+    async fn create_volume(
+        &self,
+        request: CreateVolumeDTO,
+        caller: &Caller,
+    ) -> Result<Volume, Error>;
+    async fn delete_volume(&self, name: &str, caller: &Caller) -> Result<(), Error>;
+    async fn create_snapshot(
+        &self,
+        volume: &str,
+        request: CreateSnapshotDTO,
+        caller: &Caller,
+    ) -> Result<Snapshot, Error>;
+    async fn delete_snapshot(&self, volume: &str, name: &str, caller: &Caller)
+        -> Result<(), Error>;
+    async fn clone_volume(
+        &self,
+        volume: &str,
+        request: CloneVolumeDTO,
+        caller: &Caller,
+    ) -> Result<Volume, Error>;
 }
 
 pub struct LambdoApiService {
     pub config: LambdoConfig,
-    pub vm_manager: Box<dyn VMManagerTrait>,
+    pub events: Arc<EventStore>,
+    pub vm_manager: Arc<dyn VMManagerTrait>,
     pub image_manager: Box<dyn ImageManager>,
+    pub volume_manager: Arc<VolumeManager>,
+    /// Held while the image store is audited, one audit running at a time
+    auditing: tokio::sync::Mutex<()>,
+    /// WASM plugins the requests to start VMs go through
+    plugins: Arc<PluginHost>,
+    /// Lock of each function in each namespace, held while it is invoked for
+    /// concurrent invocations to share the VM booted for it
+    invoking: tokio::sync::Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+    /// Lock of each stack name in each namespace, held while a stack of that
+    /// name is created, for two of them not to be started as one
+    creating_stacks: tokio::sync::Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+    /// Jobs, run by `run_jobs`
+    jobs: JobStore,
+    /// Resources of the VMs being started, by namespace, which do not count
+    /// in the usage of their namespace yet. Held while quotas are checked
+    starting: tokio::sync::Mutex<HashMap<String, NamespaceUsage>>,
+}
+
+/// Refuse the tenants other than the one of the namespace `caller` is
+/// restricted to, not to reach the bridge and images of another.
+fn check_tenant_scope(request: &VMOptionsDTO, caller: &Caller) -> Result<(), Error> {
+    if let (Some(scope), Some(tenant)) = (caller.scope(), &request.tenant) {
+        if scope != tenant {
+            info!("Refusing tenant {} to namespace {}", tenant, scope);
+            return Err(Error::TenantNotFound);
+        }
+    }
+    Ok(())
 }
 
 impl LambdoApiService {
     pub async fn new(
         config: LambdoConfig,
         image_manager: Box<dyn ImageManager>,
+        volume_manager: VolumeManager,
     ) -> Result<Self, Error> {
-        let state = crate::vm_manager::state::LambdoState::new(config.clone());
-        let vm_manager =
-            VMManager::from_state(std::sync::Arc::new(tokio::sync::Mutex::new(state))).await?;
+        let events = EventStore::new(config.api.events.clone()).map_err(Error::Other)?;
+        let events = Arc::new(events);
+        let state = crate::vm_manager::state::LambdoState::new(config.clone(), events.clone());
+        let vm_manager = VMManager::from_state(Arc::new(tokio::sync::Mutex::new(state))).await?;
+        let plugins = Arc::new(load_plugins(&config)?);
+        let jobs = JobStore::new(&config.api.jobs);
         Ok(LambdoApiService {
             config,
-            vm_manager: Box::new(vm_manager),
+            events,
+            vm_manager: Arc::new(vm_manager),
             image_manager,
+            volume_manager: Arc::new(volume_manager),
+            auditing: tokio::sync::Mutex::new(()),
+            plugins,
+            invoking: tokio::sync::Mutex::new(HashMap::new()),
+            creating_stacks: tokio::sync::Mutex::new(HashMap::new()),
+            jobs,
+            starting: tokio::sync::Mutex::new(HashMap::new()),
         })
     }
 
     pub async fn to_options(&self, request: VMOptionsDTO) -> Result<VMOptions, Error> {
+        let tenant = request.tenant.as_deref();
+        // Kernels are shared, tenants only have their own rootfs and disks
         let kernel = self.find_kernel(&request.boot.kernel).await?;
         let rootfs = if let Some(path) = request.boot.initrd {
-            Some(self.find_rootfs(&path).await?)
+            Some(self.find_rootfs(&self.scoped(&path, tenant)?).await?)
         } else {
             None
         };
 
-        let disks = request.disks.iter().map(|disk| async {
-            self.image_manager
-                .find_disk(&disk.image)
-                .await
-                .map(|image| DiskOptions {
-                    image,
-                    is_readonly: disk.is_readonly,
-                    is_root_device: disk.is_root_device,
-                })
-        });
+        let manifests = request
+            .disks
+            .iter()
+            .map(|disk| self.scoped(&disk.image, tenant))
+            .collect::<Result<Vec<_>, Error>>()?;
+        let disks = request
+            .disks
+            .iter()
+            .zip(manifests)
+            .map(|(disk, manifest)| async move {
+                self.image_manager
+                    .find_disk(&manifest)
+                    .await
+                    .map(|image| DiskOptions {
+                        image,
+                        is_readonly: disk.is_readonly,
+                        is_root_device: disk.is_root_device,
+                        is_persistent: false,
+                    })
+            });
 
         let disks = futures::future::try_join_all(disks)
             .await
             .map_err(Error::ImageError)?;
 
+        self.config
+            .api
+            .guest_tuning
+            .check(&request.boot.sysctls, &request.boot.kernel_modules)
+            .map_err(Error::InvalidOptions)?;
+
+        let mut network = request.network;
+        network.port_mapping = self.allocate_host_ports(network.port_mapping).await?;
+
         Ok(VMOptions {
             boot: BootOptions {
                 kernel,
                 initrd: rootfs,
                 boot_args: request.boot.boot_args,
+                sysctls: request.boot.sysctls,
+                kernel_modules: request.boot.kernel_modules,
+                read_only_rootfs: request.boot.read_only_rootfs,
             },
             disks,
-            network: request.network,
+            network,
+            tenant: request.tenant,
+            vcpu_count: request.vcpu_count,
+            mem_size_mib: request.mem_size_mib,
+            machine_class: request.machine_class,
+            snapshot_policy: request.snapshot_policy,
+            restart_policy: request.restart_policy,
+            ttl_seconds: request.ttl_seconds,
+            namespace: request.namespace,
+            stack: request.stack,
         })
     }
 
+    /// Replace the host ports set to 0 in `port_mapping` with free host
+    /// ports.
+    async fn allocate_host_ports(
+        &self,
+        port_mapping: Vec<(u16, u16)>,
+    ) -> Result<Vec<(u16, u16)>, Error> {
+        let count = port_mapping.iter().filter(|(host, _)| *host == 0).count();
+        if count == 0 {
+            return Ok(port_mapping);
+        }
+
+        let mut used_ports = self.vm_manager.get_used_ports().await;
+        used_ports.extend(port_mapping.iter().map(|(host, _)| *host));
+        let network = &self.config.api.network;
+        let mut host_ports =
+            PortAllocator::new(network.port_allocation, network.port_range.ports())
+                .allocate(&used_ports, count)?
+                .into_iter();
+
+        Ok(port_mapping
+            .into_iter()
+            .map(|(host, guest)| match host {
+                0 => (host_ports.next().unwrap_or_default(), guest),
+                _ => (host, guest),
+            })
+            .collect())
+    }
+
+    fn require_feature(&self, feature: &str) -> Result<(), Error> {
+        if self.config.feature_enabled(feature) {
+            Ok(())
+        } else {
+            Err(Error::FeatureDisabled(feature.to_string()))
+        }
+    }
+
+    /// Pass a request to start a VM through the WASM plugins, which may
+    /// change it or deny it.
+    async fn admit(&self, request: VMOptionsDTO) -> Result<VMOptionsDTO, Error> {
+        if self.plugins.is_empty() {
+            return Ok(request);
+        }
+
+        let json = serde_json::to_string(&request).map_err(|e| Error::Other(e.into()))?;
+        let plugins = self.plugins.clone();
+        let admission = tokio::task::spawn_blocking(move || plugins.admit(json))
+            .await
+            .map_err(|e| Error::Other(e.into()))?
+            .map_err(Error::Other)?;
+
+        match admission {
+            Admission::Allowed(json) => serde_json::from_str(&json).map_err(|e| {
+                Error::Other(anyhow::anyhow!(
+                    "the plugins returned an invalid request: {}",
+                    e
+                ))
+            }),
+            Admission::Denied { plugin, reason } => {
+                info!("Plugin {} denied a request: {}", plugin, reason);
+                self.events.record(
+                    "vm.admission_denied",
+                    None,
+                    serde_json::json!({ "plugin": plugin, "reason": reason }),
+                );
+                Err(Error::AdmissionDenied(reason))
+            }
+        }
+    }
+
+    /// Ask the policy engine, if one is configured, whether `caller` may
+    /// start a VM with `request`.
+    async fn check_policy(&self, request: &VMOptionsDTO, caller: &Caller) -> Result<(), Error> {
+        let Some(config) = &self.config.api.policy else {
+            return Ok(());
+        };
+
+        let reasons = policy::evaluate(config, request, caller)
+            .await
+            .map_err(|e| {
+                error!("Error while evaluating the policy: {:?}", e);
+                Error::PolicyUnavailable(e.to_string())
+            })?;
+        if reasons.is_empty() {
+            return Ok(());
+        }
+
+        info!("The policy denied a request: {}", reasons.join("; "));
+        self.events.record(
+            "vm.admission_denied",
+            None,
+            serde_json::json!({ "policy": config.url, "reasons": reasons, "caller": caller }),
+        );
+        Err(Error::AdmissionDenied(reasons.join("; ")))
+    }
+
+    /// Pass a request of `caller` to start a VM through the plugins and the
+    /// policy engine, moving it to the namespace of the caller. Every VM
+    /// started for a caller is admitted by it.
+    ///
+    /// Callers restricted to a namespace only start VMs of the tenant of the
+    /// same name, if any, not to reach the bridge and images of another.
+    async fn admitted(
+        &self,
+        request: VMOptionsDTO,
+        caller: &Caller,
+    ) -> Result<VMOptionsDTO, Error> {
+        let mut request = self
+            .admit(request)
+            .instrument(trace_span!("plugins"))
+            .await?;
+        check_tenant_scope(&request, caller)?;
+        self.check_policy(&request, caller)
+            .instrument(trace_span!("policy"))
+            .await?;
+        request.namespace = caller.namespace.clone();
+        Ok(request)
+    }
+
+    /// Check that starting a VM with `options` keeps `namespace` within
+    /// `quota`, counting the VMs of the namespace being started.
+    async fn check_quota(
+        &self,
+        namespace: &str,
+        quota: &NamespaceConfig,
+        requested: NamespaceUsage,
+        starting: &HashMap<String, NamespaceUsage>,
+    ) -> Result<(), Error> {
+        let usage = self.vm_manager.get_namespace_usage(namespace).await
+            + starting.get(namespace).copied().unwrap_or_default()
+            + requested;
+        match usage.violation(namespace, quota) {
+            Some(violation) => Err(Error::QuotaExceeded(violation)),
+            None => Ok(()),
+        }
+    }
+
+    /// Volume `name`, unless it is in another namespace than the one
+    /// `caller` is restricted to
+    async fn volume_of(&self, name: &str, caller: &Caller) -> Result<Volume, Error> {
+        self.volume_manager
+            .get(name)
+            .await
+            .ok()
+            .filter(|volume| caller.scope().is_none_or(|scope| scope == volume.namespace))
+            .ok_or(Error::VolumeNotFound)
+    }
+
+    /// Move an image in the namespace of `tenant`, if any.
+    fn scoped(
+        &self,
+        manifest: &ImageManifest,
+        tenant: Option<&str>,
+    ) -> Result<ImageManifest, Error> {
+        match tenant {
+            None => Ok(manifest.clone()),
+            Some(tenant) if !self.config.api.tenants.contains_key(tenant) => {
+                Err(Error::TenantNotFound)
+            }
+            Some(tenant) => manifest.in_namespace(tenant).map_err(Error::ImageError),
+        }
+    }
+
     pub async fn new_with_state(
         state: LambdoStateRef,
         image_manager: Box<dyn ImageManager>,
+        volume_manager: VolumeManager,
     ) -> Result<Self, Error> {
-        let config = state.lock().await.config.clone();
-        let vm_manager = VMManager::from_state(state).await?;
-        Ok(LambdoApiService {
+        let (config, events) = {
+            let state = state.lock().await;
+            (state.config.clone(), state.events.clone())
+        };
+        let vm_manager = VMManager::from_state(state.clone()).await?;
+        let plugins = Arc::new(load_plugins(&config)?);
+        let jobs = JobStore::new(&config.api.jobs);
+        let service = LambdoApiService {
             config,
-            vm_manager: Box::new(vm_manager),
+            events,
+            vm_manager: Arc::new(vm_manager),
             image_manager,
-        })
+            volume_manager: Arc::new(volume_manager),
+            auditing: tokio::sync::Mutex::new(()),
+            plugins,
+            invoking: tokio::sync::Mutex::new(HashMap::new()),
+            creating_stacks: tokio::sync::Mutex::new(HashMap::new()),
+            jobs,
+            starting: tokio::sync::Mutex::new(HashMap::new()),
+        };
+
+        // Pick up where the VMs reattached after a restart were left
+        let (vm_ids, deadlines): (Vec<String>, Vec<(String, u64)>) = {
+            let state = state.lock().await;
+            (
+                state.vms.iter().map(|vm| vm.get_id()).collect(),
+                state
+                    .vms
+                    .iter()
+                    .filter_map(|vm| vm.destroy_at.map(|at| (vm.get_id(), at)))
+                    .collect(),
+            )
+        };
+        service.volume_manager.release_unknown(&vm_ids).await?;
+        for (id, destroy_at) in deadlines {
+            service.destroy_at(&id, destroy_at);
+        }
+        tokio::spawn(release_expired(
+            service.events.subscribe(),
+            service.vm_manager.clone(),
+            service.volume_manager.clone(),
+        ));
+
+        Ok(service)
     }
 
-    async fn find_kernel(&self, kernel: &ImageManifest) -> Result<Image, Error> {
-        self.image_manager
-            .find_kernel(kernel)
-            .await
-            .map_err(Error::ImageError)
+    /// Destroy a VM at `destroy_at` (in ms since epoch), unless its
+    /// destruction is cancelled or scheduled again in the meantime.
+    fn destroy_at(&self, id: &str, destroy_at: u64) {
+        let vm_manager = self.vm_manager.clone();
+        let volume_manager = self.volume_manager.clone();
+        let id = id.to_string();
+        tokio::spawn(async move {
+            let now = now_ms();
+            tokio::time::sleep(Duration::from_millis(destroy_at.saturating_sub(now))).await;
+
+            // The destruction may have been cancelled, and even scheduled again
+            if vm_manager.get_destroy_deadline_of_vm(&id).await != Some(destroy_at) {
+                debug!("Destruction of VM {} was cancelled", id);
+                return;
+            }
+
+            if let Err(e) = destroy(&*vm_manager, &volume_manager, &id).await {
+                error!("Error while destroying VM {}: {:?}", id, e);
+            }
+        });
     }
 
-    async fn find_rootfs(&self, rootfs: &ImageManifest) -> Result<Image, Error> {
-        self.image_manager
-            .find_rootfs(rootfs)
-            .await
-            .map_err(Error::ImageError)
+    /// Reserve the requested volumes of `namespace` for `owner` and turn them
+    /// into disks.
+    async fn attach_volumes(
+        &self,
+        volumes: &[VolumeAttachmentDTO],
+        namespace: &str,
+        owner: &str,
+    ) -> Result<Vec<DiskOptions>, Error> {
+        let names: Vec<String> = volumes.iter().map(|v| v.name.clone()).collect();
+        let attached = self.volume_manager.attach(&names, namespace, owner).await?;
+
+        Ok(attached
+            .iter()
+            .zip(volumes)
+            .map(|(volume, request)| DiskOptions {
+                image: volume.image(),
+                is_readonly: request.is_readonly,
+                is_root_device: false,
+                is_persistent: true,
+            })
+            .collect())
     }
-}
 
-#[async_trait::async_trait]
-impl LambdoApiServiceTrait for LambdoApiService {
-    async fn start(&self, request: VMOptionsDTO) -> Result<(String, HashMap<u16, u16>), Error> {
-        let options = self.to_options(request).await?;
+    /// Start the task keeping the warm pools filled, if any are configured.
+    pub async fn start_warm_pools(&self) -> Result<(), Error> {
+        let config = &self.config.api.warm_pool;
+        if config.pools.is_empty() {
+            return Ok(());
+        }
 
-        match self
-            .vm_manager
-            .start_vm(options)
-            .await
-            .map(|id| async move {
+        let mut pools = Vec::new();
+        for entry in &config.pools {
+            let kernel = self
+                .find_kernel(&ImageManifest {
+                    id: entry.kernel.clone(),
+                    location: entry.kernel.clone(),
+                    sha256: None,
+                })
+                .await?;
+            let rootfs = self
+                .find_rootfs(&ImageManifest {
+                    id: entry.rootfs.clone(),
+                    location: entry.rootfs.clone(),
+                    sha256: None,
+                })
+                .await?;
+
+            pools.push(WarmPool {
+                key: warm_pool::pool_key(&kernel, &rootfs),
+                size: entry.size,
+                options: VMOptions {
+                    boot: BootOptions {
+                        kernel,
+                        initrd: None,
+                        boot_args: None,
+                        sysctls: HashMap::new(),
+                        kernel_modules: Vec::new(),
+                        read_only_rootfs: false,
+                    },
+                    disks: vec![DiskOptions {
+                        image: rootfs,
+                        is_readonly: false,
+                        is_root_device: true,
+                        is_persistent: false,
+                    }],
+                    network: NetworkOptions {
+                        port_mapping: Vec::new(),
+                        egress_profile: None,
+                    },
+                    tenant: None,
+                    vcpu_count: None,
+                    mem_size_mib: None,
+                    machine_class: None,
+                    snapshot_policy: None,
+                    restart_policy: None,
+                    ttl_seconds: None,
+                    namespace: None,
+                    stack: None,
+                },
+            });
+        }
+
+        tokio::spawn(warm_pool::maintain(
+            self.vm_manager.clone(),
+            pools,
+            Duration::from_secs(config.refill_interval),
+        ));
+        Ok(())
+    }
+
+    /// Start a VM for a request admitted by the plugins and the policy
+    /// engine, with the volumes it asks for.
+    async fn launch(&self, request: VMOptionsDTO) -> Result<(String, HashMap<u16, u16>), Error> {
+        let reservation = Uuid::new_v4().to_string();
+        // Each phase is timed in the debug log of debugged requests
+        let namespace = request.namespace.as_deref().unwrap_or(DEFAULT_NAMESPACE);
+        let volumes = self
+            .attach_volumes(&request.volumes, namespace, &reservation)
+            .instrument(trace_span!("volumes"))
+            .await?;
+
+        let result = async {
+            let mut options = self
+                .to_options(request)
+                .instrument(trace_span!("images"))
+                .await?;
+            options.disks.extend(volumes);
+            self.start_vm(options).instrument(trace_span!("vm")).await
+        }
+        .await;
+
+        match result {
+            Ok(id) => {
+                self.volume_manager.transfer(&reservation, &id).await?;
                 let ports = self.vm_manager.get_used_ports_of_vm(&id).await;
-                (id, ports.unwrap_or_default())
-            }) {
-            Ok(response) => Ok(response.await),
-            Err(e) => Err(e),
+                Ok((id, ports.unwrap_or_default()))
+            }
+            Err(e) => {
+                self.volume_manager.detach_all(&reservation).await?;
+                Err(e)
+            }
         }
     }
 
-    async fn stop(&self, id: &str) -> Result<(), Error> {
-        self.vm_manager.stop_vm(id).await.map(|_| ())
+    /// Run the command of an item handed out by the scheduler in a VM booted
+    /// for it and destroyed once the command exited, giving the item back to
+    /// be run again if the host lacks capacity or the command is retried.
+    async fn run_item(&self, item: Dispatched) {
+        let (id, index) = (item.job_id.clone(), item.index);
+        let request = item.request.clone();
+        let timeout = request.timeout.unwrap_or(self.config.api.jobs.max_timeout);
+        let mut env = request.env.clone();
+        if let Some(index) = index {
+            env.insert(JOB_INDEX_ENV.to_string(), index.to_string());
+            env.insert(
+                JOB_COUNT_ENV.to_string(),
+                request.count.unwrap_or(1).to_string(),
+            );
+        }
+
+        let mut artifacts = Vec::new();
+        let result = match self.launch(request.vm.clone()).await {
+            Err(e @ Error::InsufficientCapacity(_))
+            | Err(e @ Error::QuotaExceeded(_))
+            | Err(e @ Error::Cordoned) => {
+                debug!("Job {} ({:?}) waits for capacity: {}", id, index, e);
+                self.update_job_run(&id, index, |run| run.status = JobStatus::Queued);
+                self.jobs.requeue(item, true);
+                return;
+            }
+            Err(e) => {
+                self.update_job_run(&id, index, |run| run.attempt(None));
+                Err(e)
+            }
+            Ok((vm_id, _)) => {
+                debug!("Running job {} ({:?}) in VM {}", id, index, vm_id);
+                self.update_job_run(&id, index, |run| run.attempt(Some(vm_id.clone())));
+                let result = async {
+                    self.wait_for_agent(&vm_id).await?;
+                    let output = self
+                        .exec_in(&vm_id, request.command.clone(), env, timeout)
+                        .await?;
+                    if let Some(files) = &request.artifacts {
+                        artifacts = self.upload_artifacts(&vm_id, &id, index, files).await?;
+                    }
+                    Ok(output)
+                }
+                .await;
+                if let Err(e) = destroy(&*self.vm_manager, &self.volume_manager, &vm_id).await {
+                    error!(
+                        "Error while destroying VM {} after job {}: {:?}",
+                        vm_id, id, e
+                    );
+                }
+                result
+            }
+        };
+
+        if let Err(e) = &result {
+            error!("Error while running job {} ({:?}): {:?}", id, index, e);
+        }
+        if !matches!(result, Ok((0, _, _))) && item.attempts < request.retries {
+            debug!(
+                "Retrying job {} ({:?}) after attempt {}",
+                id,
+                index,
+                item.attempts + 1
+            );
+            self.update_job_run(&id, index, |run| run.status = JobStatus::Queued);
+            self.jobs.requeue(item, false);
+            return;
+        }
+
+        let job = self.update_job_run(&id, index, |run| run.finish(&result, artifacts));
+        self.jobs.done(item);
+        if let Some(job) = job.filter(|job| job.run.status.is_finished()) {
+            info!("Job {} is {:?}", id, job.run.status);
+            self.events.record(
+                "job.finished",
+                job.run.vm_id.as_deref(),
+                serde_json::json!({
+                    "job_id": job.id,
+                    "namespace": job.namespace,
+                    "status": job.run.status,
+                    "exit_code": job.run.exit_code,
+                    "summary": job.summary,
+                }),
+            );
+        }
     }
 
-    async fn simple_spawn(
+    /// Upload the files of the guest of VM `id` asked for by `request`, under
+    /// the prefix of the job `job_id`, or of its item `index`.
+    async fn upload_artifacts(
         &self,
-        request: SimpleSpawn,
-    ) -> Result<(String, HashMap<u16, u16>), Error> {
-        let used_ports = self.vm_manager.get_used_ports().await;
+        id: &str,
+        job_id: &str,
+        index: Option<u32>,
+        request: &ArtifactsRequest,
+    ) -> Result<Vec<Artifact>, Error> {
+        let destination = self
+            .config
+            .api
+            .jobs
+            .artifacts
+            .get(&request.destination)
+            .ok_or_else(|| {
+                Error::InvalidOptions(format!(
+                    "unknown artifact destination {}",
+                    request.destination
+                ))
+            })?;
+        let prefix = match index {
+            Some(index) => format!("{}/{}", job_id, index),
+            None => job_id.to_string(),
+        };
 
-        let port_mapping = request
-            .requested_ports
+        let client = reqwest::Client::new();
+        let mut uploaded = Vec::new();
+        for path in &request.paths {
+            let name = artifacts::file_name(path).map_err(Error::Other)?;
+            let read = AgentRequest::ReadFile { path: path.clone() };
+            let content = match self.vm_manager.call_agent(id, read).await? {
+                AgentResponse::File { content } => openssl::base64::decode_block(&content)
+                    .map_err(|e| {
+                        Error::AgentUnavailable(format!("invalid file {}: {}", path, e))
+                    })?,
+                AgentResponse::Error { message } => {
+                    return Err(Error::AgentUnavailable(format!(
+                        "cannot read {}: {}",
+                        path, message
+                    )))
+                }
+                response => {
+                    return Err(Error::AgentUnavailable(format!(
+                        "unexpected reply {:?}",
+                        response
+                    )))
+                }
+            };
+
+            let size = content.len() as u64;
+            let key = format!("{}/{}", prefix, name);
+            let url = artifacts::upload(&client, destination, &key, content)
+                .await
+                .map_err(Error::Other)?;
+            debug!("Uploaded {} of VM {} to {}", path, id, url);
+            uploaded.push(Artifact {
+                path: path.clone(),
+                url,
+                size,
+            });
+        }
+        Ok(uploaded)
+    }
+
+    /// Apply `change` to the run of the job `id`, or of its item `index`.
+    fn update_job_run(
+        &self,
+        id: &str,
+        index: Option<u32>,
+        change: impl FnOnce(&mut JobRun),
+    ) -> Option<Job> {
+        self.jobs.update(id, |job| match index {
+            Some(index) => job.update_item(index, change),
+            None => change(&mut job.run),
+        })
+    }
+
+    /// Services of the stack `name` of the namespace `scope`, or of the only
+    /// namespace with a stack of that name if unset
+    async fn stack_vms(&self, scope: Option<&str>, name: &str) -> Result<Vec<VMDetails>, Error> {
+        let vms: Vec<VMDetails> = self
+            .vm_manager
+            .list_vms(scope.map(str::to_string))
+            .await
+            .into_iter()
+            .filter(|vm| vm.stack.as_ref().is_some_and(|member| member.stack == name))
+            .collect();
+
+        let namespaces: BTreeSet<&str> = vms.iter().map(|vm| vm.namespace.as_str()).collect();
+        if namespaces.len() > 1 {
+            return Err(Error::InvalidOptions(format!(
+                "stack {} exists in namespaces {}, pick one",
+                name,
+                namespaces.into_iter().collect::<Vec<_>>().join(", ")
+            )));
+        }
+        Ok(vms)
+    }
+
+    /// Stack made of `vms`, along with the share link of its ingress
+    async fn stack_of(&self, vms: &[VMDetails]) -> Option<Stack> {
+        let ingress = vms
             .iter()
-            .map(|guest| {
-                for i in 10000_u16..20000 {
-                    if !used_ports.contains(&i) {
-                        return Ok((i, *guest));
-                    }
+            .find(|vm| vm.stack.as_ref().is_some_and(|member| member.ingress))
+            .map(|vm| vm.id.clone());
+        let link = match ingress {
+            Some(id) => self
+                .vm_manager
+                .get_share_links_of_vm(&id)
+                .await
+                .and_then(|links| links.into_iter().next()),
+            None => None,
+        };
+        Stack::of(vms, link)
+    }
+
+    /// Start the services of `request` after the ones they depend on,
+    /// waiting for the ones with a readiness probe to pass it, and pushing
+    /// the id of each VM to `started` as soon as it is running.
+    async fn start_stack_services(
+        &self,
+        request: StackDTO,
+        caller: &Caller,
+        started: &mut Vec<String>,
+    ) -> Result<(), Error> {
+        let mut hosts: Vec<String> = Vec::new();
+        for service in request.ordered()? {
+            let ingress = request
+                .ingress
+                .as_ref()
+                .filter(|ingress| ingress.service == service.name);
+            let mut vm = self.admitted(service.vm.clone(), caller).await?;
+
+            if let Some(ingress) = ingress {
+                if !vm
+                    .network
+                    .port_mapping
+                    .iter()
+                    .any(|(_, guest)| *guest == ingress.port)
+                {
+                    vm.network.port_mapping.push((0, ingress.port));
                 }
-                Err(Error::NetSetupError(anyhow::anyhow!("No free port found")))
-            })
-            .collect::<Result<Vec<(u16, u16)>, Error>>()?;
+            }
+            // Passed to init as environment, like the addresses of the guest
+            let mut boot_args = vm
+                .boot
+                .boot_args
+                .take()
+                .unwrap_or(DEFAULT_BOOT_ARGS.to_string());
+            boot_args.push_str(&format!(" stack={}", request.name));
+            if !hosts.is_empty() {
+                boot_args.push_str(&format!(" stack_hosts={}", hosts.join(",")));
+            }
+            vm.boot.boot_args = Some(boot_args);
+            vm.stack = Some(StackMember {
+                stack: request.name.clone(),
+                service: service.name.clone(),
+                ingress: ingress.is_some(),
+            });
 
-        let options = VMOptions {
+            let (id, _) = self.launch(vm).await?;
+            started.push(id.clone());
+            info!(
+                "Started service {} of stack {} as VM {}",
+                service.name, request.name, id
+            );
+
+            // The services depending on it are not started before it is
+            // ready, nor is the stack returned
+            if let Some(probe) = request.readiness(service) {
+                self.vm_manager
+                    .wait_until_ready(&id, probe.port, probe.timeout_seconds)
+                    .await
+                    .map_err(|e| match e {
+                        Error::BootTimeout(failure) => {
+                            Error::ServiceUnhealthy(service.name.clone(), failure)
+                        }
+                        e => e,
+                    })?;
+                info!(
+                    "Service {} of stack {} is ready",
+                    service.name, request.name
+                );
+            }
+
+            if let Some(ip) = self.vm(&id).await?.ip {
+                let address = ip.split('/').next().unwrap_or_default();
+                hosts.push(format!("{}:{}", service.name, address));
+            }
+            if let Some(ingress) = ingress {
+                let link = ShareLinkDTO {
+                    port: ingress.port,
+                    ttl_seconds: ingress.ttl_seconds,
+                };
+                self.create_share_link(&id, link).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Start a VM in its namespace, if that keeps the namespace within its
+    /// quotas.
+    async fn start_vm(&self, options: VMOptions) -> Result<String, Error> {
+        let namespace = options
+            .namespace
+            .clone()
+            .unwrap_or(DEFAULT_NAMESPACE.to_string());
+        let requested = self.reserve_quota(&namespace, &options).await?;
+        let result = self.start_or_claim_vm(options).await;
+
+        let mut starting = self.starting.lock().await;
+        if let Some(usage) = starting.get_mut(&namespace) {
+            *usage = *usage - requested;
+        }
+        result
+    }
+
+    /// Count a VM started with `options` in the resources of `namespace`
+    /// being started, failing if that goes over a quota of the namespace.
+    async fn reserve_quota(
+        &self,
+        namespace: &str,
+        options: &VMOptions,
+    ) -> Result<NamespaceUsage, Error> {
+        let requested = self.requested_usage(options);
+        let Some(quota) = self.vm_manager.get_namespace_quota(namespace).await else {
+            return Ok(NamespaceUsage::default());
+        };
+
+        let mut starting = self.starting.lock().await;
+        self.check_quota(namespace, &quota, requested, &starting)
+            .await?;
+
+        let reserved = starting.entry(namespace.to_string()).or_default();
+        *reserved = *reserved + requested;
+        Ok(requested)
+    }
+
+    /// Resources a VM started with `options` takes from its namespace
+    fn requested_usage(&self, options: &VMOptions) -> NamespaceUsage {
+        let class = options
+            .machine_class
+            .as_ref()
+            .and_then(|class| self.config.api.admission.classes.get(class));
+        NamespaceUsage {
+            vms: 1,
+            vcpus: match class {
+                Some(class) => class.vcpu_count as u64,
+                None => options.vcpu_count.unwrap_or(DEFAULT_VCPU_COUNT) as u64,
+            },
+            memory_mib: match class {
+                Some(class) => class.mem_size_mib as u64,
+                None => options.mem_size_mib.unwrap_or(DEFAULT_MEM_SIZE_MIB) as u64,
+            },
+            ports: options.network.port_mapping.len() as u32,
+        }
+    }
+
+    /// Start a VM, handing out a pre-booted one when a warm pool matches its
+    /// options.
+    async fn start_or_claim_vm(&self, options: VMOptions) -> Result<String, Error> {
+        // Services of stacks are told about each other at boot, which warm
+        // VMs were booted without
+        let pool = warm_pool::pool_for(&options).filter(|_| options.stack.is_none());
+        let warm = match pool {
+            Some(pool) => self.vm_manager.claim_warm_vm(&pool, &options).await?,
+            None => None,
+        };
+        let Some(id) = warm else {
+            return self.vm_manager.start_vm(options).await;
+        };
+
+        debug!("Handing out warm VM {}", id);
+        if let Some(namespace) = &options.namespace {
+            self.vm_manager.set_namespace_of_vm(&id, namespace).await?;
+        }
+        let update = PortsUpdateDTO {
+            add: options.network.port_mapping,
+            remove: Vec::new(),
+        };
+        if let Err(e) = self.vm_manager.update_ports_of_vm(&id, update).await {
+            destroy(&*self.vm_manager, &self.volume_manager, &id).await?;
+            return Err(e);
+        }
+        if let Some(ttl_seconds) = options.ttl_seconds {
+            self.vm_manager.set_ttl_of_vm(&id, ttl_seconds).await?;
+        }
+        self.vm_manager.run_post_start_hooks(&id).await;
+
+        Ok(id)
+    }
+
+    /// Options of a VM booting `rootfs` with `kernel`, with nothing else set,
+    /// which warm pools can serve.
+    async fn plain_options(
+        &self,
+        kernel: &str,
+        rootfs: &ImageManifest,
+    ) -> Result<VMOptions, Error> {
+        Ok(VMOptions {
             boot: BootOptions {
                 kernel: self
                     .find_kernel(&ImageManifest {
-                        id: "vmlinux".to_string(),
-                        location: "vmlinux".to_string(),
+                        id: kernel.to_string(),
+                        location: kernel.to_string(),
+                        sha256: None,
                     })
                     .await?,
                 initrd: None,
                 boot_args: None,
+                sysctls: HashMap::new(),
+                kernel_modules: Vec::new(),
+                read_only_rootfs: false,
             },
             disks: vec![DiskOptions {
-                image: self.find_rootfs(&request.rootfs).await?,
+                image: self.find_rootfs(rootfs).await?,
+                is_readonly: false,
+                is_root_device: true,
+                is_persistent: false,
+            }],
+            network: NetworkOptions {
+                port_mapping: Vec::new(),
+                egress_profile: None,
+            },
+            tenant: None,
+            vcpu_count: None,
+            mem_size_mib: None,
+            machine_class: None,
+            snapshot_policy: None,
+            restart_policy: None,
+            ttl_seconds: None,
+            namespace: None,
+            stack: None,
+        })
+    }
+
+    /// Request for a VM booting `rootfs` with `kernel`, with nothing else
+    /// set, which warm pools can serve.
+    fn plain_request(kernel: &str, rootfs: ImageManifest) -> VMOptionsDTO {
+        VMOptionsDTO {
+            boot: BootOptionsDTO {
+                boot_args: None,
+                initrd: None,
+                kernel: ImageManifest {
+                    id: kernel.to_string(),
+                    location: kernel.to_string(),
+                    sha256: None,
+                },
+                sysctls: HashMap::new(),
+                kernel_modules: Vec::new(),
+                read_only_rootfs: false,
+            },
+            disks: vec![DiskOptionsDTO {
+                image: rootfs,
                 is_readonly: false,
                 is_root_device: true,
             }],
-            network: NetworkOptions { port_mapping },
+            volumes: Vec::new(),
+            network: NetworkOptions {
+                port_mapping: Vec::new(),
+                egress_profile: None,
+            },
+            tenant: None,
+            vcpu_count: None,
+            mem_size_mib: None,
+            machine_class: None,
+            snapshot_policy: None,
+            restart_policy: None,
+            ttl_seconds: None,
+            namespace: None,
+            stack: None,
+        }
+    }
+
+    /// Wait for the agent of a VM that just booted to answer.
+    async fn wait_for_agent(&self, id: &str) -> Result<(), Error> {
+        let deadline =
+            tokio::time::Instant::now() + Duration::from_secs(self.config.api.agent.timeout);
+        loop {
+            match self.vm_manager.call_agent(id, AgentRequest::Health).await {
+                Ok(_) => return Ok(()),
+                Err(Error::AgentUnavailable(_)) if tokio::time::Instant::now() < deadline => {
+                    tokio::time::sleep(AGENT_POLL_INTERVAL).await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Run `code` in the VM `id` of `runtime`.
+    async fn run_in(
+        &self,
+        id: &str,
+        runtime: &RuntimeConfig,
+        request: RunRequest,
+        timeout: u64,
+    ) -> Result<(i32, String, String), Error> {
+        self.wait_for_agent(id).await?;
+
+        let mut command = runtime.command.clone();
+        command.push(request.code);
+        self.exec_in(id, command, request.env, timeout).await
+    }
+
+    /// Run `command` in the VM `id` through its agent, returning its exit
+    /// code and output.
+    async fn exec_in(
+        &self,
+        id: &str,
+        command: Vec<String>,
+        env: HashMap<String, String>,
+        timeout: u64,
+    ) -> Result<(i32, String, String), Error> {
+        let exec = AgentRequest::Exec {
+            command,
+            env,
+            timeout_seconds: Some(timeout),
         };
+        match self.vm_manager.call_agent(id, exec).await? {
+            AgentResponse::Exec {
+                exit_code,
+                stdout,
+                stderr,
+            } => Ok((exit_code, stdout, stderr)),
+            AgentResponse::Error { message } => Err(Error::AgentUnavailable(message)),
+            response => Err(Error::AgentUnavailable(format!(
+                "unexpected reply {:?}",
+                response
+            ))),
+        }
+    }
 
-        match self
-            .vm_manager
-            .start_vm(options)
+    async fn find_kernel(&self, kernel: &ImageManifest) -> Result<Image, Error> {
+        self.image_manager
+            .find_kernel(kernel)
             .await
-            .map(|id| async move {
-                let ports = self.vm_manager.get_used_ports_of_vm(&id).await;
-                (id, ports.unwrap_or_default())
-            }) {
-            Ok(response) => Ok(response.await),
-            Err(e) => Err(e),
-        }
+            .map_err(Error::ImageError)
+    }
+
+    async fn find_rootfs(&self, rootfs: &ImageManifest) -> Result<Image, Error> {
+        self.image_manager
+            .find_rootfs(rootfs)
+            .await
+            .map_err(Error::ImageError)
+    }
+}
+
+#[async_trait::async_trait]
+impl LambdoApiServiceTrait for LambdoApiService {
+    async fn start(
+        &self,
+        request: VMOptionsDTO,
+        caller: Caller,
+    ) -> Result<(String, HashMap<u16, u16>), Error> {
+        let request = self.admitted(request, &caller).await?;
+        self.launch(request).await
+    }
+
+    async fn create_stack(&self, request: StackDTO, caller: Caller) -> Result<Stack, Error> {
+        request.check()?;
+        if request.ingress.is_some() && !self.config.api.ingress_proxy.enabled {
+            return Err(Error::FeatureDisabled("ingress proxy".to_string()));
+        }
+        let name = request.name.clone();
+        let namespace = caller.namespace().to_string();
+        let creating = self
+            .creating_stacks
+            .lock()
+            .await
+            .entry(format!("{}/{}", namespace, name))
+            .or_default()
+            .clone();
+        let _creating = creating.lock().await;
+        if !self.stack_vms(Some(&namespace), &name).await?.is_empty() {
+            return Err(Error::StackAlreadyExists);
+        }
+
+        let mut started = Vec::new();
+        let result = self
+            .start_stack_services(request, &caller, &mut started)
+            .await;
+        if let Err(e) = result {
+            // Not to leave half of a stack behind
+            warn!(
+                "Cannot start stack {}, destroying its services: {}",
+                name, e
+            );
+            for id in &started {
+                if let Err(e) = destroy(&*self.vm_manager, &self.volume_manager, id).await {
+                    error!(
+                        "Error while destroying VM {} of stack {}: {:?}",
+                        id, name, e
+                    );
+                }
+            }
+            return Err(e);
+        }
+
+        self.events.record(
+            "stack.created",
+            None,
+            serde_json::json!({ "name": name, "namespace": namespace, "vms": started }),
+        );
+        let vms = self.stack_vms(Some(&namespace), &name).await?;
+        self.stack_of(&vms).await.ok_or(Error::StackNotFound)
+    }
+
+    async fn stacks(&self, caller: &Caller) -> Vec<Stack> {
+        let vms = self.list_vms(caller).await;
+        let mut stacks = Vec::new();
+        for vms in stacks::group(vms).into_values() {
+            stacks.extend(self.stack_of(&vms).await);
+        }
+        stacks
+    }
+
+    async fn stack(&self, name: &str, caller: &Caller) -> Result<Stack, Error> {
+        let vms = self.stack_vms(caller.scope(), name).await?;
+        self.stack_of(&vms).await.ok_or(Error::StackNotFound)
+    }
+
+    async fn discover(
+        &self,
+        stack: &str,
+        service: &str,
+        caller: &Caller,
+    ) -> Result<ServiceEndpoints, Error> {
+        let vms = self.stack_vms(caller.scope(), stack).await?;
+        if vms.is_empty() {
+            return Err(Error::StackNotFound);
+        }
+        ServiceEndpoints::of(&vms, service).ok_or(Error::ServiceNotFound)
+    }
+
+    async fn destroy_stack(&self, name: &str, caller: &Caller) -> Result<(), Error> {
+        let vms = self.stack_vms(caller.scope(), name).await?;
+        let Some(namespace) = vms.first().map(|vm| vm.namespace.clone()) else {
+            return Err(Error::StackNotFound);
+        };
+
+        // Every service is destroyed even if one fails, the first error
+        // being returned
+        let mut result = Ok(());
+        for vm in &vms {
+            if let Err(e) = destroy(&*self.vm_manager, &self.volume_manager, &vm.id).await {
+                error!(
+                    "Error while destroying VM {} of stack {}: {:?}",
+                    vm.id, name, e
+                );
+                result = result.and(Err(e));
+            }
+        }
+        self.events.record(
+            "stack.destroyed",
+            None,
+            serde_json::json!({ "name": name, "namespace": namespace }),
+        );
+        result
+    }
+
+    async fn submit_job(&self, mut request: JobRequest, caller: Caller) -> Result<Job, Error> {
+        if request.command.is_empty() {
+            return Err(Error::InvalidOptions("job command is empty".to_string()));
+        }
+        let max_timeout = self.config.api.jobs.max_timeout;
+        let timeout = request.timeout.unwrap_or(max_timeout);
+        if timeout == 0 || timeout > max_timeout {
+            return Err(Error::InvalidOptions(format!(
+                "jobs run between 1 and {} seconds",
+                max_timeout
+            )));
+        }
+        request.timeout = Some(timeout);
+        let jobs = &self.config.api.jobs;
+        if request
+            .count
+            .is_some_and(|count| count == 0 || count > jobs.max_array_size)
+        {
+            return Err(Error::InvalidOptions(format!(
+                "array jobs have between 1 and {} items",
+                jobs.max_array_size
+            )));
+        }
+        if request.parallelism == Some(0) {
+            return Err(Error::InvalidOptions(
+                "array jobs run at least one item at once".to_string(),
+            ));
+        }
+        if request.retries > jobs.max_retries {
+            return Err(Error::InvalidOptions(format!(
+                "jobs are retried at most {} times",
+                jobs.max_retries
+            )));
+        }
+        if let Some(artifacts) = &request.artifacts {
+            if !jobs.artifacts.contains_key(&artifacts.destination) {
+                return Err(Error::InvalidOptions(format!(
+                    "unknown artifact destination {}",
+                    artifacts.destination
+                )));
+            }
+            let mut names = HashSet::new();
+            for path in &artifacts.paths {
+                let name =
+                    artifacts::file_name(path).map_err(|e| Error::InvalidOptions(e.to_string()))?;
+                if !names.insert(name) {
+                    return Err(Error::InvalidOptions(format!(
+                        "artifacts named {} would overwrite each other",
+                        name
+                    )));
+                }
+            }
+        }
+
+        request.vm = self.admitted(request.vm, &caller).await?;
+        // Destroyed by the time to live if the job is interrupted, each file
+        // to upload being read in the time the agent has to reply
+        let files = request.artifacts.as_ref().map_or(0, |a| a.paths.len()) as u64;
+        let ttl = timeout + (2 + files) * self.config.api.agent.timeout + RUN_TTL_MARGIN;
+        request.vm.ttl_seconds = Some(request.vm.ttl_seconds.map_or(ttl, |t| t.min(ttl)));
+
+        let class = request
+            .vm
+            .machine_class
+            .as_ref()
+            .and_then(|class| self.config.api.admission.classes.get(class));
+        let resources = match class {
+            Some(class) => (class.vcpu_count as u64, class.mem_size_mib as u64),
+            None => (
+                request.vm.vcpu_count.unwrap_or(DEFAULT_VCPU_COUNT) as u64,
+                request.vm.mem_size_mib.unwrap_or(DEFAULT_MEM_SIZE_MIB) as u64,
+            ),
+        };
+        let job = self.jobs.submit(request, resources);
+        info!("Queued job {}", job.id);
+        Ok(job)
+    }
+
+    async fn job(&self, id: &str, caller: &Caller) -> Result<Job, Error> {
+        self.jobs
+            .get(id)
+            .filter(|job| caller.scope().is_none_or(|scope| scope == job.namespace))
+            .ok_or(Error::JobNotFound)
+    }
+
+    async fn stop(&self, id: &str) -> Result<Option<u64>, Error> {
+        let grace_period = self.config.api.destroy_grace_period;
+        if grace_period == 0 {
+            return destroy(&*self.vm_manager, &self.volume_manager, id)
+                .await
+                .map(|_| None);
+        }
+
+        if let Some(destroy_at) = self.vm_manager.get_destroy_deadline_of_vm(id).await {
+            return Ok(Some(destroy_at));
+        }
+
+        let destroy_at = now_ms() + grace_period * 1000;
+        if !self.vm_manager.schedule_destroy_vm(id, destroy_at).await? {
+            return Ok(self.vm_manager.get_destroy_deadline_of_vm(id).await);
+        }
+
+        info!("VM {} will be destroyed in {}s", id, grace_period);
+        self.destroy_at(id, destroy_at);
+
+        Ok(Some(destroy_at))
+    }
+
+    async fn undo_stop(&self, id: &str) -> Result<(), Error> {
+        self.vm_manager.cancel_destroy_vm(id).await
+    }
+
+    async fn pause(&self, id: &str) -> Result<(), Error> {
+        self.vm_manager.pause_vm(id).await
+    }
+
+    async fn resume(&self, id: &str) -> Result<(), Error> {
+        self.vm_manager.resume_vm(id).await
+    }
+
+    async fn export(&self, id: &str, options: ExportOptions) -> Result<ExportResult, Error> {
+        self.vm_manager.export_vm(id, options).await
+    }
+
+    async fn rollback(&self, id: &str, snapshot: &str) -> Result<(), Error> {
+        self.vm_manager.rollback_vm(id, snapshot).await
+    }
+
+    async fn call_agent(&self, id: &str, request: AgentRequest) -> Result<AgentResponse, Error> {
+        self.vm_manager.call_agent(id, request).await
+    }
+
+    async fn adopt(
+        &self,
+        mut options: AdoptOptions,
+        caller: &Caller,
+    ) -> Result<(String, HashMap<u16, u16>), Error> {
+        options.namespace = Some(caller.namespace().to_string());
+        let id = self.vm_manager.adopt_vm(options).await?;
+        let ports = self.vm_manager.get_used_ports_of_vm(&id).await;
+        Ok((id, ports.unwrap_or_default()))
+    }
+
+    async fn vm(&self, id: &str) -> Result<VMDetails, Error> {
+        self.vm_manager.get_vm(id).await.ok_or(Error::VmNotFound)
+    }
+
+    async fn list_vms(&self, caller: &Caller) -> Vec<VMDetails> {
+        self.vm_manager
+            .list_vms(caller.scope().map(str::to_string))
+            .await
+    }
+
+    async fn namespace_quota(&self, namespace: &str) -> NamespaceQuota {
+        NamespaceQuota {
+            namespace: namespace.to_string(),
+            quota: self.vm_manager.get_namespace_quota(namespace).await,
+            usage: self.vm_manager.get_namespace_usage(namespace).await,
+        }
+    }
+
+    async fn set_namespace_quota(&self, namespace: &str, quota: NamespaceConfig) -> NamespaceQuota {
+        // Running VMs over the new quota are left alone, only new ones are
+        // refused
+        self.vm_manager.set_namespace_quota(namespace, quota).await;
+        self.namespace_quota(namespace).await
+    }
+
+    async fn tenant_usage(&self, tenant: &str, caller: &Caller) -> Result<TenantUsage, Error> {
+        if caller.scope().is_some_and(|scope| scope != tenant) {
+            return Err(Error::TenantNotFound);
+        }
+        self.vm_manager
+            .get_tenant_usage(tenant)
+            .await
+            .ok_or(Error::TenantNotFound)
+    }
+
+    async fn ports(&self, id: &str) -> Result<HashMap<u16, u16>, Error> {
+        self.vm_manager
+            .get_used_ports_of_vm(id)
+            .await
+            .ok_or(Error::VmNotFound)
+    }
+
+    async fn port_owner(&self, host_port: u16, caller: &Caller) -> Option<PortOwner> {
+        let owner = self.vm_manager.get_port_owner(host_port).await?;
+        if let Some(scope) = caller.scope() {
+            let vm = self.vm(&owner.vm_id).await.ok()?;
+            if vm.namespace != scope {
+                return None;
+            }
+        }
+        Some(owner)
+    }
+
+    async fn create_share_link(&self, id: &str, request: ShareLinkDTO) -> Result<ShareLink, Error> {
+        self.vm_manager
+            .create_share_link(id, request.port, request.ttl_seconds)
+            .await
+    }
+
+    async fn share_links(&self, id: &str) -> Result<Vec<ShareLink>, Error> {
+        self.vm_manager
+            .get_share_links_of_vm(id)
+            .await
+            .ok_or(Error::VmNotFound)
+    }
+
+    async fn revoke_share_link(&self, id: &str, link_id: &str) -> Result<(), Error> {
+        self.vm_manager.revoke_share_link(id, link_id).await
+    }
+
+    async fn firewall_rules(&self, id: &str) -> Result<Vec<FirewallRule>, Error> {
+        self.vm_manager
+            .get_firewall_rules_of_vm(id)
+            .await
+            .ok_or(Error::VmNotFound)
+    }
+
+    async fn network(&self, id: &str) -> Result<VMNetwork, Error> {
+        self.vm_manager
+            .get_network_of_vm(id)
+            .await
+            .ok_or(Error::VmNotFound)
+    }
+
+    async fn update_ports(
+        &self,
+        id: &str,
+        update: PortsUpdateDTO,
+    ) -> Result<HashMap<u16, u16>, Error> {
+        self.vm_manager.update_ports_of_vm(id, update).await
+    }
+
+    async fn explain_start(&self, request: VMOptionsDTO, caller: &Caller) -> StartExplanation {
+        let mut checks = Vec::new();
+
+        // Same admission as start, without reserving anything
+        let mut request = match self.admit(request.clone()).await {
+            Ok(admitted) => {
+                checks.push(Check::new(
+                    "plugins",
+                    Ok::<_, Error>("the plugins let the request through".into()),
+                ));
+                admitted
+            }
+            Err(e) => {
+                checks.push(Check::new("plugins", Err(e)));
+                request
+            }
+        };
+        let scope = check_tenant_scope(&request, caller);
+        let in_scope = scope.is_ok();
+        checks.push(Check::new(
+            "tenant scope",
+            scope.map(|_| match &request.tenant {
+                Some(tenant) => format!("tenant {} is reachable by the caller", tenant),
+                None => "no tenant".to_string(),
+            }),
+        ));
+        // Nothing is told about the images of a tenant out of reach
+        if !in_scope {
+            return StartExplanation::refused(checks);
+        }
+        checks.push(Check::new(
+            "policy",
+            self.check_policy(&request, caller)
+                .await
+                .map(|_| "the policy allows the request".to_string()),
+        ));
+        request.namespace = caller.namespace.clone();
+
+        let tenant = request.tenant.as_deref();
+
+        // Same lookups as to_options, without downloading anything
+        let mut images = Vec::new();
+        let manifests = std::iter::once(("kernel", Ok(request.boot.kernel.clone())))
+            .chain(
+                request
+                    .boot
+                    .initrd
+                    .iter()
+                    .map(|initrd| ("initrd", self.scoped(initrd, tenant))),
+            )
+            .chain(
+                request
+                    .disks
+                    .iter()
+                    .map(|disk| ("disk", self.scoped(&disk.image, tenant))),
+            )
+            .collect::<Vec<_>>();
+        for (role, manifest) in manifests {
+            match manifest {
+                Ok(manifest) => images.push(ImageChoice {
+                    role,
+                    source: self.image_manager.describe(&manifest).await,
+                }),
+                Err(e) => checks.push(Check::new("images", Err(e))),
+            }
+        }
+        for image in images
+            .iter()
+            .filter(|i| i.source.origin == ImageOrigin::Missing)
+        {
+            checks.push(Check::new(
+                "images",
+                Err(format!("image {} not found", image.source.id)),
+            ));
+        }
+
+        checks.push(Check::new(
+            "guest tuning",
+            self.config
+                .api
+                .guest_tuning
+                .check(&request.boot.sysctls, &request.boot.kernel_modules)
+                .map(|_| "sysctls and kernel modules allowed".to_string()),
+        ));
+
+        let used_ports = self.vm_manager.get_used_ports().await;
+        let taken: Vec<u16> = request
+            .network
+            .port_mapping
+            .iter()
+            .map(|(host, _)| *host)
+            .filter(|host| *host != 0 && used_ports.contains(host))
+            .collect();
+        let port_mapping = match self
+            .allocate_host_ports(request.network.port_mapping.clone())
+            .await
+        {
+            Ok(mapping) if taken.is_empty() => {
+                checks.push(Check::new("host ports", Ok::<_, Error>("available".into())));
+                mapping
+            }
+            Ok(mapping) => {
+                checks.push(Check::new("host ports", Err(Error::PortInUse(taken[0]))));
+                mapping
+            }
+            Err(e) => {
+                checks.push(Check::new("host ports", Err(e)));
+                request.network.port_mapping.clone()
+            }
+        };
+
+        let mut explanation = StartExplanation {
+            would_start: false,
+            warm_pool: WarmPoolDecision::default(),
+            images,
+            port_mapping: port_mapping.clone(),
+            bridge: None,
+            boot_args: None,
+            vcpu_count: None,
+            mem_size_mib: None,
+            read_only_rootfs: false,
+            checks,
+        };
+        // The plan needs every image
+        if explanation
+            .checks
+            .iter()
+            .any(|check| check.name == "images")
+        {
+            return explanation;
+        }
+
+        let mut sources = explanation.images.iter().map(|image| Image {
+            id: image.source.id.clone(),
+            path: image.source.path.clone(),
+        });
+        let Some(kernel) = sources.next() else {
+            return explanation;
+        };
+        let initrd = request.boot.initrd.as_ref().and_then(|_| sources.next());
+        let options = VMOptions {
+            boot: BootOptions {
+                kernel,
+                initrd,
+                boot_args: request.boot.boot_args,
+                sysctls: request.boot.sysctls,
+                kernel_modules: request.boot.kernel_modules,
+                read_only_rootfs: request.boot.read_only_rootfs,
+            },
+            disks: request
+                .disks
+                .iter()
+                .zip(sources)
+                .map(|(disk, image)| DiskOptions {
+                    image,
+                    is_readonly: disk.is_readonly,
+                    is_root_device: disk.is_root_device,
+                    is_persistent: false,
+                })
+                .collect(),
+            network: NetworkOptions {
+                port_mapping,
+                egress_profile: request.network.egress_profile,
+            },
+            tenant: request.tenant,
+            vcpu_count: request.vcpu_count,
+            mem_size_mib: request.mem_size_mib,
+            machine_class: request.machine_class,
+            snapshot_policy: request.snapshot_policy,
+            restart_policy: request.restart_policy,
+            ttl_seconds: request.ttl_seconds,
+            namespace: request.namespace,
+            stack: request.stack,
+        };
+
+        explanation.warm_pool = match warm_pool::pool_for(&options) {
+            Some(pool) => {
+                let idle = self.vm_manager.count_warm_vms(&pool).await;
+                WarmPoolDecision {
+                    reason: if idle > 0 {
+                        "an idle VM of the pool would be handed out".to_string()
+                    } else {
+                        "no idle VM in the pool, a VM would be booted".to_string()
+                    },
+                    pool: Some(pool),
+                    idle,
+                    claimed: idle > 0,
+                }
+            }
+            None => WarmPoolDecision {
+                reason: warm_pool::not_poolable(&options)
+                    .unwrap_or_default()
+                    .to_string(),
+                ..Default::default()
+            },
+        };
+
+        let namespace = options.namespace.as_deref().unwrap_or(DEFAULT_NAMESPACE);
+        let quota = match self.vm_manager.get_namespace_quota(namespace).await {
+            Some(quota) => {
+                let starting = self.starting.lock().await;
+                let requested = self.requested_usage(&options);
+                self.check_quota(namespace, &quota, requested, &starting)
+                    .await
+                    .map(|_| format!("within the quota of namespace {}", namespace))
+            }
+            None => Ok(format!("namespace {} has no quota", namespace)),
+        };
+        explanation.checks.push(Check::new("quota", quota));
+
+        let plan = self.vm_manager.plan_start(options).await;
+        explanation.bridge = Some(plan.bridge);
+        explanation.boot_args = Some(plan.boot_args);
+        explanation.vcpu_count = Some(plan.vcpu_count);
+        explanation.mem_size_mib = Some(plan.mem_size_mib);
+        explanation.read_only_rootfs = plan.read_only_rootfs;
+        explanation.checks.extend(plan.checks);
+        explanation.would_start = explanation.checks.iter().all(|check| check.passed);
+        explanation
+    }
+
+    async fn open_ssh(&self, id: &str) -> Result<SshAccess, Error> {
+        self.require_feature("ssh")?;
+        let vm = self.vm_manager.get_vm(id).await.ok_or(Error::VmNotFound)?;
+        if vm.status != VMStatus::Running {
+            return Err(Error::VmNotRunning);
+        }
+        // Checked before forwarding a port to a guest without keys
+        let mut access = ssh::access(&self.config.api.ssh, id, 0).map_err(Error::Other)?;
+
+        access.port = match vm
+            .port_mapping
+            .iter()
+            .find(|(_, guest)| *guest == ssh::SSH_PORT)
+        {
+            Some((host, _)) => *host,
+            None => {
+                let add = self.allocate_host_ports(vec![(0, ssh::SSH_PORT)]).await?;
+                let port = add[0].0;
+                let update = PortsUpdateDTO {
+                    add,
+                    remove: Vec::new(),
+                };
+                self.vm_manager.update_ports_of_vm(id, update).await?;
+                port
+            }
+        };
+
+        self.events.record(
+            "vm.ssh_opened",
+            Some(id),
+            serde_json::json!({ "port": access.port, "user": access.user }),
+        );
+        Ok(access)
+    }
+
+    async fn simple_spawn(
+        &self,
+        request: SimpleSpawn,
+        caller: Caller,
+    ) -> Result<(String, HashMap<u16, u16>), Error> {
+        let mut vm = Self::plain_request("vmlinux", request.rootfs);
+        vm.network.port_mapping = request
+            .requested_ports
+            .iter()
+            .map(|guest_port| (0, *guest_port))
+            .collect();
+        vm.tenant = request.tenant;
+        vm.ttl_seconds = request.ttl_seconds;
+
+        let vm = self.admitted(vm, &caller).await?;
+        self.launch(vm).await
+    }
+
+    async fn run(&self, request: RunRequest, caller: Caller) -> Result<RunResult, Error> {
+        let started = std::time::Instant::now();
+        let runtime = self
+            .config
+            .api
+            .runtimes
+            .get(&request.language)
+            .ok_or_else(|| {
+                Error::InvalidOptions(format!("no runtime for language {}", request.language))
+            })?;
+        let timeout = request.timeout.unwrap_or(runtime.max_timeout);
+        if timeout == 0 || timeout > runtime.max_timeout {
+            return Err(Error::InvalidOptions(format!(
+                "code runs between 1 and {} seconds",
+                runtime.max_timeout
+            )));
+        }
+
+        let rootfs = ImageManifest {
+            id: runtime.rootfs.clone(),
+            location: runtime.rootfs.clone(),
+            sha256: None,
+        };
+        let mut vm = self
+            .admitted(Self::plain_request(&runtime.kernel, rootfs), &caller)
+            .await?;
+        // Destroyed by the time to live if the run is interrupted
+        let ttl = timeout + 2 * self.config.api.agent.timeout + RUN_TTL_MARGIN;
+        vm.ttl_seconds = Some(vm.ttl_seconds.map_or(ttl, |t| t.min(ttl)));
+        let (id, _) = self.launch(vm).await?;
+        debug!("Running {} code in VM {}", request.language, id);
+
+        let result = self.run_in(&id, runtime, request, timeout).await;
+        if let Err(e) = destroy(&*self.vm_manager, &self.volume_manager, &id).await {
+            error!("Error while destroying VM {} after a run: {:?}", id, e);
+        }
+
+        let (exit_code, stdout, stderr) = result?;
+        Ok(RunResult {
+            exit_code,
+            stdout,
+            stderr,
+            duration_ms: started.elapsed().as_millis() as u64,
+        })
+    }
+
+    async fn register_function(&self, request: FunctionDTO) -> Result<(), Error> {
+        if request.name.is_empty() {
+            return Err(Error::InvalidOptions("function name is empty".to_string()));
+        }
+        // Invoked by name in a path, and told apart from its namespace by it
+        if request.name.contains('/') {
+            return Err(Error::InvalidOptions(
+                "function name contains a /".to_string(),
+            ));
+        }
+        request.function.check().map_err(Error::InvalidOptions)?;
+
+        self.vm_manager
+            .register_function(&request.name, request.function)
+            .await
+    }
+
+    async fn invoke_function(
+        &self,
+        name: &str,
+        caller: Caller,
+    ) -> Result<(String, HashMap<u16, u16>), Error> {
+        let function = self
+            .vm_manager
+            .get_function(name)
+            .await
+            .ok_or(Error::FunctionNotFound)?;
+
+        let namespace = caller.namespace().to_string();
+        let invoking = self
+            .invoking
+            .lock()
+            .await
+            .entry(function_vm_key(&namespace, name))
+            .or_default()
+            .clone();
+        let _invoking = invoking.lock().await;
+        if let Some(id) = self.vm_manager.get_function_vm(&namespace, name).await {
+            debug!("Function {} is served by VM {}", name, id);
+            let ports = self.vm_manager.get_used_ports_of_vm(&id).await;
+            return Ok((id, ports.unwrap_or_default()));
+        }
+
+        let rootfs = ImageManifest {
+            id: function.rootfs.clone(),
+            location: function.rootfs.clone(),
+            sha256: None,
+        };
+        let mut vm = Self::plain_request(&function.kernel, rootfs);
+        vm.vcpu_count = function.vcpu_count;
+        vm.mem_size_mib = function.mem_size_mib;
+        // Parameters unknown to the kernel are passed to init as environment
+        if !function.env.is_empty() {
+            let mut env: Vec<_> = function.env.iter().collect();
+            env.sort();
+            let env: Vec<String> = env
+                .into_iter()
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect();
+            vm.boot.boot_args = Some(format!("{} {}", DEFAULT_BOOT_ARGS, env.join(" ")));
+        }
+        vm.network.port_mapping = function.ports.iter().map(|port| (0, *port)).collect();
+
+        let vm = self.admitted(vm, &caller).await?;
+        let (id, ports) = self.launch(vm).await?;
+        info!("VM {} started for function {}", id, name);
+        self.vm_manager.set_function_vm(&namespace, name, &id).await;
+        Ok((id, ports))
+    }
+
+    async fn events(&self, since: u64, kind: Option<String>) -> Vec<Event> {
+        self.events.since(since, kind.as_deref())
+    }
+
+    fn subscribe_events(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    async fn fetch_samples(&self) -> Result<Vec<Image>, Error> {
+        let images = samples::fetch_samples(&self.config.api.image_manager)
+            .await
+            .map_err(Error::ImageError)?;
+        self.events.record(
+            "images.samples_fetched",
+            None,
+            serde_json::json!({ "images": images }),
+        );
+        Ok(images)
+    }
+
+    async fn promote_image(&self, request: PromotionDTO) -> Result<Promotion, Error> {
+        let image_manager = &self.config.api.image_manager;
+        let Some(config) = image_manager.promotion.clone() else {
+            return Err(Error::FeatureDisabled("image promotion".to_string()));
+        };
+        let images_folder = PathBuf::from(&image_manager.images_folder);
+
+        let result = {
+            let request = request.clone();
+            tokio::task::spawn_blocking(move || {
+                promotion::promote(&config, &images_folder, &request)
+            })
+            .await
+            .map_err(|e| Error::Other(e.into()))?
+        };
+
+        match &result {
+            Ok(promotion) => {
+                self.events.record(
+                    "image.promoted",
+                    None,
+                    serde_json::to_value(promotion).unwrap_or_default(),
+                );
+            }
+            Err(Error::PromotionRejected(reason)) => {
+                self.events.record(
+                    "image.promotion_rejected",
+                    None,
+                    serde_json::json!({
+                        "image": request.image,
+                        "sha256": request.sha256,
+                        "reason": reason,
+                    }),
+                );
+            }
+            Err(_) => {}
+        }
+        result
+    }
+
+    async fn verify_images(&self) -> Result<AuditReport, Error> {
+        let _auditing = self.auditing.lock().await;
+        let config = &self.config.api.image_manager;
+        let folder = PathBuf::from(&config.images_folder);
+        let quarantine = PathBuf::from(&config.audit.quarantine_folder);
+        // Only the url strategy downloads images
+        let can_download = config.strategy == ImageManagerStrategy::Url;
+
+        let images = {
+            let (folder, quarantine) = (folder.clone(), quarantine.clone());
+            tokio::task::spawn_blocking(move || audit::stored_images(&folder, &quarantine))
+                .await
+                .map_err(|e| Error::Other(e.into()))?
+                .map_err(Error::ImageError)?
+        };
+
+        let mut report = AuditReport::default();
+        for (path, manifest) in images {
+            let Some(manifest) = manifest else {
+                report.unrecorded += 1;
+                continue;
+            };
+            report.checked += 1;
+            let actual = match audit::check(&path, &manifest).await {
+                Ok(Some(actual)) => actual,
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!("Cannot audit image {}: {:?}", path.display(), e);
+                    continue;
+                }
+            };
+
+            warn!("Image {} is corrupted", manifest.id);
+            let quarantined_to =
+                audit::quarantine(&folder, &quarantine, &path).map_err(Error::ImageError)?;
+            let restored = if can_download && manifest.location.contains("://") {
+                self.image_manager.find_disk(&manifest).await.map(|_| ())
+            } else {
+                Err(anyhow::anyhow!(
+                    "image {} has no location to download it from",
+                    manifest.id
+                ))
+            };
+
+            let corrupted = CorruptedImage {
+                image: manifest.id,
+                expected: manifest.sha256.unwrap_or_default(),
+                actual,
+                quarantined_to,
+                restored: restored.is_ok(),
+                error: restored.err().map(|e| e.to_string()),
+            };
+            self.events.record(
+                "image.corrupted",
+                None,
+                serde_json::to_value(&corrupted).unwrap_or_default(),
+            );
+            report.corrupted.push(corrupted);
+        }
+
+        info!(
+            "Audited {} images, {} corrupted",
+            report.checked,
+            report.corrupted.len()
+        );
+        self.events.record(
+            "images.audited",
+            None,
+            serde_json::json!({
+                "checked": report.checked,
+                "unrecorded": report.unrecorded,
+                "corrupted": report.corrupted.len(),
+                "restored": report.corrupted.iter().filter(|image| image.restored).count(),
+            }),
+        );
+        Ok(report)
+    }
+
+    async fn shutdown(&self) {
+        self.vm_manager.shutdown().await
+    }
+
+    async fn cordon(&self, reason: Option<String>) -> Cordon {
+        self.vm_manager.cordon(reason).await
+    }
+
+    async fn uncordon(&self) -> bool {
+        self.vm_manager.uncordon().await
+    }
+
+    async fn node_status(&self) -> NodeStatus {
+        self.vm_manager.node_status().await
+    }
+
+    async fn selftest(&self) -> SelftestReport {
+        let config = &self.config.api.selftest;
+        let mut report = SelftestReport::start();
+
+        let options = report
+            .step("images", async {
+                let rootfs = ImageManifest {
+                    id: config.rootfs.clone(),
+                    location: config.rootfs.clone(),
+                    sha256: None,
+                };
+                let mut options = self.plain_options(&config.kernel, &rootfs).await?;
+                options.tenant = config.tenant.clone();
+                Ok((
+                    options,
+                    format!("found {} and {}", config.kernel, config.rootfs),
+                ))
+            })
+            .await;
+
+        // Booted rather than taken from a warm pool, booting being tested
+        let started = match options {
+            Some(options) => {
+                report
+                    .step("start", async {
+                        let id = self.vm_manager.start_vm(options).await?;
+                        Ok((id.clone(), format!("started VM {}", id)))
+                    })
+                    .await
+            }
+            None => None,
+        };
+
+        if let Some(id) = started {
+            report.vm_id = Some(id.clone());
+            let probed = report
+                .step("probe", async {
+                    self.wait_for_agent(&id).await?;
+                    Ok(((), "the guest agent answered".to_string()))
+                })
+                .await;
+            if probed.is_some() {
+                let command = vec!["echo".to_string(), selftest::MARKER.to_string()];
+                report
+                    .step("exec", async {
+                        let (exit_code, stdout, stderr) = self
+                            .exec_in(&id, command, HashMap::new(), config.timeout)
+                            .await?;
+                        if exit_code != 0 || !stdout.contains(selftest::MARKER) {
+                            return Err(Error::Other(anyhow::anyhow!(
+                                "command exited with {}, stdout: {:?}, stderr: {:?}",
+                                exit_code,
+                                stdout,
+                                stderr
+                            )));
+                        }
+                        Ok(((), "the command ran in the guest".to_string()))
+                    })
+                    .await;
+            }
+
+            // Stopped whichever step failed
+            report
+                .step("stop", async {
+                    destroy(&*self.vm_manager, &self.volume_manager, &id).await?;
+                    Ok(((), format!("destroyed VM {}", id)))
+                })
+                .await;
+        }
+
+        let report = report.finish();
+        if !report.passed {
+            warn!("Self test failed at step {:?}", report.failed_step());
+        }
+        self.events.record(
+            "node.selftest",
+            report.vm_id.as_deref(),
+            serde_json::json!({
+                "passed": report.passed,
+                "durationMs": report.duration_ms,
+                "failedStep": report.failed_step(),
+            }),
+        );
+        report
+    }
+
+    async fn support_bundle(&self) -> Result<Vec<u8>, Error> {
+        let events = self.events.since(0, None);
+        let mut recent_events = Vec::new();
+        for event in &events[events.len().saturating_sub(support_bundle::BUNDLE_EVENTS)..] {
+            serde_json::to_writer(&mut recent_events, event).map_err(|e| Error::Other(e.into()))?;
+            recent_events.push(b'\n');
+        }
+
+        let json = |value: serde_json::Value| serde_json::to_vec_pretty(&value).unwrap_or_default();
+        let files: BundleFiles = vec![
+            (
+                "version.json",
+                json(support_bundle::version_info(&self.config.api.executor).await),
+            ),
+            (
+                "config.json",
+                json(support_bundle::sanitize_config(&self.config)),
+            ),
+            ("state.json", json(self.vm_manager.dump_state().await)),
+            (
+                "volumes.json",
+                json(serde_json::to_value(self.volume_manager.list().await).unwrap_or_default()),
+            ),
+            ("events.jsonl", recent_events),
+        ];
+
+        support_bundle::pack(files).await.map_err(Error::Other)
+    }
+
+    async fn capabilities(&self) -> HashMap<String, FeatureStatus> {
+        FEATURES
+            .iter()
+            .map(|(feature, _)| {
+                let status = FeatureStatus {
+                    enabled: self.config.feature_enabled(feature),
+                    available: !UNAVAILABLE_FEATURES.contains(feature),
+                };
+                (feature.to_string(), status)
+            })
+            .collect()
+    }
+
+    async fn list_volumes(&self, caller: &Caller) -> Vec<Volume> {
+        let mut volumes = self.volume_manager.list().await;
+        if let Some(scope) = caller.scope() {
+            volumes.retain(|volume| volume.namespace == scope);
+        }
+        volumes
+    }
+
+    async fn get_volume(&self, name: &str, caller: &Caller) -> Result<Volume, Error> {
+        self.volume_of(name, caller).await
+    }
+
+    async fn create_volume(
+        &self,
+        request: CreateVolumeDTO,
+        caller: &Caller,
+    ) -> Result<Volume, Error> {
+        let source = match &request.image {
+            Some(manifest) => Some(
+                self.image_manager
+                    .find_disk(&self.scoped(manifest, caller.scope())?)
+                    .await
+                    .map_err(Error::ImageError)?,
+            ),
+            None => None,
+        };
+
+        let volume = self
+            .volume_manager
+            .create(
+                &request.name,
+                request.size_mib,
+                source.as_ref(),
+                caller.namespace(),
+            )
+            .await?;
+        self.events.record(
+            "volume.created",
+            None,
+            serde_json::json!({ "volume": volume.name, "size_mib": volume.size_mib }),
+        );
+        Ok(volume)
+    }
+
+    async fn delete_volume(&self, name: &str, caller: &Caller) -> Result<(), Error> {
+        self.volume_of(name, caller).await?;
+        self.volume_manager.delete(name).await?;
+        self.events.record(
+            "volume.deleted",
+            None,
+            serde_json::json!({ "volume": name }),
+        );
+        Ok(())
+    }
+
+    async fn create_snapshot(
+        &self,
+        volume: &str,
+        request: CreateSnapshotDTO,
+        caller: &Caller,
+    ) -> Result<Snapshot, Error> {
+        self.require_feature("snapshots")?;
+        self.volume_of(volume, caller).await?;
+        let snapshot = self
+            .volume_manager
+            .create_snapshot(volume, &request.name)
+            .await?;
+        self.events.record(
+            "volume.snapshotted",
+            None,
+            serde_json::json!({ "volume": volume, "snapshot": snapshot.name }),
+        );
+        Ok(snapshot)
+    }
+
+    async fn delete_snapshot(
+        &self,
+        volume: &str,
+        name: &str,
+        caller: &Caller,
+    ) -> Result<(), Error> {
+        self.require_feature("snapshots")?;
+        self.volume_of(volume, caller).await?;
+        self.volume_manager.delete_snapshot(volume, name).await?;
+        self.events.record(
+            "volume.snapshot_deleted",
+            None,
+            serde_json::json!({ "volume": volume, "snapshot": name }),
+        );
+        Ok(())
+    }
+
+    async fn clone_volume(
+        &self,
+        volume: &str,
+        request: CloneVolumeDTO,
+        caller: &Caller,
+    ) -> Result<Volume, Error> {
+        if request.snapshot.is_some() {
+            self.require_feature("snapshots")?;
+        }
+        self.volume_of(volume, caller).await?;
+        let clone = self
+            .volume_manager
+            .clone_volume(volume, request.snapshot.as_deref(), &request.name)
+            .await?;
+        self.events.record(
+            "volume.cloned",
+            None,
+            serde_json::json!({
+                "volume": clone.name,
+                "source": volume,
+                "snapshot": request.snapshot,
+            }),
+        );
+        Ok(clone)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::{
+        config::{FunctionConfig, PolicyConfig},
+        vm_manager::{
+            image_manager::folder_manager::FolderImageManager, state::VMState,
+            volume_manager::file_driver::FileStorageDriver, MockVMManagerTrait,
+        },
+    };
+
+    const CONFIG: &str = r#"
+apiVersion: lambdo.io/v1alpha1
+kind: Config
+api:
+  network:
+    webHost: 127.0.0.1
+    webPort: 3000
+  imageManager: {}
+  tenants:
+    team-a: {}
+    team-b: {}
+"#;
+
+    /// Policy engine denying every request with `reasons`, returning the URL
+    /// of its denial messages
+    async fn policy_engine(reasons: &[&str]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "http://{}/v1/data/lambdo/admission/deny",
+            listener.local_addr().unwrap()
+        );
+        let body = serde_json::json!({ "result": reasons }).to_string();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                // The whole query is read, for the client not to see the
+                // connection reset before the answer
+                let mut query = Vec::new();
+                let mut buffer = [0; 4096];
+                while let Ok(read @ 1..) = stream.read(&mut buffer).await {
+                    query.extend_from_slice(&buffer[..read]);
+                    let query = String::from_utf8_lossy(&query);
+                    let Some((headers, content)) = query.split_once("\r\n\r\n") else {
+                        continue;
+                    };
+                    let length = headers
+                        .lines()
+                        .find_map(|line| {
+                            let (name, value) = line.split_once(':')?;
+                            name.eq_ignore_ascii_case("content-length")
+                                .then(|| value.trim().parse::<usize>().ok())?
+                        })
+                        .unwrap_or(0);
+                    if content.len() >= length {
+                        break;
+                    }
+                }
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        url
+    }
+
+    /// Service starting VMs with `vm_manager`, asking the policy engine at
+    /// `policy` if any, with its images and files in a folder of its own
+    async fn service(vm_manager: MockVMManagerTrait, policy: Option<String>) -> LambdoApiService {
+        let folder = std::env::temp_dir().join(format!("lambdo-service-{}", Uuid::new_v4()));
+        let mut config: LambdoConfig = serde_yaml::from_str(CONFIG).unwrap();
+        config.api.image_manager.images_folder = folder.display().to_string();
+        config.api.events.path = folder.join("events.json").display().to_string();
+        config.api.policy = policy.map(|url| PolicyConfig { url, timeout: 5 });
+        let volume_manager = VolumeManager::new(
+            folder.join("volumes").display().to_string(),
+            Box::new(FileStorageDriver::new(folder.join("volumes"))),
+        )
+        .await
+        .unwrap();
+
+        LambdoApiService {
+            events: Arc::new(EventStore::new(config.api.events.clone()).unwrap()),
+            vm_manager: Arc::new(vm_manager),
+            image_manager: Box::new(FolderImageManager::new(
+                config.api.image_manager.images_folder.clone(),
+            )),
+            volume_manager: Arc::new(volume_manager),
+            auditing: tokio::sync::Mutex::new(()),
+            plugins: Arc::new(load_plugins(&config).unwrap()),
+            invoking: tokio::sync::Mutex::new(HashMap::new()),
+            creating_stacks: tokio::sync::Mutex::new(HashMap::new()),
+            jobs: JobStore::new(&config.api.jobs),
+            starting: tokio::sync::Mutex::new(HashMap::new()),
+            config,
+        }
+    }
+
+    fn caller(namespace: &str) -> Caller {
+        Caller {
+            token: Some(format!("{}-token", namespace)),
+            namespace: Some(namespace.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn manifest(location: &str) -> ImageManifest {
+        ImageManifest {
+            id: location.to_string(),
+            location: location.to_string(),
+            sha256: None,
+        }
+    }
+
+    /// Request for a VM attaching the volumes `volumes`
+    fn request(volumes: &[&str]) -> VMOptionsDTO {
+        let mut request = LambdoApiService::plain_request("vmlinux", manifest("rootfs.ext4"));
+        request.volumes = volumes
+            .iter()
+            .map(|name| VolumeAttachmentDTO {
+                name: name.to_string(),
+                is_readonly: false,
+            })
+            .collect();
+        request
+    }
+
+    /// Service with a volume `data` of namespace `team-a`
+    async fn service_with_volume() -> LambdoApiService {
+        let service = service(MockVMManagerTrait::new(), None).await;
+        let folder = PathBuf::from(&service.config.api.image_manager.images_folder).join("team-a");
+        std::fs::create_dir_all(&folder).unwrap();
+        std::fs::write(folder.join("seed.ext4"), b"seed").unwrap();
+        let request = CreateVolumeDTO {
+            name: "data".to_string(),
+            size_mib: 1,
+            image: Some(manifest("seed.ext4")),
+        };
+        service
+            .create_volume(request, &caller("team-a"))
+            .await
+            .unwrap();
+        service
+    }
+
+    #[tokio::test]
+    async fn the_policy_denies_spawned_vms() {
+        // Denied before anything is asked of the VM manager
+        let policy = policy_engine(&["no spawning in team"]).await;
+        let service = service(MockVMManagerTrait::new(), Some(policy)).await;
+
+        let request = SimpleSpawn {
+            rootfs: ImageManifest {
+                id: "rootfs".to_string(),
+                location: "rootfs.ext4".to_string(),
+                sha256: None,
+            },
+            requested_ports: vec![80],
+            tenant: None,
+            ttl_seconds: None,
+        };
+        let result = service.simple_spawn(request, caller("team")).await;
+        assert!(
+            matches!(&result, Err(Error::AdmissionDenied(reason)) if reason == "no spawning in team"),
+            "{:?}",
+            result
+        );
+    }
+
+    /// Check `name` of `explanation`
+    fn check<'a>(explanation: &'a StartExplanation, name: &str) -> &'a Check {
+        explanation
+            .checks
+            .iter()
+            .find(|check| check.name == name)
+            .unwrap_or_else(|| panic!("no {} check in {:?}", name, explanation.checks))
+    }
+
+    #[tokio::test]
+    async fn explains_starts_the_policy_denies() {
+        let policy = policy_engine(&["no VMs in team"]).await;
+        let mut vm_manager = MockVMManagerTrait::new();
+        vm_manager.expect_get_used_ports().returning(Vec::new);
+        let service = service(vm_manager, Some(policy)).await;
+
+        let explanation = service.explain_start(request(&[]), &caller("team")).await;
+        assert!(!explanation.would_start);
+        let policy = check(&explanation, "policy");
+        assert!(!policy.passed);
+        assert!(policy.detail.contains("no VMs in team"), "{:?}", policy);
+    }
+
+    #[tokio::test]
+    async fn explains_nothing_about_the_tenants_of_other_namespaces() {
+        // Refused before anything is asked of the VM manager
+        let service = service(MockVMManagerTrait::new(), None).await;
+
+        let mut request = request(&[]);
+        request.tenant = Some("team-a".to_string());
+        let explanation = service.explain_start(request, &caller("team-b")).await;
+        assert!(!explanation.would_start);
+        assert!(!check(&explanation, "tenant scope").passed);
+        assert!(explanation.images.is_empty());
+    }
+
+    /// VM of the service `stack` of the stack of the same name, in
+    /// `namespace`
+    fn stack_vm(namespace: &str, stack: &str) -> VMDetails {
+        let mut vm = VMState::new(firepilot::builder::Configuration::new(format!(
+            "{}-{}",
+            namespace, stack
+        )));
+        vm.namespace = namespace.to_string();
+        vm.stack = Some(StackMember {
+            stack: stack.to_string(),
+            service: stack.to_string(),
+            ingress: false,
+        });
+        VMDetails::from(&vm)
+    }
+
+    #[tokio::test]
+    async fn finds_the_stacks_of_every_namespace_for_admins() {
+        let mut vm_manager = MockVMManagerTrait::new();
+        vm_manager.expect_list_vms().returning(|scope| {
+            [
+                stack_vm("team-a", "web"),
+                stack_vm("team-b", "web"),
+                stack_vm("team-c", "db"),
+            ]
+            .into_iter()
+            .filter(|vm| scope.as_ref().is_none_or(|scope| *scope == vm.namespace))
+            .collect()
+        });
+        let service = service(vm_manager, None).await;
+        let admin = Caller {
+            all_namespaces: true,
+            ..caller("ops")
+        };
+
+        let stack = service.stack("db", &admin).await.unwrap();
+        assert_eq!(stack.namespace, "team-c");
+        let result = service.stack("web", &admin).await;
+        assert!(
+            matches!(result, Err(Error::InvalidOptions(_))),
+            "{:?}",
+            result
+        );
+        let within = admin.within(Some("team-b".to_string()));
+        assert_eq!(
+            service.stack("web", &within).await.unwrap().namespace,
+            "team-b"
+        );
+
+        // Other tokens only see the stacks of their namespace
+        let team = caller("team-a").within(Some("team-b".to_string()));
+        assert_eq!(
+            service.stack("web", &team).await.unwrap().namespace,
+            "team-a"
+        );
+        assert!(matches!(
+            service.stack("db", &team).await,
+            Err(Error::StackNotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn hides_the_volumes_of_other_namespaces() {
+        let service = service_with_volume().await;
+        let (owner, other) = (caller("team-a"), caller("team-b"));
+
+        assert_eq!(service.list_volumes(&owner).await.len(), 1);
+        assert!(service.list_volumes(&other).await.is_empty());
+        assert!(service.get_volume("data", &owner).await.is_ok());
+        assert!(matches!(
+            service.get_volume("data", &other).await,
+            Err(Error::VolumeNotFound)
+        ));
+
+        // Admin tokens see every namespace
+        let admin = Caller {
+            all_namespaces: true,
+            ..caller("ops")
+        };
+        assert_eq!(service.list_volumes(&admin).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn refuses_to_change_the_volumes_of_other_namespaces() {
+        let service = service_with_volume().await;
+        let other = caller("team-b");
+
+        let snapshot = CreateSnapshotDTO {
+            name: "before".to_string(),
+        };
+        assert!(matches!(
+            service.create_snapshot("data", snapshot, &other).await,
+            Err(Error::VolumeNotFound)
+        ));
+        assert!(matches!(
+            service.delete_snapshot("data", "before", &other).await,
+            Err(Error::VolumeNotFound)
+        ));
+        let clone = CloneVolumeDTO {
+            name: "stolen".to_string(),
+            snapshot: None,
+        };
+        assert!(matches!(
+            service.clone_volume("data", clone, &other).await,
+            Err(Error::VolumeNotFound)
+        ));
+        assert!(matches!(
+            service.delete_volume("data", &other).await,
+            Err(Error::VolumeNotFound)
+        ));
+        assert!(service.get_volume("data", &caller("team-a")).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn seeds_volumes_from_the_images_of_the_namespace_of_the_caller() {
+        let service = service_with_volume().await;
+
+        for location in ["seed.ext4", "../team-a/seed.ext4"] {
+            let request = CreateVolumeDTO {
+                name: "stolen".to_string(),
+                size_mib: 1,
+                image: Some(manifest(location)),
+            };
+            let result = service.create_volume(request, &caller("team-b")).await;
+            assert!(
+                matches!(result, Err(Error::ImageError(_))),
+                "{}: {:?}",
+                location,
+                result
+            );
+        }
+        assert!(service.list_volumes(&caller("team-b")).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn refuses_to_attach_the_volumes_of_other_namespaces() {
+        // Refused before anything is asked of the VM manager
+        let service = service_with_volume().await;
+
+        let result = service.start(request(&["data"]), caller("team-b")).await;
+        assert!(matches!(result, Err(Error::VolumeNotFound)), "{:?}", result);
+        let volume = service.get_volume("data", &caller("team-a")).await.unwrap();
+        assert_eq!(volume.attached_to, None);
+    }
+
+    #[tokio::test]
+    async fn refuses_the_tenants_of_other_namespaces() {
+        let service = service(MockVMManagerTrait::new(), None).await;
+
+        let mut request = request(&[]);
+        request.tenant = Some("team-a".to_string());
+        let result = service.start(request, caller("team-b")).await;
+        assert!(matches!(result, Err(Error::TenantNotFound)), "{:?}", result);
+
+        assert!(matches!(
+            service.tenant_usage("team-a", &caller("team-b")).await,
+            Err(Error::TenantNotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn hands_out_the_function_vms_of_the_namespace_of_the_caller() {
+        let mut vm_manager = MockVMManagerTrait::new();
+        vm_manager.expect_get_function().returning(|_| {
+            Some(FunctionConfig {
+                kernel: "vmlinux".to_string(),
+                rootfs: "rootfs.ext4".to_string(),
+                vcpu_count: None,
+                mem_size_mib: None,
+                env: HashMap::new(),
+                ports: Vec::new(),
+                idle_timeout: None,
+            })
+        });
+        vm_manager
+            .expect_get_function_vm()
+            .returning(|namespace, _| Some(format!("{}-vm", namespace)));
+        vm_manager
+            .expect_get_used_ports_of_vm()
+            .returning(|_| Some(HashMap::new()));
+        let service = service(vm_manager, None).await;
+
+        let (id, _) = service
+            .invoke_function("hello", caller("team-b"))
+            .await
+            .unwrap();
+        assert_eq!(id, "team-b-vm");
     }
 }
@@ -0,0 +1,336 @@
+use std::collections::{BTreeMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::vm_manager::{
+    ingress_proxy::ShareLink,
+    state::{VMDetails, VMStatus},
+    Error, VMOptionsDTO,
+};
+
+/// VMs started and destroyed together on the bridge of their tenant. They
+/// get no network nor name server of their own: each service is told the
+/// addresses of the services started before it, as `stack_hosts=name:ip,...`
+/// on its kernel command line, and finds the others through `/discovery`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct StackDTO {
+    pub name: String,
+    /// Started in order, after the services they depend on
+    pub services: Vec<StackServiceDTO>,
+    /// Share link created to a port of a service, the only way into the
+    /// stack from outside the host
+    #[serde(default)]
+    pub ingress: Option<StackIngressDTO>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct StackServiceDTO {
+    pub name: String,
+    /// Services to start before this one, with the condition they have to
+    /// meet first
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub depends_on: BTreeMap<String, StackDependency>,
+    /// Probe the service has to pass once started, the whole stack failing
+    /// if it does not
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub readiness: Option<ReadinessProbe>,
+    #[serde(flatten)]
+    pub vm: VMOptionsDTO,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct StackDependency {
+    #[serde(default)]
+    pub condition: DependencyCondition,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DependencyCondition {
+    /// The dependency only has to be started
+    #[default]
+    ServiceStarted,
+    /// The dependency has to pass its readiness probe
+    ServiceHealthy,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ReadinessProbe {
+    /// Guest port the service has to accept connections on, which does not
+    /// have to be mapped. The service only has to answer pings if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    /// Seconds the service has to pass the probe once started
+    #[serde(default = "default_readiness_timeout")]
+    pub timeout_seconds: u64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct StackIngressDTO {
+    pub service: String,
+    /// Guest port of the service, mapped to a free host port if it is not
+    pub port: u16,
+    /// Seconds the share link is valid for
+    #[serde(default = "default_ingress_ttl")]
+    pub ttl_seconds: u64,
+}
+
+fn default_ingress_ttl() -> u64 {
+    3600
+}
+
+fn default_readiness_timeout() -> u64 {
+    60
+}
+
+impl StackDTO {
+    /// Check that the names of the stack and of its services can be used as
+    /// host names, and that its ingress goes to one of its services.
+    pub fn check(&self) -> Result<(), Error> {
+        if !is_label(&self.name) {
+            return Err(Error::InvalidOptions(format!(
+                "stack name {:?} is not a valid host name",
+                self.name
+            )));
+        }
+        if self.services.is_empty() {
+            return Err(Error::InvalidOptions("stack has no service".to_string()));
+        }
+
+        let mut names = HashSet::new();
+        for service in &self.services {
+            if !is_label(&service.name) {
+                return Err(Error::InvalidOptions(format!(
+                    "service name {:?} is not a valid host name",
+                    service.name
+                )));
+            }
+            if !names.insert(service.name.as_str()) {
+                return Err(Error::InvalidOptions(format!(
+                    "service {} is defined twice",
+                    service.name
+                )));
+            }
+        }
+
+        for service in &self.services {
+            if let Some(dependency) = service
+                .depends_on
+                .keys()
+                .find(|dependency| !names.contains(dependency.as_str()))
+            {
+                return Err(Error::InvalidOptions(format!(
+                    "service {} depends on {}, which is not a service of the stack",
+                    service.name, dependency
+                )));
+            }
+        }
+        self.ordered()?;
+
+        match &self.ingress {
+            Some(ingress) if !names.contains(ingress.service.as_str()) => {
+                Err(Error::InvalidOptions(format!(
+                    "ingress service {} is not a service of the stack",
+                    ingress.service
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Services in the order they are started in, the one of the request
+    /// unless a service depends on one after it
+    pub fn ordered(&self) -> Result<Vec<&StackServiceDTO>, Error> {
+        let mut ordered: Vec<&StackServiceDTO> = Vec::with_capacity(self.services.len());
+        while ordered.len() < self.services.len() {
+            let is_started = |name: &String| ordered.iter().any(|started| started.name == *name);
+            let next = self.services.iter().find(|service| {
+                !is_started(&service.name) && service.depends_on.keys().all(is_started)
+            });
+            match next {
+                Some(service) => ordered.push(service),
+                None => {
+                    let cycle: Vec<&str> = self
+                        .services
+                        .iter()
+                        .filter(|service| !is_started(&service.name))
+                        .map(|service| service.name.as_str())
+                        .collect();
+                    return Err(Error::InvalidOptions(format!(
+                        "services {} depend on each other",
+                        cycle.join(", ")
+                    )));
+                }
+            }
+        }
+        Ok(ordered)
+    }
+
+    /// Probe `service` has to pass once started, its own or, if a service
+    /// waits for it to be healthy, one of its first guest port
+    pub fn readiness(&self, service: &StackServiceDTO) -> Option<ReadinessProbe> {
+        if service.readiness.is_some() {
+            return service.readiness.clone();
+        }
+        let awaited = self.services.iter().any(|other| {
+            other
+                .depends_on
+                .get(&service.name)
+                .is_some_and(|dependency| {
+                    dependency.condition == DependencyCondition::ServiceHealthy
+                })
+        });
+        awaited.then(|| ReadinessProbe {
+            port: service
+                .vm
+                .network
+                .port_mapping
+                .iter()
+                .map(|(_, guest)| *guest)
+                .min(),
+            timeout_seconds: default_readiness_timeout(),
+        })
+    }
+}
+
+/// Lowercase letters, digits and inner dashes, up to 63 characters
+fn is_label(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 63
+        && !name.starts_with('-')
+        && !name.ends_with('-')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Stack {
+    pub name: String,
+    pub namespace: String,
+    pub services: Vec<StackService>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ingress: Option<ShareLink>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StackService {
+    pub name: String,
+    pub vm_id: String,
+    pub ip: Option<String>,
+    pub status: VMStatus,
+}
+
+impl Stack {
+    /// Stack made of `vms`, all of which are services of the same stack
+    pub fn of(vms: &[VMDetails], ingress: Option<ShareLink>) -> Option<Self> {
+        let first = vms.first()?;
+        // In the order they were started, which is the one of the request
+        let mut vms: Vec<&VMDetails> = vms.iter().collect();
+        vms.sort_by_key(|vm| vm.started_at);
+        let services = vms
+            .iter()
+            .filter_map(|vm| {
+                Some(StackService {
+                    name: vm.stack.as_ref()?.service.clone(),
+                    vm_id: vm.id.clone(),
+                    ip: vm.ip.clone(),
+                    status: vm.status,
+                })
+            })
+            .collect();
+        Some(Stack {
+            name: first.stack.as_ref()?.stack.clone(),
+            namespace: first.namespace.clone(),
+            services,
+            ingress,
+        })
+    }
+}
+
+/// Where the members of a service of a stack can be reached, for guests to
+/// find their peers
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServiceEndpoints {
+    pub stack: String,
+    pub service: String,
+    pub namespace: String,
+    /// Running VMs of the service
+    pub members: Vec<ServiceMember>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServiceMember {
+    pub vm_id: String,
+    /// Address of the VM on its bridge, without its prefix length
+    pub ip: Option<String>,
+    pub ports: Vec<ServicePort>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServicePort {
+    /// Port peers on the bridge connect to
+    pub guest: u16,
+    /// Port of the host it is mapped to
+    pub host: u16,
+}
+
+impl ServiceEndpoints {
+    /// Endpoints of `service` among the services of the stack `vms`, if it is
+    /// one of them
+    pub fn of(vms: &[VMDetails], service: &str) -> Option<Self> {
+        let stack = vms.first()?.stack.as_ref()?;
+        let vms: Vec<&VMDetails> = vms
+            .iter()
+            .filter(|vm| {
+                vm.stack
+                    .as_ref()
+                    .is_some_and(|member| member.service == service)
+            })
+            .collect();
+        let first = vms.first()?;
+        let members = vms
+            .iter()
+            .filter(|vm| vm.status == VMStatus::Running)
+            .map(|vm| ServiceMember {
+                vm_id: vm.id.clone(),
+                ip: vm
+                    .ip
+                    .as_ref()
+                    .and_then(|ip| ip.split('/').next())
+                    .map(str::to_string),
+                ports: vm
+                    .port_mapping
+                    .iter()
+                    .map(|(host, guest)| ServicePort {
+                        guest: *guest,
+                        host: *host,
+                    })
+                    .collect(),
+            })
+            .collect();
+        Some(ServiceEndpoints {
+            stack: stack.stack.clone(),
+            service: service.to_string(),
+            namespace: first.namespace.clone(),
+            members,
+        })
+    }
+}
+
+/// VMs of `vms` that are services of a stack, by namespace and stack
+pub fn group(vms: Vec<VMDetails>) -> BTreeMap<(String, String), Vec<VMDetails>> {
+    let mut stacks: BTreeMap<(String, String), Vec<VMDetails>> = BTreeMap::new();
+    for vm in vms {
+        if let Some(member) = &vm.stack {
+            let key = (vm.namespace.clone(), member.stack.clone());
+            stacks.entry(key).or_default().push(vm);
+        }
+    }
+    stacks
+}
@@ -0,0 +1,59 @@
+use std::{fs::File, io::BufReader, sync::Arc};
+
+use anyhow::{anyhow, Result};
+use rustls::{
+    pki_types::{CertificateDer, PrivateKeyDer},
+    server::WebPkiClientVerifier,
+    RootCertStore, ServerConfig,
+};
+
+use crate::config::TlsConfig;
+
+/// TLS configuration of the API server, requiring a certificate signed by
+/// the client CA from every client if one is configured.
+pub fn server_config(config: &TlsConfig) -> Result<ServerConfig> {
+    let certs = load_certs(&config.cert)?;
+    let key = load_key(&config.key)?;
+
+    let builder = match &config.client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(client_ca)? {
+                roots
+                    .add(cert)
+                    .map_err(|e| anyhow!("invalid client CA in {}: {}", client_ca, e))?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                .build()
+                .map_err(|e| anyhow!("cannot verify clients against {}: {}", client_ca, e))?;
+            ServerConfig::builder().with_client_cert_verifier(verifier)
+        }
+        None => ServerConfig::builder().with_no_client_auth(),
+    };
+
+    builder
+        .with_single_cert(certs, key)
+        .map_err(|e| anyhow!("invalid certificate or key: {}", e))
+}
+
+fn open(path: &str) -> Result<BufReader<File>> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| anyhow!("cannot open {}: {}", path, e))
+}
+
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = rustls_pemfile::certs(&mut open(path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| anyhow!("cannot read certificates from {}: {}", path, e))?;
+    if certs.is_empty() {
+        return Err(anyhow!("no certificate in {}", path));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &str) -> Result<PrivateKeyDer<'static>> {
+    rustls_pemfile::private_key(&mut open(path)?)
+        .map_err(|e| anyhow!("cannot read private key from {}: {}", path, e))?
+        .ok_or(anyhow!("no private key in {}", path))
+}
@@ -1,11 +1,17 @@
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
 use std::{
-    fs::File,
-    io::{self, BufReader},
+    collections::HashMap,
+    fmt::Display,
+    fs, io,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
 };
 use thiserror::Error;
 
+pub mod init;
+
 #[derive(Error, Debug)]
 pub enum LambdoConfigError {
     #[error("cannot load config file")]
@@ -16,45 +22,1565 @@ pub enum LambdoConfigError {
     KindNotSupported,
     #[error("unsupported config api version")]
     VersionNotSupported,
+    #[error("cannot include config file {0}")]
+    Include(PathBuf, #[source] io::Error),
+    #[error("config file {0} includes itself")]
+    IncludeCycle(PathBuf),
+    #[error("config file {0} is nested more than {MAX_INCLUDE_DEPTH} includes deep")]
+    IncludeTooDeep(PathBuf),
+    #[error("invalid include directive, expected a file path")]
+    InvalidInclude,
+    #[error("environment variable {0} is not set")]
+    MissingVariable(String),
+    #[error("unterminated variable reference")]
+    UnterminatedVariable,
+    #[error("unknown feature {0}")]
+    UnknownFeature(String),
+    #[error("invalid port range: {0}")]
+    InvalidPortRange(String),
+    #[error("invalid function {0}: {1}")]
+    InvalidFunction(String, String),
+}
+
+/// Experimental subsystems which can be toggled with `features`, and whether
+/// they are enabled by default
+pub const FEATURES: [(&str, bool); 4] = [
+    ("snapshots", true),
+    ("overlayNetworking", false),
+    ("wasm", false),
+    ("ssh", false),
+];
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub enum ImageManagerStrategy {
+    #[serde(rename = "folder")]
+    Folder,
+    #[serde(rename = "url")]
+    Url,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ShutdownAction {
+    /// Stop the VMs and remove their network setup
+    Stop,
+    /// Leave the VMs running, to reattach to or adopt them later
+    Leave,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+pub enum PortAllocationStrategy {
+    /// Lowest free port first
+    #[serde(rename = "sequential")]
+    Sequential,
+    /// Any free port, picked at random
+    #[serde(rename = "random")]
+    Random,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, Default)]
+pub enum NetworkMode {
+    /// VMs go out through NAT, and are reached through the host ports
+    /// mapped to them
+    #[default]
+    #[serde(rename = "nat")]
+    Nat,
+    /// The subnets of the bridges are routed to the host, the VMs being
+    /// reached on their own address
+    #[serde(rename = "routed")]
+    Routed,
+}
+
+/// What applies the firewall rules of lambdo
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, Default)]
+pub enum FirewallBackend {
+    #[default]
+    #[serde(rename = "iptables")]
+    Iptables,
+    /// The nft command, for hosts without iptables, the rules being kept in
+    /// the lambdo tables
+    #[serde(rename = "nftables")]
+    Nftables,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct LambdoConfig {
+    /// The api version of the lambdo config file
+    pub api_version: String,
+    /// The kind of the lambdo config file
+    pub kind: String,
+    /// The lambdo api configuration
+    pub api: LambdoApiConfig,
+    /// Experimental subsystems to enable or disable on this node
+    #[serde(default)]
+    pub features: HashMap<String, bool>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct LambdoApiConfig {
+    /// Network configuration
+    pub network: NetworkConfig,
+    /// Image manager configuration
+    pub image_manager: ImageManagerConfig,
+    /// Volume manager configuration
+    #[serde(default)]
+    pub volume_manager: VolumeManagerConfig,
+    /// Event store configuration
+    #[serde(default)]
+    pub events: EventsConfig,
+    /// Egress proxy configuration
+    #[serde(default)]
+    pub egress_proxy: EgressProxyConfig,
+    /// Proxy serving the share links of the VMs
+    #[serde(default)]
+    pub ingress_proxy: IngressProxyConfig,
+    /// gRPC server offering part of the API, next to the web server
+    #[serde(default)]
+    pub grpc: GrpcConfig,
+    /// Seconds during which a destroyed VM is only paused and can be
+    /// restored, 0 to destroy VMs right away
+    #[serde(default)]
+    pub destroy_grace_period: u64,
+    /// Seconds a VM has to become reachable after it started, after which
+    /// it is destroyed, 0 to not wait for VMs to boot
+    #[serde(default)]
+    pub boot_timeout: u64,
+    /// Tenants allowed to start VMs, with their own images and network
+    #[serde(default)]
+    pub tenants: HashMap<String, TenantConfig>,
+    /// Wireguard tunnel to other nodes or private networks
+    #[serde(default)]
+    pub wireguard: Option<WireguardConfig>,
+    /// VMs booted ahead of time and handed out on start
+    #[serde(default)]
+    pub warm_pool: WarmPoolConfig,
+    /// Limits on the host resources committed to VMs
+    #[serde(default)]
+    pub admission: AdmissionConfig,
+    /// Queue consumed for spawn requests
+    #[serde(default)]
+    pub queue: Option<QueueConfig>,
+    /// File in which the VMs are saved, so that they are reattached after a
+    /// restart instead of being stopped on shutdown
+    #[serde(default)]
+    pub state_file: Option<String>,
+    /// What to do with the VMs when lambdo shuts down, leaving them running
+    /// with a state file and stopping them otherwise by default
+    #[serde(default)]
+    pub on_shutdown: Option<ShutdownAction>,
+    /// File in which the addresses and host ports held by VMs are saved, so
+    /// that they are not handed out again after a restart while still used
+    #[serde(default = "default_lease_file")]
+    pub lease_file: String,
+    /// CPU time accounting of the tenants, enforcing their CPU budgets
+    #[serde(default)]
+    pub cpu_accounting: CpuAccountingConfig,
+    /// Accounting of the traffic of the VMs, enforcing their transfer cap
+    #[serde(default)]
+    pub net_accounting: NetAccountingConfig,
+    /// Checks of the disk, memory and network of the host, reported by
+    /// `/readyz`
+    #[serde(default)]
+    pub node_conditions: NodeConditionsConfig,
+    /// Kernel parameters and modules start requests may set in their guest
+    #[serde(default)]
+    pub guest_tuning: GuestTuningConfig,
+    /// How guests with a read-only rootfs get a writable root
+    #[serde(default)]
+    pub read_only_rootfs: ReadOnlyRootfsConfig,
+    /// SSH access to the VMs, with the `ssh` feature
+    #[serde(default)]
+    pub ssh: SshConfig,
+    /// Periodic disk snapshots of the VMs asking for them
+    #[serde(default)]
+    pub vm_snapshots: VmSnapshotsConfig,
+    /// Run firecracker through the jailer, instead of directly as the user
+    /// of lambdo
+    #[serde(default)]
+    pub jailer: Option<JailerConfig>,
+    /// Firecracker binary and the folder of the VM workspaces
+    #[serde(default)]
+    pub executor: ExecutorConfig,
+    /// Per-request debug mode, disabled if unset
+    #[serde(default)]
+    pub debug: Option<DebugConfig>,
+    /// Channel to the agent of the guests, over their vsock device
+    #[serde(default)]
+    pub agent: AgentConfig,
+    /// Runtimes `/run` executes code with, by language
+    #[serde(default)]
+    pub runtimes: HashMap<String, RuntimeConfig>,
+    /// Commands run to completion through `/jobs`
+    #[serde(default)]
+    pub jobs: JobsConfig,
+    /// Functions invoked by name, along with the ones registered through the
+    /// API
+    #[serde(default)]
+    pub functions: HashMap<String, FunctionConfig>,
+    /// Commands and endpoints called at points of the lifecycle of the VMs
+    #[serde(default)]
+    pub hooks: Vec<HookConfig>,
+    /// WASM components the requests to start VMs go through, in order, with
+    /// the `wasm` feature
+    #[serde(default)]
+    pub plugins: Vec<PluginConfig>,
+    /// Policy engine deciding whether VMs may be started
+    #[serde(default)]
+    pub policy: Option<PolicyConfig>,
+    /// VM booted by `/admin/selftest`
+    #[serde(default)]
+    pub selftest: SelftestConfig,
+    /// Bearer tokens requests have to carry, any request being accepted if
+    /// unset
+    #[serde(default)]
+    pub auth: Option<AuthConfig>,
+    /// Quotas of the namespaces of the tokens, by namespace
+    #[serde(default)]
+    pub namespaces: HashMap<String, NamespaceConfig>,
+}
+
+impl LambdoApiConfig {
+    /// What to do with the VMs on shutdown
+    pub fn shutdown_action(&self) -> ShutdownAction {
+        self.on_shutdown.unwrap_or(match self.state_file {
+            Some(_) => ShutdownAction::Leave,
+            None => ShutdownAction::Stop,
+        })
+    }
+
+    /// Bridge the VMs of `tenant` are attached to
+    pub fn bridge_for(&self, tenant: Option<&str>) -> BridgeConfig {
+        tenant
+            .and_then(|tenant| self.tenants.get(tenant))
+            .and_then(|tenant| tenant.network.clone())
+            .unwrap_or_else(|| BridgeConfig {
+                bridge: self.network.bridge.clone(),
+                bridge_address: self.network.bridge_address.clone(),
+                bridge_address_v6: self.network.bridge_address_v6.clone(),
+                vlan_id: self.network.vlan_id,
+                uplink_interface: self.network.uplink_interface.clone(),
+                snat_address: self.network.snat_address.clone(),
+                ip_range: self.network.ip_range.clone(),
+            })
+    }
+
+    /// Interface configured for the traffic of `bridge` to leave the host
+    /// through, if any
+    pub fn uplink_for(&self, bridge: &BridgeConfig) -> Option<String> {
+        bridge
+            .uplink_interface
+            .clone()
+            .or_else(|| self.network.uplink_interface.clone())
+    }
+
+    /// Host address the outbound traffic of `bridge` is source NATed from,
+    /// masqueraded if unset
+    pub fn snat_for(&self, bridge: &BridgeConfig) -> Option<String> {
+        bridge
+            .snat_address
+            .clone()
+            .or_else(|| self.network.snat_address.clone())
+    }
+
+    /// Every bridge used by lambdo, the default one first
+    pub fn bridges(&self) -> Vec<BridgeConfig> {
+        let mut bridges = vec![self.bridge_for(None)];
+        for tenant in self.tenants.values() {
+            if let Some(network) = &tenant.network {
+                if !bridges.iter().any(|b| b.bridge == network.bridge) {
+                    bridges.push(network.clone());
+                }
+            }
+        }
+        bridges
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct TenantConfig {
+    /// Network of the VMs of the tenant, isolated from the other bridges. The
+    /// default network is shared with the VMs without tenant otherwise
+    #[serde(default)]
+    pub network: Option<BridgeConfig>,
+    /// CPU time the VMs of the tenant may use in each period
+    #[serde(default)]
+    pub cpu_budget: Option<CpuBudgetConfig>,
+    /// Always attach the rootfs of the VMs of the tenant read-only, their
+    /// writes going to a tmpfs overlay inside the guest
+    #[serde(default)]
+    pub read_only_rootfs: bool,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CpuBudgetConfig {
+    /// Period after which the consumed CPU time starts over
+    #[serde(default)]
+    pub period: BudgetPeriod,
+    /// vCPU-seconds after which the VMs of the tenant are throttled
+    #[serde(default)]
+    pub soft_limit: Option<u64>,
+    /// vCPU-seconds after which the tenant cannot start VMs anymore
+    #[serde(default)]
+    pub hard_limit: Option<u64>,
+    /// CPUs shared by the VMs of the tenant once they are throttled
+    #[serde(default = "default_budget_throttle")]
+    pub throttle: f64,
+}
+
+fn default_budget_throttle() -> f64 {
+    0.5
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum BudgetPeriod {
+    Daily,
+    #[default]
+    Monthly,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CpuAccountingConfig {
+    /// Whether the firecracker processes of the tenants are put in cgroups
+    /// to account for their CPU time
+    #[serde(default)]
+    pub enabled: bool,
+    /// cgroup (v2) under which a cgroup is created for each tenant
+    #[serde(default = "default_cpu_accounting_cgroup")]
+    pub cgroup: String,
+    /// Seconds between two readings of the CPU time of the tenants
+    #[serde(default = "default_cpu_accounting_interval")]
+    pub interval: u64,
+    /// File in which the CPU time of the current periods is kept
+    #[serde(default = "default_cpu_usage_file")]
+    pub usage_file: String,
+}
+
+impl Default for CpuAccountingConfig {
+    fn default() -> Self {
+        CpuAccountingConfig {
+            enabled: false,
+            cgroup: default_cpu_accounting_cgroup(),
+            interval: default_cpu_accounting_interval(),
+            usage_file: default_cpu_usage_file(),
+        }
+    }
+}
+
+fn default_cpu_accounting_cgroup() -> String {
+    "/sys/fs/cgroup/lambdo".to_string()
+}
+
+fn default_cpu_accounting_interval() -> u64 {
+    10
+}
+
+fn default_cpu_usage_file() -> String {
+    "/var/lib/lambdo/cpu-usage.json".to_string()
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct NetAccountingConfig {
+    /// Whether the bytes sent and received by the VMs are counted
+    #[serde(default)]
+    pub enabled: bool,
+    /// Seconds between two readings of the counters of the tap devices
+    #[serde(default = "default_net_accounting_interval")]
+    pub interval: u64,
+    /// Bytes a VM may send and receive in total, unlimited if unset
+    #[serde(default)]
+    pub transfer_cap: Option<u64>,
+    /// What happens to a VM going over its transfer cap
+    #[serde(default)]
+    pub cap_action: TransferCapAction,
+    /// Bytes per second a VM over its transfer cap is throttled to, in each
+    /// direction
+    #[serde(default = "default_throttle_rate")]
+    pub throttle_rate: u64,
+}
+
+impl Default for NetAccountingConfig {
+    fn default() -> Self {
+        NetAccountingConfig {
+            enabled: false,
+            interval: default_net_accounting_interval(),
+            transfer_cap: None,
+            cap_action: TransferCapAction::default(),
+            throttle_rate: default_throttle_rate(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct NodeConditionsConfig {
+    /// Seconds between two checks of the host
+    #[serde(default = "default_node_conditions_interval")]
+    pub interval: u64,
+    /// Share of the folders of the images, volumes and workspaces below which
+    /// the host is under disk pressure
+    #[serde(default = "default_min_free_percent")]
+    pub min_free_disk_percent: f64,
+    /// Share of the memory of the host below which it is under memory
+    /// pressure
+    #[serde(default = "default_min_free_percent")]
+    pub min_available_memory_percent: f64,
+}
+
+impl Default for NodeConditionsConfig {
+    fn default() -> Self {
+        NodeConditionsConfig {
+            interval: default_node_conditions_interval(),
+            min_free_disk_percent: default_min_free_percent(),
+            min_available_memory_percent: default_min_free_percent(),
+        }
+    }
+}
+
+fn default_node_conditions_interval() -> u64 {
+    30
+}
+
+fn default_min_free_percent() -> f64 {
+    10.0
+}
+
+fn default_net_accounting_interval() -> u64 {
+    10
+}
+
+fn default_throttle_rate() -> u64 {
+    128 * 1024
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferCapAction {
+    /// Slow the traffic of the VM down to `throttleRate`
+    #[default]
+    Throttle,
+    /// Destroy the VM
+    Stop,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct BridgeConfig {
+    /// Bridge to bind to
+    pub bridge: String,
+    /// Address of the bridge
+    pub bridge_address: String,
+    /// IPv6 address of the bridge, along with the prefix length of the
+    /// subnet the VMs get an IPv6 address in. IPv4 only if unset
+    #[serde(default)]
+    pub bridge_address_v6: Option<String>,
+    /// VLAN the bridge is connected to, through a VLAN sub-interface of the
+    /// uplink
+    #[serde(default)]
+    pub vlan_id: Option<u16>,
+    /// Interface the traffic of the VMs is routed and masqueraded through,
+    /// the one of `network` if unset
+    #[serde(default)]
+    pub uplink_interface: Option<String>,
+    /// Host address the outbound traffic of the VMs is source NATed from,
+    /// the one of `network` if unset
+    #[serde(default)]
+    pub snat_address: Option<String>,
+    /// Addresses handed out to the VMs, the whole subnet if unset
+    #[serde(default)]
+    pub ip_range: Option<IpRangeConfig>,
+}
+
+/// Addresses of a subnet, both included
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct IpRangeConfig {
+    pub start: String,
+    pub end: String,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageManagerConfig {
+    /// Folder path for the images
+    #[serde(default = "default_images_folder")]
+    pub images_folder: String,
+    /// Image manager strategy
+    #[serde(default = "default_image_manager_strategy")]
+    pub strategy: ImageManagerStrategy,
+    /// Folder path for the VM disk exports
+    #[serde(default = "default_exports_folder")]
+    pub exports_folder: String,
+    /// Test kernel and rootfs fetched by `images fetch-samples`
+    #[serde(default)]
+    pub samples: SamplesConfig,
+    /// Staging store images are promoted from, promotion being disabled if
+    /// unset
+    #[serde(default)]
+    pub promotion: Option<PromotionConfig>,
+    /// Refuse to start VMs below the minimums of the boot profiles of their
+    /// images, instead of only logging it
+    #[serde(default)]
+    pub enforce_boot_profiles: bool,
+    /// Size in bytes above which the least recently used downloaded images
+    /// are evicted, the cache growing forever if unset
+    #[serde(default)]
+    pub max_cache_bytes: Option<u64>,
+    /// Periodic check of the stored images against their recorded digests
+    #[serde(default)]
+    pub audit: ImageAuditConfig,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageAuditConfig {
+    /// Seconds between two audits, images only being audited on request if
+    /// unset
+    #[serde(default)]
+    pub interval: Option<u64>,
+    /// Folder the corrupted images are moved to
+    #[serde(default = "default_quarantine_folder")]
+    pub quarantine_folder: String,
+}
+
+impl Default for ImageAuditConfig {
+    fn default() -> Self {
+        ImageAuditConfig {
+            interval: None,
+            quarantine_folder: default_quarantine_folder(),
+        }
+    }
+}
+
+fn default_quarantine_folder() -> String {
+    "/var/lib/lambdo/quarantine".to_string()
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PromotionConfig {
+    /// Folder of the images under test
+    pub staging_folder: String,
+    /// PEM public key the staged images must be signed with, each image
+    /// having its signature in a `.sig` file next to it
+    pub public_key: String,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SamplesConfig {
+    #[serde(default = "default_sample_kernel")]
+    pub kernel: SampleSource,
+    #[serde(default = "default_sample_rootfs")]
+    pub rootfs: SampleSource,
+}
+
+impl Default for SamplesConfig {
+    fn default() -> Self {
+        SamplesConfig {
+            kernel: default_sample_kernel(),
+            rootfs: default_sample_rootfs(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SampleSource {
+    pub url: String,
+    /// Expected SHA-256 digest of the file, in hexadecimal
+    #[serde(default)]
+    pub sha256: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct VolumeManagerConfig {
+    /// Folder path for the persistent volumes
+    #[serde(default = "default_volumes_folder")]
+    pub volumes_folder: String,
+}
+
+impl Default for VolumeManagerConfig {
+    fn default() -> Self {
+        VolumeManagerConfig {
+            volumes_folder: default_volumes_folder(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct EventsConfig {
+    /// File in which the events are stored
+    #[serde(default = "default_events_path")]
+    pub path: String,
+    /// Number of events kept for each event type
+    #[serde(default = "default_events_retention")]
+    pub retention: usize,
+    /// Number of events kept for specific event types, overriding `retention`
+    #[serde(default)]
+    pub retention_per_type: HashMap<String, usize>,
+    /// External systems the events are forwarded to
+    #[serde(default)]
+    pub sinks: Vec<EventSinkConfig>,
+}
+
+impl Default for EventsConfig {
+    fn default() -> Self {
+        EventsConfig {
+            path: default_events_path(),
+            retention: default_events_retention(),
+            retention_per_type: HashMap::new(),
+            sinks: Vec::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct EventSinkConfig {
+    #[serde(flatten)]
+    pub kind: EventSinkKind,
+    /// Only forward events of these types, all of them if empty
+    #[serde(default)]
+    pub types: Vec<String>,
+    /// Largest number of events sent at once
+    #[serde(default = "default_sink_batch_size")]
+    pub batch_size: usize,
+    /// Milliseconds to wait for a batch to fill before sending it
+    #[serde(default = "default_sink_batch_interval")]
+    pub batch_interval: u64,
+}
+
+fn default_sink_batch_size() -> usize {
+    100
+}
+
+fn default_sink_batch_interval() -> u64 {
+    1000
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct HookConfig {
+    /// Points of the lifecycle of the VMs the hook is called at
+    pub on: Vec<HookPoint>,
+    #[serde(flatten)]
+    pub target: HookTarget,
+    /// Seconds the hook has to complete
+    #[serde(default = "default_hook_timeout")]
+    pub timeout: u64,
+    #[serde(default)]
+    pub on_failure: HookFailurePolicy,
+}
+
+fn default_hook_timeout() -> u64 {
+    10
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum HookTarget {
+    /// Command run with the JSON payload on its standard input, failing if
+    /// it exits with a non-zero code
+    Exec { command: Vec<String> },
+    /// Endpoint the JSON payload is POSTed to, failing unless it answers
+    /// with a 2xx status
+    Http { url: String },
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum HookPoint {
+    /// Before a VM is created, with the options it is started with
+    PreStart,
+    /// Once the network of a new VM is set up, while it boots
+    NetworkSetup,
+    /// Once a new VM booted
+    PostStart,
+    /// Before a VM is stopped
+    PreStop,
+    /// Once a VM is stopped
+    PostStop,
+}
+
+impl Display for HookPoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            HookPoint::PreStart => "pre-start",
+            HookPoint::NetworkSetup => "network-setup",
+            HookPoint::PostStart => "post-start",
+            HookPoint::PreStop => "pre-stop",
+            HookPoint::PostStop => "post-stop",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum HookFailurePolicy {
+    /// Log the failure and go on
+    #[default]
+    Ignore,
+    /// Give up on the start or the stop, for the hooks called before the
+    /// point of no return: pre-start, network-setup and pre-stop
+    Abort,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PluginConfig {
+    /// WASM component implementing the `plugin` world of `wit/plugin.wit`
+    pub path: String,
+    /// Fuel a call to the plugin may burn, roughly one unit per instruction
+    #[serde(default = "default_plugin_fuel")]
+    pub fuel: u64,
+}
+
+fn default_plugin_fuel() -> u64 {
+    100_000_000
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PolicyConfig {
+    /// OPA data API URL of the set of messages denying a request, such as
+    /// http://localhost:8181/v1/data/lambdo/admission/deny
+    pub url: String,
+    /// Seconds the policy engine has to answer
+    #[serde(default = "default_policy_timeout")]
+    pub timeout: u64,
+}
+
+fn default_policy_timeout() -> u64 {
+    5
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SelftestConfig {
+    /// Kernel of the VM, the sample one if unset
+    #[serde(default = "default_selftest_kernel")]
+    pub kernel: String,
+    /// Root filesystem of the VM, which has to run the guest agent, the
+    /// sample one if unset
+    #[serde(default = "default_selftest_rootfs")]
+    pub rootfs: String,
+    /// Tenant the VM belongs to, for it to be attached to the network of the
+    /// tenant instead of the default bridge
+    #[serde(default)]
+    pub tenant: Option<String>,
+    /// Seconds the command run in the VM has to answer
+    #[serde(default = "default_selftest_timeout")]
+    pub timeout: u64,
+}
+
+impl Default for SelftestConfig {
+    fn default() -> Self {
+        SelftestConfig {
+            kernel: default_selftest_kernel(),
+            rootfs: default_selftest_rootfs(),
+            tenant: None,
+            timeout: default_selftest_timeout(),
+        }
+    }
+}
+
+fn default_selftest_kernel() -> String {
+    "samples/vmlinux".to_string()
+}
+
+fn default_selftest_rootfs() -> String {
+    "samples/rootfs.ext4".to_string()
+}
+
+fn default_selftest_timeout() -> u64 {
+    10
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthConfig {
+    #[serde(default)]
+    pub tokens: Vec<TokenConfig>,
+    /// YAML file of more tokens, as a list formatted like `tokens`
+    #[serde(default)]
+    pub token_file: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TokenConfig {
+    /// Name of the token, given to the policy engine and in the logs
+    pub name: String,
+    /// Value of the `Authorization: Bearer` header
+    pub token: String,
+    /// What the token allows on top of reading, nothing else if empty
+    #[serde(default)]
+    pub scopes: Vec<TokenScope>,
+    /// Namespace the VMs started with the token go to, the only one it sees
+    /// unless it has the admin scope, `default` if unset
+    #[serde(default)]
+    pub namespace: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct NamespaceConfig {
+    /// VMs the namespace may have at once, unlimited if unset
+    #[serde(default)]
+    pub max_vms: Option<u32>,
+    /// Memory of the VMs of the namespace in MiB, unlimited if unset
+    #[serde(default)]
+    pub max_memory_mib: Option<u64>,
+    /// Host ports mapped to the VMs of the namespace, unlimited if unset
+    #[serde(default)]
+    pub max_ports: Option<u32>,
+    /// vCPUs of the VMs of the namespace, unlimited if unset
+    #[serde(default)]
+    pub max_vcpus: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenScope {
+    /// Start VMs, run code and jobs, and change running VMs
+    Start,
+    /// Destroy and pause VMs
+    Stop,
+    /// Everything, including `/admin` and the image store
+    Admin,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum EventSinkKind {
+    Nats(NatsSinkConfig),
+    Kafka(KafkaSinkConfig),
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct NatsSinkConfig {
+    #[serde(flatten)]
+    pub server: NatsServerConfig,
+    /// Subject prefix, events are published on `<subject>.<event type>`
+    pub subject: String,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct NatsServerConfig {
+    /// Address of the NATS server, such as `nats://nats.example.com:4222`
+    pub url: String,
+    /// Token authentication
+    #[serde(default)]
+    pub token: Option<String>,
+    /// User and password authentication
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct KafkaSinkConfig {
+    /// URL of the Kafka REST proxy
+    pub rest_proxy_url: String,
+    /// Topic the events are produced to, keyed by VM id
+    pub topic: String,
+    /// Basic authentication on the REST proxy
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct QueueConfig {
+    #[serde(flatten)]
+    pub kind: QueueKind,
+    /// Largest number of VMs started from the queue running at once. They
+    /// are started one after the other whatever it is
+    #[serde(default = "default_queue_concurrency")]
+    pub concurrency: usize,
+}
+
+fn default_queue_concurrency() -> usize {
+    4
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum QueueKind {
+    Nats(NatsQueueConfig),
+    Sqs(SqsQueueConfig),
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct NatsQueueConfig {
+    #[serde(flatten)]
+    pub server: NatsServerConfig,
+    /// JetStream stream holding the requests
+    pub stream: String,
+    /// Durable pull consumer of the stream used by lambdo
+    pub consumer: String,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SqsQueueConfig {
+    /// URL of the queue, such as
+    /// `https://sqs.eu-west-1.amazonaws.com/123456789012/lambdo-jobs`
+    pub queue_url: String,
+    pub region: String,
+    /// Credentials, taken from the AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY
+    /// and AWS_SESSION_TOKEN environment variables when unset
+    #[serde(default)]
+    pub access_key_id: Option<String>,
+    #[serde(default)]
+    pub secret_access_key: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AdmissionConfig {
+    /// vCPUs that can be given to VMs for each host CPU, unlimited if unset
+    #[serde(default = "default_cpu_overcommit")]
+    pub cpu_overcommit: Option<f64>,
+    /// Memory that can be given to VMs as a ratio of the host memory,
+    /// unlimited if unset
+    #[serde(default = "default_memory_overcommit")]
+    pub memory_overcommit: Option<f64>,
+    /// Machine classes start requests may ask for instead of a size, by name
+    #[serde(default)]
+    pub classes: HashMap<String, MachineClassConfig>,
+}
+
+impl Default for AdmissionConfig {
+    fn default() -> Self {
+        AdmissionConfig {
+            cpu_overcommit: default_cpu_overcommit(),
+            memory_overcommit: default_memory_overcommit(),
+            classes: HashMap::new(),
+        }
+    }
+}
+
+/// A fixed VM size, with the share of the host set aside for it
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct MachineClassConfig {
+    pub vcpu_count: u8,
+    pub mem_size_mib: u32,
+    /// Number of VMs of the class the host runs at most, only limited by the
+    /// overcommit ratios if unset
+    #[serde(default)]
+    pub slots: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct GuestTuningConfig {
+    /// Sysctls start requests may set, a trailing `*` allowing every sysctl
+    /// with that prefix
+    #[serde(default)]
+    pub allowed_sysctls: Vec<String>,
+    /// Kernel modules start requests may load
+    #[serde(default)]
+    pub allowed_kernel_modules: Vec<String>,
+}
+
+impl GuestTuningConfig {
+    /// Check that the requested sysctls and modules are allowed and cannot
+    /// smuggle other boot arguments in.
+    pub fn check(
+        &self,
+        sysctls: &HashMap<String, String>,
+        kernel_modules: &[String],
+    ) -> Result<(), String> {
+        let is_word = |s: &str, extra: &[char]| {
+            !s.is_empty()
+                && s.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || extra.contains(&c))
+        };
+
+        for (name, value) in sysctls {
+            if !is_word(name, &['.', '-', '/']) || !is_word(value, &['.', '-', ':', ',']) {
+                return Err(format!("invalid sysctl {}={}", name, value));
+            }
+            let allowed =
+                self.allowed_sysctls
+                    .iter()
+                    .any(|allowed| match allowed.strip_suffix('*') {
+                        Some(prefix) => name.starts_with(prefix),
+                        None => allowed == name,
+                    });
+            if !allowed {
+                return Err(format!("sysctl {} is not allowed", name));
+            }
+        }
+
+        for module in kernel_modules {
+            if !is_word(module, &['-']) {
+                return Err(format!("invalid kernel module {}", module));
+            }
+            if !self.allowed_kernel_modules.contains(module) {
+                return Err(format!("kernel module {} is not allowed", module));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ReadOnlyRootfsConfig {
+    /// Init of the images mounting a tmpfs overlay over the read-only root
+    /// before starting the real init
+    #[serde(default = "default_overlay_init")]
+    pub overlay_init: String,
+    /// Size of the tmpfs overlay, such as `256m`, the guest default if unset
+    #[serde(default)]
+    pub overlay_size: Option<String>,
+}
+
+impl Default for ReadOnlyRootfsConfig {
+    fn default() -> Self {
+        ReadOnlyRootfsConfig {
+            overlay_init: default_overlay_init(),
+            overlay_size: None,
+        }
+    }
+}
+
+fn default_overlay_init() -> String {
+    "/sbin/overlay-init".to_string()
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SshConfig {
+    /// Folder of the SSH keys and seed disks of the VMs
+    #[serde(default = "default_ssh_folder")]
+    pub folder: String,
+    /// User to log in the guests as
+    #[serde(default = "default_ssh_user")]
+    pub user: String,
+    /// Host reported to connect to, clients using the host of the API
+    /// otherwise
+    #[serde(default)]
+    pub advertised_host: Option<String>,
+}
+
+impl Default for SshConfig {
+    fn default() -> Self {
+        SshConfig {
+            folder: default_ssh_folder(),
+            user: default_ssh_user(),
+            advertised_host: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ExecutorConfig {
+    /// Path of the firecracker binary, checked at startup
+    #[serde(default = "default_firecracker_path")]
+    pub firecracker_path: String,
+    /// Folder the workspaces of the VMs are created in, unless they are
+    /// jailed
+    #[serde(default = "default_executor_chroot_base")]
+    pub chroot_base: String,
+}
+
+impl Default for ExecutorConfig {
+    fn default() -> Self {
+        ExecutorConfig {
+            firecracker_path: default_firecracker_path(),
+            chroot_base: default_executor_chroot_base(),
+        }
+    }
+}
+
+fn default_firecracker_path() -> String {
+    "/usr/bin/firecracker".to_string()
+}
+
+fn default_executor_chroot_base() -> String {
+    "/tmp".to_string()
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct JailerConfig {
+    /// Path of the jailer binary
+    #[serde(default = "default_jailer_binary")]
+    pub binary: String,
+    /// User and group firecracker runs as
+    pub uid: u32,
+    pub gid: u32,
+    /// Folder under which the jails are created
+    #[serde(default = "default_chroot_base")]
+    pub chroot_base: String,
+    /// Cgroup version of the host, 1 or 2
+    #[serde(default)]
+    pub cgroup_version: Option<u8>,
+    /// Cgroup under which the cgroups of the VMs are created
+    #[serde(default)]
+    pub parent_cgroup: Option<String>,
+    /// Cgroup settings of every VM, such as `cpu.max=100000 100000`
+    #[serde(default)]
+    pub cgroups: Vec<String>,
+}
+
+fn default_jailer_binary() -> String {
+    "/usr/bin/jailer".to_string()
+}
+
+fn default_chroot_base() -> String {
+    "/srv/jailer".to_string()
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AgentConfig {
+    /// vsock port the guest agent listens on
+    #[serde(default = "default_agent_port")]
+    pub port: u32,
+    /// Seconds the agent has to reply, on top of the time commands are given
+    /// to run
+    #[serde(default = "default_agent_timeout")]
+    pub timeout: u64,
+    /// Folder the transcripts of the commands run in the guests are recorded
+    /// to, one file per VM. Set to null not to record them
+    #[serde(default = "default_sessions_folder")]
+    pub sessions_folder: Option<String>,
+}
+
+impl Default for AgentConfig {
+    fn default() -> Self {
+        AgentConfig {
+            port: default_agent_port(),
+            timeout: default_agent_timeout(),
+            sessions_folder: default_sessions_folder(),
+        }
+    }
+}
+
+fn default_agent_port() -> u32 {
+    52
+}
+
+fn default_agent_timeout() -> u64 {
+    10
+}
+
+fn default_sessions_folder() -> Option<String> {
+    Some("/var/lib/lambdo/sessions".to_string())
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RuntimeConfig {
+    /// Kernel image of the VMs of the runtime
+    #[serde(default = "default_warm_pool_kernel")]
+    pub kernel: String,
+    /// Root filesystem image with the interpreter and the guest agent
+    pub rootfs: String,
+    /// Command the code is run with, the code being its last argument
+    pub command: Vec<String>,
+    /// Most seconds code may run
+    #[serde(default = "default_runtime_max_timeout")]
+    pub max_timeout: u64,
+}
+
+fn default_runtime_max_timeout() -> u64 {
+    30
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
-pub enum ImageManagerStrategy {
-    #[serde(rename = "folder")]
-    Folder,
-    #[serde(rename = "url")]
-    Url,
+#[serde(rename_all = "camelCase")]
+pub struct JobsConfig {
+    /// Most seconds the command of a job may run
+    #[serde(default = "default_job_max_timeout")]
+    pub max_timeout: u64,
+    /// Seconds the result of a job is kept once it finished
+    #[serde(default = "default_job_retention")]
+    pub retention: u64,
+    /// Most items of an array job
+    #[serde(default = "default_job_max_array_size")]
+    pub max_array_size: u32,
+    /// Most times a job may ask for its command to be run again
+    #[serde(default = "default_job_max_retries")]
+    pub max_retries: u32,
+    /// Weight of each tenant in the share of the host its jobs get when
+    /// they queue for capacity, 1 if unset
+    #[serde(default)]
+    pub weights: HashMap<String, f64>,
+    /// Buckets jobs may have their output files uploaded to, by name
+    #[serde(default)]
+    pub artifacts: HashMap<String, ArtifactDestinationConfig>,
 }
 
+/// Bucket of an object store with the S3 API, such as S3 itself or GCS
+/// through its interoperability API and HMAC keys
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
-pub struct LambdoConfig {
-    /// The api version of the lambdo config file
-    pub api_version: String,
-    /// The kind of the lambdo config file
-    pub kind: String,
-    /// The lambdo api configuration
-    pub api: LambdoApiConfig,
+pub struct ArtifactDestinationConfig {
+    /// Endpoint of the store, such as `https://s3.eu-west-1.amazonaws.com`
+    /// or `https://storage.googleapis.com`
+    pub endpoint: String,
+    pub bucket: String,
+    /// `auto` for GCS
+    pub region: String,
+    /// Prefix of the keys, under which each job gets a prefix of its own
+    #[serde(default)]
+    pub prefix: String,
+    /// Credentials, taken from the AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY
+    /// and AWS_SESSION_TOKEN environment variables when unset
+    #[serde(default)]
+    pub access_key_id: Option<String>,
+    #[serde(default)]
+    pub secret_access_key: Option<String>,
+}
+
+impl Default for JobsConfig {
+    fn default() -> Self {
+        JobsConfig {
+            max_timeout: default_job_max_timeout(),
+            retention: default_job_retention(),
+            max_array_size: default_job_max_array_size(),
+            max_retries: default_job_max_retries(),
+            weights: HashMap::new(),
+            artifacts: HashMap::new(),
+        }
+    }
+}
+
+fn default_job_max_timeout() -> u64 {
+    3600
+}
+
+fn default_job_retention() -> u64 {
+    24 * 3600
+}
+
+fn default_job_max_array_size() -> u32 {
+    1000
+}
+
+fn default_job_max_retries() -> u32 {
+    10
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
-pub struct LambdoApiConfig {
-    /// Network configuration
-    pub network: NetworkConfig,
-    /// Image manager configuration
-    pub image_manager: ImageManagerConfig,
+pub struct FunctionConfig {
+    /// Kernel image of the VMs of the function
+    #[serde(default = "default_warm_pool_kernel")]
+    pub kernel: String,
+    /// Root filesystem image of the VMs of the function
+    pub rootfs: String,
+    #[serde(default)]
+    pub vcpu_count: Option<u8>,
+    #[serde(default)]
+    pub mem_size_mib: Option<u32>,
+    /// Environment of the init of the VMs, given on the kernel command line
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// Guest ports mapped to host ports allocated automatically
+    #[serde(default)]
+    pub ports: Vec<u16>,
+    /// Seconds without invocation after which the VM of the function is
+    /// destroyed, the next invocation booting a new one. Kept running if unset
+    #[serde(default)]
+    pub idle_timeout: Option<u64>,
+}
+
+impl FunctionConfig {
+    /// Check that the environment fits on the kernel command line.
+    pub fn check(&self) -> Result<(), String> {
+        for (name, value) in &self.env {
+            if name.is_empty() || name.contains('=') || name.contains(char::is_whitespace) {
+                return Err(format!("invalid environment variable name {:?}", name));
+            }
+            if value.contains(char::is_whitespace) || value.contains('"') {
+                return Err(format!(
+                    "environment variable {} cannot hold spaces nor quotes",
+                    name
+                ));
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
-pub struct ImageManagerConfig {
-    /// Folder path for the images
-    #[serde(default = "default_images_folder")]
-    pub images_folder: String,
-    /// Image manager strategy
-    #[serde(default = "default_image_manager_strategy")]
-    pub strategy: ImageManagerStrategy,
+pub struct DebugConfig {
+    /// Token requests have to carry in `x-lambdo-admin-token` to be debugged
+    pub admin_token: String,
+    /// File the traces of the debugged requests are appended to
+    #[serde(default = "default_debug_log")]
+    pub log_file: String,
+}
+
+fn default_debug_log() -> String {
+    "/var/log/lambdo/debug.log".to_string()
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct VmSnapshotsConfig {
+    /// Folder of the snapshots, one subfolder per VM
+    #[serde(default = "default_vm_snapshots_folder")]
+    pub folder: String,
+    /// Most snapshots a VM may keep
+    #[serde(default = "default_max_retention")]
+    pub max_retention: usize,
+    /// Shortest interval between two snapshots of a VM, in seconds
+    #[serde(default = "default_min_snapshot_interval")]
+    pub min_interval: u64,
+}
+
+impl Default for VmSnapshotsConfig {
+    fn default() -> Self {
+        VmSnapshotsConfig {
+            folder: default_vm_snapshots_folder(),
+            max_retention: default_max_retention(),
+            min_interval: default_min_snapshot_interval(),
+        }
+    }
+}
+
+fn default_vm_snapshots_folder() -> String {
+    "/var/lib/lambdo/vm-snapshots".to_string()
+}
+
+fn default_max_retention() -> usize {
+    10
+}
+
+fn default_min_snapshot_interval() -> u64 {
+    60
+}
+
+fn default_ssh_folder() -> String {
+    "/var/lib/lambdo/ssh".to_string()
+}
+
+fn default_ssh_user() -> String {
+    "root".to_string()
+}
+
+fn default_lease_file() -> String {
+    "/var/lib/lambdo/leases.json".to_string()
+}
+
+fn default_cpu_overcommit() -> Option<f64> {
+    Some(4.0)
+}
+
+fn default_memory_overcommit() -> Option<f64> {
+    Some(1.0)
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct WarmPoolConfig {
+    /// Pools to keep filled, one per kernel and rootfs combination
+    #[serde(default)]
+    pub pools: Vec<WarmPoolEntry>,
+    /// Seconds between two refills of the pools
+    #[serde(default = "default_warm_pool_refill_interval")]
+    pub refill_interval: u64,
+}
+
+impl Default for WarmPoolConfig {
+    fn default() -> Self {
+        WarmPoolConfig {
+            pools: Vec::new(),
+            refill_interval: default_warm_pool_refill_interval(),
+        }
+    }
+}
+
+fn default_warm_pool_refill_interval() -> u64 {
+    5
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct WarmPoolEntry {
+    /// Kernel image of the pooled VMs
+    #[serde(default = "default_warm_pool_kernel")]
+    pub kernel: String,
+    /// Root filesystem image of the pooled VMs
+    pub rootfs: String,
+    /// Number of idle VMs to keep booted
+    pub size: usize,
+}
+
+fn default_warm_pool_kernel() -> String {
+    String::from("vmlinux")
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct EgressProxyConfig {
+    /// Whether the egress proxy is started
+    #[serde(default)]
+    pub enabled: bool,
+    /// The port on which the proxy listens, on the bridge address
+    #[serde(default = "default_egress_proxy_port")]
+    pub port: u16,
+    /// Egress profiles that VMs can be started with
+    #[serde(default)]
+    pub profiles: HashMap<String, EgressProfile>,
+}
+
+impl Default for EgressProxyConfig {
+    fn default() -> Self {
+        EgressProxyConfig {
+            enabled: false,
+            port: default_egress_proxy_port(),
+            profiles: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct IngressProxyConfig {
+    /// Whether the ingress proxy is started
+    #[serde(default)]
+    pub enabled: bool,
+    /// The host on which the proxy listens
+    #[serde(default = "default_ingress_proxy_host")]
+    pub host: String,
+    /// The port on which the proxy listens
+    #[serde(default = "default_ingress_proxy_port")]
+    pub port: u16,
+    /// Base of the share links, the proxy being reached on it, such as
+    /// https://preview.example.com behind a load balancer. The host and port
+    /// of the proxy if unset
+    #[serde(default)]
+    pub public_url: Option<String>,
+    /// Key the share links are signed with. A random one if unset, the links
+    /// then not working anymore once lambdo restarts
+    #[serde(default)]
+    pub secret: Option<String>,
+    /// Seconds a share link may be valid for at most
+    #[serde(default = "default_share_link_max_ttl")]
+    pub max_ttl: u64,
+    /// Seconds the requests proxied to the VM of a function scaled to zero
+    /// have to finish before the VM is destroyed
+    #[serde(default = "default_drain_timeout")]
+    pub drain_timeout: u64,
+}
+
+impl IngressProxyConfig {
+    /// Base of the share links, without a trailing slash
+    pub fn public_url(&self) -> String {
+        match &self.public_url {
+            Some(url) => url.trim_end_matches('/').to_string(),
+            None => format!("http://{}:{}", self.host, self.port),
+        }
+    }
+}
+
+impl Default for IngressProxyConfig {
+    fn default() -> Self {
+        IngressProxyConfig {
+            enabled: false,
+            host: default_ingress_proxy_host(),
+            port: default_ingress_proxy_port(),
+            public_url: None,
+            secret: None,
+            max_ttl: default_share_link_max_ttl(),
+            drain_timeout: default_drain_timeout(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct GrpcConfig {
+    /// Whether the gRPC server is started
+    #[serde(default)]
+    pub enabled: bool,
+    /// The host on which the gRPC server listens
+    #[serde(default = "default_grpc_host")]
+    pub host: String,
+    /// The port on which the gRPC server listens
+    #[serde(default = "default_grpc_port")]
+    pub port: u16,
+}
+
+impl Default for GrpcConfig {
+    fn default() -> Self {
+        GrpcConfig {
+            enabled: false,
+            host: default_grpc_host(),
+            port: default_grpc_port(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct EgressProfile {
+    /// Domains the VMs can reach, `*.example.com` matches any subdomain
+    #[serde(default)]
+    pub allowed_domains: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct WireguardConfig {
+    /// Name of the wireguard interface
+    #[serde(default = "default_wireguard_interface")]
+    pub interface: String,
+    /// Address of the host on the tunnel, in CIDR notation
+    pub address: String,
+    /// UDP port on which the tunnel listens
+    #[serde(default = "default_wireguard_port")]
+    pub listen_port: u16,
+    /// File holding the private key of the host, generated if missing
+    #[serde(default = "default_wireguard_key_file")]
+    pub private_key_file: String,
+    /// Peers reachable through the tunnel
+    #[serde(default)]
+    pub peers: Vec<WireguardPeer>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct WireguardPeer {
+    pub public_key: String,
+    /// `host:port` of the peer, if it can be reached directly
+    #[serde(default)]
+    pub endpoint: Option<String>,
+    /// Networks routed to the peer, such as its bridge
+    #[serde(default)]
+    pub allowed_ips: Vec<String>,
+    /// Seconds between two keepalive packets, useful behind a NAT
+    #[serde(default)]
+    pub persistent_keepalive: Option<u16>,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
@@ -66,10 +1592,116 @@ pub struct NetworkConfig {
     /// Address of the bridge
     #[serde(default = "default_bridge_address")]
     pub bridge_address: String,
+    /// IPv6 address of the bridge, along with the prefix length of the
+    /// subnet the VMs get an IPv6 address in. IPv4 only if unset
+    #[serde(default)]
+    pub bridge_address_v6: Option<String>,
     /// The host on which the API server will listen
     pub web_host: String,
     /// The port on which the API server will listen
     pub web_port: u16,
+    /// Serve the API over TLS instead of plaintext HTTP
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    /// Whether lambdo creates and configures the bridge itself, or uses an
+    /// existing one managed by the host as is
+    #[serde(default = "default_true")]
+    pub manage_bridge: bool,
+    /// VLAN the bridge is connected to, through a VLAN sub-interface of the
+    /// uplink
+    #[serde(default)]
+    pub vlan_id: Option<u16>,
+    /// Interface the traffic of the VMs is routed and masqueraded through,
+    /// the one of the default route if unset
+    #[serde(default)]
+    pub uplink_interface: Option<String>,
+    /// Host address the outbound traffic of the VMs is source NATed from,
+    /// instead of being masqueraded behind the address of the uplink
+    #[serde(default)]
+    pub snat_address: Option<String>,
+    /// Addresses of the bridge subnet handed out to the VMs, the whole
+    /// subnet if unset
+    #[serde(default)]
+    pub ip_range: Option<IpRangeConfig>,
+    /// Whether the traffic of the VMs is NATed or routed
+    #[serde(default)]
+    pub mode: NetworkMode,
+    /// Command run for each bridge at startup in routed mode, to announce
+    /// its subnets to the network, with LAMBDO_BRIDGE, LAMBDO_SUBNET,
+    /// LAMBDO_SUBNET_V6 and LAMBDO_UPLINK in its environment
+    #[serde(default)]
+    pub announce_command: Option<Vec<String>>,
+    /// What applies the firewall rules
+    #[serde(default)]
+    pub firewall: FirewallBackend,
+    /// Seconds between two checks of the bridge configuration, 0 to disable
+    #[serde(default = "default_reconcile_interval")]
+    pub reconcile_interval: u64,
+    /// How host ports are picked when they are allocated automatically
+    #[serde(default = "default_port_allocation")]
+    pub port_allocation: PortAllocationStrategy,
+    /// Host ports handed out when they are allocated automatically
+    #[serde(default)]
+    pub port_range: PortRangeConfig,
+    /// Connections each VM may have tracked by the host, for a single guest
+    /// not to exhaust the conntrack table
+    #[serde(default)]
+    pub connection_limits: ConnectionLimitsConfig,
+    /// Whether responses are compressed (gzip, zstd or brotli) for clients
+    /// accepting it
+    #[serde(default = "default_true")]
+    pub compression: bool,
+    /// Whether large list responses are streamed instead of being buffered
+    #[serde(default = "default_true")]
+    pub stream_responses: bool,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TlsConfig {
+    /// PEM file of the certificate chain of the server
+    pub cert: String,
+    /// PEM file of the private key of the server
+    pub key: String,
+    /// PEM file of the CAs client certificates are checked against, making
+    /// one required from every client. Any client is accepted if unset
+    #[serde(default)]
+    pub client_ca: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub struct PortRangeConfig {
+    /// First port of the range
+    pub from: u16,
+    /// Last port of the range, included
+    pub to: u16,
+}
+
+impl Default for PortRangeConfig {
+    fn default() -> Self {
+        PortRangeConfig {
+            from: 10000,
+            to: 20000,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectionLimitsConfig {
+    /// Connections open at once from, and to, each VM, unlimited if unset
+    #[serde(default)]
+    pub max_connections: Option<u32>,
+    /// New connections each VM may open per second, unlimited if unset
+    #[serde(default)]
+    pub max_new_per_second: Option<u32>,
+}
+
+impl PortRangeConfig {
+    pub fn ports(&self) -> RangeInclusive<u16> {
+        self.from..=self.to
+    }
 }
 
 fn default_bridge() -> String {
@@ -80,10 +1712,96 @@ fn default_bridge_address() -> String {
     String::from("192.168.10.1/24")
 }
 
+fn default_true() -> bool {
+    true
+}
+
+fn default_reconcile_interval() -> u64 {
+    30
+}
+
 fn default_images_folder() -> String {
     String::from("/var/lib/lambdo/images")
 }
 
+fn default_sample_kernel() -> SampleSource {
+    SampleSource {
+        url: String::from(
+            "https://s3.amazonaws.com/spec.ccfc.min/img/quickstart_guide/x86_64/kernels/vmlinux.bin",
+        ),
+        sha256: None,
+    }
+}
+
+fn default_sample_rootfs() -> SampleSource {
+    SampleSource {
+        url: String::from(
+            "https://s3.amazonaws.com/spec.ccfc.min/img/quickstart_guide/x86_64/rootfs/bionic.rootfs.ext4",
+        ),
+        sha256: None,
+    }
+}
+
+fn default_exports_folder() -> String {
+    String::from("/var/lib/lambdo/exports")
+}
+
+fn default_volumes_folder() -> String {
+    String::from("/var/lib/lambdo/volumes")
+}
+
+fn default_events_path() -> String {
+    String::from("/var/lib/lambdo/events.jsonl")
+}
+
+fn default_events_retention() -> usize {
+    1000
+}
+
+fn default_ingress_proxy_host() -> String {
+    String::from("0.0.0.0")
+}
+
+fn default_ingress_proxy_port() -> u16 {
+    8080
+}
+
+fn default_grpc_host() -> String {
+    String::from("0.0.0.0")
+}
+
+fn default_grpc_port() -> u16 {
+    50051
+}
+
+fn default_share_link_max_ttl() -> u64 {
+    86400
+}
+
+fn default_drain_timeout() -> u64 {
+    30
+}
+
+fn default_egress_proxy_port() -> u16 {
+    3128
+}
+
+fn default_wireguard_interface() -> String {
+    String::from("lambdo-wg")
+}
+
+fn default_wireguard_port() -> u16 {
+    51820
+}
+
+fn default_wireguard_key_file() -> String {
+    String::from("/etc/lambdo/wireguard.key")
+}
+
+fn default_port_allocation() -> PortAllocationStrategy {
+    PortAllocationStrategy::Sequential
+}
+
 fn default_image_manager_strategy() -> ImageManagerStrategy {
     ImageManagerStrategy::Folder
 }
@@ -91,6 +1809,11 @@ fn default_image_manager_strategy() -> ImageManagerStrategy {
 impl LambdoConfig {
     /// Load a LambdoConfig from a file.
     ///
+    /// `${VAR}` and `${VAR:-default}` references in string values are
+    /// replaced by the value of the environment variable, and any node
+    /// tagged with `!include <path>` is replaced by the content of that file
+    /// (relative to the including file).
+    ///
     /// Arguments:
     ///
     /// * `path`: The path to the config file.
@@ -99,10 +1822,9 @@ impl LambdoConfig {
     ///
     /// A Result<LambdoConfig>
     pub fn load(path: &str) -> Result<Self> {
-        let file = File::open(path).map_err(LambdoConfigError::Load)?;
-        let reader = BufReader::new(file);
+        let value = load_value(Path::new(path), &mut Vec::new())?;
         let config: LambdoConfig =
-            serde_yaml::from_reader(reader).map_err(LambdoConfigError::Parse)?;
+            serde_yaml::from_value(value).map_err(LambdoConfigError::Parse)?;
 
         if config.kind != "Config" {
             return Err(LambdoConfigError::KindNotSupported.into());
@@ -112,6 +1834,315 @@ impl LambdoConfig {
             return Err(LambdoConfigError::VersionNotSupported.into());
         }
 
+        if let Some(feature) = config
+            .features
+            .keys()
+            .find(|feature| !FEATURES.iter().any(|(name, _)| name == feature))
+        {
+            return Err(LambdoConfigError::UnknownFeature(feature.clone()).into());
+        }
+
+        let network = &config.api.network;
+        let range = network.port_range;
+        if range.from == 0 || range.from > range.to {
+            return Err(LambdoConfigError::InvalidPortRange(format!(
+                "{}-{} is empty or starts at 0",
+                range.from, range.to
+            ))
+            .into());
+        }
+        if range.ports().contains(&network.web_port) {
+            return Err(LambdoConfigError::InvalidPortRange(format!(
+                "{}-{} contains the web port {}",
+                range.from, range.to, network.web_port
+            ))
+            .into());
+        }
+        let ingress = &config.api.ingress_proxy;
+        if ingress.enabled && range.ports().contains(&ingress.port) {
+            return Err(LambdoConfigError::InvalidPortRange(format!(
+                "{}-{} contains the ingress proxy port {}",
+                range.from, range.to, ingress.port
+            ))
+            .into());
+        }
+        let grpc = &config.api.grpc;
+        if grpc.enabled && range.ports().contains(&grpc.port) {
+            return Err(LambdoConfigError::InvalidPortRange(format!(
+                "{}-{} contains the gRPC port {}",
+                range.from, range.to, grpc.port
+            ))
+            .into());
+        }
+
+        for (name, function) in &config.api.functions {
+            function
+                .check()
+                .map_err(|e| LambdoConfigError::InvalidFunction(name.clone(), e))?;
+        }
+
         Ok(config)
     }
+
+    /// Whether an experimental subsystem is enabled
+    pub fn feature_enabled(&self, feature: &str) -> bool {
+        self.features.get(feature).copied().unwrap_or_else(|| {
+            FEATURES
+                .iter()
+                .find(|(name, _)| *name == feature)
+                .is_some_and(|(_, default)| *default)
+        })
+    }
+}
+
+/// How many files deep `!include` tags may nest
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// Read, parse and interpolate a YAML file, resolving its `!include` tags.
+///
+/// `stack` holds the files currently being loaded, to detect include cycles.
+fn load_value(path: &Path, stack: &mut Vec<PathBuf>) -> Result<Value, LambdoConfigError> {
+    let canonical = path
+        .canonicalize()
+        .map_err(|e| LambdoConfigError::Include(path.to_path_buf(), e))?;
+    if stack.contains(&canonical) {
+        return Err(LambdoConfigError::IncludeCycle(canonical));
+    }
+    if stack.len() > MAX_INCLUDE_DEPTH {
+        return Err(LambdoConfigError::IncludeTooDeep(canonical));
+    }
+
+    let content = fs::read_to_string(path).map_err(|e| {
+        if stack.is_empty() {
+            LambdoConfigError::Load(e)
+        } else {
+            LambdoConfigError::Include(path.to_path_buf(), e)
+        }
+    })?;
+    let value = interpolate_value(serde_yaml::from_str(&content)?)?;
+
+    let base = canonical
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_default();
+    stack.push(canonical);
+    let value = resolve_includes(value, &base, stack)?;
+    stack.pop();
+
+    Ok(value)
+}
+
+fn resolve_includes(
+    value: Value,
+    base: &Path,
+    stack: &mut Vec<PathBuf>,
+) -> Result<Value, LambdoConfigError> {
+    match value {
+        Value::Tagged(tagged) if tagged.tag == "include" => {
+            let file = tagged
+                .value
+                .as_str()
+                .ok_or(LambdoConfigError::InvalidInclude)?;
+            load_value(&base.join(file), stack)
+        }
+        Value::Tagged(mut tagged) => {
+            tagged.value = resolve_includes(tagged.value, base, stack)?;
+            Ok(Value::Tagged(tagged))
+        }
+        Value::Mapping(mapping) => mapping
+            .into_iter()
+            .map(|(key, value)| Ok((key, resolve_includes(value, base, stack)?)))
+            .collect::<Result<_, _>>()
+            .map(Value::Mapping),
+        Value::Sequence(sequence) => sequence
+            .into_iter()
+            .map(|value| resolve_includes(value, base, stack))
+            .collect::<Result<_, _>>()
+            .map(Value::Sequence),
+        value => Ok(value),
+    }
+}
+
+/// Interpolate the string scalars of `value`. The document is parsed first,
+/// for the values of the environment not to change its structure, and for
+/// the commented out lines to be left alone.
+fn interpolate_value(value: Value) -> Result<Value, LambdoConfigError> {
+    match value {
+        Value::String(scalar) => interpolate_scalar(&scalar),
+        Value::Tagged(mut tagged) => {
+            tagged.value = interpolate_value(tagged.value)?;
+            Ok(Value::Tagged(tagged))
+        }
+        Value::Mapping(mapping) => mapping
+            .into_iter()
+            .map(|(key, value)| Ok((key, interpolate_value(value)?)))
+            .collect::<Result<_, _>>()
+            .map(Value::Mapping),
+        Value::Sequence(sequence) => sequence
+            .into_iter()
+            .map(interpolate_value)
+            .collect::<Result<_, _>>()
+            .map(Value::Sequence),
+        value => Ok(value),
+    }
+}
+
+/// A scalar made of a single reference takes the number or boolean its
+/// value is, for those to be set from the environment too.
+fn interpolate_scalar(scalar: &str) -> Result<Value, LambdoConfigError> {
+    let interpolated = interpolate(scalar)?;
+    let single_reference = scalar.starts_with("${") && scalar.find('}') == Some(scalar.len() - 1);
+    if single_reference {
+        if let Ok(value @ (Value::Number(_) | Value::Bool(_))) =
+            serde_yaml::from_str::<Value>(&interpolated)
+        {
+            return Ok(value);
+        }
+    }
+    Ok(Value::String(interpolated))
+}
+
+/// Replace `${VAR}` and `${VAR:-default}` with values from the environment.
+/// `$$` escapes a literal `$`.
+fn interpolate(content: &str) -> Result<String, LambdoConfigError> {
+    let mut output = String::with_capacity(content.len());
+    let mut rest = content;
+
+    while let Some(index) = rest.find('$') {
+        output.push_str(&rest[..index]);
+        rest = &rest[index + 1..];
+
+        if let Some(stripped) = rest.strip_prefix('$') {
+            output.push('$');
+            rest = stripped;
+        } else if let Some(stripped) = rest.strip_prefix('{') {
+            let end = stripped
+                .find('}')
+                .ok_or(LambdoConfigError::UnterminatedVariable)?;
+            let reference = &stripped[..end];
+            let (name, default) = match reference.split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (reference, None),
+            };
+
+            match (std::env::var(name), default) {
+                (Ok(value), _) => output.push_str(&value),
+                (Err(_), Some(default)) => output.push_str(default),
+                (Err(_), None) => return Err(LambdoConfigError::MissingVariable(name.into())),
+            }
+            rest = &stripped[end + 1..];
+        } else {
+            output.push('$');
+        }
+    }
+
+    output.push_str(rest);
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Folder of its own for the files of a test
+    fn folder() -> PathBuf {
+        let folder = std::env::temp_dir().join(format!("lambdo-config-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&folder).unwrap();
+        folder
+    }
+
+    fn load(folder: &Path, files: &[(&str, &str)]) -> Result<Value, LambdoConfigError> {
+        for (name, content) in files {
+            fs::write(folder.join(name), content).unwrap();
+        }
+        load_value(&folder.join(files[0].0), &mut Vec::new())
+    }
+
+    #[test]
+    fn resolves_includes() {
+        let folder = folder();
+        let value = load(
+            &folder,
+            &[
+                ("main.yaml", "api: !include api.yaml"),
+                ("api.yaml", "port: 3000"),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(value["api"]["port"], Value::from(3000));
+    }
+
+    #[test]
+    fn rejects_a_missing_include() {
+        let folder = folder();
+        let result = load(&folder, &[("main.yaml", "api: !include api.yaml")]);
+
+        assert!(
+            matches!(result, Err(LambdoConfigError::Include(path, _)) if path == folder.join("api.yaml"))
+        );
+    }
+
+    #[test]
+    fn rejects_an_include_cycle() {
+        let folder = folder();
+        let result = load(
+            &folder,
+            &[
+                ("a.yaml", "b: !include b.yaml"),
+                ("b.yaml", "a: !include a.yaml"),
+            ],
+        );
+
+        assert!(
+            matches!(result, Err(LambdoConfigError::IncludeCycle(path)) if path.ends_with("a.yaml"))
+        );
+    }
+
+    #[test]
+    fn rejects_includes_nested_too_deep() {
+        let folder = folder();
+        let files: Vec<(String, String)> = (0..=MAX_INCLUDE_DEPTH + 1)
+            .map(|i| {
+                (
+                    format!("{i}.yaml"),
+                    format!("next: !include {}.yaml", i + 1),
+                )
+            })
+            .collect();
+        let files: Vec<(&str, &str)> = files
+            .iter()
+            .map(|(name, content)| (name.as_str(), content.as_str()))
+            .collect();
+
+        let result = load(&folder, &files);
+
+        assert!(matches!(result, Err(LambdoConfigError::IncludeTooDeep(_))));
+    }
+
+    #[test]
+    fn rejects_an_undefined_variable() {
+        let result = interpolate("url: ${LAMBDO_TEST_UNDEFINED}");
+
+        assert!(
+            matches!(result, Err(LambdoConfigError::MissingVariable(name)) if name == "LAMBDO_TEST_UNDEFINED")
+        );
+    }
+
+    #[test]
+    fn falls_back_to_the_default_of_an_undefined_variable() {
+        let value = interpolate_scalar("${LAMBDO_TEST_UNDEFINED:-8080}").unwrap();
+
+        assert_eq!(value, Value::from(8080));
+    }
+
+    #[test]
+    fn rejects_an_unterminated_variable() {
+        let result = interpolate("url: ${LAMBDO_TEST_UNDEFINED");
+
+        assert!(matches!(
+            result,
+            Err(LambdoConfigError::UnterminatedVariable)
+        ));
+    }
 }
@@ -0,0 +1,211 @@
+use std::{
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use cidr::{Ipv4Cidr, Ipv4Inet};
+use tokio::process::Command;
+
+use super::LambdoConfig;
+
+/// Commented config every option of which is documented, with its defaults
+const TEMPLATE: &str = include_str!("../../config.yaml");
+
+/// Folder lambdo keeps its images, volumes and state in by default
+const STATE_FOLDER: &str = "/var/lib/lambdo";
+
+/// Folders lambdo may keep its images, volumes and state in, the one with
+/// the most space available being picked when probing
+const STATE_FOLDERS: [&str; 3] = [STATE_FOLDER, "/srv/lambdo", "/data/lambdo"];
+
+const BRIDGE_ADDRESS_DOC: &str =
+    "    # Address of the bridge, along with the prefix length of the subnet the
+    # VMs get their address in";
+
+/// Settings picked for the host the config is generated on
+#[derive(Debug, Clone)]
+pub struct Probed {
+    /// Address of the bridge, in a subnet no route of the host goes to
+    pub bridge_address: String,
+    /// Interface of the default route
+    pub uplink: Option<String>,
+    pub state_folder: String,
+}
+
+impl Default for Probed {
+    fn default() -> Self {
+        Probed {
+            bridge_address: String::from("192.168.10.1/24"),
+            uplink: None,
+            state_folder: STATE_FOLDER.to_string(),
+        }
+    }
+}
+
+/// Look at the routes and the filesystems of the host for settings that
+/// work on it, keeping the defaults for what cannot be found out.
+pub async fn probe() -> Probed {
+    let defaults = Probed::default();
+    let routes = routes().unwrap_or_default();
+
+    let bridge_address = bridge_candidates()
+        .find(|candidate| {
+            let subnet = candidate.network();
+            !routes.iter().any(|route| {
+                route.destination.network_length() > 0
+                    && (route.destination.contains(&subnet.first_address())
+                        || subnet.contains(&route.destination.first_address()))
+            })
+        })
+        .map(|address| address.to_string())
+        .unwrap_or(defaults.bridge_address);
+
+    let uplink = routes
+        .iter()
+        .filter(|route| route.destination.network_length() == 0)
+        .min_by_key(|route| route.metric)
+        .map(|route| route.interface.clone());
+
+    let state_folder = largest_folder().await.unwrap_or(defaults.state_folder);
+
+    Probed {
+        bridge_address,
+        uplink,
+        state_folder,
+    }
+}
+
+/// The commented config, with the settings of `probed`
+pub fn render(probed: &Probed) -> Result<String> {
+    let mut lines = Vec::new();
+    for line in TEMPLATE.lines() {
+        let line = match line {
+            // The template sets ip, which is not read, instead of the address
+            // of the bridge
+            "    # The IP address of the bridge" => BRIDGE_ADDRESS_DOC.to_string(),
+            "    ip: 10.0.50.0/8" => format!("    bridgeAddress: {}", probed.bridge_address),
+            "    # uplinkInterface: eth1" => match &probed.uplink {
+                Some(uplink) => format!("    uplinkInterface: {}", uplink),
+                None => line.to_string(),
+            },
+            _ => line.replace(STATE_FOLDER, &probed.state_folder),
+        };
+        lines.push(line);
+    }
+    let content = lines.join("\n") + "\n";
+
+    if !content.contains(&format!("bridgeAddress: {}", probed.bridge_address)) {
+        return Err(anyhow!("the config template has no bridge address to set"));
+    }
+    Ok(content)
+}
+
+/// Write the config to `path`, checking that it loads first, and refusing
+/// to replace an existing file unless `force` is set.
+pub fn write(path: &str, content: &str, force: bool) -> Result<()> {
+    let path = Path::new(path);
+    if path.exists() && !force {
+        return Err(anyhow!(
+            "{} already exists, pass --force to replace it",
+            path.display()
+        ));
+    }
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        std::fs::create_dir_all(parent)
+            .map_err(|e| anyhow!("cannot create {}: {}", parent.display(), e))?;
+    }
+
+    // Checked next to its destination, for the config on disk to always be
+    // a valid one
+    let mut staged = PathBuf::from(path);
+    staged.set_extension("yaml.new");
+    std::fs::write(&staged, content)
+        .map_err(|e| anyhow!("cannot write {}: {}", staged.display(), e))?;
+    if let Err(e) = LambdoConfig::load(&staged.to_string_lossy()) {
+        let _ = std::fs::remove_file(&staged);
+        return Err(anyhow!("the generated config does not load: {:?}", e));
+    }
+    std::fs::rename(&staged, path)
+        .map_err(|e| anyhow!("cannot move the config to {}: {}", path.display(), e))
+}
+
+struct Route {
+    interface: String,
+    destination: Ipv4Cidr,
+    metric: u32,
+}
+
+/// IPv4 routes of the main table, from /proc/net/route
+fn routes() -> Result<Vec<Route>> {
+    let table = std::fs::read_to_string("/proc/net/route")?;
+    // Addresses are in hex, in the byte order of the host
+    let address = |field: &str| {
+        u32::from_str_radix(field, 16)
+            .ok()
+            .map(|value| Ipv4Addr::from(value.to_le_bytes()))
+    };
+
+    // Interface, destination, gateway, flags, refcnt, use, metric and mask
+    // of each route, after a header
+    let routes = table
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let destination = address(fields.get(1)?)?;
+            let mask = address(fields.get(7)?)?;
+            let destination =
+                Ipv4Cidr::new(destination, u32::from(mask).count_ones() as u8).ok()?;
+            Some(Route {
+                interface: fields.first()?.to_string(),
+                destination,
+                metric: fields.get(6)?.parse().ok()?,
+            })
+        })
+        .collect();
+    Ok(routes)
+}
+
+/// Bridge addresses tried in turn, in /24 private subnets
+fn bridge_candidates() -> impl Iterator<Item = Ipv4Inet> {
+    let in_192 = (10..=254).map(|third| Ipv4Addr::new(192, 168, third, 1));
+    let in_10 = (50..=254).map(|third| Ipv4Addr::new(10, 0, third, 1));
+    in_192
+        .chain(in_10)
+        .filter_map(|address| Ipv4Inet::new(address, 24).ok())
+}
+
+/// Folder of `STATE_FOLDERS` on the filesystem with the most space
+/// available, among the ones the parent of which exists
+async fn largest_folder() -> Option<String> {
+    let mut largest: Option<(u64, &str)> = None;
+    for folder in STATE_FOLDERS {
+        let parent = Path::new(folder).parent()?;
+        if !parent.exists() {
+            continue;
+        }
+        let output = Command::new("df")
+            .arg("-Pk")
+            .arg(parent)
+            .output()
+            .await
+            .ok()?;
+        // Filesystem, size, used, available, capacity and mount point, after
+        // a header
+        let available = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .nth(1)
+            .and_then(|line| line.split_whitespace().nth(3)?.parse::<u64>().ok());
+        if let Some(available) = available {
+            // Ties go to the earlier folder, the default one first
+            if largest.is_none_or(|(most, _)| available > most) {
+                largest = Some((available, folder));
+            }
+        }
+    }
+    largest.map(|(_, folder)| folder.to_string())
+}
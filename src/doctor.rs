@@ -0,0 +1,201 @@
+use std::{fmt::Display, path::Path, time::Duration};
+
+use anyhow::{anyhow, Result};
+use tokio::process::Command;
+
+use crate::{
+    config::LambdoConfig,
+    vm_manager::{boot_test, firecracker_version, image_manager::samples::sample_paths},
+};
+
+/// Address of the bridge created in the test network namespace
+const TEST_BRIDGE_ADDRESS: &str = "192.168.254.1";
+
+enum Outcome {
+    Pass,
+    Fail(String),
+    Skip(String),
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Outcome::Pass => write!(f, "PASS"),
+            Outcome::Fail(_) => write!(f, "FAIL"),
+            Outcome::Skip(_) => write!(f, "SKIP"),
+        }
+    }
+}
+
+impl From<Result<()>> for Outcome {
+    fn from(result: Result<()>) -> Self {
+        match result {
+            Ok(()) => Outcome::Pass,
+            Err(e) => Outcome::Fail(e.to_string()),
+        }
+    }
+}
+
+/// Check that this host can run VMs, printing a report.
+///
+/// Returns whether every check passed.
+pub async fn run(config: &LambdoConfig) -> bool {
+    let mut failed = false;
+    let mut report = |name: &str, outcome: Outcome| {
+        match &outcome {
+            Outcome::Pass => println!("[{}] {}", outcome, name),
+            Outcome::Fail(reason) | Outcome::Skip(reason) => {
+                println!("[{}] {}: {}", outcome, name, reason)
+            }
+        }
+        failed |= matches!(outcome, Outcome::Fail(_));
+    };
+
+    report("running as root", check_root().into());
+    report("KVM is available", check_kvm().into());
+    report(
+        "firecracker is installed",
+        firecracker_version(&config.api.executor)
+            .await
+            .map(|_| ())
+            .into(),
+    );
+    report(
+        "iproute2 is installed",
+        command_works("ip", &["-V"]).await.into(),
+    );
+    report(
+        "iptables is installed",
+        command_works("iptables", &["--version"]).await.into(),
+    );
+    report(
+        "mkfs.ext4 is installed",
+        command_works("mkfs.ext4", &["-V"]).await.into(),
+    );
+    report(
+        "bridge networking works",
+        check_bridge_networking().await.into(),
+    );
+
+    let (kernel, rootfs) = sample_paths(Path::new(&config.api.image_manager.images_folder));
+    let outcome = if kernel.exists() && rootfs.exists() {
+        boot_test(
+            &config.api.executor,
+            &kernel,
+            &rootfs,
+            Duration::from_secs(3),
+        )
+        .await
+        .map_err(|e| anyhow!("{}", e))
+        .into()
+    } else {
+        Outcome::Skip(format!(
+            "no test kernel and rootfs in {}, run `lambdo images fetch-samples`",
+            kernel.parent().unwrap_or(&kernel).display()
+        ))
+    };
+    report("a test VM boots", outcome);
+
+    println!();
+    if failed {
+        println!("Some checks failed, VMs will likely not start on this host");
+    } else {
+        println!("This host is ready to run VMs");
+    }
+
+    !failed
+}
+
+/// Whether lambdo runs as root, which it needs to set up the network
+pub fn check_root() -> Result<()> {
+    let status = std::fs::read_to_string("/proc/self/status")?;
+    let uid = status
+        .lines()
+        .find_map(|line| line.strip_prefix("Uid:"))
+        .and_then(|uids| uids.split_whitespace().nth(1))
+        .ok_or(anyhow!("cannot read the user id"))?;
+
+    if uid != "0" {
+        return Err(anyhow!(
+            "lambdo needs root to manage bridges, taps and iptables"
+        ));
+    }
+    Ok(())
+}
+
+fn check_kvm() -> Result<()> {
+    std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/kvm")
+        .map(|_| ())
+        .map_err(|e| anyhow!("cannot open /dev/kvm: {}", e))
+}
+
+async fn command_works(program: &str, args: &[&str]) -> Result<()> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .await
+        .map_err(|e| anyhow!("cannot run {}: {}", program, e))?;
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{} exited with {}: {}",
+            program,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+/// Create a bridge with a tap in a throwaway network namespace, and check
+/// that its address answers.
+async fn check_bridge_networking() -> Result<()> {
+    let namespace = format!("lambdo-doctor-{}", &uuid::Uuid::new_v4().to_string()[..8]);
+    command_works("ip", &["netns", "add", &namespace]).await?;
+
+    let result = async {
+        let steps: [&[&str]; 7] = [
+            // Local addresses are only reachable once the loopback is up
+            &["link", "set", "lo", "up"],
+            &["link", "add", "doctor0", "type", "bridge"],
+            &[
+                "addr",
+                "add",
+                &format!("{}/24", TEST_BRIDGE_ADDRESS),
+                "dev",
+                "doctor0",
+            ],
+            &["link", "set", "doctor0", "up"],
+            &["tuntap", "add", "dev", "doctor-tap", "mode", "tap"],
+            &["link", "set", "doctor-tap", "master", "doctor0"],
+            &["link", "set", "doctor-tap", "up"],
+        ];
+        for step in steps {
+            let args = [&["netns", "exec", &namespace, "ip"], step].concat();
+            command_works("ip", &args).await?;
+        }
+
+        command_works(
+            "ip",
+            &[
+                "netns",
+                "exec",
+                &namespace,
+                "ping",
+                "-c",
+                "1",
+                "-W",
+                "1",
+                TEST_BRIDGE_ADDRESS,
+            ],
+        )
+        .await
+    }
+    .await;
+
+    command_works("ip", &["netns", "delete", &namespace]).await?;
+    result
+}
@@ -1,25 +1,52 @@
 pub mod api;
 pub mod config;
+pub mod doctor;
 pub mod model;
+pub mod nats;
 pub mod vm_manager;
 
 use std::sync::Arc;
 
+use anyhow::anyhow;
 use config::{ImageManagerStrategy, LambdoConfig};
 use thiserror::Error;
-use tracing_subscriber::EnvFilter;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
 
 use crate::{
-    api::{service::LambdoApiService, simple_spawn_route, start_route, stop_route},
+    api::{
+        adopt_route, agent_route, capabilities_route, clone_volume_route, cordon_route,
+        create_share_link_route, create_snapshot_route, create_stack_route, create_volume_route,
+        delete_snapshot_route, delete_volume_route, destroy_stack_route, discovery_route,
+        error::ApiError,
+        events_route, explain_start_route, export_route, fetch_samples_route, firewall_route,
+        get_volume_route, invoke_function_route, job_route, list_snapshots_route,
+        list_stacks_route, list_vms_route, list_volumes_route, namespace_quota_route,
+        network_route, pause_route, port_owner_route, ports_route, promote_image_route,
+        readyz_route, register_function_route, resume_route, revoke_share_link_route,
+        rollback_route, run_route, selftest_route,
+        service::{LambdoApiService, LambdoApiServiceTrait},
+        set_namespace_quota_route, share_links_route, simple_spawn_route, ssh_route, stack_route,
+        start_route, stop_route, submit_job_route, support_bundle_route, tenant_usage_route,
+        uncordon_route, undo_destroy_route, update_ports_route, verify_images_route, vm_route,
+    },
     vm_manager::{
+        egress_proxy, event_sinks,
+        events::EventStore,
+        firecracker_version,
         image_manager::{
-            folder_manager::FolderImageManager, url_manager::UrlImageManager, ImageManager,
+            folder_manager::FolderImageManager, samples, url_manager::UrlImageManager, ImageManager,
         },
+        ingress_proxy,
         state::LambdoState,
+        volume_manager::{file_driver::FileStorageDriver, VolumeManager},
+        wireguard,
     },
 };
-use actix_web::{web, App, HttpServer};
-use clap::Parser;
+use actix_web::{
+    dev::Service, error::InternalError, http::StatusCode, middleware, web, App, HttpServer,
+    ResponseError,
+};
+use clap::{Parser, Subcommand};
 use tokio::sync::Mutex;
 use tracing::{debug, error, info, trace};
 
@@ -33,68 +60,414 @@ pub struct LambdoOpts {
     /// Config file path
     #[clap(short, long, default_value = "/etc/lambdo/config.yaml")]
     config: String,
+    #[clap(subcommand)]
+    command: Option<LambdoCommand>,
+}
+
+#[derive(Subcommand)]
+pub enum LambdoCommand {
+    /// Check that this host can run VMs and print a report
+    Doctor,
+    /// Manage the image store
+    Images {
+        #[clap(subcommand)]
+        command: ImagesCommand,
+    },
+    /// Manage the config file
+    Config {
+        #[clap(subcommand)]
+        command: ConfigCommand,
+    },
 }
 
+#[derive(Subcommand)]
+pub enum ConfigCommand {
+    /// Write a commented default config to the config file path
+    Init {
+        /// Pick the bridge subnet, uplink interface and data folder for this
+        /// host instead of using the defaults
+        #[clap(long)]
+        probe: bool,
+        /// Replace the config file if it exists
+        #[clap(long)]
+        force: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ImagesCommand {
+    /// Download the test kernel and rootfs into the image store
+    FetchSamples,
+}
+
+/// Why lambdo could not start, each cause exiting with its own code for
+/// supervisors to tell the failures worth retrying from the fatal ones
 #[derive(Error, Debug)]
 pub enum LambdoError {
+    #[error("invalid config: {0}")]
+    Config(anyhow::Error),
+    #[error("insufficient privileges: {0}")]
+    Privilege(anyhow::Error),
+    #[error("cannot set up the network: {0}")]
+    Bridge(anyhow::Error),
+    #[error("cannot listen on {0}: {1}")]
+    Bind(String, std::io::Error),
     #[error(transparent)]
     Other(#[from] anyhow::Error),
     #[error("unknown lambdo error")]
     Unknown,
 }
 
+impl LambdoError {
+    /// Exit code of the process, following sysexits.h
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            LambdoError::Config(_) => 78,
+            LambdoError::Privilege(_) => 77,
+            LambdoError::Bridge(_) => 71,
+            LambdoError::Bind(_, _) => 75,
+            LambdoError::Other(_) | LambdoError::Unknown => 1,
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            LambdoError::Config(_) => "config",
+            LambdoError::Privilege(_) => "privilege",
+            LambdoError::Bridge(_) => "bridge",
+            LambdoError::Bind(_, _) => "bind",
+            LambdoError::Other(_) | LambdoError::Unknown => "startup",
+        }
+    }
+
+    /// Whether starting again may work without changing the config or the
+    /// host, such as once the address is free
+    pub fn retryable(&self) -> bool {
+        matches!(self, LambdoError::Bridge(_) | LambdoError::Bind(_, _))
+    }
+
+    /// Print the error as a single JSON line on stderr and exit with its code
+    pub fn exit(self) -> ! {
+        let line = serde_json::json!({
+            "level": "fatal",
+            "kind": self.kind(),
+            "exitCode": self.exit_code(),
+            "retryable": self.retryable(),
+            "message": self.to_string(),
+        });
+        eprintln!("{}", line);
+        std::process::exit(self.exit_code());
+    }
+}
+
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
-    tracing_subscriber::fmt::fmt()
-        .with_env_filter(
+    let options = LambdoOpts::parse();
+    // Run before the config is loaded, as there is none yet
+    if let Some(LambdoCommand::Config {
+        command: ConfigCommand::Init { probe, force },
+    }) = options.command
+    {
+        init_config(&options.config, probe, force).await;
+    }
+    let config = LambdoConfig::load(options.config.as_str())
+        .unwrap_or_else(|e| LambdoError::Config(e).exit());
+
+    // The debug log is set up from the config, which is loaded first
+    let debug_layer = config.api.debug.as_ref().map(|debug| {
+        api::debug::layer(debug).unwrap_or_else(|e| {
+            LambdoError::Config(anyhow!("cannot open debug log {}: {}", debug.log_file, e)).exit()
+        })
+    });
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer().with_filter(
             EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
-        )
+        ))
+        .with(debug_layer)
         .init();
 
     info!("starting up ...");
 
-    let options = LambdoOpts::parse();
-
-    debug!("loading config file at {}", options.config);
-    let config = LambdoConfig::load(options.config.as_str()).unwrap();
+    debug!("loaded config file at {}", options.config);
     trace!(
         "config file loaded successfully with content: {:#?}",
         config
     );
 
+    match options.command {
+        Some(LambdoCommand::Doctor) => {
+            let passed = doctor::run(&config).await;
+            std::process::exit(if passed { 0 } else { 1 });
+        }
+        Some(LambdoCommand::Images {
+            command: ImagesCommand::FetchSamples,
+        }) => match samples::fetch_samples(&config.api.image_manager).await {
+            Ok(images) => {
+                for image in images {
+                    println!("{} -> {}", image.id, image.path.display());
+                }
+                std::process::exit(0);
+            }
+            Err(e) => {
+                error!("failed to fetch sample images: {}", e);
+                std::process::exit(1);
+            }
+        },
+        Some(LambdoCommand::Config { .. }) | None => {}
+    }
+
+    match firecracker_version(&config.api.executor).await {
+        Ok(version) => info!(
+            "using firecracker {} at {}",
+            version, config.api.executor.firecracker_path
+        ),
+        Err(e) => LambdoError::Config(anyhow!("invalid executor config: {}", e)).exit(),
+    }
+    if let Err(e) = doctor::check_root() {
+        LambdoError::Privilege(e).exit();
+    }
+
     info!("setting up");
-    let lambdo_state = Arc::new(Mutex::new(LambdoState::new(config.clone())));
+    let events = EventStore::new(config.api.events.clone()).unwrap_or_else(|e| {
+        LambdoError::Other(anyhow!("cannot set up the event store: {}", e)).exit()
+    });
+    event_sinks::start(&events, &config.api.events.sinks);
+    let events = Arc::new(events);
+    let lambdo_state = Arc::new(Mutex::new(LambdoState::new(config.clone(), events.clone())));
 
     let image_manager: Box<dyn ImageManager> = match config.api.image_manager.strategy {
         ImageManagerStrategy::Folder => Box::new(FolderImageManager::new(
-            config.api.image_manager.images_folder,
+            config.api.image_manager.images_folder.clone(),
         )),
-        ImageManagerStrategy::Url => {
-            Box::new(UrlImageManager::new(config.api.image_manager.images_folder))
-        }
+        ImageManagerStrategy::Url => Box::new(
+            UrlImageManager::new(config.api.image_manager.images_folder.clone())
+                .with_events(events)
+                .with_eviction(
+                    config.api.image_manager.max_cache_bytes,
+                    lambdo_state.clone(),
+                ),
+        ),
     };
 
-    let api_service = LambdoApiService::new_with_state(lambdo_state, image_manager)
+    let volumes_folder = config.api.volume_manager.volumes_folder.clone();
+    let volume_manager = VolumeManager::new(
+        volumes_folder.clone(),
+        Box::new(FileStorageDriver::new(volumes_folder.into())),
+    )
+    .await
+    .unwrap_or_else(|e| {
+        LambdoError::Other(anyhow!("cannot set up the volume manager: {}", e)).exit()
+    });
+
+    let api_service =
+        LambdoApiService::new_with_state(lambdo_state.clone(), image_manager, volume_manager)
+            .await
+            .unwrap_or_else(|e| match e {
+                vm_manager::Error::NetSetupError(e) => LambdoError::Bridge(e).exit(),
+                e => LambdoError::Other(anyhow!("cannot set up the API service: {}", e)).exit(),
+            });
+
+    if let Err(e) = api_service.start_warm_pools().await {
+        LambdoError::Other(anyhow!("cannot set up the warm pools: {}", e)).exit();
+    }
+
+    if let Some(wireguard) = config
+        .api
+        .wireguard
+        .as_ref()
+        .filter(|_| config.feature_enabled("overlayNetworking"))
+    {
+        wireguard::setup(
+            wireguard,
+            &config.api.bridges(),
+            config.api.network.firewall,
+        )
         .await
-        .map_err(|e| {
-            error!("failed to set up API service: {}", e);
-        })
-        .unwrap();
+        .unwrap_or_else(|e| LambdoError::Bridge(anyhow!("wireguard: {}", e)).exit());
+    }
+
+    if config.api.egress_proxy.enabled {
+        let lambdo_state = lambdo_state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = egress_proxy::run(lambdo_state).await {
+                error!("egress proxy stopped: {}", e);
+            }
+        });
+    }
+
+    if config.api.ingress_proxy.enabled {
+        tokio::spawn(async move {
+            if let Err(e) = ingress_proxy::run(lambdo_state).await {
+                error!("ingress proxy stopped: {}", e);
+            }
+        });
+    }
 
     info!("everything is set up, starting servers");
 
     let http_host = &config.api.network.web_host;
     let http_port = config.api.network.web_port;
+    let compression = config.api.network.compression;
+    let tokens = match config.api.auth.as_ref().map(api::auth::Tokens::load) {
+        Some(Ok(tokens)) => Some(Arc::new(tokens)),
+        Some(Err(e)) => LambdoError::Config(anyhow!("invalid auth config: {}", e)).exit(),
+        None => None,
+    };
+    let tls = match config.api.network.tls.as_ref().map(api::tls::server_config) {
+        Some(Ok(tls)) => Some(tls),
+        Some(Err(e)) => LambdoError::Config(anyhow!("invalid TLS config: {}", e)).exit(),
+        None => None,
+    };
     let app_state = web::Data::new(api_service);
-    info!("Starting web server on {}:{}", http_host, http_port);
-    HttpServer::new(move || {
+    if let Some(queue) = &config.api.queue {
+        api::queue::start(app_state.clone().into_inner(), queue);
+    }
+    if let Some(interval) = config
+        .api
+        .image_manager
+        .audit
+        .interval
+        .filter(|interval| *interval > 0)
+    {
+        tokio::spawn(api::service::audit_images(
+            app_state.clone().into_inner(),
+            std::time::Duration::from_secs(interval),
+        ));
+    }
+    tokio::spawn(api::service::scale_functions_to_zero(
+        app_state.clone().into_inner(),
+    ));
+    tokio::spawn(api::service::run_jobs(app_state.clone().into_inner()));
+    if config.api.grpc.enabled {
+        let grpc = config.api.grpc.clone();
+        let service = app_state.clone().into_inner();
+        let tokens = tokens.clone();
+        tokio::spawn(async move {
+            if let Err(e) = api::grpc::serve(&grpc, service, tokens).await {
+                error!("gRPC server stopped: {}", e);
+            }
+        });
+    }
+    let service = app_state.clone();
+    info!(
+        "Starting web server on {}://{}:{}",
+        if tls.is_some() { "https" } else { "http" },
+        http_host,
+        http_port
+    );
+    // Stops accepting requests on SIGTERM and SIGINT, and returns once the
+    // ones in flight are done
+    let server = HttpServer::new(move || {
+        let tokens = tokens.clone();
         App::new()
+            .wrap(middleware::Condition::new(
+                compression,
+                middleware::Compress::default(),
+            ))
+            // Outermost, for unauthenticated requests not to reach anything
+            .wrap_fn(move |request, service| {
+                let authenticated = match &tokens {
+                    Some(tokens) => api::auth::authenticate(tokens, &request),
+                    None => Ok(()),
+                };
+                let response = authenticated.map(|_| service.call(request));
+                async move { response?.await }
+            })
             .app_data(app_state.clone())
+            // Bodies that do not parse are answered like any other error
+            .app_data(web::JsonConfig::default().error_handler(|e, _| {
+                let error = ApiError::new(StatusCode::BAD_REQUEST, "invalid_body", e.to_string());
+                InternalError::from_response(e, error.error_response()).into()
+            }))
             .service(start_route)
+            .service(explain_start_route)
             .service(simple_spawn_route)
+            .service(run_route)
+            .service(submit_job_route)
+            .service(job_route)
+            .service(register_function_route)
+            .service(invoke_function_route)
             .service(stop_route)
-    })
-    .bind((http_host.clone(), http_port))?
-    .run()
-    .await
+            .service(undo_destroy_route)
+            .service(events_route)
+            .service(capabilities_route)
+            .service(list_vms_route)
+            .service(vm_route)
+            .service(tenant_usage_route)
+            .service(namespace_quota_route)
+            .service(set_namespace_quota_route)
+            .service(pause_route)
+            .service(resume_route)
+            .service(fetch_samples_route)
+            .service(promote_image_route)
+            .service(support_bundle_route)
+            .service(verify_images_route)
+            .service(cordon_route)
+            .service(uncordon_route)
+            .service(readyz_route)
+            .service(selftest_route)
+            .service(adopt_route)
+            .service(firewall_route)
+            .service(network_route)
+            .service(ports_route)
+            .service(ssh_route)
+            .service(create_share_link_route)
+            .service(share_links_route)
+            .service(revoke_share_link_route)
+            .service(create_stack_route)
+            .service(list_stacks_route)
+            .service(stack_route)
+            .service(destroy_stack_route)
+            .service(discovery_route)
+            .service(rollback_route)
+            .service(agent_route)
+            .service(update_ports_route)
+            .service(port_owner_route)
+            .service(export_route)
+            .service(list_volumes_route)
+            .service(create_volume_route)
+            .service(get_volume_route)
+            .service(delete_volume_route)
+            .service(list_snapshots_route)
+            .service(create_snapshot_route)
+            .service(delete_snapshot_route)
+            .service(clone_volume_route)
+    });
+    let bound = match tls {
+        Some(tls) => server.bind_rustls_0_22((http_host.clone(), http_port), tls),
+        None => server.bind((http_host.clone(), http_port)),
+    };
+    let server = bound
+        .unwrap_or_else(|e| LambdoError::Bind(format!("{}:{}", http_host, http_port), e).exit());
+    let result = server.run().await;
+
+    info!("web server stopped, shutting down");
+    service.shutdown().await;
+    result
+}
+
+/// Write a default config to `path`, exiting with whether it worked
+async fn init_config(path: &str, probe: bool, force: bool) -> ! {
+    let probed = if probe {
+        config::init::probe().await
+    } else {
+        config::init::Probed::default()
+    };
+    let written = config::init::render(&probed)
+        .and_then(|content| config::init::write(path, &content, force));
+    match written {
+        Ok(()) => {
+            println!("wrote {}", path);
+            println!("  bridge address: {}", probed.bridge_address);
+            if let Some(uplink) = &probed.uplink {
+                println!("  uplink interface: {}", uplink);
+            }
+            println!("  data folder: {}", probed.state_folder);
+            std::process::exit(0);
+        }
+        Err(e) => {
+            eprintln!("cannot write the config to {}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
 }
@@ -0,0 +1,189 @@
+use anyhow::{anyhow, Error};
+use serde_json::json;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+use tracing::{debug, trace};
+
+use crate::config::NatsServerConfig;
+
+/// A message delivered on a subscription
+#[derive(Debug)]
+pub struct NatsMessage {
+    pub subject: String,
+    pub reply: Option<String>,
+    /// Raw headers, starting with the `NATS/1.0` status line
+    pub headers: Option<String>,
+    pub payload: Vec<u8>,
+}
+
+impl NatsMessage {
+    /// Status code of the message, for the control messages sent by
+    /// JetStream such as `404 No Messages`
+    pub fn status(&self) -> Option<u16> {
+        self.headers
+            .as_deref()?
+            .lines()
+            .next()?
+            .split_whitespace()
+            .nth(1)?
+            .parse()
+            .ok()
+    }
+}
+
+/// Minimal client for the core NATS text protocol, over plain TCP.
+pub struct NatsConnection {
+    stream: BufReader<TcpStream>,
+}
+
+impl NatsConnection {
+    pub async fn connect(server: &NatsServerConfig) -> Result<Self, Error> {
+        let address = server
+            .url
+            .trim_start_matches("nats://")
+            .trim_end_matches('/');
+        debug!("connecting to NATS server {}", address);
+        let mut connection = NatsConnection {
+            stream: BufReader::new(TcpStream::connect(address).await?),
+        };
+
+        // The server greets clients with its INFO
+        let info = connection.read_line().await?;
+        if !info.starts_with("INFO") {
+            return Err(anyhow!("unexpected greeting from NATS server: {}", info));
+        }
+
+        let options = json!({
+            "verbose": false,
+            "pedantic": false,
+            "headers": true,
+            "no_responders": true,
+            "name": "lambdo",
+            "lang": "rust",
+            "version": env!("CARGO_PKG_VERSION"),
+            "auth_token": server.token,
+            "user": server.user,
+            "pass": server.password,
+        });
+        connection
+            .write(format!("CONNECT {}\r\n", options).as_bytes())
+            .await?;
+
+        Ok(connection)
+    }
+
+    /// Publish `payload` on `subject`, asking for replies on `reply`.
+    pub async fn publish(
+        &mut self,
+        subject: &str,
+        reply: Option<&str>,
+        payload: &[u8],
+    ) -> Result<(), Error> {
+        let mut buffer = match reply {
+            Some(reply) => format!("PUB {} {} {}\r\n", subject, reply, payload.len()),
+            None => format!("PUB {} {}\r\n", subject, payload.len()),
+        }
+        .into_bytes();
+        buffer.extend(payload);
+        buffer.extend(b"\r\n");
+        self.write(&buffer).await
+    }
+
+    pub async fn subscribe(&mut self, subject: &str, sid: u64) -> Result<(), Error> {
+        self.write(format!("SUB {} {}\r\n", subject, sid).as_bytes())
+            .await
+    }
+
+    /// Wait until the server has processed everything sent so far.
+    ///
+    /// Messages received meanwhile are dropped, so this is only meant for
+    /// connections without subscriptions.
+    pub async fn flush(&mut self) -> Result<(), Error> {
+        self.write(b"PING\r\n").await?;
+        loop {
+            let line = self.read_line().await?;
+            match line.split_whitespace().next() {
+                Some("PONG") => return Ok(()),
+                Some("PING") => self.write(b"PONG\r\n").await?,
+                Some("-ERR") => return Err(anyhow!("NATS server error: {}", line)),
+                _ => trace!("ignoring NATS line {}", line),
+            }
+        }
+    }
+
+    /// Wait for the next message on one of the subscriptions.
+    pub async fn next_message(&mut self) -> Result<NatsMessage, Error> {
+        loop {
+            let line = self.read_line().await?;
+            let mut parts = line.split_whitespace();
+            match parts.next() {
+                Some("MSG") => {
+                    // MSG <subject> <sid> [reply] <size>
+                    let args: Vec<&str> = parts.collect();
+                    let (subject, reply, size) = match args.as_slice() {
+                        [subject, _, size] => (subject, None, size),
+                        [subject, _, reply, size] => (subject, Some(reply), size),
+                        _ => return Err(anyhow!("invalid NATS message: {}", line)),
+                    };
+                    let payload = self.read_payload(size.parse()?).await?;
+                    return Ok(NatsMessage {
+                        subject: subject.to_string(),
+                        reply: reply.map(|r| r.to_string()),
+                        headers: None,
+                        payload,
+                    });
+                }
+                Some("HMSG") => {
+                    // HMSG <subject> <sid> [reply] <header size> <total size>
+                    let args: Vec<&str> = parts.collect();
+                    let (subject, reply, header_size, size) = match args.as_slice() {
+                        [subject, _, header_size, size] => (subject, None, header_size, size),
+                        [subject, _, reply, header_size, size] => {
+                            (subject, Some(reply), header_size, size)
+                        }
+                        _ => return Err(anyhow!("invalid NATS message: {}", line)),
+                    };
+                    let header_size: usize = header_size.parse()?;
+                    let mut payload = self.read_payload(size.parse()?).await?;
+                    if header_size > payload.len() {
+                        return Err(anyhow!("invalid NATS message: {}", line));
+                    }
+                    let headers = String::from_utf8_lossy(&payload[..header_size]).to_string();
+                    payload.drain(..header_size);
+                    return Ok(NatsMessage {
+                        subject: subject.to_string(),
+                        reply: reply.map(|r| r.to_string()),
+                        headers: Some(headers),
+                        payload,
+                    });
+                }
+                Some("PING") => self.write(b"PONG\r\n").await?,
+                Some("-ERR") => return Err(anyhow!("NATS server error: {}", line)),
+                _ => trace!("ignoring NATS line {}", line),
+            }
+        }
+    }
+
+    async fn write(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.stream.get_mut().write_all(bytes).await?;
+        Ok(())
+    }
+
+    async fn read_line(&mut self) -> Result<String, Error> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line).await? == 0 {
+            return Err(anyhow!("NATS server closed the connection"));
+        }
+        Ok(line.trim_end().to_string())
+    }
+
+    /// Read a payload of `size` bytes and its trailing CRLF.
+    async fn read_payload(&mut self, size: usize) -> Result<Vec<u8>, Error> {
+        let mut payload = vec![0; size + 2];
+        self.stream.read_exact(&mut payload).await?;
+        payload.truncate(size);
+        Ok(payload)
+    }
+}
@@ -0,0 +1,168 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::UnixStream,
+};
+use tracing::debug;
+
+use crate::config::AgentConfig;
+
+pub mod protocol;
+
+pub use protocol::{AgentRequest, AgentResponse};
+
+/// Socket of the vsock device of a VM, in the folder firecracker sees as `/`
+pub const VSOCK_SOCKET: &str = "vsock.sock";
+/// Context id of the guests, which is local to the vsock device of each VM
+pub const GUEST_CID: i32 = 3;
+
+/// Send `request` to the agent of the guest whose vsock device is at
+/// `socket`, and wait for its reply.
+pub async fn call(
+    config: &AgentConfig,
+    socket: &Path,
+    request: &AgentRequest,
+) -> Result<AgentResponse> {
+    // Commands are given their own time to run, on top of the exchange
+    let timeout = match request {
+        AgentRequest::Exec {
+            timeout_seconds: Some(seconds),
+            ..
+        } => Duration::from_secs(config.timeout + seconds),
+        _ => Duration::from_secs(config.timeout),
+    };
+
+    tokio::time::timeout(timeout, exchange(config.port, socket, request))
+        .await
+        .map_err(|_| anyhow!("no reply from the guest agent in {:?}", timeout))?
+}
+
+async fn exchange(port: u32, socket: &Path, request: &AgentRequest) -> Result<AgentResponse> {
+    let mut stream = connect(port, socket).await?;
+
+    let mut line = serde_json::to_vec(request)?;
+    line.push(b'\n');
+    stream.get_mut().write_all(&line).await?;
+
+    let mut reply = String::new();
+    if stream.read_line(&mut reply).await? == 0 {
+        return Err(anyhow!("the guest agent closed the connection"));
+    }
+    serde_json::from_str(&reply).map_err(|e| anyhow!("invalid reply from the guest agent: {}", e))
+}
+
+/// Command run in a guest, as recorded in the transcript of its VM
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Session<'a> {
+    pub vm_id: &'a str,
+    pub command: &'a [String],
+    /// Names of the variables the command was given, not their values which
+    /// may be secrets
+    pub env: Vec<&'a String>,
+    /// In ms since epoch
+    pub started_at: u64,
+    /// In ms since epoch
+    pub ended_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stdout: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stderr: Option<&'a str>,
+    /// Why the command did not run to completion
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl<'a> Session<'a> {
+    /// Session of `request` sent to the agent of `vm_id` at `started_at`,
+    /// if it ran a command, ended with `result` at `ended_at`
+    pub fn of(
+        vm_id: &'a str,
+        request: &'a AgentRequest,
+        result: &'a Result<AgentResponse>,
+        started_at: u64,
+        ended_at: u64,
+    ) -> Option<Self> {
+        let AgentRequest::Exec { command, env, .. } = request else {
+            return None;
+        };
+        let mut env: Vec<_> = env.keys().collect();
+        env.sort();
+
+        let mut session = Session {
+            vm_id,
+            command,
+            env,
+            started_at,
+            ended_at,
+            exit_code: None,
+            stdout: None,
+            stderr: None,
+            error: None,
+        };
+        match result {
+            Ok(AgentResponse::Exec {
+                exit_code,
+                stdout,
+                stderr,
+            }) => {
+                session.exit_code = Some(*exit_code);
+                session.stdout = Some(stdout);
+                session.stderr = Some(stderr);
+            }
+            Ok(AgentResponse::Error { message }) => session.error = Some(message.clone()),
+            Ok(response) => session.error = Some(format!("unexpected reply {:?}", response)),
+            Err(e) => session.error = Some(e.to_string()),
+        }
+        Some(session)
+    }
+
+    /// Append the session to the transcript of its VM in `folder`,
+    /// returning the path of the transcript.
+    pub fn record(&self, folder: &str) -> Result<PathBuf> {
+        std::fs::create_dir_all(folder)?;
+        let path = Path::new(folder).join(format!("{}.jsonl", self.vm_id));
+
+        let mut line = serde_json::to_vec(self)?;
+        line.push(b'\n');
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)?;
+        std::io::Write::write_all(&mut file, &line)?;
+        Ok(path)
+    }
+}
+
+/// Open a connection to `port` of the guest, through the socket firecracker
+/// multiplexes the vsock connections initiated by the host on.
+async fn connect(port: u32, socket: &Path) -> Result<BufReader<UnixStream>> {
+    let stream = UnixStream::connect(socket)
+        .await
+        .map_err(|e| anyhow!("cannot reach the vsock device: {}", e))?;
+    let mut stream = BufReader::new(stream);
+
+    stream
+        .get_mut()
+        .write_all(format!("CONNECT {}\n", port).as_bytes())
+        .await?;
+    let mut ack = String::new();
+    stream.read_line(&mut ack).await?;
+    if !ack.starts_with("OK ") {
+        return Err(anyhow!(
+            "the guest agent does not listen on vsock port {}",
+            port
+        ));
+    }
+    debug!("connected to guest agent through {}", socket.display());
+
+    Ok(stream)
+}
@@ -0,0 +1,61 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+/// Request sent to the guest agent, as a line of JSON
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(
+    tag = "type",
+    rename_all = "snake_case",
+    rename_all_fields = "camelCase"
+)]
+pub enum AgentRequest {
+    /// Check that the agent is up
+    Health,
+    /// Run a command in the guest and wait for it to exit
+    Exec {
+        command: Vec<String>,
+        /// Environment of the command, on top of the one of the agent
+        #[serde(default)]
+        env: HashMap<String, String>,
+        /// Seconds the command may run, the default timeout of the agent
+        /// channel if unset
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timeout_seconds: Option<u64>,
+    },
+    /// Add variables to the environment of the guest, for the commands and
+    /// services started from now on
+    SetEnv { env: HashMap<String, String> },
+    /// Hand over the content of a file of the guest
+    ReadFile { path: String },
+}
+
+/// Reply of the guest agent, as a line of JSON
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(
+    tag = "type",
+    rename_all = "snake_case",
+    rename_all_fields = "camelCase"
+)]
+pub enum AgentResponse {
+    Health {
+        /// Version of the agent
+        version: String,
+        /// Seconds since the guest booted
+        uptime_seconds: u64,
+    },
+    Exec {
+        exit_code: i32,
+        stdout: String,
+        stderr: String,
+    },
+    EnvSet,
+    File {
+        /// Content of the file, in base64
+        content: String,
+    },
+    /// The request could not be handled
+    Error {
+        message: String,
+    },
+}
@@ -0,0 +1,112 @@
+use std::{
+    fmt::Display,
+    net::{Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    process::Stdio,
+    time::Duration,
+};
+
+use serde::Serialize;
+use tokio::{
+    net::{TcpStream, UnixStream},
+    process::Command,
+    time::Instant,
+};
+use tracing::{debug, trace};
+
+/// Delay between two probes of a booting VM
+const PROBE_INTERVAL: Duration = Duration::from_millis(250);
+/// Time a single probe may take
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Why a VM did not become reachable before its boot deadline
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BootFailure {
+    /// The firecracker process exited, usually after a guest kernel panic
+    VmmExited,
+    /// The guest does not answer on the network at all
+    GuestUnreachable,
+    /// The guest answers pings, but nothing listens on the probed port
+    PortClosed,
+}
+
+impl Display for BootFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BootFailure::VmmExited => write!(f, "the VMM exited during boot"),
+            BootFailure::GuestUnreachable => write!(f, "the guest is unreachable"),
+            BootFailure::PortClosed => write!(f, "the guest does not listen on its port"),
+        }
+    }
+}
+
+/// What to probe to know that a VM booted
+#[derive(Debug, Clone)]
+pub struct BootTarget {
+    pub ip: Ipv4Addr,
+    /// Guest port expected to accept connections, the guest only having to
+    /// answer pings if unset
+    pub guest_port: Option<u16>,
+    /// API socket of the firecracker process
+    pub socket: PathBuf,
+}
+
+/// Probe `target` until it is reachable, failing once `timeout` elapsed or
+/// as soon as its firecracker process exits.
+pub async fn wait_until_reachable(
+    target: &BootTarget,
+    timeout: Duration,
+) -> Result<(), BootFailure> {
+    let deadline = Instant::now() + timeout;
+    debug!(
+        "waiting up to {:?} for {} to be reachable",
+        timeout, target.ip
+    );
+
+    loop {
+        if UnixStream::connect(&target.socket).await.is_err() {
+            return Err(BootFailure::VmmExited);
+        }
+
+        let reachable = match target.guest_port {
+            Some(port) => connects(target.ip, port).await,
+            None => pings(target.ip).await,
+        };
+        if reachable {
+            debug!("{} is reachable", target.ip);
+            return Ok(());
+        }
+
+        if Instant::now() + PROBE_INTERVAL >= deadline {
+            break;
+        }
+        trace!("{} is not reachable yet", target.ip);
+        tokio::time::sleep(PROBE_INTERVAL).await;
+    }
+
+    if target.guest_port.is_some() && pings(target.ip).await {
+        Err(BootFailure::PortClosed)
+    } else {
+        Err(BootFailure::GuestUnreachable)
+    }
+}
+
+async fn connects(ip: Ipv4Addr, port: u16) -> bool {
+    let connect = TcpStream::connect(SocketAddr::from((ip, port)));
+    matches!(
+        tokio::time::timeout(PROBE_TIMEOUT, connect).await,
+        Ok(Ok(_))
+    )
+}
+
+async fn pings(ip: Ipv4Addr) -> bool {
+    let timeout = PROBE_TIMEOUT.as_secs().max(1).to_string();
+    Command::new("ping")
+        .args(["-c", "1", "-W", &timeout, &ip.to_string()])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .await
+        .is_ok_and(|status| status.success())
+}
@@ -0,0 +1,315 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use tracing::{debug, error, info, warn};
+
+use super::{
+    state::{LambdoState, LambdoStateRef},
+    Error,
+};
+use crate::config::{BudgetPeriod, CpuAccountingConfig, CpuBudgetConfig};
+
+/// Length of the scheduling period used for the throttling quota
+const CPU_PERIOD_USEC: u64 = 100_000;
+
+/// CPU time used by a tenant during the current budget period
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TenantCpuUsage {
+    /// Start of the period, in ms since epoch
+    pub period_start: u64,
+    /// CPU time used since the start of the period, in microseconds
+    pub used_usec: u64,
+    /// Last value read from the cgroup counter, which only grows
+    pub last_reading: u64,
+    /// Whether the VMs of the tenant are throttled for going over the soft
+    /// limit
+    pub throttled: bool,
+    /// Whether the tenant went over its hard limit
+    pub exhausted: bool,
+}
+
+/// CPU usage of a tenant, as returned by the API
+#[derive(Debug, Clone, Serialize)]
+pub struct TenantUsage {
+    pub tenant: String,
+    pub period: BudgetPeriod,
+    /// Start of the period, in ms since epoch
+    pub period_start: u64,
+    pub cpu_seconds: f64,
+    pub soft_limit: Option<u64>,
+    pub hard_limit: Option<u64>,
+    pub throttled: bool,
+    pub exhausted: bool,
+}
+
+impl TenantUsage {
+    pub fn new(tenant: &str, budget: Option<&CpuBudgetConfig>, usage: &TenantCpuUsage) -> Self {
+        let period = budget.map(|budget| budget.period).unwrap_or_default();
+        let current = period_start(period, OffsetDateTime::now_utc());
+        // Nothing was used yet if the period started after the last reading
+        let (used_usec, throttled, exhausted) = if usage.period_start == current {
+            (usage.used_usec, usage.throttled, usage.exhausted)
+        } else {
+            (0, false, false)
+        };
+
+        TenantUsage {
+            tenant: tenant.to_string(),
+            period,
+            period_start: current,
+            cpu_seconds: used_usec as f64 / 1_000_000.0,
+            soft_limit: budget.and_then(|budget| budget.soft_limit),
+            hard_limit: budget.and_then(|budget| budget.hard_limit),
+            throttled,
+            exhausted,
+        }
+    }
+}
+
+/// Move the firecracker process listening on `socket` to the cgroup of
+/// `tenant`.
+pub fn attach(config: &CpuAccountingConfig, tenant: &str, socket: &Path) -> Result<()> {
+    let root = Path::new(&config.cgroup);
+    if !root.exists() {
+        std::fs::create_dir_all(root)
+            .map_err(|e| anyhow!("cannot create cgroup {}: {}", root.display(), e))?;
+    }
+    // The tenant cgroups can only be throttled with the cpu controller
+    // enabled all the way down
+    for cgroup in [root.parent(), Some(root)].into_iter().flatten() {
+        let _ = std::fs::write(cgroup.join("cgroup.subtree_control"), "+cpu");
+    }
+
+    let cgroup = tenant_cgroup(config, tenant);
+    std::fs::create_dir_all(&cgroup)
+        .map_err(|e| anyhow!("cannot create cgroup {}: {}", cgroup.display(), e))?;
+
+    let pid = firecracker_pid(socket)?;
+    debug!("moving firecracker process {} to {}", pid, cgroup.display());
+    std::fs::write(cgroup.join("cgroup.procs"), pid.to_string())
+        .map_err(|e| anyhow!("cannot move process {} to {}: {}", pid, cgroup.display(), e))
+}
+
+/// Refuse to start VMs for a tenant over its hard limit.
+pub fn check_budget(state: &LambdoState, tenant: &str) -> Result<(), Error> {
+    if !state.config.api.cpu_accounting.enabled {
+        return Ok(());
+    }
+    let Some(budget) = state
+        .config
+        .api
+        .tenants
+        .get(tenant)
+        .and_then(|tenant| tenant.cpu_budget.as_ref())
+    else {
+        return Ok(());
+    };
+
+    let usage = state.cpu_usage.get(tenant).cloned().unwrap_or_default();
+    let usage = TenantUsage::new(tenant, Some(budget), &usage);
+    match budget.hard_limit {
+        Some(limit) if usage.cpu_seconds >= limit as f64 => Err(Error::BudgetExceeded(format!(
+            "tenant {} used {:.0} of its {} vCPU-seconds for this period",
+            tenant, usage.cpu_seconds, limit
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Read the CPU time of the tenants every configured interval, throttling
+/// the ones over their soft limit.
+pub async fn run(state: LambdoStateRef) {
+    let (config, usage_file) = {
+        let mut state = state.lock().await;
+        let config = state.config.api.cpu_accounting.clone();
+        match load(Path::new(&config.usage_file)) {
+            Ok(usage) => state.cpu_usage = usage,
+            Err(e) => error!("Error while loading CPU usage: {:?}", e),
+        }
+        let usage_file = PathBuf::from(&config.usage_file);
+        (config, usage_file)
+    };
+    info!(
+        "accounting for the CPU time of tenants in {}",
+        config.cgroup
+    );
+
+    let mut ticker = tokio::time::interval(Duration::from_secs(config.interval.max(1)));
+    loop {
+        ticker.tick().await;
+
+        let mut state = state.lock().await;
+        update(&mut state, &config);
+        if let Err(e) = save(&usage_file, &state.cpu_usage) {
+            error!("Error while saving CPU usage: {:?}", e);
+        }
+    }
+}
+
+fn update(state: &mut LambdoState, config: &CpuAccountingConfig) {
+    let now = OffsetDateTime::now_utc();
+    let tenants: Vec<(String, Option<CpuBudgetConfig>)> = state
+        .config
+        .api
+        .tenants
+        .iter()
+        .map(|(name, tenant)| (name.clone(), tenant.cpu_budget.clone()))
+        .collect();
+
+    for (tenant, budget) in tenants {
+        let cgroup = tenant_cgroup(config, &tenant);
+        if !cgroup.exists() {
+            continue;
+        }
+        let reading = match read_usage(&cgroup) {
+            Ok(reading) => reading,
+            Err(e) => {
+                error!("Error while reading CPU time of tenant {}: {:?}", tenant, e);
+                continue;
+            }
+        };
+
+        let usage = state.cpu_usage.entry(tenant.clone()).or_default();
+        // The counter starts over when the cgroup is recreated
+        let delta = reading.checked_sub(usage.last_reading).unwrap_or(reading);
+        usage.last_reading = reading;
+
+        let start = period_start(budget.as_ref().map(|b| b.period).unwrap_or_default(), now);
+        if usage.period_start != start {
+            debug!("new CPU budget period for tenant {}", tenant);
+            usage.period_start = start;
+            usage.used_usec = 0;
+            usage.exhausted = false;
+            if usage.throttled {
+                match set_cpu_max(&cgroup, None) {
+                    Ok(()) => usage.throttled = false,
+                    Err(e) => error!("Error while unthrottling tenant {}: {:?}", tenant, e),
+                }
+            }
+        }
+        usage.used_usec += delta;
+
+        let Some(budget) = budget else {
+            continue;
+        };
+        let used = usage.used_usec / 1_000_000;
+
+        if budget.soft_limit.is_some_and(|limit| used >= limit) && !usage.throttled {
+            warn!(
+                "tenant {} used {} vCPU-seconds, throttling it to {} CPUs",
+                tenant, used, budget.throttle
+            );
+            match set_cpu_max(&cgroup, Some(budget.throttle)) {
+                Ok(()) => usage.throttled = true,
+                Err(e) => error!("Error while throttling tenant {}: {:?}", tenant, e),
+            }
+            state.events.record(
+                "tenant.cpu_budget_exceeded",
+                None,
+                serde_json::json!({ "tenant": tenant, "limit": "soft", "cpu_seconds": used }),
+            );
+        }
+
+        if budget.hard_limit.is_some_and(|limit| used >= limit) && !usage.exhausted {
+            warn!(
+                "tenant {} used {} vCPU-seconds, rejecting its new VMs",
+                tenant, used
+            );
+            usage.exhausted = true;
+            state.events.record(
+                "tenant.cpu_budget_exceeded",
+                None,
+                serde_json::json!({ "tenant": tenant, "limit": "hard", "cpu_seconds": used }),
+            );
+        }
+    }
+}
+
+fn tenant_cgroup(config: &CpuAccountingConfig, tenant: &str) -> PathBuf {
+    Path::new(&config.cgroup).join(tenant)
+}
+
+/// CPU time used by the processes of `cgroup` since its creation, in
+/// microseconds.
+fn read_usage(cgroup: &Path) -> Result<u64> {
+    let stat = std::fs::read_to_string(cgroup.join("cpu.stat"))?;
+    stat.lines()
+        .find_map(|line| line.strip_prefix("usage_usec "))
+        .ok_or(anyhow!("no usage_usec in {}", cgroup.display()))?
+        .trim()
+        .parse()
+        .map_err(|e| anyhow!("invalid usage_usec in {}: {}", cgroup.display(), e))
+}
+
+/// Limit the processes of `cgroup` to `cpus` CPUs, or lift the limit.
+fn set_cpu_max(cgroup: &Path, cpus: Option<f64>) -> Result<()> {
+    let quota = match cpus {
+        Some(cpus) => ((cpus * CPU_PERIOD_USEC as f64) as u64)
+            .max(1000)
+            .to_string(),
+        None => "max".to_string(),
+    };
+    std::fs::write(
+        cgroup.join("cpu.max"),
+        format!("{} {}", quota, CPU_PERIOD_USEC),
+    )
+    .map_err(|e| anyhow!("cannot set cpu.max of {}: {}", cgroup.display(), e))
+}
+
+/// Process started with `--api-sock <socket>`, found from the command lines
+/// in /proc.
+fn firecracker_pid(socket: &Path) -> Result<u32> {
+    let socket = socket.to_string_lossy();
+    for entry in std::fs::read_dir("/proc")?.flatten() {
+        let Some(pid) = entry.file_name().to_str().and_then(|s| s.parse().ok()) else {
+            continue;
+        };
+        let Ok(cmdline) = std::fs::read(entry.path().join("cmdline")) else {
+            continue;
+        };
+        let args: Vec<&[u8]> = cmdline.split(|b| *b == 0).collect();
+        if args
+            .windows(2)
+            .any(|pair| pair[0] == b"--api-sock" && pair[1] == socket.as_bytes())
+        {
+            return Ok(pid);
+        }
+    }
+    Err(anyhow!("no firecracker process listens on {}", socket))
+}
+
+/// Start of the period containing `now`, in ms since epoch.
+fn period_start(period: BudgetPeriod, now: OffsetDateTime) -> u64 {
+    let date = match period {
+        BudgetPeriod::Daily => now.date(),
+        BudgetPeriod::Monthly => now.date().replace_day(1).unwrap_or(now.date()),
+    };
+    let start = date.midnight().assume_utc();
+    (start.unix_timestamp() as u64) * 1000
+}
+
+fn load(path: &Path) -> Result<HashMap<String, TenantCpuUsage>> {
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+    let content = std::fs::read(path)?;
+    serde_json::from_slice(&content)
+        .map_err(|e| anyhow!("cannot parse CPU usage file {}: {}", path.display(), e))
+}
+
+fn save(path: &Path, usage: &HashMap<String, TenantCpuUsage>) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, serde_json::to_vec_pretty(usage)?)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
@@ -0,0 +1,234 @@
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::{anyhow, Result};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tracing::{debug, info, trace};
+
+use super::state::LambdoStateRef;
+
+/// Maximum size of a request head
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+/// Run the egress proxy on the address of every bridge until a listener
+/// fails.
+///
+/// VMs started with an egress profile have their direct connections dropped
+/// and can only reach the domains allowed by their profile through this
+/// proxy, either with `CONNECT` or with absolute-form HTTP requests. Every
+/// request is recorded as an `egress.request` event.
+pub async fn run(state: LambdoStateRef) -> Result<()> {
+    let addresses = {
+        let state = state.lock().await;
+        state
+            .config
+            .api
+            .bridges()
+            .iter()
+            .map(|bridge| {
+                let ip = bridge
+                    .bridge_address
+                    .split('/')
+                    .next()
+                    .unwrap_or_default()
+                    .parse::<IpAddr>()
+                    .map_err(|e| anyhow!("invalid bridge address: {}", e))?;
+                Ok(SocketAddr::new(ip, state.config.api.egress_proxy.port))
+            })
+            .collect::<Result<Vec<_>>>()?
+    };
+
+    let mut listeners = Vec::new();
+    for address in addresses {
+        let listener = TcpListener::bind(address)
+            .await
+            .map_err(|e| anyhow!("cannot bind egress proxy on {}: {}", address, e))?;
+        info!("egress proxy listening on {}", address);
+        listeners.push(tokio::spawn(listen(listener, state.clone())));
+    }
+
+    futures::future::select_all(listeners).await.0?
+}
+
+async fn listen(listener: TcpListener, state: LambdoStateRef) -> Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let state = state.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, peer, state).await {
+                debug!("egress proxy connection from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn handle(mut client: TcpStream, peer: SocketAddr, state: LambdoStateRef) -> Result<()> {
+    let mut buffer = Vec::with_capacity(4096);
+    let head_end = loop {
+        if let Some(index) = find_head_end(&buffer) {
+            break index;
+        }
+        if buffer.len() > MAX_HEAD_SIZE {
+            return Err(anyhow!("request head too large"));
+        }
+
+        let mut chunk = [0u8; 4096];
+        let read = client.read(&mut chunk).await?;
+        if read == 0 {
+            return Err(anyhow!("connection closed before end of request head"));
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    };
+
+    let head = String::from_utf8_lossy(&buffer[..head_end]).to_string();
+    let request_line = head.lines().next().unwrap_or_default();
+    trace!("egress proxy request from {}: {}", peer, request_line);
+
+    let mut parts = request_line.split_whitespace();
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) => (method, target, version),
+        _ => {
+            client
+                .write_all(response(400, "Bad Request").as_bytes())
+                .await?;
+            return Err(anyhow!("malformed request line"));
+        }
+    };
+
+    let (host, port, path) = if method.eq_ignore_ascii_case("CONNECT") {
+        let (host, port) = split_host_port(target, 443)?;
+        (host, port, None)
+    } else {
+        let rest = target
+            .strip_prefix("http://")
+            .ok_or(anyhow!("only absolute http URIs are supported"))?;
+        let (authority, path) = match rest.find('/') {
+            Some(index) => (&rest[..index], &rest[index..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = split_host_port(authority, 80)?;
+        (host, port, Some(path.to_string()))
+    };
+
+    let (vm_id, allowed) = authorize(&state, peer.ip(), &host).await;
+    {
+        let state = state.lock().await;
+        state.events.record(
+            "egress.request",
+            vm_id.as_deref(),
+            serde_json::json!({
+                "source": peer.ip(),
+                "method": method,
+                "host": host,
+                "port": port,
+                "allowed": allowed,
+            }),
+        );
+    }
+
+    if !allowed {
+        debug!(
+            "egress proxy denied {} {}:{} for {}",
+            method, host, port, peer
+        );
+        client
+            .write_all(response(403, "Forbidden").as_bytes())
+            .await?;
+        return Ok(());
+    }
+
+    let mut upstream = match TcpStream::connect((host.as_str(), port)).await {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            client
+                .write_all(response(502, "Bad Gateway").as_bytes())
+                .await?;
+            return Err(anyhow!("cannot connect to {}:{}: {}", host, port, e));
+        }
+    };
+
+    match path {
+        None => {
+            client
+                .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+                .await?;
+        }
+        Some(path) => {
+            // Forward the request in origin-form, keeping the headers and any
+            // body bytes already read
+            let rest_of_head = &head[request_line.len()..];
+            upstream
+                .write_all(format!("{} {} {}{}", method, path, version, rest_of_head).as_bytes())
+                .await?;
+            upstream.write_all(&buffer[head_end..]).await?;
+        }
+    }
+
+    tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+    Ok(())
+}
+
+/// Find the VM owning `source` and whether its egress profile allows `host`.
+async fn authorize(state: &LambdoStateRef, source: IpAddr, host: &str) -> (Option<String>, bool) {
+    let state = state.lock().await;
+    let vm = state
+        .vms
+        .iter()
+        .find(|vm| vm.ip.map(|ip| IpAddr::V4(ip.address())) == Some(source));
+
+    let Some(vm) = vm else {
+        return (None, false);
+    };
+
+    let allowed = vm
+        .egress_profile
+        .as_ref()
+        .and_then(|profile| state.config.api.egress_proxy.profiles.get(profile))
+        .map(|profile| {
+            profile
+                .allowed_domains
+                .iter()
+                .any(|pattern| domain_matches(pattern, host))
+        })
+        .unwrap_or(false);
+
+    (Some(vm.get_id()), allowed)
+}
+
+fn domain_matches(pattern: &str, host: &str) -> bool {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    let pattern = pattern.to_ascii_lowercase();
+
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => host.ends_with(&format!(".{}", suffix)),
+        None => host == pattern,
+    }
+}
+
+fn split_host_port(authority: &str, default_port: u16) -> Result<(String, u16)> {
+    match authority.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() => Ok((
+            host.trim_matches(|c| c == '[' || c == ']').to_string(),
+            port.parse().map_err(|_| anyhow!("invalid port {}", port))?,
+        )),
+        _ if !authority.is_empty() => Ok((authority.to_string(), default_port)),
+        _ => Err(anyhow!("missing host")),
+    }
+}
+
+fn find_head_end(buffer: &[u8]) -> Option<usize> {
+    buffer
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map(|index| index + 4)
+}
+
+fn response(status: u16, reason: &str) -> String {
+    format!(
+        "HTTP/1.1 {} {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        status, reason
+    )
+}
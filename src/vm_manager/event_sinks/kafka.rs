@@ -0,0 +1,61 @@
+use anyhow::{anyhow, Error};
+use serde_json::json;
+
+use super::EventSink;
+use crate::{config::KafkaSinkConfig, vm_manager::events::Event};
+
+/// Produces events to a Kafka topic through the Confluent REST proxy, keyed
+/// by VM id so that the events of a VM stay ordered.
+pub struct KafkaSink {
+    config: KafkaSinkConfig,
+    client: reqwest::Client,
+}
+
+impl KafkaSink {
+    pub fn new(config: KafkaSinkConfig) -> Self {
+        KafkaSink {
+            config,
+            client: reqwest::Client::new(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl EventSink for KafkaSink {
+    fn name(&self) -> String {
+        format!("kafka topic {}", self.config.topic)
+    }
+
+    async fn publish(&mut self, events: &[Event]) -> Result<(), Error> {
+        let records: Vec<_> = events
+            .iter()
+            .map(|event| json!({ "key": event.vm_id, "value": event }))
+            .collect();
+
+        let url = format!(
+            "{}/topics/{}",
+            self.config.rest_proxy_url.trim_end_matches('/'),
+            self.config.topic
+        );
+        let mut request = self
+            .client
+            .post(&url)
+            .header("Content-Type", "application/vnd.kafka.json.v2+json")
+            .header("Accept", "application/vnd.kafka.v2+json")
+            .body(json!({ "records": records }).to_string());
+        if let Some(username) = &self.config.username {
+            request = request.basic_auth(username, self.config.password.as_ref());
+        }
+
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            return Err(anyhow!(
+                "REST proxy answered {}: {}",
+                response.status(),
+                response.text().await.unwrap_or_default()
+            ));
+        }
+
+        Ok(())
+    }
+}
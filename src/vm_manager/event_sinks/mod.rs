@@ -0,0 +1,91 @@
+use std::time::Duration;
+
+use anyhow::Error;
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+use tracing::{debug, error, info, warn};
+
+use super::events::{Event, EventStore};
+use crate::config::{EventSinkConfig, EventSinkKind};
+
+pub mod kafka;
+pub mod nats;
+
+/// Number of batches kept while a sink is unreachable, older events are
+/// dropped past it
+const PENDING_BATCHES: usize = 10;
+
+/// External system lifecycle events are forwarded to.
+#[async_trait::async_trait]
+pub trait EventSink: Send {
+    /// Name of the sink, for logging
+    fn name(&self) -> String;
+    /// Deliver `events`, in order.
+    async fn publish(&mut self, events: &[Event]) -> Result<(), Error>;
+}
+
+/// Start forwarding the events of `store` to every configured sink.
+pub fn start(store: &EventStore, configs: &[EventSinkConfig]) {
+    for config in configs {
+        let sink: Box<dyn EventSink> = match &config.kind {
+            EventSinkKind::Nats(nats) => Box::new(nats::NatsSink::new(nats.clone())),
+            EventSinkKind::Kafka(kafka) => Box::new(kafka::KafkaSink::new(kafka.clone())),
+        };
+        info!("forwarding events to {}", sink.name());
+        tokio::spawn(forward(store.subscribe(), sink, config.clone()));
+    }
+}
+
+/// Send the events received on `receiver` to `sink`, in batches of at most
+/// `batch_size` events or every `batch_interval`.
+///
+/// Batches that cannot be delivered are retried with the next one.
+async fn forward(
+    mut receiver: Receiver<Event>,
+    mut sink: Box<dyn EventSink>,
+    config: EventSinkConfig,
+) {
+    let batch_size = config.batch_size.max(1);
+    let mut interval = tokio::time::interval(Duration::from_millis(config.batch_interval.max(1)));
+    let mut pending: Vec<Event> = Vec::new();
+
+    loop {
+        tokio::select! {
+            received = receiver.recv() => match received {
+                Ok(event) => {
+                    if config.types.is_empty() || config.types.contains(&event.kind) {
+                        pending.push(event);
+                    }
+                    if pending.len() < batch_size {
+                        continue;
+                    }
+                }
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("{} missed {} events", sink.name(), missed);
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            },
+            _ = interval.tick() => {}
+        }
+
+        while !pending.is_empty() {
+            let count = pending.len().min(batch_size);
+            match sink.publish(&pending[..count]).await {
+                Ok(()) => {
+                    debug!("sent {} events to {}", count, sink.name());
+                    pending.drain(..count);
+                }
+                Err(e) => {
+                    error!("cannot send events to {}: {}", sink.name(), e);
+                    let limit = batch_size * PENDING_BATCHES;
+                    if pending.len() > limit {
+                        let dropped = pending.len() - limit;
+                        warn!("dropping {} events for {}", dropped, sink.name());
+                        pending.drain(..dropped);
+                    }
+                    break;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,48 @@
+use anyhow::Error;
+
+use super::EventSink;
+use crate::{config::NatsSinkConfig, nats::NatsConnection, vm_manager::events::Event};
+
+/// Publishes events on a NATS server, on `<subject>.<event type>`.
+///
+/// Each batch is flushed, so that it is only considered delivered once the
+/// server has processed it.
+pub struct NatsSink {
+    config: NatsSinkConfig,
+    connection: Option<NatsConnection>,
+}
+
+impl NatsSink {
+    pub fn new(config: NatsSinkConfig) -> Self {
+        NatsSink {
+            config,
+            connection: None,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl EventSink for NatsSink {
+    fn name(&self) -> String {
+        format!("NATS subject {}", self.config.subject)
+    }
+
+    async fn publish(&mut self, events: &[Event]) -> Result<(), Error> {
+        // A failed batch drops the connection, the next one reconnects
+        let mut connection = match self.connection.take() {
+            Some(connection) => connection,
+            None => NatsConnection::connect(&self.config.server).await?,
+        };
+
+        for event in events {
+            let subject = format!("{}.{}", self.config.subject, event.kind);
+            connection
+                .publish(&subject, None, &serde_json::to_vec(event)?)
+                .await?;
+        }
+        connection.flush().await?;
+
+        self.connection = Some(connection);
+        Ok(())
+    }
+}
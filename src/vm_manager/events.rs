@@ -0,0 +1,248 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::broadcast;
+use tracing::{debug, error, info, trace};
+
+use super::now_ms;
+use crate::config::EventsConfig;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Event {
+    /// Sequence number of the event, increasing across restarts
+    pub id: u64,
+    /// Emission date, in milliseconds since the epoch
+    pub timestamp: u64,
+    /// Type of the event, such as `vm.created`
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vm_id: Option<String>,
+    /// Namespace of the VM or stack the event is about, none for events of
+    /// the host
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    #[serde(default, skip_serializing_if = "Value::is_null")]
+    pub data: Value,
+}
+
+impl Event {
+    /// Whether a caller restricted to the namespace `scope`, if it is, may
+    /// see the event
+    pub fn is_visible_in(&self, scope: Option<&str>) -> bool {
+        scope.is_none_or(|scope| self.namespace.as_deref() == Some(scope))
+    }
+}
+
+struct Inner {
+    events: VecDeque<Event>,
+    counts: HashMap<String, usize>,
+    next_id: u64,
+    /// Number of events in the file that are no longer retained
+    stale: usize,
+    /// Namespace of each VM that was not destroyed, as of its events
+    vm_namespaces: HashMap<String, String>,
+}
+
+impl Inner {
+    /// Fill in the namespace of `event` from its data, or from the events of
+    /// its VM, and remember the namespace of its VM for the next ones.
+    fn stamp(&mut self, event: &mut Event) {
+        if event.namespace.is_none() {
+            event.namespace = match event.data.get("namespace").and_then(Value::as_str) {
+                Some(namespace) => Some(namespace.to_string()),
+                None => event
+                    .vm_id
+                    .as_ref()
+                    .and_then(|id| self.vm_namespaces.get(id).cloned()),
+            };
+        }
+        let (Some(id), Some(namespace)) = (&event.vm_id, &event.namespace) else {
+            return;
+        };
+        if event.kind == "vm.destroyed" {
+            self.vm_namespaces.remove(id);
+        } else {
+            self.vm_namespaces.insert(id.clone(), namespace.clone());
+        }
+    }
+}
+
+/// Bounded event log persisted as JSON lines, so that events can be replayed
+/// after a restart.
+///
+/// Each event type keeps at most its configured number of events; the file is
+/// rewritten once it holds as many evicted events as retained ones.
+pub struct EventStore {
+    path: PathBuf,
+    config: EventsConfig,
+    inner: Mutex<Inner>,
+    /// Channel the new events are published on
+    sender: broadcast::Sender<Event>,
+}
+
+/// Number of events a slow subscriber can fall behind before missing some
+const SUBSCRIBER_CAPACITY: usize = 1024;
+
+impl EventStore {
+    pub fn new(config: EventsConfig) -> Result<Self> {
+        let path = PathBuf::from(&config.path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| anyhow!("cannot create events folder: {}", e))?;
+        }
+
+        let store = EventStore {
+            path,
+            config,
+            inner: Mutex::new(Inner {
+                events: VecDeque::new(),
+                counts: HashMap::new(),
+                next_id: 1,
+                stale: 0,
+                vm_namespaces: HashMap::new(),
+            }),
+            sender: broadcast::channel(SUBSCRIBER_CAPACITY).0,
+        };
+
+        if store.path.exists() {
+            store.load()?;
+        }
+
+        Ok(store)
+    }
+
+    fn load(&self) -> Result<()> {
+        let file = File::open(&self.path).map_err(|e| anyhow!("cannot open event store: {}", e))?;
+        let mut inner = self.inner.lock().unwrap();
+
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(|e| anyhow!("cannot read event store: {}", e))?;
+            match serde_json::from_str::<Event>(&line) {
+                Ok(mut event) => {
+                    inner.next_id = inner.next_id.max(event.id + 1);
+                    // Events recorded before they had a namespace
+                    inner.stamp(&mut event);
+                    self.retain(&mut inner, event);
+                }
+                // A crash may leave a truncated last line
+                Err(e) => debug!("skipping invalid event line: {}", e),
+            }
+        }
+
+        info!(
+            "loaded {} events from {}",
+            inner.events.len(),
+            self.path.display()
+        );
+
+        self.compact(&mut inner)
+    }
+
+    /// Record an event and return it.
+    pub fn record(&self, kind: &str, vm_id: Option<&str>, data: Value) -> Event {
+        let mut inner = self.inner.lock().unwrap();
+
+        let mut event = Event {
+            id: inner.next_id,
+            timestamp: now_ms(),
+            kind: kind.to_string(),
+            vm_id: vm_id.map(str::to_string),
+            namespace: None,
+            data,
+        };
+        inner.stamp(&mut event);
+        inner.next_id += 1;
+        trace!("recording event {:?}", event);
+
+        if let Err(e) = self.append(&event) {
+            error!("cannot persist event {}: {}", event.id, e);
+        }
+        self.retain(&mut inner, event.clone());
+        // Sending only fails when nobody is subscribed
+        let _ = self.sender.send(event.clone());
+
+        if inner.stale > 0 && inner.stale >= inner.events.len() {
+            if let Err(e) = self.compact(&mut inner) {
+                error!("cannot compact event store: {}", e);
+            }
+        }
+
+        event
+    }
+
+    /// Receive the events recorded from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+
+    /// Events with an id greater than `since`, optionally of a single type.
+    pub fn since(&self, since: u64, kind: Option<&str>) -> Vec<Event> {
+        self.inner
+            .lock()
+            .unwrap()
+            .events
+            .iter()
+            .filter(|event| event.id > since)
+            .filter(|event| kind.is_none_or(|kind| event.kind == kind))
+            .cloned()
+            .collect()
+    }
+
+    fn limit(&self, kind: &str) -> usize {
+        self.config
+            .retention_per_type
+            .get(kind)
+            .copied()
+            .unwrap_or(self.config.retention)
+    }
+
+    fn retain(&self, inner: &mut Inner, event: Event) {
+        let limit = self.limit(&event.kind);
+        let count = inner.counts.entry(event.kind.clone()).or_default();
+        *count += 1;
+        let evict = *count > limit;
+        let kind = event.kind.clone();
+        inner.events.push_back(event);
+
+        if evict {
+            if let Some(index) = inner.events.iter().position(|e| e.kind == kind) {
+                inner.events.remove(index);
+                *inner.counts.get_mut(&kind).unwrap() -= 1;
+                inner.stale += 1;
+            }
+        }
+    }
+
+    fn append(&self, event: &Event) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}", serde_json::to_string(event)?)?;
+        Ok(())
+    }
+
+    fn compact(&self, inner: &mut Inner) -> Result<()> {
+        debug!("compacting event store {}", self.path.display());
+        let tmp = self.path.with_extension("tmp");
+
+        let mut file = File::create(&tmp)?;
+        for event in &inner.events {
+            writeln!(file, "{}", serde_json::to_string(event)?)?;
+        }
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+
+        inner.stale = 0;
+        Ok(())
+    }
+}
@@ -0,0 +1,110 @@
+use std::{process::Stdio, time::Duration};
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use tokio::{io::AsyncWriteExt, process::Command};
+use tracing::{debug, warn};
+
+use crate::config::{HookConfig, HookFailurePolicy, HookPoint, HookTarget};
+
+/// Call the hooks of `point` in order, with the VM `vm_id` (unknown before
+/// it is started) and `vm` as JSON payload.
+///
+/// Fails on the first failing hook whose policy is to abort, the failures
+/// of the other hooks being only logged.
+pub async fn run<T: Serialize>(
+    hooks: &[HookConfig],
+    point: HookPoint,
+    vm_id: Option<&str>,
+    vm: &T,
+) -> Result<()> {
+    let hooks: Vec<&HookConfig> = hooks
+        .iter()
+        .filter(|hook| hook.on.contains(&point))
+        .collect();
+    if hooks.is_empty() {
+        return Ok(());
+    }
+    let payload = serde_json::to_vec(&serde_json::json!({
+        "hook": point,
+        "vmId": vm_id,
+        "vm": vm,
+    }))?;
+
+    for hook in hooks {
+        let timeout = Duration::from_secs(hook.timeout);
+        let result = tokio::time::timeout(timeout, call(&hook.target, &payload))
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("timed out after {}s", hook.timeout)));
+
+        match result {
+            Ok(()) => debug!("{} hook {} succeeded", point, describe(&hook.target)),
+            Err(e) if hook.on_failure == HookFailurePolicy::Abort => {
+                return Err(anyhow!(
+                    "{} hook {} failed: {}",
+                    point,
+                    describe(&hook.target),
+                    e
+                ));
+            }
+            Err(e) => warn!(
+                "{} hook {} failed, ignoring it: {}",
+                point,
+                describe(&hook.target),
+                e
+            ),
+        }
+    }
+
+    Ok(())
+}
+
+async fn call(target: &HookTarget, payload: &[u8]) -> Result<()> {
+    match target {
+        HookTarget::Exec { command } => {
+            let (program, args) = command
+                .split_first()
+                .ok_or_else(|| anyhow!("empty command"))?;
+            let mut child = Command::new(program)
+                .args(args)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::null())
+                .stderr(Stdio::piped())
+                .kill_on_drop(true)
+                .spawn()?;
+            // Closed once written, for the command to see the end of input
+            if let Some(mut stdin) = child.stdin.take() {
+                stdin.write_all(payload).await?;
+            }
+
+            let output = child.wait_with_output().await?;
+            if !output.status.success() {
+                return Err(anyhow!(
+                    "{}: {}",
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ));
+            }
+            Ok(())
+        }
+        HookTarget::Http { url } => {
+            let response = reqwest::Client::new()
+                .post(url)
+                .header("content-type", "application/json")
+                .body(payload.to_vec())
+                .send()
+                .await?;
+            if !response.status().is_success() {
+                return Err(anyhow!("answered {}", response.status()));
+            }
+            Ok(())
+        }
+    }
+}
+
+fn describe(target: &HookTarget) -> String {
+    match target {
+        HookTarget::Exec { command } => command.join(" "),
+        HookTarget::Http { url } => url.clone(),
+    }
+}
@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+
+use super::{now_ms, Error};
+
+/// How long the VM started by a request is answered to retries of it
+const IDEMPOTENCY_KEY_TTL_MS: u64 = 24 * 3600 * 1000;
+/// How long a key stays claimed by a request that never settled it, should
+/// the release of a dropped request not run
+const PENDING_KEY_TTL_MS: u64 = 10 * 60 * 1000;
+
+/// Id and port mapping of the VM a start request started
+pub type StartedVm = (String, HashMap<u16, u16>);
+
+/// Request sent with an idempotency key, remembered for its retries to be
+/// answered without starting another VM
+#[derive(Debug, Clone)]
+pub struct IdempotentRequest {
+    /// Digest of the request the key was first sent with
+    pub fingerprint: String,
+    /// In ms since epoch
+    pub created_at: u64,
+    /// VM the request started and its port mapping, none while it is
+    /// starting
+    pub started: Option<StartedVm>,
+}
+
+/// Recent requests sent with an idempotency key, by namespace and key
+#[derive(Debug, Default)]
+pub struct IdempotencyKeys(HashMap<(String, String), IdempotentRequest>);
+
+impl IdempotencyKeys {
+    /// Claim `key` of `namespace` for a request with `fingerprint`,
+    /// returning the VM started by a previous request with the key if there
+    /// was one.
+    pub fn claim(
+        &mut self,
+        namespace: &str,
+        key: &str,
+        fingerprint: &str,
+    ) -> Result<Option<StartedVm>, Error> {
+        self.claim_at(now_ms(), namespace, key, fingerprint)
+    }
+
+    fn claim_at(
+        &mut self,
+        now: u64,
+        namespace: &str,
+        key: &str,
+        fingerprint: &str,
+    ) -> Result<Option<StartedVm>, Error> {
+        self.0.retain(|_, request| {
+            let ttl = match request.started {
+                Some(_) => IDEMPOTENCY_KEY_TTL_MS,
+                None => PENDING_KEY_TTL_MS,
+            };
+            now.saturating_sub(request.created_at) < ttl
+        });
+
+        let id = (namespace.to_string(), key.to_string());
+        match self.0.get(&id) {
+            Some(request) if request.fingerprint != fingerprint => Err(Error::IdempotencyKeyReused),
+            Some(request) => request
+                .started
+                .clone()
+                .map(Some)
+                .ok_or(Error::IdempotencyKeyInUse),
+            None => {
+                self.0.insert(
+                    id,
+                    IdempotentRequest {
+                        fingerprint: fingerprint.to_string(),
+                        created_at: now,
+                        started: None,
+                    },
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    /// Record the VM the request that claimed `key` of `namespace` started,
+    /// or release the key if it failed for the request to be retried.
+    pub fn settle(&mut self, namespace: &str, key: &str, started: Option<StartedVm>) {
+        let id = (namespace.to_string(), key.to_string());
+        match started {
+            Some(started) => {
+                if let Some(request) = self.0.get_mut(&id) {
+                    request.started = Some(started);
+                }
+            }
+            None => {
+                self.0.remove(&id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn started(id: &str) -> StartedVm {
+        (id.to_string(), HashMap::from([(30000, 80)]))
+    }
+
+    #[test]
+    fn answers_retries_with_the_started_vm() {
+        let mut keys = IdempotencyKeys::default();
+        assert!(matches!(keys.claim_at(0, "ns", "key", "a"), Ok(None)));
+        keys.settle("ns", "key", Some(started("vm")));
+
+        let retried = keys.claim_at(1000, "ns", "key", "a").unwrap();
+        assert_eq!(retried, Some(started("vm")));
+    }
+
+    #[test]
+    fn refuses_keys_reused_for_other_requests() {
+        let mut keys = IdempotencyKeys::default();
+        keys.claim_at(0, "ns", "key", "a").unwrap();
+        assert!(matches!(
+            keys.claim_at(0, "ns", "key", "b"),
+            Err(Error::IdempotencyKeyReused)
+        ));
+
+        keys.settle("ns", "key", Some(started("vm")));
+        assert!(matches!(
+            keys.claim_at(0, "ns", "key", "b"),
+            Err(Error::IdempotencyKeyReused)
+        ));
+    }
+
+    #[test]
+    fn refuses_keys_of_requests_in_progress() {
+        let mut keys = IdempotencyKeys::default();
+        keys.claim_at(0, "ns", "key", "a").unwrap();
+        assert!(matches!(
+            keys.claim_at(1000, "ns", "key", "a"),
+            Err(Error::IdempotencyKeyInUse)
+        ));
+    }
+
+    #[test]
+    fn scopes_keys_by_namespace() {
+        let mut keys = IdempotencyKeys::default();
+        keys.claim_at(0, "ns", "key", "a").unwrap();
+        assert!(matches!(keys.claim_at(0, "other", "key", "b"), Ok(None)));
+    }
+
+    #[test]
+    fn releases_keys_of_failed_requests() {
+        let mut keys = IdempotencyKeys::default();
+        keys.claim_at(0, "ns", "key", "a").unwrap();
+        keys.settle("ns", "key", None);
+        assert!(matches!(keys.claim_at(0, "ns", "key", "b"), Ok(None)));
+    }
+
+    #[test]
+    fn expires_pending_keys_before_settled_ones() {
+        let mut keys = IdempotencyKeys::default();
+        keys.claim_at(0, "ns", "pending", "a").unwrap();
+        keys.claim_at(0, "ns", "settled", "a").unwrap();
+        keys.settle("ns", "settled", Some(started("vm")));
+
+        let now = PENDING_KEY_TTL_MS;
+        assert!(matches!(keys.claim_at(now, "ns", "pending", "b"), Ok(None)));
+        assert!(keys.claim_at(now, "ns", "settled", "a").unwrap().is_some());
+
+        let now = IDEMPOTENCY_KEY_TTL_MS;
+        assert!(matches!(keys.claim_at(now, "ns", "settled", "b"), Ok(None)));
+    }
+}
@@ -0,0 +1,140 @@
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Error};
+use serde::Serialize;
+use tracing::debug;
+
+use super::{sha256_file, url_manager::SIDECAR_SUFFIXES, ImageManifest};
+
+/// Suffix of the file recording the manifest an image was stored from,
+/// pinned to the digest of the stored file
+pub const MANIFEST_SUFFIX: &str = ".manifest";
+
+/// Outcome of an audit of the image store
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditReport {
+    /// Images whose digest was checked
+    pub checked: usize,
+    /// Images without a recorded digest, which cannot be checked
+    pub unrecorded: usize,
+    pub corrupted: Vec<CorruptedImage>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CorruptedImage {
+    pub image: String,
+    pub expected: String,
+    pub actual: String,
+    /// Where the corrupted file was moved
+    pub quarantined_to: PathBuf,
+    /// Whether the image was downloaded again from its manifest
+    pub restored: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Record that the image at `path` was stored from `manifest`, with the
+/// digest `sha256`.
+pub fn record(path: &Path, manifest: &ImageManifest, sha256: &str) -> Result<(), Error> {
+    let recorded = ImageManifest {
+        sha256: Some(sha256.to_string()),
+        ..manifest.clone()
+    };
+    std::fs::write(
+        with_suffix(path, MANIFEST_SUFFIX),
+        serde_json::to_vec(&recorded)?,
+    )?;
+    Ok(())
+}
+
+/// Images of `folder` along with their recorded manifest, if any, leaving
+/// `quarantine` out.
+pub fn stored_images(
+    folder: &Path,
+    quarantine: &Path,
+) -> Result<Vec<(PathBuf, Option<ImageManifest>)>, Error> {
+    let mut images = Vec::new();
+    list_images(folder, quarantine, &mut images)?;
+    Ok(images)
+}
+
+fn list_images(
+    folder: &Path,
+    quarantine: &Path,
+    images: &mut Vec<(PathBuf, Option<ImageManifest>)>,
+) -> Result<(), Error> {
+    for entry in std::fs::read_dir(folder)? {
+        let entry = entry?;
+        let path = entry.path();
+        if path == quarantine {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            list_images(&path, quarantine, images)?;
+            continue;
+        }
+
+        let name = entry.file_name().to_string_lossy().to_string();
+        // Sidecar files, downloads and promotions in progress
+        if name.ends_with("download")
+            || name.contains(".promote-")
+            || SIDECAR_SUFFIXES.iter().any(|suffix| name.ends_with(suffix))
+        {
+            continue;
+        }
+        let manifest = std::fs::read(with_suffix(&path, MANIFEST_SUFFIX))
+            .ok()
+            .and_then(|manifest| serde_json::from_slice(&manifest).ok());
+        images.push((path, manifest));
+    }
+    Ok(())
+}
+
+/// Hash the image at `path`, returning its digest if it differs from the
+/// recorded one.
+pub async fn check(path: &Path, manifest: &ImageManifest) -> Result<Option<String>, Error> {
+    let expected = manifest
+        .sha256
+        .as_deref()
+        .ok_or_else(|| anyhow!("no digest recorded for image {}", manifest.id))?;
+
+    debug!("Auditing image {}", path.display());
+    let digest = {
+        let path = path.to_path_buf();
+        tokio::task::spawn_blocking(move || sha256_file(&path)).await??
+    };
+    Ok((!digest.eq_ignore_ascii_case(expected)).then_some(digest))
+}
+
+/// Move the image at `path` of `folder` to `quarantine`, under the same
+/// relative path suffixed with the time, returning where it was moved.
+pub fn quarantine(folder: &Path, quarantine: &Path, path: &Path) -> Result<PathBuf, Error> {
+    let relative = path.strip_prefix(folder).unwrap_or(path);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let destination = with_suffix(&quarantine.join(relative), &format!(".{}", now));
+    if let Some(parent) = destination.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    // The quarantine may be on another filesystem
+    if std::fs::rename(path, &destination).is_err() {
+        std::fs::copy(path, &destination)?;
+        std::fs::remove_file(path)?;
+    }
+    Ok(destination)
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = OsString::from(path);
+    path.push(suffix);
+    PathBuf::from(path)
+}
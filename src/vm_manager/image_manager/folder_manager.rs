@@ -3,15 +3,19 @@ use std::path::PathBuf;
 use anyhow::Error;
 use tracing::trace;
 
-use super::{Image, ImageManager, ImageManifest};
+use super::{DigestCache, Image, ImageManager, ImageManifest, ImageOrigin, ImageSource};
 
 pub struct FolderImageManager {
     pub path: PathBuf,
+    digests: DigestCache,
 }
 
 impl FolderImageManager {
     pub fn new(path: String) -> Self {
-        Self { path: path.into() }
+        Self {
+            path: path.into(),
+            digests: DigestCache::default(),
+        }
     }
 }
 
@@ -34,10 +38,26 @@ impl ImageManager for FolderImageManager {
             path,
         };
 
+        self.digests.verify(manifest, &image).await?;
+
         trace!("find_disk {:?}", image);
         Ok(image)
     }
 
+    async fn describe(&self, manifest: &ImageManifest) -> ImageSource {
+        let path = self.path.join(&manifest.location);
+        ImageSource {
+            id: manifest.id.clone(),
+            origin: if path.exists() {
+                ImageOrigin::Folder
+            } else {
+                ImageOrigin::Missing
+            },
+            path,
+            pinned: manifest.sha256.is_some(),
+        }
+    }
+
     async fn find_kernel(&self, manifest: &ImageManifest) -> Result<Image, Error> {
         self.find_disk(manifest).await
     }
@@ -1,9 +1,22 @@
-use std::path::PathBuf;
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
 
-use anyhow::Error;
+use anyhow::{anyhow, Error};
+use openssl::sha::Sha256;
 use serde::{Deserialize, Serialize};
+use tracing::debug;
 
+pub mod audit;
 pub mod folder_manager;
+pub mod profile;
+pub mod promotion;
+pub mod samples;
 pub mod url_manager;
 
 #[async_trait::async_trait]
@@ -11,6 +24,32 @@ pub trait ImageManager: Sync + Send {
     async fn find_kernel(&self, manifest: &ImageManifest) -> Result<Image, Error>;
     async fn find_rootfs(&self, manifest: &ImageManifest) -> Result<Image, Error>;
     async fn find_disk(&self, manifest: &ImageManifest) -> Result<Image, Error>;
+    /// Where an image would be taken from, without downloading it
+    async fn describe(&self, manifest: &ImageManifest) -> ImageSource;
+}
+
+/// Where an image comes from
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageSource {
+    pub id: String,
+    pub path: PathBuf,
+    pub origin: ImageOrigin,
+    /// Whether the image is checked against a pinned digest before use
+    pub pinned: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageOrigin {
+    /// Found in the image folder
+    Folder,
+    /// Downloaded before
+    Cache,
+    /// Would be downloaded from its location
+    Download,
+    /// Not found
+    Missing,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -23,4 +62,105 @@ pub struct Image {
 pub struct ImageManifest {
     pub id: String,
     pub location: String,
+    /// Expected SHA-256 digest of the image, in hexadecimal
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+impl ImageManifest {
+    /// The same image, looked up among the images of `tenant`.
+    pub fn in_namespace(&self, tenant: &str) -> Result<ImageManifest, Error> {
+        if [&self.id, &self.location]
+            .iter()
+            .any(|s| s.split('/').any(|part| part == ".."))
+        {
+            return Err(anyhow::anyhow!(
+                "image {} is outside of the namespace of {}",
+                self.id,
+                tenant
+            ));
+        }
+
+        let location = if self.location.contains("://") {
+            self.location.clone()
+        } else {
+            format!("{}/{}", tenant, self.location.trim_start_matches('/'))
+        };
+
+        Ok(ImageManifest {
+            id: format!("{}/{}", tenant, self.id),
+            location,
+            sha256: self.sha256.clone(),
+        })
+    }
+}
+
+/// Digests of the images already hashed, so that an image is only hashed
+/// again once its file changes
+#[derive(Default)]
+pub struct DigestCache {
+    /// Digest of each file, with the modification time and size it was
+    /// computed for
+    digests: Mutex<HashMap<PathBuf, (SystemTime, u64, String)>>,
+}
+
+impl DigestCache {
+    /// Check that `image` has the digest pinned in `manifest`, if any.
+    pub async fn verify(&self, manifest: &ImageManifest, image: &Image) -> Result<(), Error> {
+        let Some(expected) = &manifest.sha256 else {
+            return Ok(());
+        };
+
+        let digest = self.digest(&image.path).await?;
+        if !digest.eq_ignore_ascii_case(expected) {
+            return Err(anyhow!(
+                "Digest mismatch for image {}: expected {}, got {}",
+                manifest.id,
+                expected,
+                digest
+            ));
+        }
+        Ok(())
+    }
+
+    /// Forget the digest of `path`, before replacing the file.
+    pub fn forget(&self, path: &Path) {
+        self.digests.lock().unwrap().remove(path);
+    }
+
+    async fn digest(&self, path: &Path) -> Result<String, Error> {
+        let metadata = tokio::fs::metadata(path).await?;
+        let key = (metadata.modified()?, metadata.len());
+        if let Some((modified, len, digest)) = self.digests.lock().unwrap().get(path) {
+            if (*modified, *len) == key {
+                return Ok(digest.clone());
+            }
+        }
+
+        debug!("Computing digest of {}", path.display());
+        let digest = {
+            let path = path.to_path_buf();
+            tokio::task::spawn_blocking(move || sha256_file(&path)).await??
+        };
+        self.digests
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), (key.0, key.1, digest.clone()));
+        Ok(digest)
+    }
+}
+
+/// SHA-256 digest of the file at `path`, in hexadecimal
+pub fn sha256_file(path: &Path) -> Result<String, Error> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 1024 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hex::encode(hasher.finish()))
 }
@@ -0,0 +1,164 @@
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use super::Image;
+use crate::vm_manager::{vmm::DEFAULT_MEM_SIZE_MIB, VMOptions};
+
+/// Suffix of the file holding the boot profile of an image, next to it
+pub const PROFILE_SUFFIX: &str = ".profile.json";
+
+/// How an image is meant to be booted, published next to the image
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BootProfile {
+    /// Kernel boot arguments recommended for the image
+    #[serde(default)]
+    pub boot_args: Option<String>,
+    /// Memory below which the image does not work, in MiB
+    #[serde(default)]
+    pub min_mem_size_mib: Option<u32>,
+    /// Oldest guest kernel the image runs on, such as `5.10`
+    #[serde(default)]
+    pub min_kernel_version: Option<String>,
+    /// Version of the kernel, for kernel images
+    #[serde(default)]
+    pub kernel_version: Option<String>,
+}
+
+impl BootProfile {
+    /// The profile of `image`, if it has one.
+    pub fn load(image: &Image) -> Result<Option<BootProfile>> {
+        let path = profile_path(&image.path);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let content = std::fs::read(&path)
+            .map_err(|e| anyhow!("cannot read boot profile {}: {}", path.display(), e))?;
+        serde_json::from_slice(&content)
+            .map(Some)
+            .map_err(|e| anyhow!("invalid boot profile {}: {}", path.display(), e))
+    }
+}
+
+/// Path of the boot profile of the image at `path`
+pub fn profile_path(path: &Path) -> PathBuf {
+    let mut path = OsString::from(path);
+    path.push(PROFILE_SUFFIX);
+    PathBuf::from(path)
+}
+
+/// Use the boot profiles of the images of `options` as defaults, and check
+/// the options against their minimums.
+///
+/// A violated minimum is only logged, unless `enforce` is set in which case
+/// the violations are returned.
+pub fn apply(options: &mut VMOptions, enforce: bool) -> Result<(), String> {
+    let kernel = load_or_warn(&options.boot.kernel);
+    let images: Vec<BootProfile> = options
+        .boot
+        .initrd
+        .iter()
+        .chain(options.disks.iter().map(|disk| &disk.image))
+        .filter_map(load_or_warn)
+        .collect();
+    if kernel.is_none() && images.is_empty() {
+        return Ok(());
+    }
+
+    let recommended: Vec<&str> = kernel
+        .iter()
+        .chain(images.iter())
+        .filter_map(|profile| profile.boot_args.as_deref())
+        .collect();
+    if !recommended.is_empty() {
+        let defaults = recommended.join(" ");
+        let boot_args = match &options.boot.boot_args {
+            Some(boot_args) => merge_boot_args(&defaults, boot_args),
+            None => merge_boot_args("", &defaults),
+        };
+        debug!("boot arguments with image defaults: {}", boot_args);
+        options.boot.boot_args = Some(boot_args);
+    }
+
+    let mut violations = Vec::new();
+
+    if let Some(min) = images.iter().filter_map(|p| p.min_mem_size_mib).max() {
+        match options.mem_size_mib {
+            Some(mem) if mem < min => violations.push(format!(
+                "the images need {} MiB of memory, {} MiB requested",
+                min, mem
+            )),
+            Some(_) => {}
+            None if DEFAULT_MEM_SIZE_MIB < min => options.mem_size_mib = Some(min),
+            None => {}
+        }
+    }
+
+    let required = images
+        .iter()
+        .filter_map(|p| p.min_kernel_version.as_deref())
+        .max_by_key(|version| parse_version(version));
+    if let Some(required) = required {
+        match kernel.as_ref().and_then(|k| k.kernel_version.as_deref()) {
+            Some(version) if parse_version(version) < parse_version(required) => {
+                violations.push(format!(
+                    "the images need kernel {} or later, kernel {} is {}",
+                    required, options.boot.kernel.id, version
+                ))
+            }
+            Some(_) => {}
+            None => warn!(
+                "the images need kernel {} or later, but the version of kernel {} is unknown",
+                required, options.boot.kernel.id
+            ),
+        }
+    }
+
+    if violations.is_empty() {
+        return Ok(());
+    }
+    let violations = violations.join(", ");
+    if enforce {
+        Err(violations)
+    } else {
+        warn!("boot profile not followed: {}", violations);
+        Ok(())
+    }
+}
+
+fn load_or_warn(image: &Image) -> Option<BootProfile> {
+    BootProfile::load(image).unwrap_or_else(|e| {
+        warn!("ignoring boot profile of image {}: {:?}", image.id, e);
+        None
+    })
+}
+
+/// `defaults` with the arguments of `args` added, an argument of `args`
+/// replacing the default with the same key.
+fn merge_boot_args(defaults: &str, args: &str) -> String {
+    let key = |arg: &str| arg.split('=').next().unwrap_or(arg).to_string();
+    let overridden: Vec<String> = args.split_whitespace().map(key).collect();
+
+    let mut merged: Vec<&str> = Vec::new();
+    for arg in defaults.split_whitespace() {
+        if !overridden.contains(&key(arg)) && !merged.contains(&arg) {
+            merged.push(arg);
+        }
+    }
+    merged.extend(args.split_whitespace());
+    merged.join(" ")
+}
+
+/// Numeric components of a kernel version, `5.10.186-1` giving 5, 10, 186
+fn parse_version(version: &str) -> Vec<u64> {
+    version
+        .split(['.', '-', '+'])
+        .map_while(|part| part.parse().ok())
+        .collect()
+}
@@ -0,0 +1,203 @@
+use std::{
+    ffi::OsString,
+    fs::File,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::anyhow;
+use openssl::{
+    hash::MessageDigest,
+    pkey::{PKey, Public},
+    sha::Sha256,
+    sign::Verifier,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+
+use super::{audit, sha256_file, ImageManifest};
+use crate::{
+    config::PromotionConfig,
+    vm_manager::{now_ms, Error},
+};
+
+/// Size of the chunks an image is copied by
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PromotionDTO {
+    /// Path of the image in the staging store
+    pub image: String,
+    /// SHA-256 digest of the tested image, in hexadecimal
+    pub sha256: String,
+    /// Path of the image in the image store, the staging path if unset
+    #[serde(default)]
+    pub target: Option<String>,
+}
+
+/// What was promoted, kept as the audit record of the promotion
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Promotion {
+    pub image: String,
+    pub target: String,
+    pub sha256: String,
+    /// SHA-256 digest of the DER public key the signature was checked with
+    pub key_fingerprint: String,
+    /// Digest of the image previously stored at the target, if any
+    pub replaced: Option<String>,
+    /// When the image was promoted, in ms since epoch
+    pub promoted_at: u64,
+}
+
+/// Copy a staged image into `images_folder`, checking that the copied bytes
+/// have the requested digest and were signed with the promotion key.
+///
+/// The image is hashed and verified while it is copied, so that what ends up
+/// in the image store is exactly what was checked even if the staged file
+/// changes in the meantime.
+pub fn promote(
+    config: &PromotionConfig,
+    images_folder: &Path,
+    request: &PromotionDTO,
+) -> Result<Promotion, Error> {
+    let target = request.target.as_deref().unwrap_or(&request.image);
+    for path in [&request.image, target] {
+        if path.is_empty() || path.starts_with('/') || path.split('/').any(|part| part == "..") {
+            return Err(Error::PromotionRejected(format!(
+                "invalid image path {}",
+                path
+            )));
+        }
+    }
+
+    let staged = Path::new(&config.staging_folder).join(&request.image);
+    if !staged.is_file() {
+        return Err(Error::PromotionRejected(format!(
+            "image {} is not staged",
+            request.image
+        )));
+    }
+    let signature = std::fs::read(with_suffix(&staged, ".sig")).map_err(|e| {
+        Error::PromotionRejected(format!("no signature for image {}: {}", request.image, e))
+    })?;
+    let key = public_key(&config.public_key).map_err(Error::ImageError)?;
+
+    let destination = images_folder.join(target);
+    if let Some(parent) = destination.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| Error::ImageError(e.into()))?;
+    }
+    let tmp = with_suffix(
+        &destination,
+        &format!(".promote-{}", uuid::Uuid::new_v4().simple()),
+    );
+
+    debug!(
+        "promoting {} to {}",
+        staged.display(),
+        destination.display()
+    );
+    let checked = copy_and_verify(&staged, &tmp, &key, &signature).and_then(|(digest, valid)| {
+        if !digest.eq_ignore_ascii_case(&request.sha256) {
+            Err(Error::PromotionRejected(format!(
+                "digest mismatch for {}: expected {}, got {}",
+                request.image, request.sha256, digest
+            )))
+        } else if !valid {
+            Err(Error::PromotionRejected(format!(
+                "invalid signature for image {}",
+                request.image
+            )))
+        } else {
+            Ok(digest)
+        }
+    });
+    let digest = match checked {
+        Ok(digest) => digest,
+        Err(e) => {
+            let _ = std::fs::remove_file(&tmp);
+            return Err(e);
+        }
+    };
+
+    let replaced = if destination.exists() {
+        Some(sha256_file(&destination).map_err(Error::ImageError)?)
+    } else {
+        None
+    };
+    std::fs::rename(&tmp, &destination)
+        .and_then(|_| std::fs::write(with_suffix(&destination, ".sig"), &signature))
+        .map_err(|e| Error::ImageError(e.into()))?;
+    let manifest = ImageManifest {
+        id: target.to_string(),
+        location: target.to_string(),
+        sha256: None,
+    };
+    audit::record(&destination, &manifest, &digest).map_err(Error::ImageError)?;
+
+    info!(
+        "promoted image {} ({}) to {}",
+        request.image,
+        digest,
+        destination.display()
+    );
+
+    Ok(Promotion {
+        image: request.image.clone(),
+        target: target.to_string(),
+        sha256: digest,
+        key_fingerprint: key
+            .public_key_to_der()
+            .map(|der| hex::encode(openssl::sha::sha256(&der)))
+            .unwrap_or_default(),
+        replaced,
+        promoted_at: now_ms(),
+    })
+}
+
+fn public_key(path: &str) -> anyhow::Result<PKey<Public>> {
+    let pem =
+        std::fs::read(path).map_err(|e| anyhow!("cannot read promotion key {}: {}", path, e))?;
+    PKey::public_key_from_pem(&pem).map_err(|e| anyhow!("invalid promotion key {}: {}", path, e))
+}
+
+/// Copy `source` to `destination`, returning the digest of the copied bytes
+/// and whether `signature` is valid for them.
+fn copy_and_verify(
+    source: &Path,
+    destination: &Path,
+    key: &PKey<Public>,
+    signature: &[u8],
+) -> Result<(String, bool), Error> {
+    let io = |e: std::io::Error| Error::ImageError(e.into());
+    let ssl = |e: openssl::error::ErrorStack| Error::ImageError(e.into());
+
+    let mut input = File::open(source).map_err(io)?;
+    let mut output = File::create(destination).map_err(io)?;
+    let mut hasher = Sha256::new();
+    let mut verifier = Verifier::new(MessageDigest::sha256(), key).map_err(ssl)?;
+
+    let mut buffer = vec![0; CHUNK_SIZE];
+    loop {
+        let read = input.read(&mut buffer).map_err(io)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        verifier.update(&buffer[..read]).map_err(ssl)?;
+        output.write_all(&buffer[..read]).map_err(io)?;
+    }
+    output.sync_all().map_err(io)?;
+
+    // A malformed signature is as untrusted as a wrong one
+    let valid = verifier.verify(signature).unwrap_or(false);
+    Ok((hex::encode(hasher.finish()), valid))
+}
+
+/// `path` with `suffix` appended to its file name
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = OsString::from(path);
+    path.push(suffix);
+    PathBuf::from(path)
+}
@@ -0,0 +1,95 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Error};
+use futures::StreamExt;
+use openssl::sha::Sha256;
+use tokio::io::AsyncWriteExt;
+use tracing::{info, warn};
+
+use super::Image;
+use crate::config::{ImageManagerConfig, SampleSource};
+
+/// Folder of the image store holding the kernel and rootfs used for tests
+pub const SAMPLES_FOLDER: &str = "samples";
+pub const SAMPLE_KERNEL: &str = "vmlinux";
+pub const SAMPLE_ROOTFS: &str = "rootfs.ext4";
+
+/// Paths of the test kernel and rootfs in the image store
+pub fn sample_paths(images_folder: &Path) -> (PathBuf, PathBuf) {
+    let samples = images_folder.join(SAMPLES_FOLDER);
+    (samples.join(SAMPLE_KERNEL), samples.join(SAMPLE_ROOTFS))
+}
+
+/// Download the test kernel and rootfs into the image store, where they can
+/// be used with the `samples/vmlinux` and `samples/rootfs.ext4` ids.
+pub async fn fetch_samples(config: &ImageManagerConfig) -> Result<Vec<Image>, Error> {
+    let (kernel, rootfs) = sample_paths(Path::new(&config.images_folder));
+
+    let mut images = Vec::new();
+    for (path, source) in [
+        (kernel, &config.samples.kernel),
+        (rootfs, &config.samples.rootfs),
+    ] {
+        fetch(source, &path).await?;
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        images.push(Image {
+            id: format!("{}/{}", SAMPLES_FOLDER, name),
+            path,
+        });
+    }
+
+    Ok(images)
+}
+
+/// Download `source` to `path`, checking its digest before moving it in
+/// place.
+async fn fetch(source: &SampleSource, path: &Path) -> Result<(), Error> {
+    info!("Downloading {} to {}", source.url, path.display());
+
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+
+    let response = reqwest::get(&source.url).await?;
+    if !response.status().is_success() {
+        return Err(anyhow!(
+            "Failed to download {}: {}",
+            source.url,
+            response.status()
+        ));
+    }
+
+    let download = path.with_extension("download");
+    let mut file = tokio::fs::File::create(&download).await?;
+    let mut hasher = Sha256::new();
+    let mut byte_stream = response.bytes_stream();
+    while let Some(item) = byte_stream.next().await {
+        let item = item?;
+        hasher.update(&item);
+        file.write_all(&item).await?;
+    }
+    file.flush().await?;
+
+    let digest = hex::encode(hasher.finish());
+    match &source.sha256 {
+        Some(expected) if !expected.eq_ignore_ascii_case(&digest) => {
+            tokio::fs::remove_file(&download).await?;
+            return Err(anyhow!(
+                "Digest mismatch for {}: expected {}, got {}",
+                source.url,
+                expected,
+                digest
+            ));
+        }
+        Some(_) => {}
+        None => warn!(
+            "No digest pinned for {}, downloaded file has sha256 {}",
+            source.url, digest
+        ),
+    }
+
+    tokio::fs::rename(&download, path).await?;
+    info!("Downloaded {} ({})", path.display(), digest);
+
+    Ok(())
+}
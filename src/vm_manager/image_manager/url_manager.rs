@@ -1,24 +1,208 @@
-use std::path::PathBuf;
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
 
 use anyhow::Error;
 use futures::StreamExt;
+use openssl::sha::Sha256;
+use tokio::io::AsyncWriteExt;
 use tracing::debug;
 use tracing::info;
 use tracing::trace;
+use tracing::warn;
+use uuid::Uuid;
 
-use super::{Image, ImageManager, ImageManifest};
+use super::{
+    audit, profile, DigestCache, Image, ImageManager, ImageManifest, ImageOrigin, ImageSource,
+};
+use crate::vm_manager::{
+    events::EventStore,
+    state::{LambdoStateRef, VMStatus},
+};
 
 pub struct UrlImageManager {
     pub cache: PathBuf,
+    /// Where downloads are recorded
+    pub events: Option<Arc<EventStore>>,
+    /// Size of the cache above which the least recently used images are
+    /// evicted
+    pub max_cache_bytes: Option<u64>,
+    /// VMs whose images cannot be evicted
+    state: Option<LambdoStateRef>,
+    /// When each cached image was last handed out since lambdo started
+    last_used: Mutex<HashMap<PathBuf, SystemTime>>,
+    /// Lock of each image being looked up, so that concurrent requests for
+    /// an uncached image wait for a single download
+    in_flight: Mutex<HashMap<PathBuf, Arc<tokio::sync::Mutex<()>>>>,
+    digests: DigestCache,
 }
 
 impl UrlImageManager {
     pub fn new(cache: String) -> Self {
         Self {
             cache: cache.into(),
+            events: None,
+            max_cache_bytes: None,
+            state: None,
+            last_used: Mutex::new(HashMap::new()),
+            in_flight: Mutex::new(HashMap::new()),
+            digests: DigestCache::default(),
         }
     }
 
+    pub fn with_events(mut self, events: Arc<EventStore>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    pub fn with_eviction(mut self, max_cache_bytes: Option<u64>, state: LambdoStateRef) -> Self {
+        self.max_cache_bytes = max_cache_bytes;
+        self.state = Some(state);
+        self
+    }
+
+    fn touch(&self, path: &Path) {
+        self.last_used
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), SystemTime::now());
+    }
+
+    /// Remove the least recently used images until the cache fits in its
+    /// maximum size, keeping `keep` and the images of the VMs.
+    async fn evict(&self, keep: &Path) {
+        let Some(max) = self.max_cache_bytes else {
+            return;
+        };
+
+        let mut images = Vec::new();
+        let mut total = 0;
+        if let Err(e) = cached_images(&self.cache, &mut images, &mut total) {
+            warn!("Cannot list the image cache: {}", e);
+            return;
+        }
+        if total <= max {
+            return;
+        }
+
+        let in_use = self.images_in_use().await;
+        {
+            let last_used = self.last_used.lock().unwrap();
+            for image in images.iter_mut() {
+                if let Some(used) = last_used.get(&image.path) {
+                    image.last_used = *used;
+                }
+            }
+        }
+        images.sort_by_key(|image| image.last_used);
+
+        for image in images {
+            if total <= max {
+                break;
+            }
+            if image.path == keep || in_use.contains(&image.path) {
+                continue;
+            }
+
+            debug!("Evicting {} from the image cache", image.path.display());
+            if let Err(e) = std::fs::remove_file(&image.path) {
+                warn!("Cannot evict {}: {}", image.path.display(), e);
+                continue;
+            }
+            for sidecar in sidecars(&image.path) {
+                let _ = std::fs::remove_file(sidecar);
+            }
+            self.digests.forget(&image.path);
+            self.last_used.lock().unwrap().remove(&image.path);
+            total = total.saturating_sub(image.size);
+
+            let id = image.path.strip_prefix(&self.cache).unwrap_or(&image.path);
+            info!("Evicted image {} from the cache", id.display());
+            if let Some(events) = &self.events {
+                events.record(
+                    "image.evicted",
+                    None,
+                    serde_json::json!({ "image": id, "size": image.size }),
+                );
+            }
+        }
+
+        if total > max {
+            warn!(
+                "Image cache holds {} bytes, over its maximum of {}, with every other image in use",
+                total, max
+            );
+        }
+    }
+
+    /// Paths in the cache of the images of the VMs which have not ended
+    async fn images_in_use(&self) -> HashSet<PathBuf> {
+        let Some(state) = &self.state else {
+            return HashSet::new();
+        };
+        let state = state.lock().await;
+        state
+            .vms
+            .iter()
+            .filter(|vm| !matches!(vm.status, VMStatus::Exited | VMStatus::Terminated))
+            .flat_map(|vm| {
+                vm.images
+                    .kernel
+                    .iter()
+                    .chain(vm.images.initrd.iter())
+                    .chain(vm.images.disks.iter())
+                    .map(|id| self.cache.join(id))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// Look `manifest` up in the cache, downloading it if needed, one lookup
+    /// at a time for each image.
+    async fn fetch(&self, manifest: &ImageManifest) -> Result<Image, Error> {
+        let path = self.cache.join(&manifest.id);
+        let lock = self
+            .in_flight
+            .lock()
+            .unwrap()
+            .entry(path.clone())
+            .or_default()
+            .clone();
+        let in_flight = InFlight {
+            in_flight: &self.in_flight,
+            path,
+            lock,
+        };
+
+        let _guard = in_flight.lock.lock().await;
+        self.find_or_download(manifest).await
+    }
+
+    async fn find_or_download(&self, manifest: &ImageManifest) -> Result<Image, Error> {
+        if let Some(image) = self.find_in_cache(manifest).await {
+            match self.digests.verify(manifest, &image).await {
+                Ok(()) => {
+                    debug!("Found image {} in cache", image.id);
+                    self.touch(&image.path);
+                    return Ok(image);
+                }
+                Err(e) => {
+                    warn!("Downloading cached image {} again: {}", image.id, e);
+                    self.digests.forget(&image.path);
+                    tokio::fs::remove_file(&image.path).await?;
+                }
+            }
+        }
+
+        let image = self.download_image(manifest).await?;
+        self.touch(&image.path);
+        self.evict(&image.path).await;
+        Ok(image)
+    }
+
     async fn find_in_cache(&self, image: &ImageManifest) -> Option<Image> {
         let path = self.cache.join(image.id.clone());
 
@@ -49,7 +233,7 @@ impl UrlImageManager {
         }
 
         let content_length = response.content_length();
-        let step = content_length.unwrap_or(10_000_000) / 20;
+        let step = (content_length.unwrap_or(10_000_000) / 20).max(1);
 
         if let Some(content_length) = content_length {
             info!("Content length: {}", content_length);
@@ -58,8 +242,13 @@ impl UrlImageManager {
         }
         trace!("Step: {}", step);
 
-        let mut file = tokio::fs::File::create(path.clone().with_extension(".download")).await?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let download = PartialDownload(unique_download_path(&path));
+        let mut file = tokio::fs::File::create(&download.0).await?;
         let mut byte_stream = response.bytes_stream();
+        let mut hasher = Sha256::new();
 
         let mut read = 0;
 
@@ -76,18 +265,61 @@ impl UrlImageManager {
 
             read += item.len();
 
+            hasher.update(&item);
             tokio::io::copy(&mut item.as_ref(), &mut file).await?;
         }
+        file.flush().await?;
 
-        tokio::fs::rename(path.with_extension(".download"), &path).await?;
+        let digest = check_download(image, content_length, read as u64, hasher)?;
+
+        self.digests.forget(&path);
+        tokio::fs::rename(&download.0, &path).await?;
+        if let Err(e) = audit::record(&path, image, &digest) {
+            warn!("Cannot record the digest of image {}: {}", image.id, e);
+        }
+        self.download_profile(image, &path).await;
 
         info!("Downloaded image {} to {}", image.id, path.display());
+        if let Some(events) = &self.events {
+            events.record(
+                "image.downloaded",
+                None,
+                serde_json::json!({ "image": image.id, "location": image.location, "size": read }),
+            );
+        }
 
         Ok(Image {
             id: image.id.to_string(),
             path,
         })
     }
+
+    /// Download the boot profile published next to the image, if there is
+    /// one.
+    async fn download_profile(&self, image: &ImageManifest, path: &Path) {
+        let location = format!("{}{}", image.location, profile::PROFILE_SUFFIX);
+        let profile = match reqwest::get(&location).await {
+            Ok(response) if response.status().is_success() => response.bytes().await,
+            Ok(response) => {
+                trace!("No boot profile at {}: {}", location, response.status());
+                return;
+            }
+            Err(e) => {
+                debug!("Cannot download boot profile {}: {}", location, e);
+                return;
+            }
+        };
+
+        let result = match profile {
+            Ok(profile) => tokio::fs::write(profile::profile_path(path), profile)
+                .await
+                .map_err(Error::from),
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = result {
+            warn!("Cannot save boot profile of image {}: {}", image.id, e);
+        }
+    }
 }
 
 #[async_trait::async_trait]
@@ -95,11 +327,20 @@ impl ImageManager for UrlImageManager {
     async fn find_disk(&self, manifest: &ImageManifest) -> Result<Image, Error> {
         trace!("find_disk {}, {}", manifest.id, manifest.location);
 
-        if let Some(image) = self.find_in_cache(manifest).await {
-            debug!("Found image {} in cache", image.id);
-            return Ok(image);
-        } else {
-            self.download_image(manifest).await
+        self.fetch(manifest).await
+    }
+
+    async fn describe(&self, manifest: &ImageManifest) -> ImageSource {
+        let path = self.cache.join(&manifest.id);
+        ImageSource {
+            id: manifest.id.clone(),
+            origin: if path.exists() {
+                ImageOrigin::Cache
+            } else {
+                ImageOrigin::Download
+            },
+            path,
+            pinned: manifest.sha256.is_some(),
         }
     }
 
@@ -111,3 +352,130 @@ impl ImageManager for UrlImageManager {
         self.find_disk(manifest).await
     }
 }
+
+/// Lookup of an image in progress, which forgets the lock of the image once
+/// no other lookup waits for it, even if the lookup is cancelled
+struct InFlight<'a> {
+    in_flight: &'a Mutex<HashMap<PathBuf, Arc<tokio::sync::Mutex<()>>>>,
+    path: PathBuf,
+    lock: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        // Only the map and this lookup hold the lock when nobody waits
+        if Arc::strong_count(&self.lock) == 2 {
+            in_flight.remove(&self.path);
+        }
+    }
+}
+
+/// File an image is downloaded to, removed unless it was moved into the
+/// cache
+struct PartialDownload(PathBuf);
+
+impl Drop for PartialDownload {
+    fn drop(&mut self) {
+        if self.0.exists() {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+}
+
+/// Path next to `image` to download it to, unique to the download so that
+/// no two downloads write to the same file
+fn unique_download_path(image: &Path) -> PathBuf {
+    let mut path = image.as_os_str().to_owned();
+    path.push(format!(".{}.download", Uuid::new_v4().simple()));
+    PathBuf::from(path)
+}
+
+/// Refuse a download shorter than announced, or without the pinned digest,
+/// returning the digest of the download.
+fn check_download(
+    image: &ImageManifest,
+    content_length: Option<u64>,
+    read: u64,
+    hasher: Sha256,
+) -> Result<String, Error> {
+    if let Some(content_length) = content_length.filter(|length| *length != read) {
+        return Err(anyhow::anyhow!(
+            "Truncated download of image {}: got {} of {} bytes",
+            image.id,
+            read,
+            content_length
+        ));
+    }
+
+    let digest = hex::encode(hasher.finish());
+    match &image.sha256 {
+        Some(expected) if !expected.eq_ignore_ascii_case(&digest) => Err(anyhow::anyhow!(
+            "Digest mismatch for image {}: expected {}, got {}",
+            image.id,
+            expected,
+            digest
+        )),
+        _ => Ok(digest),
+    }
+}
+
+/// Suffixes of the files kept next to an image: its boot profile, the
+/// signature it was promoted with and the manifest it was stored from
+pub(super) const SIDECAR_SUFFIXES: [&str; 3] =
+    [profile::PROFILE_SUFFIX, ".sig", audit::MANIFEST_SUFFIX];
+
+fn sidecars(image: &Path) -> impl Iterator<Item = PathBuf> + '_ {
+    SIDECAR_SUFFIXES.iter().map(move |suffix| {
+        let mut path = image.as_os_str().to_owned();
+        path.push(suffix);
+        PathBuf::from(path)
+    })
+}
+
+/// An image of the cache, which can be evicted
+struct CachedImage {
+    path: PathBuf,
+    /// Size of the image and of its sidecar files
+    size: u64,
+    /// Last access to the file, until the image is used again
+    last_used: SystemTime,
+}
+
+/// List the images in `folder`, adding the size of all its files to
+/// `total`.
+fn cached_images(
+    folder: &Path,
+    images: &mut Vec<CachedImage>,
+    total: &mut u64,
+) -> Result<(), Error> {
+    for entry in std::fs::read_dir(folder)? {
+        let entry = entry?;
+        let path = entry.path();
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            cached_images(&path, images, total)?;
+            continue;
+        }
+        *total += metadata.len();
+
+        let name = entry.file_name().to_string_lossy().to_string();
+        // Downloads in progress and sidecar files go with their image
+        if name.ends_with("download") || SIDECAR_SUFFIXES.iter().any(|s| name.ends_with(s)) {
+            continue;
+        }
+        let sidecars_size: u64 = sidecars(&path)
+            .filter_map(|sidecar| std::fs::metadata(sidecar).ok())
+            .map(|metadata| metadata.len())
+            .sum();
+        images.push(CachedImage {
+            size: metadata.len() + sidecars_size,
+            last_used: metadata
+                .accessed()
+                .or_else(|_| metadata.modified())
+                .unwrap_or(SystemTime::UNIX_EPOCH),
+            path,
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,402 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Result};
+use openssl::{hash::MessageDigest, pkey::PKey, sign::Signer};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tracing::{debug, info, trace};
+
+use super::{
+    now_ms,
+    state::{LambdoState, LambdoStateRef},
+};
+
+/// Maximum size of a request head
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+/// Path prefix of the share links
+const LINK_PREFIX: &str = "/s/";
+
+/// Link routing to a mapped guest port of a VM until it expires, without
+/// exposing the host port
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShareLink {
+    pub id: String,
+    pub vm_id: String,
+    /// Guest port the link routes to
+    pub port: u16,
+    /// In ms since epoch
+    pub created_at: u64,
+    /// In ms since epoch
+    pub expires_at: u64,
+    pub url: String,
+}
+
+impl ShareLink {
+    /// Link to `port` of `vm_id` for `ttl_seconds`, signed with the share
+    /// key of `state`
+    pub fn new(state: &LambdoState, vm_id: &str, port: u16, ttl_seconds: u64) -> Result<Self> {
+        let created_at = now_ms();
+        let mut link = ShareLink {
+            id: uuid::Uuid::new_v4().simple().to_string(),
+            vm_id: vm_id.to_string(),
+            port,
+            created_at,
+            expires_at: created_at + ttl_seconds * 1000,
+            url: String::new(),
+        };
+        link.url = format!(
+            "{}{}{}/",
+            state.config.api.ingress_proxy.public_url(),
+            LINK_PREFIX,
+            link.token(&state.share_key)?
+        );
+        Ok(link)
+    }
+
+    /// Token of the URL of the link
+    fn token(&self, key: &[u8]) -> Result<String> {
+        Ok(format!(
+            "{}.{}.{}",
+            self.id,
+            self.expires_at,
+            self.signature(key)?
+        ))
+    }
+
+    /// Signature of everything the link gives access to
+    fn signature(&self, key: &[u8]) -> Result<String> {
+        let key = PKey::hmac(key)?;
+        let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+        signer.update(
+            format!(
+                "{}.{}.{}.{}",
+                self.id, self.vm_id, self.port, self.expires_at
+            )
+            .as_bytes(),
+        )?;
+        Ok(hex::encode(signer.sign_to_vec()?))
+    }
+}
+
+/// Connections the ingress proxy has open to each VM, by id
+#[derive(Debug, Clone, Default)]
+pub struct Connections(Arc<Mutex<HashMap<String, usize>>>);
+
+impl Connections {
+    /// Count a connection to `vm_id` until the returned guard is dropped
+    pub fn open(&self, vm_id: &str) -> Connection {
+        let mut counts = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        *counts.entry(vm_id.to_string()).or_default() += 1;
+        Connection {
+            connections: self.clone(),
+            vm_id: vm_id.to_string(),
+        }
+    }
+
+    /// Number of connections open to `vm_id`
+    pub fn count(&self, vm_id: &str) -> usize {
+        let counts = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        counts.get(vm_id).copied().unwrap_or_default()
+    }
+}
+
+/// Connection counted in [`Connections`] until dropped
+pub struct Connection {
+    connections: Connections,
+    vm_id: String,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let mut counts = self.connections.0.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = counts.get_mut(&self.vm_id) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.vm_id);
+            }
+        }
+    }
+}
+
+/// Run the ingress proxy until its listener fails.
+///
+/// Requests to `/s/<token>/<path>` are forwarded as requests to `/<path>` to
+/// the guest port of the share link of the token, if it is valid, has not
+/// expired and was not revoked.
+pub async fn run(state: LambdoStateRef) -> Result<()> {
+    let address = {
+        let state = state.lock().await;
+        let config = &state.config.api.ingress_proxy;
+        format!("{}:{}", config.host, config.port)
+    };
+    let listener = TcpListener::bind(&address)
+        .await
+        .map_err(|e| anyhow!("cannot bind ingress proxy on {}: {}", address, e))?;
+    info!("ingress proxy listening on {}", address);
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let state = state.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, peer, state).await {
+                debug!("ingress proxy connection from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn handle(mut client: TcpStream, peer: SocketAddr, state: LambdoStateRef) -> Result<()> {
+    let mut buffer = Vec::with_capacity(4096);
+    let head_end = loop {
+        if let Some(index) = find_head_end(&buffer) {
+            break index;
+        }
+        if buffer.len() > MAX_HEAD_SIZE {
+            return Err(anyhow!("request head too large"));
+        }
+
+        let mut chunk = [0u8; 4096];
+        let read = client.read(&mut chunk).await?;
+        if read == 0 {
+            return Err(anyhow!("connection closed before end of request head"));
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    };
+
+    let head = String::from_utf8_lossy(&buffer[..head_end]).to_string();
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next().unwrap_or_default();
+    trace!("ingress proxy request from {}: {}", peer, request_line);
+
+    let mut parts = request_line.split_whitespace();
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) => (method, target, version),
+        _ => {
+            client
+                .write_all(response(400, "Bad Request").as_bytes())
+                .await?;
+            return Err(anyhow!("malformed request line"));
+        }
+    };
+
+    let Some((token, path)) = split_target(target) else {
+        client
+            .write_all(response(404, "Not Found").as_bytes())
+            .await?;
+        return Ok(());
+    };
+
+    let Some((vm_id, upstream_address)) = resolve(&state, token).await else {
+        debug!("ingress proxy refused an invalid link for {}", peer);
+        client
+            .write_all(response(404, "Not Found").as_bytes())
+            .await?;
+        return Ok(());
+    };
+
+    // Counted from before the upstream connection is made, for a VM being
+    // drained not to be destroyed under it
+    let _connection = state.lock().await.proxied.open(&vm_id);
+    let mut upstream = match TcpStream::connect(upstream_address).await {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            client
+                .write_all(response(502, "Bad Gateway").as_bytes())
+                .await?;
+            return Err(anyhow!("cannot connect to {}: {}", upstream_address, e));
+        }
+    };
+
+    let forwarded = forwarded_head(&format!("{} {} {}", method, path, version), lines, token);
+    upstream.write_all(forwarded.as_bytes()).await?;
+    upstream.write_all(&buffer[head_end..]).await?;
+
+    tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+    Ok(())
+}
+
+/// Token of the share link a request `target` goes through, and the path
+/// it is forwarded to, if it goes through one
+fn split_target(target: &str) -> Option<(&str, String)> {
+    let rest = target.strip_prefix(LINK_PREFIX)?;
+    let (token, path) = rest.split_at(rest.find(['/', '?']).unwrap_or(rest.len()));
+    let path = match path {
+        "" => "/".to_string(),
+        path if path.starts_with('?') => format!("/{}", path),
+        path => path.to_string(),
+    };
+    Some((token, path))
+}
+
+/// Head of the request forwarded to the guest, with `request_line` and the
+/// header `lines` of the request of the client.
+///
+/// Requests are routed by their path, so the connection only carries this
+/// one, the next ones coming on new connections.
+fn forwarded_head<'a>(
+    request_line: &str,
+    lines: impl Iterator<Item = &'a str>,
+    token: &str,
+) -> String {
+    let mut forwarded = format!("{}\r\n", request_line);
+    for line in lines.filter(|line| !line.is_empty()) {
+        let name = line.split(':').next().unwrap_or_default().trim();
+        if !name.eq_ignore_ascii_case("connection") {
+            forwarded.push_str(line);
+            forwarded.push_str("\r\n");
+        }
+    }
+    forwarded.push_str(&format!(
+        "Connection: close\r\nX-Forwarded-Prefix: {}{}\r\n\r\n",
+        LINK_PREFIX, token
+    ));
+    forwarded
+}
+
+/// Share link of `token` among `links`, if it has not expired by `now` and
+/// its signature with `key` matches
+fn verify<'a>(
+    links: &'a HashMap<String, ShareLink>,
+    key: &[u8],
+    token: &str,
+    now: u64,
+) -> Option<&'a ShareLink> {
+    let mut parts = token.splitn(3, '.');
+    let (id, expires_at, signature) = (parts.next()?, parts.next()?, parts.next()?);
+
+    let link = links.get(id)?;
+    if expires_at.parse::<u64>().ok()? != link.expires_at || link.expires_at <= now {
+        return None;
+    }
+    let expected = link.signature(key).ok()?;
+    // Compared in constant time, not to leak how much of a signature matched
+    if expected.len() != signature.len()
+        || !openssl::memcmp::eq(expected.as_bytes(), signature.as_bytes())
+    {
+        return None;
+    }
+    Some(link)
+}
+
+/// VM and address of the guest port the share link of `token` routes to, if
+/// the link exists, has not expired and its signature matches
+async fn resolve(state: &LambdoStateRef, token: &str) -> Option<(String, SocketAddr)> {
+    let state = state.lock().await;
+    let link = verify(&state.share_links, &state.share_key, token, now_ms())?;
+
+    // The port may have been unmapped since
+    let vm = state.vms.iter().find(|vm| vm.get_id() == link.vm_id)?;
+    if !vm.port_mapping.values().any(|port| *port == link.port) {
+        return None;
+    }
+    let ip = vm.ip?.address();
+    Some((link.vm_id.clone(), SocketAddr::new(ip.into(), link.port)))
+}
+
+fn find_head_end(buffer: &[u8]) -> Option<usize> {
+    buffer
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map(|index| index + 4)
+}
+
+fn response(status: u16, reason: &str) -> String {
+    format!(
+        "HTTP/1.1 {} {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        status, reason
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = b"share-key";
+    const NOW: u64 = 1_000_000;
+
+    fn links() -> HashMap<String, ShareLink> {
+        let link = ShareLink {
+            id: "link".to_string(),
+            vm_id: "vm".to_string(),
+            port: 80,
+            created_at: NOW - 1000,
+            expires_at: NOW + 1000,
+            url: String::new(),
+        };
+        HashMap::from([(link.id.clone(), link)])
+    }
+
+    #[test]
+    fn splits_the_token_from_the_forwarded_path() {
+        for (target, token, path) in [
+            ("/s/abc", "abc", "/"),
+            ("/s/abc/", "abc", "/"),
+            ("/s/abc/static/app.js", "abc", "/static/app.js"),
+            ("/s/abc?page=2", "abc", "/?page=2"),
+            ("/s/abc/search?q=a/b", "abc", "/search?q=a/b"),
+        ] {
+            assert_eq!(
+                split_target(target),
+                Some((token, path.to_string())),
+                "{}",
+                target
+            );
+        }
+        assert_eq!(split_target("/abc/"), None);
+        assert_eq!(split_target("/share/abc"), None);
+    }
+
+    #[test]
+    fn forwards_the_headers_closing_the_connection() {
+        let head = "Host: preview.example.com\r\nConnection: keep-alive\r\nconnection: upgrade\r\nAccept: */*\r\n\r\n";
+        let forwarded = forwarded_head("GET /app HTTP/1.1", head.split("\r\n"), "abc");
+
+        assert_eq!(
+            forwarded,
+            "GET /app HTTP/1.1\r\nHost: preview.example.com\r\nAccept: */*\r\nConnection: close\r\nX-Forwarded-Prefix: /s/abc\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn accepts_the_tokens_of_links() {
+        let links = links();
+        let token = links["link"].token(KEY).unwrap();
+
+        let link = verify(&links, KEY, &token, NOW).unwrap();
+        assert_eq!((link.vm_id.as_str(), link.port), ("vm", 80));
+    }
+
+    #[test]
+    fn refuses_forged_and_expired_tokens() {
+        let links = links();
+        let token = links["link"].token(KEY).unwrap();
+        let signature = token.rsplit('.').next().unwrap();
+
+        let forged = [
+            // Signed with another key
+            links["link"].token(b"other-key").unwrap(),
+            // Valid for longer than the link
+            format!("link.{}.{}", NOW + 5000, signature),
+            // Of a link that does not exist, or was revoked
+            format!("other.{}.{}", NOW + 1000, signature),
+            format!("link.{}.{}", NOW + 1000, &signature[1..]),
+            "link".to_string(),
+            String::new(),
+        ];
+        for token in forged {
+            assert!(verify(&links, KEY, &token, NOW).is_none(), "{}", token);
+        }
+        assert!(verify(&links, KEY, &token, NOW + 1000).is_none());
+        assert!(verify(&HashMap::new(), KEY, &token, NOW).is_none());
+    }
+}
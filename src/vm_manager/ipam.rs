@@ -0,0 +1,286 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::Ipv4Addr,
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Result};
+use cidr::Ipv4Inet;
+use tracing::{debug, trace};
+
+use crate::config::BridgeConfig;
+
+/// Addresses of a bridge handed out to VMs.
+///
+/// Allocation goes round the range from the address after the last one
+/// handed out, so that the address of a VM that just stopped is not reused
+/// right away.
+struct IpPool {
+    /// Address of the bridge, with the prefix length of its subnet
+    gateway: Ipv4Inet,
+    /// First and last addresses of the range, as integers
+    start: u32,
+    end: u32,
+    /// Whether each address of the range is allocated, from `start`
+    allocated: Vec<u64>,
+    /// Offset from `start` the next allocation starts looking at
+    next: u32,
+}
+
+impl IpPool {
+    fn new(bridge: &BridgeConfig) -> Result<Self> {
+        let gateway = Ipv4Inet::from_str(&bridge.bridge_address)
+            .map_err(|e| anyhow!("invalid bridge address: {}", e))?;
+        let subnet = gateway.network();
+        // The network and broadcast addresses cannot be handed out
+        let (mut start, mut end) = (
+            u32::from(subnet.first_address()).saturating_add(1),
+            u32::from(subnet.last_address()).saturating_sub(1),
+        );
+
+        if let Some(range) = &bridge.ip_range {
+            let parse = |address: &str| {
+                Ipv4Addr::from_str(address)
+                    .map_err(|e| anyhow!("invalid address {} in IP range: {}", address, e))
+            };
+            let (first, last) = (parse(&range.start)?, parse(&range.end)?);
+            if !subnet.contains(&first) || !subnet.contains(&last) || first > last {
+                return Err(anyhow!(
+                    "IP range {}-{} is not a range of {}",
+                    first,
+                    last,
+                    subnet
+                ));
+            }
+            start = start.max(u32::from(first));
+            end = end.min(u32::from(last));
+        }
+        if start > end {
+            return Err(anyhow!("no address to hand out in {}", subnet));
+        }
+
+        let size = (end - start) as usize + 1;
+        Ok(IpPool {
+            gateway,
+            start,
+            end,
+            allocated: vec![0; size.div_ceil(64)],
+            next: 0,
+        })
+    }
+
+    fn size(&self) -> u32 {
+        self.end - self.start + 1
+    }
+
+    fn is_allocated(&self, offset: u32) -> bool {
+        self.allocated[offset as usize / 64] & (1 << (offset % 64)) != 0
+    }
+
+    fn set(&mut self, address: Ipv4Addr, allocated: bool) {
+        let address = u32::from(address);
+        if address < self.start || address > self.end {
+            return;
+        }
+        let offset = address - self.start;
+        let bit = 1 << (offset % 64);
+        let word = &mut self.allocated[offset as usize / 64];
+        if allocated {
+            *word |= bit;
+        } else {
+            *word &= !bit;
+        }
+    }
+
+    /// Allocate the next address that is neither allocated, held by
+    /// something else in `held`, nor the bridge itself.
+    fn allocate(&mut self, held: &HashSet<Ipv4Addr>) -> Option<Ipv4Addr> {
+        let size = self.size();
+        let found = (0..size).map(|i| (self.next + i) % size).find(|&offset| {
+            let address = Ipv4Addr::from(self.start + offset);
+            !self.is_allocated(offset)
+                && address != self.gateway.address()
+                && !held.contains(&address)
+        })?;
+
+        let address = Ipv4Addr::from(self.start + found);
+        self.set(address, true);
+        self.next = (found + 1) % size;
+        Some(address)
+    }
+
+    fn range(&self) -> String {
+        format!(
+            "{}-{}",
+            Ipv4Addr::from(self.start),
+            Ipv4Addr::from(self.end)
+        )
+    }
+}
+
+/// Addresses handed out to the VMs, by bridge.
+///
+/// Only the addresses allocated since lambdo started are tracked here, the
+/// ones of reattached VMs and of leases being passed in as held.
+#[derive(Default)]
+pub struct Ipam {
+    pools: HashMap<String, IpPool>,
+    /// Bridge and address of each VM an address was allocated to
+    owners: HashMap<String, (String, Ipv4Addr)>,
+}
+
+impl Ipam {
+    /// Allocate an address of `bridge` to the VM `vm_id`, skipping the ones
+    /// in `held`, and failing once every address of the range is taken.
+    pub fn allocate(
+        &mut self,
+        vm_id: &str,
+        bridge: &BridgeConfig,
+        held: &HashSet<Ipv4Addr>,
+    ) -> Result<Ipv4Inet> {
+        if !self.pools.contains_key(&bridge.bridge) {
+            self.pools
+                .insert(bridge.bridge.clone(), IpPool::new(bridge)?);
+        }
+        let pool = self.pools.get_mut(&bridge.bridge).unwrap();
+
+        trace!("held addresses of {}: {:?}", bridge.bridge, held);
+        let address = pool.allocate(held).ok_or_else(|| {
+            anyhow!(
+                "every address of {} on {} is in use",
+                pool.range(),
+                bridge.bridge
+            )
+        })?;
+        let ip = Ipv4Inet::new(address, pool.gateway.network_length())
+            .map_err(|e| anyhow!("invalid address {}: {}", address, e))?;
+
+        debug!("Allocated {} to VM {}", address, vm_id);
+        if let Some((bridge, previous)) = self
+            .owners
+            .insert(vm_id.to_string(), (bridge.bridge.clone(), address))
+        {
+            self.free(&bridge, previous);
+        }
+        Ok(ip)
+    }
+
+    /// Release the address allocated to the VM `vm_id`, if any.
+    pub fn release(&mut self, vm_id: &str) {
+        if let Some((bridge, address)) = self.owners.remove(vm_id) {
+            debug!("Released {} of VM {}", address, vm_id);
+            self.free(&bridge, address);
+        }
+    }
+
+    fn free(&mut self, bridge: &str, address: Ipv4Addr) {
+        if let Some(pool) = self.pools.get_mut(bridge) {
+            pool.set(address, false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::IpRangeConfig;
+
+    use super::*;
+
+    fn bridge(address: &str, range: Option<(&str, &str)>) -> BridgeConfig {
+        BridgeConfig {
+            bridge: "lambdo0".to_string(),
+            bridge_address: address.to_string(),
+            bridge_address_v6: None,
+            vlan_id: None,
+            uplink_interface: None,
+            snat_address: None,
+            ip_range: range.map(|(start, end)| IpRangeConfig {
+                start: start.to_string(),
+                end: end.to_string(),
+            }),
+        }
+    }
+
+    fn allocate(ipam: &mut Ipam, vm_id: &str, bridge: &BridgeConfig) -> Result<String> {
+        ipam.allocate(vm_id, bridge, &HashSet::new())
+            .map(|ip| ip.to_string())
+    }
+
+    #[test]
+    fn skips_the_bridge_and_the_held_addresses() {
+        let mut ipam = Ipam::default();
+        let bridge = bridge("10.0.0.1/24", None);
+
+        assert_eq!(allocate(&mut ipam, "a", &bridge).unwrap(), "10.0.0.2/24");
+        let held = HashSet::from([Ipv4Addr::new(10, 0, 0, 3)]);
+        let ip = ipam.allocate("b", &bridge, &held).unwrap();
+        assert_eq!(ip.to_string(), "10.0.0.4/24");
+    }
+
+    #[test]
+    fn wraps_around_the_range_after_the_last_address_handed_out() {
+        let mut ipam = Ipam::default();
+        let bridge = bridge("10.0.0.1/24", Some(("10.0.0.10", "10.0.0.12")));
+
+        assert_eq!(allocate(&mut ipam, "a", &bridge).unwrap(), "10.0.0.10/24");
+        assert_eq!(allocate(&mut ipam, "b", &bridge).unwrap(), "10.0.0.11/24");
+        ipam.release("a");
+        // The address just released is not the next one handed out
+        assert_eq!(allocate(&mut ipam, "c", &bridge).unwrap(), "10.0.0.12/24");
+        assert_eq!(allocate(&mut ipam, "d", &bridge).unwrap(), "10.0.0.10/24");
+    }
+
+    #[test]
+    fn fails_once_every_address_is_taken() {
+        let mut ipam = Ipam::default();
+        let bridge = bridge("10.0.0.1/24", Some(("10.0.0.10", "10.0.0.11")));
+
+        allocate(&mut ipam, "a", &bridge).unwrap();
+        allocate(&mut ipam, "b", &bridge).unwrap();
+        assert!(allocate(&mut ipam, "c", &bridge).is_err());
+
+        ipam.release("a");
+        assert_eq!(allocate(&mut ipam, "c", &bridge).unwrap(), "10.0.0.10/24");
+    }
+
+    #[test]
+    fn releases_only_the_address_of_the_vm() {
+        let mut ipam = Ipam::default();
+        let bridge = bridge("10.0.0.1/24", Some(("10.0.0.10", "10.0.0.10")));
+
+        allocate(&mut ipam, "a", &bridge).unwrap();
+        ipam.release("unknown");
+        assert!(allocate(&mut ipam, "b", &bridge).is_err());
+        ipam.release("a");
+        ipam.release("a");
+        assert_eq!(allocate(&mut ipam, "b", &bridge).unwrap(), "10.0.0.10/24");
+    }
+
+    #[test]
+    fn hands_out_what_small_subnets_have_left() {
+        let mut ipam = Ipam::default();
+        // Network, bridge, VM and broadcast addresses
+        let bridge = bridge("10.0.0.1/30", None);
+        assert_eq!(allocate(&mut ipam, "a", &bridge).unwrap(), "10.0.0.2/30");
+        assert!(allocate(&mut ipam, "b", &bridge).is_err());
+
+        // No address but the bridge's, each on a bridge of its own
+        for (name, address) in [("lambdo1", "10.0.1.0/31"), ("lambdo2", "10.0.2.1/32")] {
+            let small = BridgeConfig {
+                bridge: name.to_string(),
+                bridge_address: address.to_string(),
+                ..bridge.clone()
+            };
+            assert!(allocate(&mut ipam, "c", &small).is_err(), "{}", address);
+        }
+    }
+
+    #[test]
+    fn refuses_ranges_outside_of_the_subnet() {
+        let mut ipam = Ipam::default();
+        for range in [("10.0.1.10", "10.0.1.20"), ("10.0.0.20", "10.0.0.10")] {
+            let bridge = bridge("10.0.0.1/24", Some(range));
+            assert!(allocate(&mut ipam, "a", &bridge).is_err(), "{:?}", range);
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use std::{
+    collections::HashMap,
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info};
+
+/// Addresses and host ports held by a firecracker process.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Lease {
+    /// Bridge the address belongs to
+    pub bridge: String,
+    pub ip: Option<Ipv4Addr>,
+    pub host_ports: Vec<u16>,
+    /// API socket of the firecracker process, which accepts connections as
+    /// long as the process runs
+    pub socket: PathBuf,
+}
+
+/// Leases of the VMs, saved to their own file on every change.
+///
+/// They are kept apart from the VM state so that, even when a VM cannot be
+/// reattached after a crash, its address and ports are not handed out again
+/// while its firecracker process may still be using them.
+pub struct Leases {
+    path: PathBuf,
+    leases: HashMap<String, Lease>,
+}
+
+impl Leases {
+    /// Read the leases from `path`, starting without any if it cannot be
+    /// read.
+    pub fn load(path: &str) -> Self {
+        let path = PathBuf::from(path);
+        let leases = match read(&path) {
+            Ok(leases) => leases,
+            Err(e) => {
+                error!("Error while loading leases: {:?}", e);
+                HashMap::new()
+            }
+        };
+        if !leases.is_empty() {
+            info!("loaded {} leases from {}", leases.len(), path.display());
+        }
+
+        Leases { path, leases }
+    }
+
+    pub fn acquire(&mut self, vm_id: &str, lease: Lease) {
+        debug!("VM {} leases {:?}", vm_id, lease);
+        self.leases.insert(vm_id.to_string(), lease);
+        self.save();
+    }
+
+    pub fn update_ports(&mut self, vm_id: &str, host_ports: Vec<u16>) {
+        if let Some(lease) = self.leases.get_mut(vm_id) {
+            lease.host_ports = host_ports;
+            self.save();
+        }
+    }
+
+    pub fn release(&mut self, vm_id: &str) {
+        if self.leases.remove(vm_id).is_some() {
+            debug!("VM {} released its lease", vm_id);
+            self.save();
+        }
+    }
+
+    /// Addresses leased on `bridge`
+    pub fn ips<'a>(&'a self, bridge: &'a str) -> impl Iterator<Item = Ipv4Addr> + 'a {
+        self.leases
+            .values()
+            .filter(move |lease| lease.bridge == bridge)
+            .filter_map(|lease| lease.ip)
+    }
+
+    pub fn host_ports(&self) -> impl Iterator<Item = u16> + '_ {
+        self.leases
+            .values()
+            .flat_map(|lease| lease.host_ports.iter().copied())
+    }
+
+    pub fn all(&self) -> &HashMap<String, Lease> {
+        &self.leases
+    }
+
+    /// Leases of VMs other than `known`, with their socket
+    pub fn orphans(&self, known: &[String]) -> Vec<(String, PathBuf)> {
+        self.leases
+            .iter()
+            .filter(|(vm_id, _)| !known.contains(vm_id))
+            .map(|(vm_id, lease)| (vm_id.clone(), lease.socket.clone()))
+            .collect()
+    }
+
+    fn save(&self) {
+        if let Err(e) = write(&self.path, &self.leases) {
+            error!("Error while saving leases: {:?}", e);
+        }
+    }
+}
+
+fn read(path: &Path) -> Result<HashMap<String, Lease>> {
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+    let content = std::fs::read(path)
+        .map_err(|e| anyhow!("cannot read lease file {}: {}", path.display(), e))?;
+    serde_json::from_slice(&content)
+        .map_err(|e| anyhow!("cannot parse lease file {}: {}", path.display(), e))
+}
+
+fn write(path: &Path, leases: &HashMap<String, Lease>) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, serde_json::to_vec_pretty(leases)?)?;
+    std::fs::rename(&tmp, path)
+        .map_err(|e| anyhow!("cannot write lease file {}: {}", path.display(), e))
+}
@@ -4,27 +4,150 @@ use network_interface::{NetworkInterface, NetworkInterfaceConfig};
 use serde::{Deserialize, Serialize};
 use tokio::process::Command;
 
+pub use vmm::firewall::{FirewallRule, RuleKind};
 pub use vmm::Error;
+pub use vmm::{
+    boot_test, firecracker_version, host_memory_mib, DEFAULT_BOOT_ARGS, DEFAULT_MEM_SIZE_MIB,
+    DEFAULT_VCPU_COUNT,
+};
+
+use crate::config::{
+    BridgeConfig, FunctionConfig, HookConfig, HookPoint, ImageManagerConfig, LambdoApiConfig,
+    NamespaceConfig, NetworkMode, ShutdownAction,
+};
 
 use anyhow::anyhow;
 
-use std::{collections::HashMap, net::IpAddr, str::FromStr};
-use tracing::{debug, error, info, trace};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::{debug, error, info, trace, trace_span, warn, Instrument};
 
 use self::{
+    agent::{AgentRequest, AgentResponse},
+    boot_watchdog::{wait_until_reachable, BootTarget},
+    cpu_accounting::TenantUsage,
+    idempotency::StartedVm,
     image_manager::{Image, ImageManifest},
-    state::LambdoStateRef,
-    vmm::{start, stop},
+    ingress_proxy::ShareLink,
+    net_accounting::NetworkUsage,
+    node::{Cordon, NodeStatus},
+    persistence::{persist, PersistedVM},
+    port_allocator::PortOwner,
+    restart::RestartPolicy,
+    state::{
+        function_of_key, function_vm_key, LambdoStateRef, NamespaceUsage, StackMember, VMDetails,
+        VMStatus,
+    },
+    vm_snapshots::SnapshotPolicy,
+    vmm::{
+        adopt, cancel_destroy, firewall::Firewall, pause, pause_vm, reap, reattach, remove_leaked,
+        restart_due, resume, resume_vm, rollback, schedule_destroy, schedule_restart, set_ttl,
+        start, stop, update_ports,
+    },
+    volume_manager::file_driver::copy_file,
 };
 
+/// How often VMs are checked for a firecracker process that exited
+const EXIT_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+/// How often VMs are checked for an expired time to live
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// How often VMs are checked for a snapshot to take
+const SNAPSHOT_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// How often a VM being drained is checked for proxied requests
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+pub mod agent;
+pub mod boot_watchdog;
+pub mod cpu_accounting;
+pub mod egress_proxy;
+pub mod event_sinks;
+pub mod events;
+pub mod hooks;
+pub mod idempotency;
 pub mod image_manager;
+pub mod ingress_proxy;
+pub mod ipam;
+pub mod leases;
+pub mod net_accounting;
+pub mod node;
+pub mod persistence;
+pub mod port_allocator;
+pub mod restart;
+pub mod ssh;
+pub mod vm_snapshots;
 mod vmm;
+pub mod volume_manager;
+pub mod warm_pool;
+pub mod wireguard;
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct SimpleSpawn {
     pub rootfs: ImageManifest,
     #[serde(rename = "requestedPorts")]
     pub requested_ports: Vec<u16>,
+    /// Tenant the VM belongs to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    /// Time after which the VM is destroyed, in seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl_seconds: Option<u64>,
+}
+
+/// Network of a VM and the firewall rules isolating it, as lambdo applied
+/// them
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VMNetwork {
+    pub ip: Option<String>,
+    pub ip6: Option<String>,
+    pub bridge: String,
+    pub gateway: String,
+    /// Interface the traffic of the VM leaves the host through
+    pub uplink: Option<String>,
+    pub port_mapping: Vec<(u16, u16)>,
+    pub egress_profile: Option<String>,
+    /// Traffic of the VM, counted with network accounting
+    pub usage: NetworkUsage,
+    /// Rules applied for the VM alone, in order
+    pub rules: Vec<FirewallRule>,
+    /// Rules of the bridge, shared with the other VMs on it
+    pub bridge_rules: Vec<FirewallRule>,
+}
+
+/// Function registered through the API
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FunctionDTO {
+    pub name: String,
+    #[serde(flatten)]
+    pub function: FunctionConfig,
+}
+
+/// Code to run in a one-off VM of the runtime of its language
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RunRequest {
+    pub language: String,
+    pub code: String,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// Seconds the code may run, the maximum of the runtime if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RunResult {
+    pub exit_code: i32,
+    pub stdout: String,
+    pub stderr: String,
+    /// Time from the request to the exit of the code, in ms
+    pub duration_ms: u64,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -37,6 +160,15 @@ pub struct BootOptionsDTO {
     pub initrd: Option<ImageManifest>,
     /// Host level path to the kernel image used to boot the guest
     pub kernel: ImageManifest,
+    /// Kernel parameters set in the guest at boot
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub sysctls: HashMap<String, String>,
+    /// Kernel modules loaded in the guest at boot
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub kernel_modules: Vec<String>,
+    /// Attach the rootfs read-only, the guest writing to a tmpfs overlay
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub read_only_rootfs: bool,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -49,6 +181,15 @@ pub struct BootOptions {
     pub initrd: Option<Image>,
     /// Host level path to the kernel image used to boot the guest
     pub kernel: Image,
+    /// Kernel parameters set in the guest at boot
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub sysctls: HashMap<String, String>,
+    /// Kernel modules loaded in the guest at boot
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub kernel_modules: Vec<String>,
+    /// Attach the rootfs read-only, the guest writing to a tmpfs overlay
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub read_only_rootfs: bool,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -58,31 +199,204 @@ pub struct DiskOptionsDTO {
     pub is_root_device: bool,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct DiskOptions {
     pub image: Image,
     pub is_readonly: bool,
     pub is_root_device: bool,
+    /// Use the image in place instead of giving the VM its own copy
+    #[serde(default)]
+    pub is_persistent: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct VolumeAttachmentDTO {
+    /// Name of the volume to attach
+    pub name: String,
+    #[serde(default)]
+    pub is_readonly: bool,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct VMOptionsDTO {
     pub boot: BootOptionsDTO,
     pub disks: Vec<DiskOptionsDTO>,
+    /// Persistent volumes to attach to the VM
+    #[serde(default)]
+    pub volumes: Vec<VolumeAttachmentDTO>,
     pub network: NetworkOptions,
+    /// Tenant the VM belongs to, scoping its images and network
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    /// Number of vCPUs, 1 or an even number
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vcpu_count: Option<u8>,
+    /// Memory of the VM, in MiB
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mem_size_mib: Option<u32>,
+    /// Machine class giving the size of the VM, instead of `vcpu_count` and
+    /// `mem_size_mib`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub machine_class: Option<String>,
+    /// Snapshot the disks of the VM periodically, for it to be rolled back
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snapshot_policy: Option<SnapshotPolicy>,
+    /// Boot the VM again when its firecracker process exits on its own
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub restart_policy: Option<RestartPolicy>,
+    /// Time after which the VM is destroyed, in seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl_seconds: Option<u64>,
+    /// Namespace the VM goes to, the one of the token of the caller rather
+    /// than anything the request says
+    #[serde(skip)]
+    pub namespace: Option<String>,
+    /// Stack the VM is started as a service of, set by the stack it is
+    /// started for
+    #[serde(skip)]
+    pub stack: Option<StackMember>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct VMOptions {
     pub boot: BootOptions,
     pub disks: Vec<DiskOptions>,
     pub network: NetworkOptions,
+    #[serde(default)]
+    pub tenant: Option<String>,
+    #[serde(default)]
+    pub vcpu_count: Option<u8>,
+    #[serde(default)]
+    pub mem_size_mib: Option<u32>,
+    #[serde(default)]
+    pub machine_class: Option<String>,
+    #[serde(default)]
+    pub snapshot_policy: Option<SnapshotPolicy>,
+    #[serde(default)]
+    pub restart_policy: Option<RestartPolicy>,
+    #[serde(default)]
+    pub ttl_seconds: Option<u64>,
+    /// Namespace the VM goes to, the default one if unset
+    #[serde(default)]
+    pub namespace: Option<String>,
+    /// Stack the VM is started as a service of
+    #[serde(default)]
+    pub stack: Option<StackMember>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct NetworkOptions {
+    /// Host to guest port mappings, host port 0 picking any free host port
+    #[serde(default)]
+    pub port_mapping: Vec<(u16, u16)>,
+    /// Egress profile restricting the VM to the egress proxy
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub egress_profile: Option<String>,
+}
+
+/// Outcome of one of the checks made before starting a VM
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub name: String,
+    pub passed: bool,
+    pub detail: String,
+}
+
+impl Check {
+    pub fn new<E: std::fmt::Display>(name: &str, result: Result<String, E>) -> Self {
+        let (passed, detail) = match result {
+            Ok(detail) => (true, detail),
+            Err(e) => (false, e.to_string()),
+        };
+        Check {
+            name: name.to_string(),
+            passed,
+            detail,
+        }
+    }
+}
+
+/// What starting a VM would do, found without starting it
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StartPlan {
+    pub bridge: String,
+    pub boot_args: String,
+    pub vcpu_count: u8,
+    pub mem_size_mib: u32,
+    pub read_only_rootfs: bool,
+    pub checks: Vec<Check>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShareLinkDTO {
+    /// Mapped guest port the link routes to
+    pub port: u16,
+    /// Seconds the link is valid for
+    #[serde(default = "default_share_link_ttl")]
+    pub ttl_seconds: u64,
+}
+
+fn default_share_link_ttl() -> u64 {
+    3600
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PortsUpdateDTO {
+    /// Host to guest port mappings to add
+    #[serde(default)]
+    pub add: Vec<(u16, u16)>,
+    /// Host ports to stop forwarding
+    #[serde(default)]
+    pub remove: Vec<u16>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AdoptOptions {
+    /// Path to the API socket of the firecracker process
+    pub socket_path: PathBuf,
+    /// Id to register the VM with, a random one is generated otherwise
+    #[serde(default)]
+    pub id: Option<String>,
+    /// IP address of the guest, in CIDR notation
+    #[serde(default)]
+    pub ip: Option<String>,
+    /// Tap device used by the VM
+    #[serde(default)]
+    pub tap: Option<String>,
+    /// Host to guest port mapping already set up for the VM
     #[serde(default)]
     pub port_mapping: Vec<(u16, u16)>,
+    /// Namespace the VM goes to, the one of the token of the caller rather
+    /// than anything the request says
+    #[serde(skip)]
+    pub namespace: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// A gzipped tarball of every writable disk
+    Tarball,
+    /// The writable root disk, registered in the image store
+    Image,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ExportOptions {
+    pub format: ExportFormat,
+    /// Name of the exported file, required when registering an image
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ExportResult {
+    pub format: ExportFormat,
+    pub path: PathBuf,
+    /// Ids of the exported disks
+    pub disks: Vec<String>,
 }
 
 #[automock]
@@ -94,228 +408,2006 @@ pub trait VMManagerTrait: Sync + Send {
 
     async fn start_vm(&self, request: VMOptions) -> Result<String, Error>;
     async fn stop_vm(&self, id: &str) -> Result<(), Error>;
+    async fn adopt_vm(&self, options: AdoptOptions) -> Result<String, Error>;
+    async fn schedule_destroy_vm(&self, id: &str, destroy_at: u64) -> Result<bool, Error>;
+    async fn cancel_destroy_vm(&self, id: &str) -> Result<(), Error>;
+    async fn get_destroy_deadline_of_vm(&self, id: &str) -> Option<u64>;
+    async fn get_vm(&self, id: &str) -> Option<VMDetails>;
+    /// VMs of `namespace`, or every VM
+    async fn list_vms(&self, namespace: Option<String>) -> Vec<VMDetails>;
+    async fn get_namespace_usage(&self, namespace: &str) -> NamespaceUsage;
+    /// Quota of `namespace`, set through the API or configured
+    async fn get_namespace_quota(&self, namespace: &str) -> Option<NamespaceConfig>;
+    /// Replace the quota of `namespace`, outliving restarts
+    async fn set_namespace_quota(&self, namespace: &str, quota: NamespaceConfig);
+    /// Move a VM to `namespace`, such as a warm VM handed out
+    async fn set_namespace_of_vm(&self, id: &str, namespace: &str) -> Result<(), Error>;
+    /// Destroy a VM `ttl_seconds` from now, returning when (in ms since
+    /// epoch)
+    async fn set_ttl_of_vm(&self, id: &str, ttl_seconds: u64) -> Result<u64, Error>;
+    /// Boot a VM again from one of its snapshots
+    async fn rollback_vm(&self, id: &str, snapshot: &str) -> Result<(), Error>;
+    /// Send a request to the agent of the guest of a running VM
+    async fn call_agent(&self, id: &str, request: AgentRequest) -> Result<AgentResponse, Error>;
+    /// Check whether a VM could be started with `request`, without starting
+    /// it
+    async fn plan_start(&self, request: VMOptions) -> StartPlan;
+    async fn get_tenant_usage(&self, tenant: &str) -> Option<TenantUsage>;
+    /// Everything the VM manager knows about the VMs, leases and resource
+    /// usage, for troubleshooting
+    async fn dump_state(&self) -> serde_json::Value;
+    /// Refuse new VMs, then stop the running ones or leave them running as
+    /// configured
+    async fn shutdown(&self);
+    /// Refuse new VMs until uncordoned, leaving the running ones alone
+    async fn cordon(&self, reason: Option<String>) -> Cordon;
+    /// Accept new VMs again, returning whether the host was cordoned
+    async fn uncordon(&self) -> bool;
+    async fn node_status(&self) -> NodeStatus;
+    async fn pause_vm(&self, id: &str) -> Result<(), Error>;
+    async fn start_warm_vm(&self, pool: &str, request: VMOptions) -> Result<String, Error>;
+    async fn count_warm_vms(&self, pool: &str) -> usize;
+    /// Take a VM out of the warm pool `pool` for `request`, once the
+    /// pre-start hooks let it through
+    async fn claim_warm_vm(&self, pool: &str, request: &VMOptions)
+        -> Result<Option<String>, Error>;
+    /// Call the post-start hooks of a claimed warm VM, once it is set up for
+    /// the request it was claimed for
+    async fn run_post_start_hooks(&self, id: &str);
+    async fn get_function(&self, name: &str) -> Option<FunctionConfig>;
+    async fn register_function(&self, name: &str, function: FunctionConfig) -> Result<(), Error>;
+    /// VM serving the function `name` to `namespace`, if it is still
+    /// running, which counts as an invocation of the function
+    async fn get_function_vm(&self, namespace: &str, name: &str) -> Option<String>;
+    async fn set_function_vm(&self, namespace: &str, name: &str, id: &str);
+    /// Forget the VMs of the functions idle for longer than their idle
+    /// timeout, returning them by function for them to be destroyed
+    async fn take_idle_function_vms(&self) -> Vec<(String, String)>;
+    /// Revoke the share links of a VM, then wait up to `timeout` for the
+    /// requests the ingress proxy is forwarding to it to finish
+    async fn drain_vm(&self, id: &str, timeout: Duration);
+    /// Forget the VMs destroyed once their time to live expired, returning
+    /// them for their volumes to be released
+    async fn take_expired_vms(&self) -> Vec<String>;
+    async fn resume_vm(&self, id: &str) -> Result<(), Error>;
     async fn get_used_ports(&self) -> Vec<u16>;
     async fn get_used_ports_of_vm(&self, vm_id: &str) -> Option<HashMap<u16, u16>>;
+    async fn get_port_owner(&self, host_port: u16) -> Option<PortOwner>;
+    /// Link to the mapped guest `port` of a VM, valid for `ttl_seconds`
+    async fn create_share_link(
+        &self,
+        vm_id: &str,
+        port: u16,
+        ttl_seconds: u64,
+    ) -> Result<ShareLink, Error>;
+    async fn get_share_links_of_vm(&self, vm_id: &str) -> Option<Vec<ShareLink>>;
+    /// Wait for a VM to accept connections on its guest `port`, or to answer
+    /// pings without one, failing with `BootTimeout` after `timeout_seconds`
+    async fn wait_until_ready(
+        &self,
+        vm_id: &str,
+        port: Option<u16>,
+        timeout_seconds: u64,
+    ) -> Result<(), Error>;
+    async fn revoke_share_link(&self, vm_id: &str, link_id: &str) -> Result<(), Error>;
+    /// VM started by a previous request sent with idempotency `key` in
+    /// `namespace`, or none once the key is claimed for a request about to
+    /// start one
+    async fn claim_idempotency_key(
+        &self,
+        namespace: &str,
+        key: &str,
+        fingerprint: &str,
+    ) -> Result<Option<StartedVm>, Error>;
+    /// Record the VM started by the request that claimed `key`, none to
+    /// release the key if it failed
+    async fn settle_idempotency_key(&self, namespace: &str, key: &str, started: Option<StartedVm>);
+    async fn get_firewall_rules_of_vm(&self, vm_id: &str) -> Option<Vec<FirewallRule>>;
+    async fn get_network_of_vm(&self, vm_id: &str) -> Option<VMNetwork>;
+    async fn update_ports_of_vm(
+        &self,
+        vm_id: &str,
+        update: PortsUpdateDTO,
+    ) -> Result<HashMap<u16, u16>, Error>;
+    async fn export_vm(&self, id: &str, options: ExportOptions) -> Result<ExportResult, Error>;
 }
 
 pub struct VMManager {
     pub state: LambdoStateRef,
 }
 
+impl VMManager {
+    /// Call the hooks of `point` for the VM `id`, described by `vm`.
+    async fn run_hooks<T: Serialize + Sync>(
+        &self,
+        point: HookPoint,
+        id: Option<&str>,
+        vm: &T,
+    ) -> Result<(), Error> {
+        let hooks = self.state.lock().await.config.api.hooks.clone();
+        hooks::run(&hooks, point, id, vm).await.map_err(|e| {
+            error!("{}", e);
+            Error::HookFailed(e.to_string())
+        })
+    }
+
+    /// Call the network setup hooks of a VM that just started, stopping it if
+    /// one of them aborts.
+    async fn setup_network(&self, id: &str) -> Result<(), Error> {
+        let vm = self.get_vm(id).await;
+        let Err(e) = self.run_hooks(HookPoint::NetworkSetup, Some(id), &vm).await else {
+            return Ok(());
+        };
+
+        let mut state = self.state.lock().await;
+        if let Err(e) = stop(&mut state, id).await {
+            error!(
+                "Error while stopping VM {} after its hook failed: {:?}",
+                id, e
+            );
+        }
+        persist(&state);
+        Err(e)
+    }
+
+    /// Wait for a VM that just started to be reachable, destroying it if it
+    /// is not by the configured boot timeout.
+    async fn watch_boot(&self, id: &str) -> Result<(), Error> {
+        let (target, timeout) = {
+            let state = self.state.lock().await;
+            let timeout = state.config.api.boot_timeout;
+            let vm = state
+                .vms
+                .iter()
+                .find(|vm| vm.configuration.vm_id == id)
+                .ok_or(Error::VmNotFound)?;
+            if timeout == 0 {
+                return Ok(());
+            }
+
+            let mut guest_ports: Vec<u16> = vm.port_mapping.values().copied().collect();
+            guest_ports.sort();
+            let Some(target) = boot_target(vm, guest_ports.first().copied()) else {
+                return Ok(());
+            };
+            (target, Duration::from_secs(timeout))
+        };
+
+        let Err(failure) = wait_until_reachable(&target, timeout).await else {
+            return Ok(());
+        };
+
+        error!("VM {} failed to boot: {}", id, failure);
+        let vm = self.get_vm(id).await;
+        // Too late to keep the VM
+        let _ = self.run_hooks(HookPoint::PreStop, Some(id), &vm).await;
+        let mut state = self.state.lock().await;
+        state.events.record(
+            "vm.boot_failed",
+            Some(id),
+            serde_json::json!({ "reason": failure }),
+        );
+        let stopped = stop(&mut state, id).await;
+        if let Err(e) = &stopped {
+            error!(
+                "Error while stopping VM {} after its boot failed: {:?}",
+                id, e
+            );
+        }
+        persist(&state);
+        drop(state);
+        if stopped.is_ok() {
+            let _ = self.run_hooks(HookPoint::PostStop, Some(id), &vm).await;
+        }
+
+        Err(Error::BootTimeout(failure))
+    }
+}
+
 #[async_trait::async_trait]
 impl VMManagerTrait for VMManager {
     async fn from_state(state: LambdoStateRef) -> Result<Self, Error> {
         let vmm_manager = VMManager { state };
 
-        {
-            let state = vmm_manager.state.lock().await;
-            setup_bridge(&state).await.map_err(|e| {
+        let (interval, cpu_accounting, net_accounting) = {
+            let mut state = vmm_manager.state.lock().await;
+            setup_bridges(&state).await.map_err(|e| {
                 error!("Error while setting up bridge: {:?}", e);
                 Error::NetSetupError(e)
             })?;
+            announce_routes(&state.config.api).await;
+            restore_vms(&mut state).await?;
+            (
+                state.config.api.network.reconcile_interval,
+                state.config.api.cpu_accounting.enabled,
+                state.config.api.net_accounting.enabled,
+            )
+        };
+
+        if interval > 0 {
+            tokio::spawn(reconcile_bridge(
+                vmm_manager.state.clone(),
+                Duration::from_secs(interval),
+            ));
+        }
+        tokio::spawn(watch_exits(vmm_manager.state.clone(), EXIT_CHECK_INTERVAL));
+        tokio::spawn(reap_expired(
+            vmm_manager.state.clone(),
+            EXPIRY_CHECK_INTERVAL,
+        ));
+        tokio::spawn(take_snapshots(
+            vmm_manager.state.clone(),
+            SNAPSHOT_CHECK_INTERVAL,
+        ));
+        if cpu_accounting {
+            tokio::spawn(cpu_accounting::run(vmm_manager.state.clone()));
         }
+        if net_accounting {
+            tokio::spawn(net_accounting::run(vmm_manager.state.clone()));
+        }
+        tokio::spawn(node::run(vmm_manager.state.clone()));
 
         Ok(vmm_manager)
     }
 
     async fn start_vm(&self, request: VMOptions) -> Result<String, Error> {
+        self.run_hooks(HookPoint::PreStart, None, &request).await?;
         let mut state = self.state.lock().await;
 
         debug!("Creating VM with option {:?}", request);
 
-        let id = start(&mut state, request).await.map_err(|e| {
-            error!("Error while running VM: {:?}", e);
-            e
-        })?;
+        let id = start(&mut state, request)
+            .instrument(trace_span!("machine"))
+            .await
+            .map_err(|e| {
+                error!("Error while running VM: {:?}", e);
+                e
+            })?;
+        persist(&state);
 
         info!("Waiting for a connection from VMM {}", id);
 
         drop(state);
+        self.setup_network(&id).await?;
+        self.watch_boot(&id).instrument(trace_span!("boot")).await?;
 
-        state = self.state.lock().await;
-        let vm = state
-            .vms
-            .iter_mut()
-            .find(|vm| vm.configuration.vm_id == id)
-            .unwrap();
+        let vm = self.get_vm(&id).await;
+        // Too late to give up on the VM
+        let _ = self.run_hooks(HookPoint::PostStart, Some(&id), &vm).await;
 
-        Ok(vm.configuration.vm_id.clone())
+        Ok(id)
     }
 
     async fn stop_vm(&self, id: &str) -> Result<(), Error> {
         debug!("Stopping VM {}", id);
+        let vm = self.get_vm(id).await.ok_or(Error::VmNotFound)?;
+        self.run_hooks(HookPoint::PreStop, Some(id), &vm).await?;
         let mut state = self.state.lock().await;
 
-        stop(&mut state, id).await.map_err(|e| {
+        let result = stop(&mut state, id).await.map_err(|e| {
             error!("Error while stopping VM: {:?}", e);
             e
+        });
+        persist(&state);
+        drop(state);
+
+        if result.is_ok() {
+            let _ = self.run_hooks(HookPoint::PostStop, Some(id), &vm).await;
+        }
+        result
+    }
+
+    async fn adopt_vm(&self, options: AdoptOptions) -> Result<String, Error> {
+        let mut state = self.state.lock().await;
+
+        let id = adopt(&mut state, options).await.map_err(|e| {
+            error!("Error while adopting VM: {:?}", e);
+            e
         })?;
+        persist(&state);
+
+        Ok(id)
+    }
+
+    async fn schedule_destroy_vm(&self, id: &str, destroy_at: u64) -> Result<bool, Error> {
+        let mut state = self.state.lock().await;
+        let scheduled = schedule_destroy(&mut state, id, destroy_at).await?;
+        persist(&state);
+
+        Ok(scheduled)
+    }
+
+    async fn cancel_destroy_vm(&self, id: &str) -> Result<(), Error> {
+        let mut state = self.state.lock().await;
+        cancel_destroy(&mut state, id).await?;
+        persist(&state);
 
         Ok(())
     }
 
-    async fn get_used_ports(&self) -> Vec<u16> {
+    async fn get_destroy_deadline_of_vm(&self, id: &str) -> Option<u64> {
+        let state = self.state.lock().await;
+        let vm = state.vms.iter().find(|vm| vm.configuration.vm_id == id);
+        vm.and_then(|vm| vm.destroy_at)
+    }
+
+    async fn get_vm(&self, id: &str) -> Option<VMDetails> {
+        let state = self.state.lock().await;
+        let vm = state.vms.iter().find(|vm| vm.configuration.vm_id == id);
+        vm.map(VMDetails::from)
+    }
+
+    async fn list_vms(&self, namespace: Option<String>) -> Vec<VMDetails> {
         let state = self.state.lock().await;
         state
             .vms
             .iter()
-            .flat_map(|vm| vm.port_mapping.keys())
-            .cloned()
+            .filter(|vm| {
+                namespace
+                    .as_ref()
+                    .is_none_or(|namespace| &vm.namespace == namespace)
+            })
+            .map(VMDetails::from)
             .collect()
     }
 
-    async fn get_used_ports_of_vm(&self, vm_id: &str) -> Option<HashMap<u16, u16>> {
+    async fn get_namespace_usage(&self, namespace: &str) -> NamespaceUsage {
         let state = self.state.lock().await;
-        let vm = state.vms.iter().find(|vm| vm.configuration.vm_id == vm_id);
-        vm.map(|vm| vm.port_mapping.clone())
+        state.namespace_usage(namespace)
     }
-}
 
-impl Drop for VMManager {
-    fn drop(&mut self) {
-        info!("Dropping VMManager");
+    async fn get_namespace_quota(&self, namespace: &str) -> Option<NamespaceConfig> {
+        let state = self.state.lock().await;
+        state.quota(namespace).cloned()
+    }
 
-        tokio::task::block_in_place(move || {
-            tokio::runtime::Handle::current().block_on(async move {
-                let mut state = self.state.lock().await;
-                let vm_ids: Vec<String> = state
-                    .vms
-                    .iter()
-                    .map(|vm| vm.configuration.vm_id.clone())
-                    .collect();
+    async fn set_namespace_quota(&self, namespace: &str, quota: NamespaceConfig) {
+        let mut state = self.state.lock().await;
+        info!("Setting the quota of namespace {}: {:?}", namespace, quota);
+        state.events.record(
+            "namespace.quota_updated",
+            None,
+            serde_json::json!({ "namespace": namespace, "quota": quota }),
+        );
+        state.quotas.insert(namespace.to_string(), quota);
+        persist(&state);
+    }
 
-                for vm_id in vm_ids {
-                    match stop(&mut state, &vm_id).await {
-                        Ok(()) => debug!("Stopped VM {}", vm_id),
-                        Err(e) => error!("Error while stopping VM: {:?}", e),
-                    }
-                }
-            });
-        });
+    async fn set_namespace_of_vm(&self, id: &str, namespace: &str) -> Result<(), Error> {
+        let mut state = self.state.lock().await;
+        let vm = state
+            .vms
+            .iter_mut()
+            .find(|vm| vm.configuration.vm_id == id)
+            .ok_or(Error::VmNotFound)?;
+        vm.namespace = namespace.to_string();
+        persist(&state);
+        Ok(())
     }
-}
 
-async fn setup_bridge(state: &state::LambdoState) -> anyhow::Result<()> {
-    let config = &state.config;
-    let bridge_name = &config.api.network.bridge;
-    let bridge_address = &config.api.network.bridge_address;
-    trace!("validating bridge address");
-    let bridge_address = cidr::Ipv4Inet::from_str(bridge_address)
-        .map_err(|e| anyhow!("invalid bridge address: {}", e))?;
-    trace!("bridge address is valid");
-    trace!("validating bridge name");
-    if bridge_name.len() > 15 {
-        return Err(anyhow!("bridge name is too long"));
+    async fn set_ttl_of_vm(&self, id: &str, ttl_seconds: u64) -> Result<u64, Error> {
+        let mut state = self.state.lock().await;
+        let expires_at = set_ttl(&mut state, id, ttl_seconds)?;
+        persist(&state);
+
+        Ok(expires_at)
     }
-    trace!("bridge name is valid");
 
-    info!(
-        "setting up bridge {} with address {}",
-        bridge_name, bridge_address
-    );
-    let (bridge, interface_exists) = network_bridge::interface_id(bridge_name)
-        .map_or_else(
-            |e| {
-                trace!("error when fetching bridge id: {}", e);
-                debug!("bridge {} does not exist, creating it", bridge_name);
-                network_bridge::create_bridge(bridge_name).map(|id| (id, false))
-            },
-            |id| {
-                debug!("bridge {} already exists, using it", bridge_name);
-                Ok((id, true))
-            },
-        )
-        .map_err(|e| {
-            error!("error when creating bridge, am I running as root?");
-            anyhow!("error when creating bridge: {}", e)
-        })?;
+    async fn rollback_vm(&self, id: &str, snapshot: &str) -> Result<(), Error> {
+        let mut state = self.state.lock().await;
+        let result = rollback(&mut state, id, snapshot).await;
+        persist(&state);
 
-    trace!("bridge id: {}", bridge);
-    debug!("looking for existing bridge address");
-    let addresses = NetworkInterface::show()
-        .map_err(|e| anyhow!("error when fetching network interfaces: {}", e))?
-        .into_iter()
-        .filter(|iface| iface.name == *bridge_name)
-        .flat_map(|iface| iface.addr)
-        .collect::<Vec<_>>();
+        result
+    }
 
-    trace!("existing addresses: {:?}", addresses);
-    let address_exists = addresses.iter().any(|addr| {
-        addr.ip() == bridge_address.address()
-            && addr.netmask() == Some(IpAddr::V4(bridge_address.mask()))
-    });
+    async fn call_agent(&self, id: &str, request: AgentRequest) -> Result<AgentResponse, Error> {
+        let (config, socket, events) = {
+            let state = self.state.lock().await;
+            let vm = state
+                .vms
+                .iter()
+                .find(|vm| vm.configuration.vm_id == id)
+                .ok_or(Error::VmNotFound)?;
+            if vm.status != VMStatus::Running {
+                return Err(Error::VmNotRunning);
+            }
+            let socket = vmm::agent_socket(&state, vm).ok_or(Error::AgentUnavailable(
+                "the VM has no vsock device".to_string(),
+            ))?;
+            (state.config.api.agent.clone(), socket, state.events.clone())
+        };
 
-    if address_exists {
-        debug!("bridge address already exists, skipping");
-    } else {
-        debug!("bridge address does not exist, creating it");
-        trace!(
-            "Values: {} {}/{}",
-            bridge_name,
-            bridge_address.address(),
-            bridge_address.network_length()
-        );
-        Command::new("ip")
-            .args([
-                "addr",
-                "add",
-                &format!(
-                    "{}/{}",
-                    bridge_address.address(),
-                    bridge_address.network_length()
-                ),
-                "dev",
-                bridge_name,
-            ])
-            .output()
-            .await
-            .map_err(|e| anyhow!("error when adding bridge address: {}", e))?;
+        // The state is not held while the guest runs the request
+        let started_at = now_ms();
+        let result = agent::call(&config, &socket, &request).await;
+
+        let session = agent::Session::of(id, &request, &result, started_at, now_ms());
+        if let (Some(session), Some(folder)) = (session, &config.sessions_folder) {
+            let transcript = match session.record(folder) {
+                Ok(path) => Some(path),
+                Err(e) => {
+                    error!("Error while recording a session of VM {}: {:?}", id, e);
+                    None
+                }
+            };
+            events.record(
+                "vm.exec",
+                Some(id),
+                serde_json::json!({
+                    "command": session.command,
+                    "exitCode": session.exit_code,
+                    "error": session.error,
+                    "transcript": transcript,
+                }),
+            );
+        }
+
+        result.map_err(|e| Error::AgentUnavailable(e.to_string()))
     }
 
-    debug!("setting up bridge firewall");
+    async fn plan_start(&self, request: VMOptions) -> StartPlan {
+        let state = self.state.lock().await;
+        vmm::plan(&state, request)
+    }
 
-    if !interface_exists || !address_exists {
-        let default_interface_name = default_net::interface::get_default_interface_name()
-            .ok_or(anyhow!("no default interface found"))?;
+    async fn shutdown(&self) {
+        let mut state = self.state.lock().await;
+        shutdown(&mut state).await;
+    }
 
-        let iptables = iptables::new(false)
-            .map_err(|e| anyhow!("error when setting up bridge firewall: {}", e))?;
+    async fn cordon(&self, reason: Option<String>) -> Cordon {
+        let mut state = self.state.lock().await;
+        if let Some(cordon) = &state.cordon {
+            return cordon.clone();
+        }
 
-        iptables
-            .append(
-                "filter",
-                "FORWARD",
-                format!("-i {} -o {} -j ACCEPT", default_interface_name, bridge_name).as_str(),
-            )
-            .map_err(|e| anyhow!("error when setting up bridge firewall: {}", e))?;
+        let cordon = Cordon {
+            reason,
+            since: now_ms(),
+        };
+        info!("Cordoning the node: {:?}", cordon.reason);
+        state.events.record(
+            "node.cordoned",
+            None,
+            serde_json::json!({ "reason": cordon.reason }),
+        );
+        state.cordon = Some(cordon.clone());
+        persist(&state);
+        cordon
+    }
 
-        iptables
-            .append(
-                "filter",
-                "FORWARD",
-                format!("-i {} -o {} -j ACCEPT", bridge_name, default_interface_name).as_str(),
-            )
-            .map_err(|e| anyhow!("error when setting up bridge firewall: {}", e))?;
+    async fn uncordon(&self) -> bool {
+        let mut state = self.state.lock().await;
+        if state.cordon.take().is_none() {
+            return false;
+        }
 
-        iptables
-            .append(
-                "nat",
-                "POSTROUTING",
-                format!("-o {} -j MASQUERADE", default_interface_name).as_str(),
-            )
-            .map_err(|e| anyhow!("error when setting up bridge firewall: {}", e))?;
-    } else {
-        debug!("bridge firewall already set up, skipping");
+        info!("Uncordoning the node");
+        state
+            .events
+            .record("node.uncordoned", None, serde_json::json!({}));
+        persist(&state);
+        true
+    }
+
+    async fn node_status(&self) -> NodeStatus {
+        let state = self.state.lock().await;
+        NodeStatus::of(&state)
     }
 
-    debug!("bringing up bridge");
+    async fn dump_state(&self) -> serde_json::Value {
+        let state = self.state.lock().await;
+        let vms: Vec<PersistedVM> = state.vms.iter().map(PersistedVM::from).collect();
+        let (vcpus, memory) = state.committed_resources();
+        let classes: HashMap<&String, serde_json::Value> = state
+            .config
+            .api
+            .admission
+            .classes
+            .iter()
+            .map(|(name, class)| {
+                (
+                    name,
+                    serde_json::json!({ "used": state.class_usage(name), "slots": class.slots }),
+                )
+            })
+            .collect();
 
-    Command::new("ip")
-        .args(["link", "set", bridge_name, "up"])
-        .output()
-        .await
-        .map_err(|e| anyhow!("error when bringing up bridge: {}", e))?;
+        serde_json::json!({
+            "vms": vms,
+            "warmVms": state.warm_vms,
+            "functionVms": state.function_vms,
+            "functionInvocations": state.function_invocations,
+            "leases": state.leases.all(),
+            "cpuUsage": state.cpu_usage,
+            "committed": { "vcpus": vcpus, "memoryMib": memory, "classes": classes },
+        })
+    }
 
-    info!("bridge {} is ready", bridge_name);
-    Ok(())
+    async fn get_tenant_usage(&self, tenant: &str) -> Option<TenantUsage> {
+        let state = self.state.lock().await;
+        let config = state.config.api.tenants.get(tenant)?;
+        let usage = state.cpu_usage.get(tenant).cloned().unwrap_or_default();
+        Some(TenantUsage::new(tenant, config.cpu_budget.as_ref(), &usage))
+    }
+
+    async fn start_warm_vm(&self, pool: &str, request: VMOptions) -> Result<String, Error> {
+        let mut state = self.state.lock().await;
+
+        let id = start(&mut state, request).await?;
+        persist(&state);
+        drop(state);
+        self.setup_network(&id).await?;
+        self.watch_boot(&id).await?;
+
+        // Only VMs that booted can be handed out
+        let mut state = self.state.lock().await;
+        state
+            .warm_vms
+            .entry(pool.to_string())
+            .or_default()
+            .push(id.clone());
+        persist(&state);
+
+        Ok(id)
+    }
+
+    async fn count_warm_vms(&self, pool: &str) -> usize {
+        let state = self.state.lock().await;
+        state.warm_vms.get(pool).map_or(0, Vec::len)
+    }
+
+    async fn claim_warm_vm(
+        &self,
+        pool: &str,
+        request: &VMOptions,
+    ) -> Result<Option<String>, Error> {
+        let id = {
+            let mut state = self.state.lock().await;
+            let Some(ids) = state.warm_vms.get_mut(pool).filter(|ids| !ids.is_empty()) else {
+                return Ok(None);
+            };
+            ids.remove(0)
+        };
+
+        if let Err(e) = self.run_hooks(HookPoint::PreStart, None, request).await {
+            // Still fit to be handed out to the next request
+            let mut state = self.state.lock().await;
+            state
+                .warm_vms
+                .entry(pool.to_string())
+                .or_default()
+                .insert(0, id);
+            return Err(e);
+        }
+
+        let state = self.state.lock().await;
+        state
+            .events
+            .record("vm.claimed", Some(&id), serde_json::json!({ "pool": pool }));
+        persist(&state);
+
+        Ok(Some(id))
+    }
+
+    async fn run_post_start_hooks(&self, id: &str) {
+        let vm = self.get_vm(id).await;
+        // Too late to give up on the VM
+        let _ = self.run_hooks(HookPoint::PostStart, Some(id), &vm).await;
+    }
+
+    async fn get_function(&self, name: &str) -> Option<FunctionConfig> {
+        let state = self.state.lock().await;
+        state.function(name).cloned()
+    }
+
+    async fn register_function(&self, name: &str, function: FunctionConfig) -> Result<(), Error> {
+        let mut state = self.state.lock().await;
+        if state.function(name).is_some() {
+            return Err(Error::FunctionAlreadyExists);
+        }
+
+        state.functions.insert(name.to_string(), function);
+        state.events.record(
+            "function.registered",
+            None,
+            serde_json::json!({ "name": name }),
+        );
+        persist(&state);
+        Ok(())
+    }
+
+    async fn get_function_vm(&self, namespace: &str, name: &str) -> Option<String> {
+        let mut state = self.state.lock().await;
+        let key = function_vm_key(namespace, name);
+        let id = state.function_vms.get(&key)?.clone();
+        state
+            .vms
+            .iter()
+            .find(|vm| vm.configuration.vm_id == id && vm.get_state() == VMStatus::Running)?;
+
+        // Recorded along with the lookup, for the VM not to be found idle
+        // before it is handed out
+        state.function_invocations.insert(key, now_ms());
+        Some(id)
+    }
+
+    async fn set_function_vm(&self, namespace: &str, name: &str, id: &str) {
+        let mut state = self.state.lock().await;
+        let key = function_vm_key(namespace, name);
+        state.function_vms.insert(key.clone(), id.to_string());
+        state.function_invocations.insert(key, now_ms());
+        persist(&state);
+    }
+
+    async fn take_expired_vms(&self) -> Vec<String> {
+        std::mem::take(&mut self.state.lock().await.expired_vms)
+    }
+
+    async fn take_idle_function_vms(&self) -> Vec<(String, String)> {
+        let mut state = self.state.lock().await;
+        let now = now_ms();
+
+        let idle: Vec<(String, String)> = state
+            .function_vms
+            .iter()
+            .filter(|(key, _)| {
+                let Some(timeout) = state
+                    .function(function_of_key(key))
+                    .and_then(|f| f.idle_timeout)
+                else {
+                    return false;
+                };
+                // VMs reattached from an older state file start counting now
+                let last = state.function_invocations.get(*key).copied().unwrap_or(now);
+                now.saturating_sub(last) >= timeout * 1000
+            })
+            .map(|(key, id)| (key.clone(), id.clone()))
+            .collect();
+        for (key, id) in &idle {
+            state.function_vms.remove(key);
+            state.events.record(
+                "function.scaled_to_zero",
+                Some(id),
+                serde_json::json!({ "name": function_of_key(key) }),
+            );
+        }
+        for name in state.function_vms.keys().cloned().collect::<Vec<_>>() {
+            state.function_invocations.entry(name).or_insert(now);
+        }
+        if !idle.is_empty() {
+            persist(&state);
+        }
+
+        idle.into_iter()
+            .map(|(key, id)| (function_of_key(&key).to_string(), id))
+            .collect()
+    }
+
+    async fn drain_vm(&self, id: &str, timeout: Duration) {
+        let proxied = {
+            let mut state = self.state.lock().await;
+            let links = state.share_links.len();
+            state.share_links.retain(|_, link| link.vm_id != id);
+            if state.share_links.len() != links {
+                persist(&state);
+            }
+            state.proxied.clone()
+        };
+
+        let deadline = tokio::time::Instant::now() + timeout;
+        while proxied.count(id) > 0 {
+            if tokio::time::Instant::now() >= deadline {
+                warn!(
+                    "{} proxied requests to VM {} did not finish in time",
+                    proxied.count(id),
+                    id
+                );
+                return;
+            }
+            tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+        }
+    }
+
+    async fn pause_vm(&self, id: &str) -> Result<(), Error> {
+        let mut state = self.state.lock().await;
+        pause_vm(&mut state, id).await?;
+        persist(&state);
+
+        Ok(())
+    }
+
+    async fn resume_vm(&self, id: &str) -> Result<(), Error> {
+        let mut state = self.state.lock().await;
+        resume_vm(&mut state, id).await?;
+        persist(&state);
+
+        Ok(())
+    }
+
+    async fn get_used_ports(&self) -> Vec<u16> {
+        let state = self.state.lock().await;
+        let mut ports: Vec<u16> = state
+            .vms
+            .iter()
+            .flat_map(|vm| vm.port_mapping.keys())
+            .cloned()
+            .collect();
+        ports.extend(state.leases.host_ports());
+        ports
+    }
+
+    async fn get_used_ports_of_vm(&self, vm_id: &str) -> Option<HashMap<u16, u16>> {
+        let state = self.state.lock().await;
+        let vm = state.vms.iter().find(|vm| vm.configuration.vm_id == vm_id);
+        vm.map(|vm| vm.port_mapping.clone())
+    }
+
+    async fn get_port_owner(&self, host_port: u16) -> Option<PortOwner> {
+        let state = self.state.lock().await;
+        port_allocator::find_owner(&state.vms, host_port)
+    }
+
+    async fn create_share_link(
+        &self,
+        vm_id: &str,
+        port: u16,
+        ttl_seconds: u64,
+    ) -> Result<ShareLink, Error> {
+        let mut state = self.state.lock().await;
+        if !state.config.api.ingress_proxy.enabled {
+            return Err(Error::FeatureDisabled("ingress proxy".to_string()));
+        }
+        let max_ttl = state.config.api.ingress_proxy.max_ttl;
+        if ttl_seconds == 0 || ttl_seconds > max_ttl {
+            return Err(Error::InvalidOptions(format!(
+                "share links are valid for 1 to {} seconds",
+                max_ttl
+            )));
+        }
+        let vm = state
+            .vms
+            .iter()
+            .find(|vm| vm.configuration.vm_id == vm_id)
+            .ok_or(Error::VmNotFound)?;
+        if !vm.port_mapping.values().any(|guest| *guest == port) {
+            return Err(Error::PortNotMapped(port));
+        }
+
+        let link = ShareLink::new(&state, vm_id, port, ttl_seconds).map_err(Error::Other)?;
+        let now = now_ms();
+        state.share_links.retain(|_, link| link.expires_at > now);
+        state.share_links.insert(link.id.clone(), link.clone());
+        state.events.record(
+            "share_link.created",
+            Some(vm_id),
+            serde_json::json!({
+                "link": link.id,
+                "port": port,
+                "expires_at": link.expires_at,
+            }),
+        );
+        persist(&state);
+        Ok(link)
+    }
+
+    async fn wait_until_ready(
+        &self,
+        vm_id: &str,
+        port: Option<u16>,
+        timeout_seconds: u64,
+    ) -> Result<(), Error> {
+        let target = {
+            let state = self.state.lock().await;
+            let vm = state
+                .vms
+                .iter()
+                .find(|vm| vm.configuration.vm_id == vm_id)
+                .ok_or(Error::VmNotFound)?;
+            boot_target(vm, port).ok_or(Error::VmNotRunning)?
+        };
+        wait_until_reachable(&target, Duration::from_secs(timeout_seconds))
+            .await
+            .map_err(Error::BootTimeout)
+    }
+
+    async fn get_share_links_of_vm(&self, vm_id: &str) -> Option<Vec<ShareLink>> {
+        let state = self.state.lock().await;
+        state
+            .vms
+            .iter()
+            .find(|vm| vm.configuration.vm_id == vm_id)?;
+        let now = now_ms();
+        let mut links: Vec<ShareLink> = state
+            .share_links
+            .values()
+            .filter(|link| link.vm_id == vm_id && link.expires_at > now)
+            .cloned()
+            .collect();
+        links.sort_by_key(|link| link.created_at);
+        Some(links)
+    }
+
+    async fn revoke_share_link(&self, vm_id: &str, link_id: &str) -> Result<(), Error> {
+        let mut state = self.state.lock().await;
+        if state
+            .share_links
+            .get(link_id)
+            .is_none_or(|link| link.vm_id != vm_id)
+        {
+            return Err(Error::ShareLinkNotFound);
+        }
+
+        state.share_links.remove(link_id);
+        state.events.record(
+            "share_link.revoked",
+            Some(vm_id),
+            serde_json::json!({ "link": link_id }),
+        );
+        persist(&state);
+        Ok(())
+    }
+
+    async fn claim_idempotency_key(
+        &self,
+        namespace: &str,
+        key: &str,
+        fingerprint: &str,
+    ) -> Result<Option<StartedVm>, Error> {
+        let mut state = self.state.lock().await;
+        state.idempotency_keys.claim(namespace, key, fingerprint)
+    }
+
+    async fn settle_idempotency_key(&self, namespace: &str, key: &str, started: Option<StartedVm>) {
+        let mut state = self.state.lock().await;
+        state.idempotency_keys.settle(namespace, key, started);
+    }
+
+    async fn get_firewall_rules_of_vm(&self, vm_id: &str) -> Option<Vec<FirewallRule>> {
+        let state = self.state.lock().await;
+        let vm = state.vms.iter().find(|vm| vm.configuration.vm_id == vm_id);
+        vm.map(|vm| vm.firewall_rules.clone())
+    }
+
+    async fn get_network_of_vm(&self, vm_id: &str) -> Option<VMNetwork> {
+        let state = self.state.lock().await;
+        let vm = state
+            .vms
+            .iter()
+            .find(|vm| vm.configuration.vm_id == vm_id)?;
+
+        let bridge = state.config.api.bridge_for(vm.tenant.as_deref());
+        // Adopted VMs and externally managed bridges get no rule from lambdo
+        let (uplink, bridge_rules) = if vm.adopted || !state.config.api.network.manage_bridge {
+            (None, Vec::new())
+        } else {
+            match bridge_rules(&state.config.api, &bridge) {
+                Ok((uplink, rules)) => (
+                    Some(uplink),
+                    rules.into_iter().map(|(rule, _)| rule).collect(),
+                ),
+                Err(e) => {
+                    warn!(
+                        "Cannot describe the rules of bridge {}: {:?}",
+                        bridge.bridge, e
+                    );
+                    (None, Vec::new())
+                }
+            }
+        };
+        let mut port_mapping: Vec<(u16, u16)> =
+            vm.port_mapping.iter().map(|(h, g)| (*h, *g)).collect();
+        port_mapping.sort();
+
+        let ip6 = vm
+            .ip
+            .and_then(|ip| vmm::ipv6_of(&bridge, ip).ok().flatten())
+            .map(|ip| ip.to_string());
+
+        Some(VMNetwork {
+            ip: vm.ip.map(|ip| ip.to_string()),
+            ip6,
+            gateway: bridge
+                .bridge_address
+                .split('/')
+                .next()
+                .unwrap_or_default()
+                .to_string(),
+            bridge: bridge.bridge,
+            uplink,
+            port_mapping,
+            egress_profile: vm.egress_profile.clone(),
+            usage: vm.network_usage.clone(),
+            rules: vm.firewall_rules.clone(),
+            bridge_rules,
+        })
+    }
+
+    async fn update_ports_of_vm(
+        &self,
+        vm_id: &str,
+        update: PortsUpdateDTO,
+    ) -> Result<HashMap<u16, u16>, Error> {
+        let mut state = self.state.lock().await;
+        let port_mapping = update_ports(&mut state, vm_id, update)?;
+        persist(&state);
+
+        Ok(port_mapping)
+    }
+
+    async fn export_vm(&self, id: &str, options: ExportOptions) -> Result<ExportResult, Error> {
+        // The name ends up in a path
+        if let Some(name) = &options.name {
+            volume_manager::validate_name(name)?;
+        }
+        let (disks, tenant, config, paused) = {
+            let state = self.state.lock().await;
+            let vm = state
+                .vms
+                .iter()
+                .find(|vm| vm.configuration.vm_id == id)
+                .ok_or(Error::VmNotFound)?;
+
+            let disks: Vec<(String, PathBuf, bool)> = vm
+                .configuration
+                .storage
+                .iter()
+                .filter(|drive| !drive.is_read_only)
+                .map(|drive| {
+                    (
+                        drive.drive_id.clone(),
+                        PathBuf::from(&drive.path_on_host),
+                        drive.is_root_device,
+                    )
+                })
+                .collect();
+
+            if disks.is_empty() {
+                return Err(Error::Other(anyhow!("VM {} has no writable disk", id)));
+            }
+
+            // Paused VMs, including the ones waiting for their destruction,
+            // are left as they are
+            let paused = vm.status == VMStatus::Paused;
+            if !paused {
+                info!("Pausing VM {} for export", id);
+                pause(vm).await?;
+            }
+
+            (
+                disks,
+                vm.tenant.clone(),
+                state.config.api.image_manager.clone(),
+                paused,
+            )
+        };
+
+        let result = export_disks(id, &disks, tenant.as_deref(), &options, &config).await;
+
+        let state = self.state.lock().await;
+        if let Ok(export) = &result {
+            state.events.record(
+                "vm.exported",
+                Some(id),
+                serde_json::json!({ "format": export.format, "path": export.path }),
+            );
+        }
+        match state.vms.iter().find(|vm| vm.configuration.vm_id == id) {
+            Some(_) if paused => debug!("VM {} was paused before its export", id),
+            Some(vm) if vm.status == VMStatus::Paused => {
+                debug!("VM {} was paused during its export", id)
+            }
+            Some(vm) => {
+                info!("Resuming VM {} after export", id);
+                resume(vm).await?;
+            }
+            None => debug!("VM {} was stopped during its export", id),
+        }
+
+        result
+    }
+}
+
+/// What to probe to know that `vm` is reachable, on its guest `port` or by
+/// pinging it, if it is running with an address
+fn boot_target(vm: &state::VMState, guest_port: Option<u16>) -> Option<BootTarget> {
+    let (Some(ip), Some(machine)) = (vm.ip, vm.machine.as_ref()) else {
+        return None;
+    };
+    Some(BootTarget {
+        ip: ip.address(),
+        guest_port,
+        socket: machine.chroot().join("firecracker.socket"),
+    })
+}
+
+async fn export_disks(
+    id: &str,
+    disks: &[(String, PathBuf, bool)],
+    tenant: Option<&str>,
+    options: &ExportOptions,
+    config: &ImageManagerConfig,
+) -> Result<ExportResult, Error> {
+    let disk_ids = disks
+        .iter()
+        .map(|(disk_id, _, _)| disk_id.clone())
+        .collect();
+
+    match options.format {
+        ExportFormat::Image => {
+            let name = options.name.as_ref().ok_or(Error::InvalidOptions(
+                "a name is required to export a VM as an image".to_string(),
+            ))?;
+            let (disk_id, source, _) = disks
+                .iter()
+                .find(|(_, _, is_root_device)| *is_root_device)
+                .or(disks.first())
+                .unwrap();
+
+            // Images of a tenant live in their own folder
+            let folder = match tenant {
+                Some(tenant) => PathBuf::from(&config.images_folder).join(tenant),
+                None => PathBuf::from(&config.images_folder),
+            };
+            tokio::fs::create_dir_all(&folder)
+                .await
+                .map_err(|e| Error::ImageError(anyhow!("cannot create images folder: {}", e)))?;
+
+            let path = folder.join(name);
+            if path.exists() {
+                return Err(Error::ImageError(anyhow!("image {} already exists", name)));
+            }
+
+            info!("Exporting disk {} of VM {} as image {}", disk_id, id, name);
+            copy_file(source, &path).await.map_err(Error::ImageError)?;
+
+            Ok(ExportResult {
+                format: options.format,
+                path,
+                disks: vec![disk_id.clone()],
+            })
+        }
+        ExportFormat::Tarball => {
+            let name = options.name.clone().unwrap_or_else(|| {
+                let timestamp = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or_default();
+                format!("{}-{}", id, timestamp)
+            });
+
+            tokio::fs::create_dir_all(&config.exports_folder)
+                .await
+                .map_err(|e| Error::Other(anyhow!("cannot create exports folder: {}", e)))?;
+            let path = PathBuf::from(&config.exports_folder).join(format!("{}.tar.gz", name));
+
+            let mut command = Command::new("tar");
+            command.arg("--sparse").arg("-czf").arg(&path);
+            for (_, disk, _) in disks {
+                let (folder, file) = (disk.parent(), disk.file_name());
+                if let (Some(folder), Some(file)) = (folder, file) {
+                    command.arg("-C").arg(folder).arg(file);
+                }
+            }
+
+            info!("Exporting VM {} to {}", id, path.display());
+            let output = command
+                .output()
+                .await
+                .map_err(|e| Error::Other(anyhow!("error when running tar: {}", e)))?;
+            if !output.status.success() {
+                return Err(Error::Other(anyhow!(
+                    "error when creating export tarball: {}",
+                    String::from_utf8_lossy(&output.stderr)
+                )));
+            }
+
+            Ok(ExportResult {
+                format: options.format,
+                path,
+                disks: disk_ids,
+            })
+        }
+    }
+}
+
+impl Drop for VMManager {
+    fn drop(&mut self) {
+        info!("Dropping VMManager");
+
+        tokio::task::block_in_place(move || {
+            tokio::runtime::Handle::current().block_on(async move {
+                let mut state = self.state.lock().await;
+                shutdown(&mut state).await;
+            });
+        });
+    }
+}
+
+/// Stop every VM, or save them to be reattached on the next start, as
+/// configured.
+async fn shutdown(state: &mut state::LambdoState) {
+    state.shutting_down = true;
+
+    match state.config.api.shutdown_action() {
+        ShutdownAction::Leave => {
+            info!("leaving {} VMs running", state.vms.len());
+            // The VMs are reattached on the next start
+            persist(state);
+        }
+        ShutdownAction::Stop => {
+            let vm_ids: Vec<String> = state
+                .vms
+                .iter()
+                .map(|vm| vm.configuration.vm_id.clone())
+                .collect();
+            if !vm_ids.is_empty() {
+                info!("stopping {} VMs", vm_ids.len());
+            }
+
+            let hooks = state.config.api.hooks.clone();
+            for vm_id in vm_ids {
+                let vm = state
+                    .vms
+                    .iter()
+                    .find(|vm| vm.configuration.vm_id == vm_id)
+                    .map(VMDetails::from);
+                run_stop_hooks(&hooks, HookPoint::PreStop, &vm_id, &vm).await;
+                match stop(state, &vm_id).await {
+                    Ok(()) => {
+                        debug!("Stopped VM {}", vm_id);
+                        run_stop_hooks(&hooks, HookPoint::PostStop, &vm_id, &vm).await;
+                    }
+                    Err(e) => error!("Error while stopping VM: {:?}", e),
+                }
+            }
+            state.warm_vms.clear();
+            persist(state);
+        }
+    }
+}
+
+/// Reattach to the VMs saved in the state file that are still running, and
+/// clean up after the others.
+async fn restore_vms(state: &mut state::LambdoState) -> Result<(), Error> {
+    let persisted = persistence::load(state.config.api.state_file.as_deref()).map_err(|e| {
+        error!("Error while loading VM state: {:?}", e);
+        Error::Other(e)
+    })?;
+
+    for vm in persisted.vms {
+        let id = vm.id.clone();
+        if let Err(e) = reattach(state, vm).await {
+            error!("Error while reattaching VM {}: {:?}", id, e);
+        }
+    }
+
+    let known: Vec<String> = state.vms.iter().map(|vm| vm.get_id()).collect();
+    state.warm_vms = persisted.warm_vms;
+    for ids in state.warm_vms.values_mut() {
+        ids.retain(|id| known.contains(id));
+    }
+    state.functions = persisted.functions;
+    state.function_vms = persisted.function_vms;
+    state.function_vms.retain(|_, id| known.contains(id));
+    state.function_invocations = persisted.function_invocations;
+    state.cordon = persisted.cordon;
+    state.quotas = persisted.quotas;
+    state.share_links = persisted.share_links;
+    state
+        .share_links
+        .retain(|_, link| known.contains(&link.vm_id));
+    if let Some(cordon) = &state.cordon {
+        warn!(
+            "Node is cordoned since {}: {:?}",
+            cordon.since, cordon.reason
+        );
+    }
+
+    if !known.is_empty() {
+        info!("Reattached {} VMs", known.len());
+    }
+    persist(state);
+    release_dead_leases(state).await;
+
+    // The VMs lambdo lost track of leave their network behind
+    match remove_leaked(state).await {
+        Ok(removed) => removed.iter().for_each(|removal| info!("{}", removal)),
+        Err(e) => error!("Error while removing leaked network artifacts: {:?}", e),
+    }
+
+    Ok(())
+}
+
+/// Release the leases of the VMs lambdo does not know about whose
+/// firecracker process is gone, such as VMs that could not be reattached.
+async fn release_dead_leases(state: &mut state::LambdoState) {
+    let known: Vec<String> = state.vms.iter().map(|vm| vm.get_id()).collect();
+    for (vm_id, socket) in state.leases.orphans(&known) {
+        if tokio::net::UnixStream::connect(&socket).await.is_ok() {
+            trace!("Unknown VM {} still holds its lease", vm_id);
+            continue;
+        }
+        info!("Releasing the lease of unknown VM {}", vm_id);
+        state.leases.release(&vm_id);
+        state.ipam.release(&vm_id);
+    }
+}
+
+/// Uplink the traffic of `bridge` leaves the host through, along with the
+/// rules letting it out, each with whether it goes first in its chain.
+fn bridge_rules(
+    config: &LambdoApiConfig,
+    bridge: &BridgeConfig,
+) -> anyhow::Result<(String, Vec<(FirewallRule, bool)>)> {
+    let subnet = cidr::Ipv4Inet::from_str(&bridge.bridge_address)
+        .map_err(|e| anyhow!("invalid bridge address: {}", e))?
+        .network();
+    let snat_address = config
+        .snat_for(bridge)
+        .map(|address| {
+            Ipv4Addr::from_str(&address).map_err(|e| anyhow!("invalid SNAT address: {}", e))
+        })
+        .transpose()?;
+    let uplink = config
+        .uplink_for(bridge)
+        .or_else(default_net::interface::get_default_interface_name)
+        .ok_or(anyhow!(
+            "no uplink interface configured nor default interface found"
+        ))?;
+
+    let subnet_v6 = bridge
+        .bridge_address_v6
+        .as_deref()
+        .map(|address| {
+            cidr::Ipv6Inet::from_str(address)
+                .map(|address| address.network())
+                .map_err(|e| anyhow!("invalid bridge IPv6 address: {}", e))
+        })
+        .transpose()?;
+
+    let forward = [
+        FirewallRule::new(
+            "filter",
+            "FORWARD",
+            format!("-i {} -o {} -j ACCEPT", uplink, bridge.bridge),
+        )
+        .with_kind(RuleKind::Forward),
+        FirewallRule::new(
+            "filter",
+            "FORWARD",
+            format!("-i {} -o {} -j ACCEPT", bridge.bridge, uplink),
+        )
+        .with_kind(RuleKind::Forward),
+    ];
+    let mut rules: Vec<(FirewallRule, bool)> =
+        forward.iter().map(|rule| (rule.clone(), false)).collect();
+    if subnet_v6.is_some() {
+        rules.extend(forward.into_iter().map(|rule| (rule.v6(), false)));
+    }
+    if config.network.mode == NetworkMode::Routed {
+        return Ok((uplink, rules));
+    }
+
+    // The SNAT address is an IPv4 one, IPv6 traffic is always masqueraded
+    if let Some(subnet_v6) = subnet_v6 {
+        rules.push((
+            FirewallRule::new(
+                "nat",
+                "POSTROUTING",
+                format!("-s {} -o {} -j MASQUERADE", subnet_v6, uplink),
+            )
+            .with_kind(RuleKind::Snat)
+            .v6(),
+            false,
+        ));
+    }
+    // Source NAT of the bridge goes first, for the blanket MASQUERADE of
+    // other bridges not to take over its traffic
+    rules.push(match snat_address {
+        Some(address) => (
+            FirewallRule::new(
+                "nat",
+                "POSTROUTING",
+                format!(
+                    "-s {} -o {} -j SNAT --to-source {}",
+                    subnet, uplink, address
+                ),
+            )
+            .with_kind(RuleKind::Snat),
+            true,
+        ),
+        None => (
+            FirewallRule::new("nat", "POSTROUTING", format!("-o {} -j MASQUERADE", uplink))
+                .with_kind(RuleKind::Snat),
+            false,
+        ),
+    });
+
+    Ok((uplink, rules))
+}
+
+/// Make sure the bridge, its address, its firewall rules and the taps of the
+/// VMs are set up, fixing whatever is missing.
+///
+/// Returns a description of every change that had to be made, which is
+/// expected to be empty once the bridge has been set up.
+async fn setup_bridge(
+    state: &state::LambdoState,
+    bridge: &BridgeConfig,
+) -> anyhow::Result<Vec<String>> {
+    let config = &state.config;
+    let bridge_name = &bridge.bridge;
+    let bridge_address = &bridge.bridge_address;
+    let vlan_id = bridge.vlan_id;
+    let mut repairs = Vec::new();
+    trace!("validating bridge address");
+    let bridge_address = cidr::Ipv4Inet::from_str(bridge_address)
+        .map_err(|e| anyhow!("invalid bridge address: {}", e))?;
+    trace!("bridge address is valid");
+    trace!("validating bridge name");
+    if bridge_name.len() > 15 {
+        return Err(anyhow!("bridge name is too long"));
+    }
+    trace!("bridge name is valid");
+
+    if !config.api.network.manage_bridge {
+        debug!(
+            "bridge {} is managed externally, only checking it",
+            bridge_name
+        );
+        network_bridge::interface_id(bridge_name).map_err(|e| {
+            anyhow!(
+                "externally managed bridge {} does not exist: {}",
+                bridge_name,
+                e
+            )
+        })?;
+        attach_taps(state, bridge_name, &mut repairs)?;
+        return Ok(repairs);
+    }
+
+    debug!(
+        "checking bridge {} with address {}",
+        bridge_name, bridge_address
+    );
+    let bridge_id = network_bridge::interface_id(bridge_name)
+        .map_or_else(
+            |e| {
+                trace!("error when fetching bridge id: {}", e);
+                debug!("bridge {} does not exist, creating it", bridge_name);
+                repairs.push(format!("created bridge {}", bridge_name));
+                network_bridge::create_bridge(bridge_name)
+            },
+            |id| {
+                debug!("bridge {} already exists, using it", bridge_name);
+                Ok(id)
+            },
+        )
+        .map_err(|e| {
+            error!("error when creating bridge, am I running as root?");
+            anyhow!("error when creating bridge: {}", e)
+        })?;
+
+    trace!("bridge id: {}", bridge_id);
+    debug!("looking for existing bridge address");
+    let addresses = NetworkInterface::show()
+        .map_err(|e| anyhow!("error when fetching network interfaces: {}", e))?
+        .into_iter()
+        .filter(|iface| iface.name == *bridge_name)
+        .flat_map(|iface| iface.addr)
+        .collect::<Vec<_>>();
+
+    trace!("existing addresses: {:?}", addresses);
+    let address_exists = addresses.iter().any(|addr| {
+        addr.ip() == bridge_address.address()
+            && addr.netmask() == Some(IpAddr::V4(bridge_address.mask()))
+    });
+
+    if address_exists {
+        debug!("bridge address already exists, skipping");
+    } else {
+        debug!("bridge address does not exist, creating it");
+        trace!(
+            "Values: {} {}/{}",
+            bridge_name,
+            bridge_address.address(),
+            bridge_address.network_length()
+        );
+        Command::new("ip")
+            .args([
+                "addr",
+                "add",
+                &format!(
+                    "{}/{}",
+                    bridge_address.address(),
+                    bridge_address.network_length()
+                ),
+                "dev",
+                bridge_name,
+            ])
+            .output()
+            .await
+            .map_err(|e| anyhow!("error when adding bridge address: {}", e))?;
+        repairs.push(format!("added address {} to bridge", bridge_address));
+    }
+
+    if let Some(address_v6) = &bridge.bridge_address_v6 {
+        let address_v6 = cidr::Ipv6Inet::from_str(address_v6)
+            .map_err(|e| anyhow!("invalid bridge IPv6 address: {}", e))?;
+        if addresses
+            .iter()
+            .any(|addr| addr.ip() == IpAddr::V6(address_v6.address()))
+        {
+            debug!("bridge IPv6 address already exists, skipping");
+        } else {
+            debug!("bridge IPv6 address does not exist, creating it");
+            Command::new("ip")
+                .args([
+                    "-6",
+                    "addr",
+                    "add",
+                    &address_v6.to_string(),
+                    "dev",
+                    bridge_name,
+                ])
+                .output()
+                .await
+                .map_err(|e| anyhow!("error when adding bridge IPv6 address: {}", e))?;
+            repairs.push(format!("added address {} to bridge", address_v6));
+        }
+    }
+
+    debug!("checking bridge firewall");
+
+    let (uplink, rules) = bridge_rules(&config.api, bridge)?;
+    network_bridge::interface_id(&uplink)
+        .map_err(|e| anyhow!("uplink interface {} does not exist: {}", uplink, e))?;
+
+    let firewall = Firewall::new(config.api.network.firewall)?;
+    for (rule, first) in rules {
+        if firewall.exists(&rule)? {
+            trace!("rule {:?} already exists, skipping", rule);
+        } else {
+            let rule = if first {
+                firewall.insert(rule, 1)?
+            } else {
+                firewall.append(rule)?
+            };
+            repairs.push(format!(
+                "added rule {} {} {}",
+                rule.table, rule.chain, rule.rule
+            ));
+        }
+    }
+
+    if let Some(vlan_id) = vlan_id {
+        setup_vlan(bridge_name, vlan_id, &uplink, &mut repairs).await?;
+    }
+
+    if is_interface_up(bridge_name) {
+        debug!("bridge is already up, skipping");
+    } else {
+        debug!("bringing up bridge");
+        Command::new("ip")
+            .args(["link", "set", bridge_name, "up"])
+            .output()
+            .await
+            .map_err(|e| anyhow!("error when bringing up bridge: {}", e))?;
+        repairs.push(format!("brought up bridge {}", bridge_name));
+    }
+
+    attach_taps(state, bridge_name, &mut repairs)?;
+
+    info!("bridge {} is ready", bridge_name);
+    Ok(repairs)
+}
+
+/// Connect a bridge to a VLAN by adding a VLAN sub-interface of `parent` to
+/// it, so that the traffic of its VMs is tagged upstream.
+async fn setup_vlan(
+    bridge_name: &str,
+    vlan_id: u16,
+    parent: &str,
+    repairs: &mut Vec<String>,
+) -> anyhow::Result<()> {
+    if !(1..=4094).contains(&vlan_id) {
+        return Err(anyhow!("invalid VLAN id {}", vlan_id));
+    }
+
+    let vlan_interface = format!("{}.{}", parent, vlan_id);
+    let vlan_id_arg = vlan_id.to_string();
+    if vlan_interface.len() > 15 {
+        return Err(anyhow!(
+            "VLAN interface name {} is too long",
+            vlan_interface
+        ));
+    }
+
+    let commands: [(bool, Vec<&str>, String); 3] = [
+        (
+            Path::new(&format!("/sys/class/net/{}", vlan_interface)).exists(),
+            vec![
+                "link",
+                "add",
+                "link",
+                parent,
+                "name",
+                &vlan_interface,
+                "type",
+                "vlan",
+                "id",
+                &vlan_id_arg,
+            ],
+            format!("created VLAN interface {}", vlan_interface),
+        ),
+        (
+            std::fs::read_link(format!("/sys/class/net/{}/master", vlan_interface))
+                .ok()
+                .as_ref()
+                .and_then(|m| m.file_name())
+                == Some(bridge_name.as_ref()),
+            vec!["link", "set", &vlan_interface, "master", bridge_name],
+            format!("attached VLAN interface {} to bridge", vlan_interface),
+        ),
+        (
+            is_interface_up(&vlan_interface),
+            vec!["link", "set", &vlan_interface, "up"],
+            format!("brought up VLAN interface {}", vlan_interface),
+        ),
+    ];
+
+    for (done, args, repair) in commands {
+        if done {
+            continue;
+        }
+
+        debug!("ip {}", args.join(" "));
+        let output = Command::new("ip")
+            .args(&args)
+            .output()
+            .await
+            .map_err(|e| anyhow!("error when setting up VLAN interface: {}", e))?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "error when setting up VLAN interface {}: {}",
+                vlan_interface,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        repairs.push(repair);
+    }
+
+    Ok(())
+}
+
+/// Set up every bridge, and prevent traffic from being routed between them
+/// so that tenants with their own network cannot reach each other.
+async fn setup_bridges(state: &state::LambdoState) -> anyhow::Result<Vec<String>> {
+    let bridges = state.config.api.bridges();
+    let mut repairs = Vec::new();
+
+    let mut vlan_ids: Vec<u16> = bridges.iter().filter_map(|b| b.vlan_id).collect();
+    vlan_ids.sort_unstable();
+    if let Some(vlan_id) = vlan_ids.windows(2).find(|w| w[0] == w[1]) {
+        return Err(anyhow!("VLAN {} is used by several networks", vlan_id[0]));
+    }
+
+    for bridge in &bridges {
+        repairs.extend(setup_bridge(state, bridge).await?);
+    }
+
+    if bridges.len() < 2 || !state.config.api.network.manage_bridge {
+        return Ok(repairs);
+    }
+
+    let firewall = Firewall::new(state.config.api.network.firewall)?;
+    let ipv6 = bridges.iter().any(|b| b.bridge_address_v6.is_some());
+    for from in &bridges {
+        for to in bridges.iter().filter(|to| to.bridge != from.bridge) {
+            let rule = FirewallRule::new(
+                "filter",
+                "FORWARD",
+                format!("-i {} -o {} -j DROP", from.bridge, to.bridge),
+            );
+            let rules = if ipv6 {
+                vec![rule.clone(), rule.v6()]
+            } else {
+                vec![rule]
+            };
+            for rule in rules {
+                if !firewall.exists(&rule)? {
+                    // Before the port mapping rules, which accept any source
+                    let rule = firewall.insert(rule, 1)?;
+                    repairs.push(format!(
+                        "added rule {} {} {}",
+                        rule.table, rule.chain, rule.rule
+                    ));
+                }
+            }
+        }
+    }
+
+    Ok(repairs)
+}
+
+/// Attach the taps of the VMs which are not (or no longer) part of the bridge
+fn attach_taps(
+    state: &state::LambdoState,
+    bridge_name: &str,
+    repairs: &mut Vec<String>,
+) -> anyhow::Result<()> {
+    let vms = state.vms.iter().filter(|vm| {
+        !vm.adopted && state.config.api.bridge_for(vm.tenant.as_deref()).bridge == bridge_name
+    });
+    for vm in vms {
+        let Some(interface) = vm.configuration.interfaces.first() else {
+            continue;
+        };
+        let tap_name = &interface.host_dev_name;
+        if !Path::new(&format!("/sys/class/net/{}", tap_name)).exists() {
+            continue;
+        }
+        let master = std::fs::read_link(format!("/sys/class/net/{}/master", tap_name)).ok();
+
+        if master.as_ref().and_then(|m| m.file_name()) != Some(bridge_name.as_ref()) {
+            debug!(
+                "tap {} is not attached to the bridge, attaching it",
+                tap_name
+            );
+            let id = network_bridge::interface_id(tap_name)
+                .map_err(|e| anyhow!("error when fetching interface id: {}", e))?;
+            network_bridge::add_interface_to_bridge(id, bridge_name)
+                .map_err(|e| anyhow!("error when adding interface to bridge: {}", e))?;
+            repairs.push(format!("attached tap {} to bridge", tap_name));
+        }
+    }
+
+    Ok(())
+}
+
+fn is_interface_up(name: &str) -> bool {
+    // IFF_UP is the lowest bit of the interface flags
+    std::fs::read_to_string(format!("/sys/class/net/{}/flags", name))
+        .ok()
+        .and_then(|flags| u32::from_str_radix(flags.trim().trim_start_matches("0x"), 16).ok())
+        .is_some_and(|flags| flags & 0x1 != 0)
+}
+
+/// Run the announce command for the subnet of each bridge, in routed mode.
+async fn announce_routes(config: &LambdoApiConfig) {
+    let Some(command) = &config.network.announce_command else {
+        return;
+    };
+    if config.network.mode != NetworkMode::Routed || command.is_empty() {
+        return;
+    }
+
+    for bridge in config.bridges() {
+        let subnet = match cidr::Ipv4Inet::from_str(&bridge.bridge_address) {
+            Ok(address) => address.network(),
+            Err(e) => {
+                error!("Invalid address of bridge {}: {}", bridge.bridge, e);
+                continue;
+            }
+        };
+        let uplink = config
+            .uplink_for(&bridge)
+            .or_else(default_net::interface::get_default_interface_name)
+            .unwrap_or_default();
+
+        debug!("announcing route to {} through {}", subnet, uplink);
+        let output = Command::new(&command[0])
+            .args(&command[1..])
+            .env("LAMBDO_BRIDGE", &bridge.bridge)
+            .env("LAMBDO_SUBNET", subnet.to_string())
+            .env("LAMBDO_UPLINK", &uplink)
+            .env(
+                "LAMBDO_SUBNET_V6",
+                bridge
+                    .bridge_address_v6
+                    .as_deref()
+                    .and_then(|address| cidr::Ipv6Inet::from_str(address).ok())
+                    .map(|address| address.network().to_string())
+                    .unwrap_or_default(),
+            )
+            .output()
+            .await;
+        match output {
+            Ok(output) if output.status.success() => {
+                info!("announced route to {} of bridge {}", subnet, bridge.bridge)
+            }
+            Ok(output) => error!(
+                "Error while announcing route to {}: {}",
+                subnet,
+                String::from_utf8_lossy(&output.stderr)
+            ),
+            Err(e) => error!("Error while running announce command: {}", e),
+        }
+    }
+}
+
+/// Periodically check the bridge set up and repair any drift, such as a
+/// network manager restart wiping the bridge address.
+async fn reconcile_bridge(state: LambdoStateRef, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await;
+
+    loop {
+        ticker.tick().await;
+        trace!("reconciling bridge configuration");
+
+        let state = state.lock().await;
+        match setup_bridges(&state).await {
+            Ok(repairs) if repairs.is_empty() => {}
+            Ok(repairs) => {
+                warn!("bridge configuration drifted, repaired: {:?}", repairs);
+                state.events.record(
+                    "network.repaired",
+                    None,
+                    serde_json::json!({ "repairs": repairs }),
+                );
+            }
+            Err(e) => error!("Error while reconciling bridge: {:?}", e),
+        }
+    }
+}
+
+/// Mark the VMs whose firecracker process is gone as exited, checking every
+/// `interval`, and restart them or release their network as their restart
+/// policy says.
+///
+/// Firecracker leaves its API socket behind, but nobody accepts connections
+/// on it once the process has exited.
+async fn watch_exits(state: LambdoStateRef, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+
+    loop {
+        ticker.tick().await;
+
+        let mut state = state.lock().await;
+        let state = &mut *state;
+        let mut exited = Vec::new();
+        let mut stopped = Vec::new();
+        for vm in state.vms.iter_mut() {
+            if !matches!(vm.status, VMStatus::Running | VMStatus::Paused) {
+                continue;
+            }
+            let Some(machine) = vm.machine.as_ref() else {
+                continue;
+            };
+
+            let socket = machine.chroot().join("firecracker.socket");
+            if tokio::net::UnixStream::connect(&socket).await.is_err() {
+                info!("VM {} has exited", vm.configuration.vm_id);
+                vm.set_state(VMStatus::Exited);
+                state.events.record(
+                    "vm.exited",
+                    Some(&vm.configuration.vm_id),
+                    serde_json::Value::Null,
+                );
+                exited.push(vm.configuration.vm_id.clone());
+                stopped.push((vm.configuration.vm_id.clone(), Some(VMDetails::from(&*vm))));
+            }
+        }
+
+        // The VMs are stopped already, whether they are restarted or not,
+        // the hooks are told in the background not to hold up the others
+        if !stopped.is_empty() {
+            let hooks = state.config.api.hooks.clone();
+            tokio::spawn(async move {
+                for (id, vm) in stopped {
+                    run_stop_hooks(&hooks, HookPoint::PreStop, &id, &vm).await;
+                    run_stop_hooks(&hooks, HookPoint::PostStop, &id, &vm).await;
+                }
+            });
+        }
+
+        restart_due(state).await;
+        if exited.is_empty() {
+            release_dead_leases(state).await;
+            continue;
+        }
+
+        for id in &exited {
+            let uptime = state
+                .vms
+                .iter()
+                .find(|vm| &vm.configuration.vm_id == id)
+                .and_then(|vm| vm.started_at)
+                .map(|started_at| Duration::from_millis(now_ms().saturating_sub(started_at)))
+                .unwrap_or_default();
+            if schedule_restart(state, id, uptime) {
+                continue;
+            }
+            if let Err(e) = reap(state, id).await {
+                error!("Error while reaping VM {}: {:?}", id, e);
+            }
+        }
+        release_dead_leases(state).await;
+
+        // Nobody owns the VMs of the warm pools, clean them up right away
+        let warm: Vec<String> = exited
+            .into_iter()
+            .filter(|id| state.warm_vms.values().any(|ids| ids.contains(id)))
+            .collect();
+        for id in warm {
+            if let Err(e) = stop(state, &id).await {
+                error!("Error while removing exited warm VM {}: {:?}", id, e);
+            }
+        }
+        persist(state);
+    }
+}
+
+/// Snapshot the running VMs with a snapshot policy whose last snapshot is
+/// older than their interval, checking every `interval`.
+async fn take_snapshots(state: LambdoStateRef, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+
+    loop {
+        ticker.tick().await;
+
+        let now = now_ms();
+        let due: Vec<String> = state
+            .lock()
+            .await
+            .vms
+            .iter()
+            .filter(|vm| vm.status == VMStatus::Running && !vm.adopted)
+            .filter(|vm| {
+                let Some(policy) = &vm.snapshot_policy else {
+                    return false;
+                };
+                let last = vm
+                    .snapshots
+                    .last()
+                    .map(|snapshot| snapshot.created_at)
+                    .or(vm.started_at)
+                    .unwrap_or_default();
+                last + policy.interval_seconds * 1000 <= now
+            })
+            .map(|vm| vm.get_id())
+            .collect();
+
+        for id in due {
+            if let Err(e) = snapshot_vm(&state, &id, now).await {
+                error!("Error while snapshotting VM {}: {:?}", id, e);
+            }
+        }
+    }
+}
+
+/// Snapshot the writable disks of a VM, pausing it while they are copied so
+/// that they are consistent with each other.
+///
+/// The state is not locked during the copy, which is short with reflinks.
+async fn snapshot_vm(state: &LambdoStateRef, id: &str, now: u64) -> Result<(), Error> {
+    let (disks, config) = {
+        let state = state.lock().await;
+        let vm = state
+            .vms
+            .iter()
+            .find(|vm| vm.configuration.vm_id == id)
+            .ok_or(Error::VmNotFound)?;
+        if vm.status != VMStatus::Running {
+            return Ok(());
+        }
+
+        let disks: Vec<(String, PathBuf)> = vm
+            .configuration
+            .storage
+            .iter()
+            .filter(|drive| !drive.is_read_only)
+            .map(|drive| (drive.drive_id.clone(), PathBuf::from(&drive.path_on_host)))
+            .collect();
+        debug!("Pausing VM {} for its snapshot", id);
+        pause(vm).await?;
+        (disks, state.config.api.vm_snapshots.clone())
+    };
+
+    let result = vm_snapshots::take(&config, id, now, &disks).await;
+
+    let mut state = state.lock().await;
+    let state = &mut *state;
+    let Some(vm) = state.vms.iter_mut().find(|vm| vm.configuration.vm_id == id) else {
+        debug!("VM {} was stopped during its snapshot", id);
+        if let Ok(snapshot) = &result {
+            vm_snapshots::remove(&config, id, snapshot);
+        }
+        return Ok(());
+    };
+    if vm.status == VMStatus::Paused {
+        debug!("VM {} was paused during its snapshot", id);
+    } else {
+        resume(vm).await?;
+    }
+
+    let snapshot = result.map_err(Error::Other)?;
+    state.events.record(
+        "vm.snapshot_taken",
+        Some(id),
+        serde_json::json!({ "snapshot": snapshot.name }),
+    );
+    vm.snapshots.push(snapshot);
+    if let Some(policy) = &vm.snapshot_policy {
+        vm_snapshots::prune(&config, id, &mut vm.snapshots, policy.retention);
+    }
+    persist(state);
+
+    Ok(())
+}
+
+/// Destroy the VMs whose time to live expired, checking every `interval`,
+/// with the stop hooks called around.
+///
+/// Their volumes are left attached, for the owner of the volumes to release
+/// them once it takes the VMs from `expired_vms`, on `vm.expired`.
+async fn reap_expired(state: LambdoStateRef, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+
+    loop {
+        ticker.tick().await;
+
+        let now = now_ms();
+        let (expired, hooks) = {
+            let state = state.lock().await;
+            let expired: Vec<(String, u64, Option<VMDetails>)> = state
+                .vms
+                .iter()
+                .filter_map(|vm| vm.expires_at.map(|at| (vm, at)))
+                .filter(|(_, expires_at)| *expires_at <= now)
+                .map(|(vm, at)| (vm.get_id(), at, Some(VMDetails::from(vm))))
+                .collect();
+            (expired, state.config.api.hooks.clone())
+        };
+        if expired.is_empty() {
+            continue;
+        }
+
+        // The hooks are called without holding the state
+        for (id, _, vm) in &expired {
+            run_stop_hooks(&hooks, HookPoint::PreStop, id, vm).await;
+        }
+        let mut removed = Vec::new();
+        {
+            let mut state = state.lock().await;
+            for (id, expires_at, vm) in expired {
+                info!("VM {} has expired", id);
+                state.events.record(
+                    "vm.expired",
+                    Some(&id),
+                    serde_json::json!({ "expires_at": expires_at }),
+                );
+                match stop(&mut state, &id).await {
+                    Ok(()) => {
+                        state.expired_vms.push(id.clone());
+                        removed.push((id, vm));
+                    }
+                    Err(e) => error!("Error while removing expired VM {}: {:?}", id, e),
+                }
+            }
+            persist(&state);
+        }
+        for (id, vm) in &removed {
+            run_stop_hooks(&hooks, HookPoint::PostStop, id, vm).await;
+        }
+    }
+}
+
+/// Call the hooks of `point` for a VM lambdo stops on its own account,
+/// which no hook can prevent: their failures are only logged.
+async fn run_stop_hooks(hooks: &[HookConfig], point: HookPoint, id: &str, vm: &Option<VMDetails>) {
+    if let Err(e) = hooks::run(hooks, point, Some(id), vm).await {
+        error!("{}, stopping VM {} anyway", e, id);
+    }
+}
+
+/// Current time, in ms since epoch
+pub(crate) fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
 }
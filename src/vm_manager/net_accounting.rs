@@ -0,0 +1,206 @@
+use std::{path::Path, time::Duration};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+use tracing::{debug, error, info, warn};
+
+use super::{
+    persistence::persist,
+    run_stop_hooks,
+    state::{LambdoState, LambdoStateRef, VMDetails, VMStatus},
+    vmm::stop,
+};
+use crate::config::{HookPoint, NetAccountingConfig, TransferCapAction};
+
+/// Traffic of a VM since it was started, seen from the guest
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NetworkUsage {
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    /// Whether the VM is throttled for going over its transfer cap
+    #[serde(default)]
+    pub throttled: bool,
+}
+
+/// Last readings of the byte counters of a tap device, from the side of the
+/// host: what it receives is what the guest sends
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TapCounters {
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+}
+
+/// Read the counters of the taps of the VMs every configured interval,
+/// throttling or stopping the VMs over their transfer cap.
+pub async fn run(state: LambdoStateRef) {
+    let config = state.lock().await.config.api.net_accounting.clone();
+    info!("accounting for the traffic of the VMs");
+
+    let mut ticker = tokio::time::interval(Duration::from_secs(config.interval.max(1)));
+    loop {
+        ticker.tick().await;
+
+        let (over_cap, hooks) = {
+            let mut state = state.lock().await;
+            let over_cap = update(&mut state, &config).await;
+            persist(&state);
+            (over_cap, state.config.api.hooks.clone())
+        };
+        if over_cap.is_empty() {
+            continue;
+        }
+
+        // Stopped like the expired VMs, the hooks being called without
+        // holding the state
+        for (id, vm) in &over_cap {
+            run_stop_hooks(&hooks, HookPoint::PreStop, id, vm).await;
+        }
+        let mut stopped = Vec::new();
+        {
+            let mut state = state.lock().await;
+            for (id, vm) in over_cap {
+                match stop(&mut state, &id).await {
+                    Ok(()) => stopped.push((id, vm)),
+                    Err(e) => error!("Error while stopping VM {} over its cap: {:?}", id, e),
+                }
+            }
+            persist(&state);
+        }
+        for (id, vm) in &stopped {
+            run_stop_hooks(&hooks, HookPoint::PostStop, id, vm).await;
+        }
+    }
+}
+
+/// Add the traffic of the VMs since the last readings, returning the VMs to
+/// stop for going over their transfer cap.
+async fn update(
+    state: &mut LambdoState,
+    config: &NetAccountingConfig,
+) -> Vec<(String, Option<VMDetails>)> {
+    let mut over_cap = Vec::new();
+
+    for vm in state.vms.iter_mut() {
+        if !matches!(vm.status, VMStatus::Running | VMStatus::Paused) {
+            continue;
+        }
+        let Some(tap) = vm
+            .configuration
+            .interfaces
+            .first()
+            .map(|interface| interface.host_dev_name.clone())
+        else {
+            continue;
+        };
+        let counters = match read_counters(&tap) {
+            Ok(counters) => counters,
+            Err(e) => {
+                debug!("Cannot read the counters of tap {}: {:?}", tap, e);
+                continue;
+            }
+        };
+
+        let last = vm.tap_counters.unwrap_or_default();
+        vm.network_usage.rx_bytes += counter_delta(last.tx_bytes, counters.tx_bytes);
+        vm.network_usage.tx_bytes += counter_delta(last.rx_bytes, counters.rx_bytes);
+        vm.tap_counters = Some(counters);
+
+        let Some(cap) = config.transfer_cap else {
+            continue;
+        };
+        let usage = &vm.network_usage;
+        let transferred = usage.rx_bytes.saturating_add(usage.tx_bytes);
+        if transferred < cap || usage.throttled || vm.adopted {
+            continue;
+        }
+
+        let id = vm.get_id();
+        warn!(
+            "VM {} transferred {} bytes, over its cap of {}",
+            id, transferred, cap
+        );
+        state.events.record(
+            "vm.transfer_cap_exceeded",
+            Some(&id),
+            serde_json::json!({
+                "cap": cap,
+                "rx_bytes": usage.rx_bytes,
+                "tx_bytes": usage.tx_bytes,
+                "action": config.cap_action,
+            }),
+        );
+        match config.cap_action {
+            TransferCapAction::Throttle => match throttle(&tap, config.throttle_rate).await {
+                Ok(()) => vm.network_usage.throttled = true,
+                Err(e) => error!("Error while throttling VM {}: {:?}", id, e),
+            },
+            TransferCapAction::Stop => over_cap.push((id, Some(VMDetails::from(&*vm)))),
+        }
+    }
+
+    over_cap
+}
+
+/// Bytes counted since `last` by a counter now at `reading`.
+///
+/// Some drivers keep 32-bit counters, which wrap, and the counters start
+/// over when the tap is created again.
+fn counter_delta(last: u64, reading: u64) -> u64 {
+    match reading.checked_sub(last) {
+        Some(delta) => delta,
+        None if last <= u32::MAX as u64 => u32::MAX as u64 - last + reading + 1,
+        None => reading,
+    }
+}
+
+fn read_counters(tap: &str) -> Result<TapCounters> {
+    let statistics = Path::new("/sys/class/net").join(tap).join("statistics");
+    let read = |name: &str| -> Result<u64> {
+        std::fs::read_to_string(statistics.join(name))?
+            .trim()
+            .parse()
+            .map_err(|e| anyhow!("invalid {} of {}: {}", name, tap, e))
+    };
+    Ok(TapCounters {
+        rx_bytes: read("rx_bytes")?,
+        tx_bytes: read("tx_bytes")?,
+    })
+}
+
+/// Limit the traffic through `tap` to `rate` bytes per second, shaping
+/// what goes to the guest and policing what comes from it.
+async fn throttle(tap: &str, rate: u64) -> Result<()> {
+    let rate = format!("{}bit", rate.saturating_mul(8));
+    tc(&[
+        "qdisc", "replace", "dev", tap, "root", "tbf", "rate", &rate, "burst", "64kb", "latency",
+        "400ms",
+    ])
+    .await?;
+    // Replaced along with its filters, if the VM was throttled before
+    let _ = tc(&["qdisc", "del", "dev", tap, "ingress"]).await;
+    tc(&["qdisc", "add", "dev", tap, "ingress"]).await?;
+    tc(&[
+        "filter", "add", "dev", tap, "parent", "ffff:", "matchall", "action", "police", "rate",
+        &rate, "burst", "64kb", "drop",
+    ])
+    .await
+}
+
+async fn tc(args: &[&str]) -> Result<()> {
+    let output = Command::new("tc")
+        .args(args)
+        .output()
+        .await
+        .map_err(|e| anyhow!("error when running tc: {}", e))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "tc {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,247 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+use tracing::{debug, info, warn};
+
+use super::{
+    now_ms,
+    state::{LambdoState, LambdoStateRef},
+};
+use crate::config::{LambdoConfig, NodeConditionsConfig};
+
+/// Kind of trouble the host may be in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConditionKind {
+    /// A folder VMs write to is running out of space
+    DiskPressure,
+    /// The host is running out of memory
+    MemoryPressure,
+    /// A bridge or an uplink of the VMs is down
+    NetworkDegraded,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NodeCondition {
+    pub kind: ConditionKind,
+    pub active: bool,
+    pub message: String,
+    /// When the condition last became active or inactive, in ms since epoch
+    pub since: u64,
+}
+
+/// Why and since when no VM is started on the host, the running ones being
+/// left alone
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Cordon {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// In ms since epoch
+    pub since: u64,
+}
+
+/// Whether the host should be given new VMs, and why not
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NodeStatus {
+    pub ready: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cordon: Option<Cordon>,
+    pub shutting_down: bool,
+    pub conditions: Vec<NodeCondition>,
+}
+
+impl NodeStatus {
+    pub fn of(state: &LambdoState) -> Self {
+        NodeStatus {
+            ready: state.cordon.is_none()
+                && !state.shutting_down
+                && state.conditions.iter().all(|condition| !condition.active),
+            cordon: state.cordon.clone(),
+            shutting_down: state.shutting_down,
+            conditions: state.conditions.clone(),
+        }
+    }
+}
+
+/// Check the conditions of the host every configured interval, recording
+/// an event whenever one becomes active or inactive.
+pub async fn run(state: LambdoStateRef) {
+    let (lambdo_config, config) = {
+        let state = state.lock().await;
+        (
+            state.config.clone(),
+            state.config.api.node_conditions.clone(),
+        )
+    };
+    info!("checking the conditions of the node");
+
+    let mut ticker = tokio::time::interval(Duration::from_secs(config.interval.max(1)));
+    loop {
+        ticker.tick().await;
+
+        // Checked without holding the state, df may take a while
+        let checked = [
+            (
+                ConditionKind::DiskPressure,
+                disk_pressure(&lambdo_config, &config).await,
+            ),
+            (ConditionKind::MemoryPressure, memory_pressure(&config)),
+            (
+                ConditionKind::NetworkDegraded,
+                network_degraded(&lambdo_config),
+            ),
+        ];
+
+        let mut state = state.lock().await;
+        for (kind, result) in checked {
+            let message = match result {
+                Ok(message) => message,
+                Err(e) => {
+                    debug!("Cannot check condition {:?}: {:?}", kind, e);
+                    continue;
+                }
+            };
+            update(&mut state, kind, message);
+        }
+    }
+}
+
+/// Set the condition `kind` from the message of its check, if active.
+fn update(state: &mut LambdoState, kind: ConditionKind, message: Option<String>) {
+    let active = message.is_some();
+    let message = message.unwrap_or_default();
+    let current = state.conditions.iter_mut().find(|c| c.kind == kind);
+    match current {
+        Some(condition) if condition.active == active => {
+            condition.message = message;
+            return;
+        }
+        Some(condition) => {
+            condition.active = active;
+            condition.message = message.clone();
+            condition.since = now_ms();
+        }
+        // Conditions are only listed once they were active
+        None if !active => return,
+        None => state.conditions.push(NodeCondition {
+            kind,
+            active,
+            message: message.clone(),
+            since: now_ms(),
+        }),
+    }
+
+    if active {
+        warn!("Node condition {:?} is active: {}", kind, message);
+    } else {
+        info!("Node condition {:?} is no longer active", kind);
+    }
+    state.events.record(
+        "node.condition_changed",
+        None,
+        serde_json::json!({ "kind": kind, "active": active, "message": message }),
+    );
+}
+
+/// Folders lambdo writes the images, the volumes and the workspaces of the
+/// VMs to, the ones missing on this host being left out
+fn folders(config: &LambdoConfig) -> Vec<String> {
+    let api = &config.api;
+    let workspaces = match &api.jailer {
+        Some(jailer) => jailer.chroot_base.clone(),
+        None => api.executor.chroot_base.clone(),
+    };
+    [
+        api.image_manager.images_folder.clone(),
+        api.volume_manager.volumes_folder.clone(),
+        workspaces,
+    ]
+    .into_iter()
+    .filter(|folder| std::path::Path::new(folder).exists())
+    .collect()
+}
+
+async fn disk_pressure(
+    lambdo_config: &LambdoConfig,
+    config: &NodeConditionsConfig,
+) -> Result<Option<String>> {
+    let folders = folders(lambdo_config);
+    if folders.is_empty() {
+        return Ok(None);
+    }
+    let output = Command::new("df")
+        .arg("-Pk")
+        .args(&folders)
+        .output()
+        .await
+        .map_err(|e| anyhow!("error when running df: {}", e))?;
+    let output = String::from_utf8_lossy(&output.stdout);
+
+    // Filesystem, size, used, available, capacity and mount point of each
+    // folder, after a header
+    let short: Vec<String> = output
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let size: f64 = fields.get(1)?.parse().ok()?;
+            let available: f64 = fields.get(3)?.parse().ok()?;
+            let mount_point = fields.get(5)?;
+            let free = available / size.max(1.0) * 100.0;
+            (free < config.min_free_disk_percent)
+                .then(|| format!("{:.1}% free on {}", free, mount_point))
+        })
+        .collect();
+
+    Ok((!short.is_empty()).then(|| short.join(", ")))
+}
+
+fn memory_pressure(config: &NodeConditionsConfig) -> Result<Option<String>> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo")?;
+    let field = |name: &str| {
+        meminfo
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .and_then(|value| value.split_whitespace().next())
+            .and_then(|value| value.parse::<f64>().ok())
+            .ok_or(anyhow!("cannot read {} from /proc/meminfo", name))
+    };
+    let (total, available) = (field("MemTotal:")?, field("MemAvailable:")?);
+
+    let free = available / total.max(1.0) * 100.0;
+    Ok((free < config.min_available_memory_percent)
+        .then(|| format!("{:.1}% of the memory is available", free)))
+}
+
+fn network_degraded(config: &LambdoConfig) -> Result<Option<String>> {
+    let mut down = Vec::new();
+    for bridge in config.api.bridges() {
+        // A bridge without any VM has no carrier, it only has to be up
+        if !is_up(&bridge.bridge) {
+            down.push(bridge.bridge.clone());
+        }
+        if let Some(uplink) = config.api.uplink_for(&bridge) {
+            let operstate = std::fs::read_to_string(format!("/sys/class/net/{}/operstate", uplink));
+            if !matches!(operstate.as_deref().map(str::trim), Ok("up" | "unknown")) {
+                down.push(uplink);
+            }
+        }
+    }
+    down.sort();
+    down.dedup();
+
+    Ok((!down.is_empty()).then(|| format!("{} down", down.join(", "))))
+}
+
+/// Whether `interface` exists and is administratively up
+fn is_up(interface: &str) -> bool {
+    std::fs::read_to_string(format!("/sys/class/net/{}/flags", interface))
+        .ok()
+        .and_then(|flags| u32::from_str_radix(flags.trim().trim_start_matches("0x"), 16).ok())
+        .is_some_and(|flags| flags & 0x1 != 0)
+}
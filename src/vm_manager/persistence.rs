@@ -0,0 +1,178 @@
+use std::{collections::HashMap, path::Path};
+
+use anyhow::{anyhow, Result};
+use firepilot_models::models::{BootSource, Drive, NetworkInterface};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error};
+
+use crate::config::{FunctionConfig, NamespaceConfig};
+
+use super::{
+    ingress_proxy::ShareLink,
+    net_accounting::{NetworkUsage, TapCounters},
+    node::Cordon,
+    restart::RestartPolicy,
+    state::{LambdoState, StackMember, VMImages, VMState, VMStatus, DEFAULT_NAMESPACE},
+    vm_snapshots::{SnapshotPolicy, VMSnapshot},
+    FirewallRule,
+};
+
+/// Everything needed to reattach to a VM after a restart, and to clean up
+/// after it if its firecracker process is gone.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PersistedVM {
+    pub id: String,
+    pub status: VMStatus,
+    pub ip: Option<String>,
+    pub port_mapping: HashMap<u16, u16>,
+    pub adopted: bool,
+    pub egress_profile: Option<String>,
+    pub firewall_rules: Vec<FirewallRule>,
+    pub destroy_at: Option<u64>,
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    pub tenant: Option<String>,
+    #[serde(default = "default_namespace")]
+    pub namespace: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stack: Option<StackMember>,
+    pub started_at: Option<u64>,
+    pub images: VMImages,
+    pub vcpu_count: Option<u8>,
+    pub mem_size_mib: Option<u32>,
+    #[serde(default)]
+    pub machine_class: Option<String>,
+    #[serde(default)]
+    pub snapshot_policy: Option<SnapshotPolicy>,
+    #[serde(default)]
+    pub snapshots: Vec<VMSnapshot>,
+    #[serde(default)]
+    pub restart_policy: Option<RestartPolicy>,
+    #[serde(default)]
+    pub restarts: u32,
+    #[serde(default)]
+    pub network_usage: NetworkUsage,
+    #[serde(default)]
+    pub tap_counters: Option<TapCounters>,
+    pub kernel: Option<BootSource>,
+    pub drives: Vec<Drive>,
+    pub interfaces: Vec<NetworkInterface>,
+}
+
+fn default_namespace() -> String {
+    DEFAULT_NAMESPACE.to_string()
+}
+
+impl From<&VMState> for PersistedVM {
+    fn from(vm: &VMState) -> Self {
+        PersistedVM {
+            id: vm.get_id(),
+            status: vm.status,
+            ip: vm.ip.map(|ip| ip.to_string()),
+            port_mapping: vm.port_mapping.clone(),
+            adopted: vm.adopted,
+            egress_profile: vm.egress_profile.clone(),
+            firewall_rules: vm.firewall_rules.clone(),
+            destroy_at: vm.destroy_at,
+            expires_at: vm.expires_at,
+            tenant: vm.tenant.clone(),
+            namespace: vm.namespace.clone(),
+            stack: vm.stack.clone(),
+            started_at: vm.started_at,
+            images: vm.images.clone(),
+            vcpu_count: vm.vcpu_count,
+            mem_size_mib: vm.mem_size_mib,
+            machine_class: vm.machine_class.clone(),
+            snapshot_policy: vm.snapshot_policy.clone(),
+            snapshots: vm.snapshots.clone(),
+            restart_policy: vm.restart_policy.clone(),
+            restarts: vm.restarts,
+            network_usage: vm.network_usage.clone(),
+            tap_counters: vm.tap_counters,
+            kernel: vm.configuration.kernel.clone(),
+            drives: vm.configuration.storage.clone(),
+            interfaces: vm.configuration.interfaces.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PersistedState {
+    pub vms: Vec<PersistedVM>,
+    /// Ids of the idle pre-booted VMs, by warm pool
+    pub warm_vms: HashMap<String, Vec<String>>,
+    /// Functions registered through the API
+    #[serde(default)]
+    pub functions: HashMap<String, FunctionConfig>,
+    /// Id of the VM serving each function, by function and namespace
+    #[serde(default)]
+    pub function_vms: HashMap<String, String>,
+    /// When each function was last invoked, in ms since epoch
+    #[serde(default)]
+    pub function_invocations: HashMap<String, u64>,
+    /// Set if the host was cordoned, which outlives restarts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cordon: Option<Cordon>,
+    /// Quotas of the namespaces set through the API
+    #[serde(default)]
+    pub quotas: HashMap<String, NamespaceConfig>,
+    /// Share links of the VMs, by id
+    #[serde(default)]
+    pub share_links: HashMap<String, ShareLink>,
+}
+
+/// Write the VMs to the state file, if one is configured.
+///
+/// The file is replaced atomically, so that a crash never leaves a truncated
+/// state behind.
+pub fn save(state: &LambdoState) -> Result<()> {
+    let Some(path) = &state.config.api.state_file else {
+        return Ok(());
+    };
+    let path = Path::new(path);
+
+    let persisted = PersistedState {
+        vms: state.vms.iter().map(PersistedVM::from).collect(),
+        warm_vms: state.warm_vms.clone(),
+        functions: state.functions.clone(),
+        function_vms: state.function_vms.clone(),
+        function_invocations: state.function_invocations.clone(),
+        cordon: state.cordon.clone(),
+        quotas: state.quotas.clone(),
+        share_links: state.share_links.clone(),
+    };
+    let content = serde_json::to_vec_pretty(&persisted)
+        .map_err(|e| anyhow!("cannot serialize VM state: {}", e))?;
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, content)
+        .and_then(|_| std::fs::rename(&tmp, path))
+        .map_err(|e| anyhow!("cannot write state file {}: {}", path.display(), e))?;
+
+    debug!("saved {} VMs to {}", persisted.vms.len(), path.display());
+    Ok(())
+}
+
+/// Same as [`save`], logging failures since the VMs themselves are fine.
+pub fn persist(state: &LambdoState) {
+    if let Err(e) = save(state) {
+        error!("Error while saving VM state: {:?}", e);
+    }
+}
+
+/// Read the VMs saved in the state file, if one is configured and exists.
+pub fn load(state_file: Option<&str>) -> Result<PersistedState> {
+    let Some(path) = state_file.map(Path::new).filter(|path| path.exists()) else {
+        return Ok(PersistedState::default());
+    };
+
+    let content = std::fs::read(path)
+        .map_err(|e| anyhow!("cannot read state file {}: {}", path.display(), e))?;
+    serde_json::from_slice(&content)
+        .map_err(|e| anyhow!("cannot parse state file {}: {}", path.display(), e))
+}
@@ -0,0 +1,62 @@
+use std::ops::RangeInclusive;
+
+use rand::seq::{IteratorRandom, SliceRandom};
+use serde::{Deserialize, Serialize};
+
+use crate::config::PortAllocationStrategy;
+
+use super::{state::VMState, Error};
+
+/// The VM forwarding a host port, and where to.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct PortOwner {
+    pub vm_id: String,
+    pub host_port: u16,
+    pub guest_port: u16,
+}
+
+/// Picks free host ports for port mappings.
+pub struct PortAllocator {
+    strategy: PortAllocationStrategy,
+    /// Host ports handed out to VMs which do not ask for a specific one
+    range: RangeInclusive<u16>,
+}
+
+impl PortAllocator {
+    pub fn new(strategy: PortAllocationStrategy, range: RangeInclusive<u16>) -> Self {
+        PortAllocator { strategy, range }
+    }
+
+    /// Allocate `count` distinct host ports, none of them being in `used`.
+    pub fn allocate(&self, used: &[u16], count: usize) -> Result<Vec<u16>, Error> {
+        let free = self.range.clone().filter(|port| !used.contains(port));
+
+        let ports: Vec<u16> = match self.strategy {
+            PortAllocationStrategy::Sequential => free.take(count).collect(),
+            PortAllocationStrategy::Random => {
+                let mut rng = rand::thread_rng();
+                let mut ports = free.choose_multiple(&mut rng, count);
+                // choose_multiple does not guarantee a random order
+                ports.shuffle(&mut rng);
+                ports
+            }
+        };
+
+        if ports.len() < count {
+            return Err(Error::NetSetupError(anyhow::anyhow!("No free port found")));
+        }
+
+        Ok(ports)
+    }
+}
+
+/// Find which VM a host port is forwarded to, if any.
+pub fn find_owner(vms: &[VMState], host_port: u16) -> Option<PortOwner> {
+    vms.iter().find_map(|vm| {
+        vm.port_mapping.get(&host_port).map(|guest_port| PortOwner {
+            vm_id: vm.get_id(),
+            host_port,
+            guest_port: *guest_port,
+        })
+    })
+}
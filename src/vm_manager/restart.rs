@@ -0,0 +1,62 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// Longest delay before restarting a VM
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+/// Time a VM has to stay up for its next exit to count as a first failure
+const STABLE_RUN: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartMode {
+    #[default]
+    Never,
+    /// Restart up to `max_retries` times in a row
+    OnFailure,
+    Always,
+}
+
+/// What to do when the firecracker process of a VM exits on its own.
+///
+/// Guests reboot, and panic, by making firecracker exit, so every exit that
+/// lambdo did not ask for counts as a failure.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RestartPolicy {
+    pub policy: RestartMode,
+    /// Restarts in a row before giving up with `on-failure`, unlimited if
+    /// unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_retries: Option<u32>,
+    /// Delay before the first restart, doubled for each restart in a row
+    #[serde(default = "default_backoff_seconds")]
+    pub backoff_seconds: u64,
+}
+
+impl RestartPolicy {
+    /// Delay before restarting a VM that exited after running for `uptime`
+    /// and was restarted `restarts` times in a row before, or None if it is
+    /// not restarted.
+    ///
+    /// Returns the number of restarts in a row along with the delay, which is
+    /// reset once the VM ran long enough.
+    pub fn next_restart(&self, restarts: u32, uptime: Duration) -> Option<(u32, Duration)> {
+        let restarts = if uptime >= STABLE_RUN { 0 } else { restarts };
+        match self.policy {
+            RestartMode::Never => return None,
+            RestartMode::OnFailure if self.max_retries.is_some_and(|max| restarts >= max) => {
+                return None
+            }
+            RestartMode::OnFailure | RestartMode::Always => {}
+        }
+
+        let backoff = Duration::from_secs(self.backoff_seconds)
+            .saturating_mul(2u32.saturating_pow(restarts))
+            .min(MAX_BACKOFF);
+        Some((restarts, backoff))
+    }
+}
+
+fn default_backoff_seconds() -> u64 {
+    1
+}
@@ -0,0 +1,144 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use tokio::process::Command;
+use tracing::{debug, error};
+
+use crate::config::SshConfig;
+
+/// Guest port SSH access is brokered to
+pub const SSH_PORT: u16 = 22;
+/// Label of the seed disk, which guests mount to install their keys
+pub const SEED_LABEL: &str = "lambdo-seed";
+/// Id of the seed disk drive
+pub const SEED_DRIVE_ID: &str = "lambdoseed";
+/// Size of the seed disk, plenty for a few keys
+const SEED_SIZE: u64 = 4 * 1024 * 1024;
+
+const CLIENT_KEY: &str = "id_ed25519";
+const HOST_KEY: &str = "ssh_host_ed25519_key";
+
+/// How to open an SSH session to a VM
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SshAccess {
+    /// Host to connect to, the one of the API if unset
+    pub host: Option<String>,
+    pub port: u16,
+    pub user: String,
+    /// Private key authorized in the guest, in OpenSSH format
+    pub private_key: String,
+    /// Public host key of the guest, to check it on connection
+    pub host_key: String,
+}
+
+/// Generate the keys of a VM and the seed disk giving them to its guest,
+/// returning the path of the disk.
+///
+/// The disk holds `authorized_keys` and the `ssh_host_ed25519_key` pair,
+/// the guest being expected to mount it by its label and install them. The
+/// private host key only exists on the disk.
+pub async fn create_seed(config: &SshConfig, vm_id: &str) -> Result<PathBuf> {
+    let folder = vm_folder(config, vm_id);
+    let result = write_seed(&folder, vm_id).await;
+    if result.is_err() {
+        remove(config, vm_id);
+    }
+    result
+}
+
+async fn write_seed(folder: &Path, vm_id: &str) -> Result<PathBuf> {
+    let content = folder.join("seed");
+    tokio::fs::create_dir_all(&content).await?;
+
+    debug!("generating SSH keys of VM {}", vm_id);
+    generate_key(&folder.join(CLIENT_KEY), vm_id).await?;
+    generate_key(&content.join(HOST_KEY), vm_id).await?;
+    tokio::fs::copy(
+        folder.join(format!("{}.pub", CLIENT_KEY)),
+        content.join("authorized_keys"),
+    )
+    .await?;
+    tokio::fs::copy(
+        content.join(format!("{}.pub", HOST_KEY)),
+        folder.join(format!("{}.pub", HOST_KEY)),
+    )
+    .await?;
+
+    let disk = folder.join("seed.ext4");
+    tokio::fs::File::create(&disk)
+        .await?
+        .set_len(SEED_SIZE)
+        .await?;
+    let output = Command::new("mkfs.ext4")
+        .arg("-q")
+        .arg("-L")
+        .arg(SEED_LABEL)
+        .arg("-d")
+        .arg(&content)
+        .arg(&disk)
+        .output()
+        .await
+        .map_err(|e| anyhow!("error when running mkfs.ext4: {}", e))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "error when creating seed disk: {}",
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    tokio::fs::remove_dir_all(&content).await?;
+
+    Ok(disk)
+}
+
+async fn generate_key(path: &Path, vm_id: &str) -> Result<()> {
+    let output = Command::new("ssh-keygen")
+        .args(["-q", "-t", "ed25519", "-N", ""])
+        .arg("-C")
+        .arg(format!("lambdo-{}", vm_id))
+        .arg("-f")
+        .arg(path)
+        .output()
+        .await
+        .map_err(|e| anyhow!("error when running ssh-keygen: {}", e))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "error when generating SSH key: {}",
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    Ok(())
+}
+
+/// Access to a VM whose guest port 22 is forwarded from host `port`.
+pub fn access(config: &SshConfig, vm_id: &str, port: u16) -> Result<SshAccess> {
+    let folder = vm_folder(config, vm_id);
+    let read = |name: &str| {
+        std::fs::read_to_string(folder.join(name))
+            .map_err(|_| anyhow!("VM {} was started without SSH keys", vm_id))
+    };
+
+    Ok(SshAccess {
+        host: config.advertised_host.clone(),
+        port,
+        user: config.user.clone(),
+        private_key: read(CLIENT_KEY)?,
+        host_key: read(&format!("{}.pub", HOST_KEY))?.trim().to_string(),
+    })
+}
+
+/// Delete the keys and seed disk of a VM.
+pub fn remove(config: &SshConfig, vm_id: &str) {
+    let folder = vm_folder(config, vm_id);
+    if !folder.exists() {
+        return;
+    }
+    if let Err(e) = std::fs::remove_dir_all(&folder) {
+        error!("Error while removing SSH keys of VM {}: {:?}", vm_id, e);
+    }
+}
+
+fn vm_folder(config: &SshConfig, vm_id: &str) -> PathBuf {
+    Path::new(&config.folder).join(vm_id)
+}
@@ -1,34 +1,393 @@
 use std::collections::HashMap;
 use std::fmt::Debug;
+use std::sync::Arc;
 
 use cidr::Ipv4Inet;
+use firepilot::executor::Action;
+use serde::{Deserialize, Serialize};
 use tracing::debug;
 
-use crate::{config::LambdoConfig, vm_manager};
+use crate::{
+    config::{FunctionConfig, LambdoConfig, NamespaceConfig},
+    vm_manager::{
+        self,
+        cpu_accounting::TenantCpuUsage,
+        events::EventStore,
+        idempotency::IdempotencyKeys,
+        ingress_proxy::{Connections, ShareLink},
+        ipam::Ipam,
+        leases::Leases,
+        net_accounting::{NetworkUsage, TapCounters},
+        node::{Cordon, NodeCondition},
+        restart::RestartPolicy,
+        vm_snapshots::{SnapshotPolicy, VMSnapshot},
+        FirewallRule,
+    },
+};
+
+/// Namespace of the VMs started without one, such as when authentication
+/// is disabled
+pub const DEFAULT_NAMESPACE: &str = "default";
 
 pub type LambdoStateRef = std::sync::Arc<tokio::sync::Mutex<LambdoState>>;
 
+/// Key of the VM serving the function `name` to `namespace`, the name alone
+/// in the default namespace, where every function VM was started before
+/// namespaces had their own
+pub fn function_vm_key(namespace: &str, name: &str) -> String {
+    if namespace == DEFAULT_NAMESPACE {
+        name.to_string()
+    } else {
+        format!("{}/{}", namespace, name)
+    }
+}
+
+/// Name of the function of a key made by [`function_vm_key`]
+pub fn function_of_key(key: &str) -> &str {
+    key.rsplit_once('/').map_or(key, |(_, name)| name)
+}
+
 pub struct LambdoState {
     pub vms: Vec<VMState>,
     pub config: LambdoConfig,
+    pub events: Arc<EventStore>,
+    /// Ids of the idle pre-booted VMs, by warm pool
+    pub warm_vms: HashMap<String, Vec<String>>,
+    /// CPU time used by the tenants in their current budget period
+    pub cpu_usage: HashMap<String, TenantCpuUsage>,
+    /// Addresses and host ports held by the VMs
+    pub leases: Leases,
+    /// Addresses allocated to the VMs since lambdo started
+    pub ipam: Ipam,
+    /// Set once lambdo started shutting down, from when no VM is started
+    pub shutting_down: bool,
+    /// Set while the host is cordoned, from when no VM is started
+    pub cordon: Option<Cordon>,
+    /// Conditions of the host that were active at some point
+    pub conditions: Vec<NodeCondition>,
+    /// Functions registered through the API, on top of the configured ones
+    pub functions: HashMap<String, FunctionConfig>,
+    /// Id of the VM serving each function, by [`function_vm_key`]
+    pub function_vms: HashMap<String, String>,
+    /// When each function was last invoked, in ms since epoch, by
+    /// [`function_vm_key`]
+    pub function_invocations: HashMap<String, u64>,
+    /// Quotas set through the API, replacing the configured ones
+    pub quotas: HashMap<String, NamespaceConfig>,
+    /// Share links of the VMs, by id
+    pub share_links: HashMap<String, ShareLink>,
+    /// Key the share links are signed with
+    pub share_key: Vec<u8>,
+    /// Connections the ingress proxy has open to the VMs
+    pub proxied: Connections,
+    /// Recent requests sent with an idempotency key, by namespace and key
+    pub idempotency_keys: IdempotencyKeys,
+    /// VMs destroyed once their time to live expired, whose volumes are
+    /// still to be released
+    pub expired_vms: Vec<String>,
 }
 
 impl LambdoState {
-    pub fn new(config: LambdoConfig) -> Self {
+    pub fn new(config: LambdoConfig, events: Arc<EventStore>) -> Self {
+        let leases = Leases::load(&config.api.lease_file);
+        let share_key = match &config.api.ingress_proxy.secret {
+            Some(secret) => secret.as_bytes().to_vec(),
+            None => rand::random::<[u8; 32]>().to_vec(),
+        };
         LambdoState {
             vms: Vec::new(),
             config,
+            events,
+            warm_vms: HashMap::new(),
+            cpu_usage: HashMap::new(),
+            leases,
+            ipam: Ipam::default(),
+            shutting_down: false,
+            cordon: None,
+            conditions: Vec::new(),
+            functions: HashMap::new(),
+            function_vms: HashMap::new(),
+            function_invocations: HashMap::new(),
+            quotas: HashMap::new(),
+            share_links: HashMap::new(),
+            share_key,
+            proxied: Connections::default(),
+            idempotency_keys: IdempotencyKeys::default(),
+            expired_vms: Vec::new(),
+        }
+    }
+
+    /// Quota of `namespace`, set through the API or configured
+    pub fn quota(&self, namespace: &str) -> Option<&NamespaceConfig> {
+        self.quotas
+            .get(namespace)
+            .or_else(|| self.config.api.namespaces.get(namespace))
+    }
+
+    /// Function named `name`, configured or registered through the API
+    pub fn function(&self, name: &str) -> Option<&FunctionConfig> {
+        self.config
+            .api
+            .functions
+            .get(name)
+            .or_else(|| self.functions.get(name))
+    }
+
+    /// Number of live VMs of machine class `class`
+    pub fn class_usage(&self, class: &str) -> u32 {
+        self.vms
+            .iter()
+            .filter(|vm| vm.holds_resources())
+            .filter(|vm| vm.machine_class.as_deref() == Some(class))
+            .count() as u32
+    }
+
+    /// vCPUs and memory (in MiB) given to the VMs, leaving out the VMs that
+    /// exited for good and adopted VMs whose size is unknown
+    pub fn committed_resources(&self) -> (u64, u64) {
+        self.vms
+            .iter()
+            .filter(|vm| vm.holds_resources())
+            .fold((0, 0), |(vcpus, memory), vm| {
+                (
+                    vcpus + vm.vcpu_count.unwrap_or(0) as u64,
+                    memory + vm.mem_size_mib.unwrap_or(0) as u64,
+                )
+            })
+    }
+
+    /// VMs, vCPUs, memory (in MiB) and host ports held by the VMs of
+    /// `namespace`
+    pub fn namespace_usage(&self, namespace: &str) -> NamespaceUsage {
+        self.vms
+            .iter()
+            .filter(|vm| vm.holds_resources() && vm.namespace == namespace)
+            .fold(NamespaceUsage::default(), |usage, vm| NamespaceUsage {
+                vms: usage.vms + 1,
+                vcpus: usage.vcpus + vm.vcpu_count.unwrap_or(0) as u64,
+                memory_mib: usage.memory_mib + vm.mem_size_mib.unwrap_or(0) as u64,
+                ports: usage.ports + vm.port_mapping.len() as u32,
+            })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NamespaceUsage {
+    pub vms: u32,
+    pub vcpus: u64,
+    pub memory_mib: u64,
+    pub ports: u32,
+}
+
+impl NamespaceUsage {
+    /// First limit of `quota` this usage is over, if any
+    pub fn violation(&self, namespace: &str, quota: &NamespaceConfig) -> Option<QuotaViolation> {
+        let limits = [
+            ("vms", self.vms as u64, quota.max_vms.map(u64::from)),
+            ("vcpus", self.vcpus, quota.max_vcpus),
+            ("memoryMib", self.memory_mib, quota.max_memory_mib),
+            ("ports", self.ports as u64, quota.max_ports.map(u64::from)),
+        ];
+        limits.into_iter().find_map(|(limit, used, max)| {
+            max.filter(|max| used > *max).map(|max| QuotaViolation {
+                namespace: namespace.to_string(),
+                limit: limit.to_string(),
+                max,
+                used,
+            })
+        })
+    }
+}
+
+/// Stack a VM is a service of
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StackMember {
+    pub stack: String,
+    pub service: String,
+    /// Whether the ingress of the stack routes to the VM
+    #[serde(default)]
+    pub ingress: bool,
+}
+
+/// Quota of a namespace, along with what its VMs use
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NamespaceQuota {
+    pub namespace: String,
+    /// Unlimited if unset
+    pub quota: Option<NamespaceConfig>,
+    pub usage: NamespaceUsage,
+}
+
+/// Limit of the quota of a namespace a VM would take it over
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QuotaViolation {
+    pub namespace: String,
+    /// Field of the usage over its limit: vms, vcpus, memoryMib or ports
+    pub limit: String,
+    pub max: u64,
+    /// What the namespace would use with the VM
+    pub used: u64,
+}
+
+impl std::ops::Add for NamespaceUsage {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        NamespaceUsage {
+            vms: self.vms + other.vms,
+            vcpus: self.vcpus + other.vcpus,
+            memory_mib: self.memory_mib + other.memory_mib,
+            ports: self.ports + other.ports,
+        }
+    }
+}
+
+impl std::ops::Sub for NamespaceUsage {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        NamespaceUsage {
+            vms: self.vms.saturating_sub(other.vms),
+            vcpus: self.vcpus.saturating_sub(other.vcpus),
+            memory_mib: self.memory_mib.saturating_sub(other.memory_mib),
+            ports: self.ports.saturating_sub(other.ports),
         }
     }
 }
 
 #[derive(Debug)]
 pub struct VMState {
-    pub machine: Option<firepilot::machine::Machine>,
+    pub machine: Option<firepilot::executor::Executor>,
     pub configuration: firepilot::builder::Configuration,
     pub status: VMStatus,
     pub ip: Option<Ipv4Inet>,
     pub port_mapping: HashMap<u16, u16>,
+    /// Whether the VM was started outside of lambdo
+    pub adopted: bool,
+    /// Egress profile of the VM, if it may only go through the egress proxy
+    pub egress_profile: Option<String>,
+    /// Firewall rules applied for the VM, in order
+    pub firewall_rules: Vec<FirewallRule>,
+    /// When the VM will be destroyed (in ms since epoch), if its destruction
+    /// was requested and can still be undone
+    pub destroy_at: Option<u64>,
+    /// When the time to live of the VM expires (in ms since epoch), if it
+    /// has one
+    pub expires_at: Option<u64>,
+    /// Tenant the VM belongs to
+    pub tenant: Option<String>,
+    /// Namespace of the token the VM was started with
+    pub namespace: String,
+    /// Stack the VM was started as a service of
+    pub stack: Option<StackMember>,
+    /// When the VM was booted (in ms since epoch), unknown for adopted VMs
+    pub started_at: Option<u64>,
+    /// Ids of the kernel, initrd and disk images the VM was created from
+    pub images: VMImages,
+    /// Number of vCPUs of the VM, unknown for adopted VMs
+    pub vcpu_count: Option<u8>,
+    /// Memory of the VM in MiB, unknown for adopted VMs
+    pub mem_size_mib: Option<u32>,
+    /// Machine class the VM was sized by, if any
+    pub machine_class: Option<String>,
+    /// How the VM is snapshotted, if it is
+    pub snapshot_policy: Option<SnapshotPolicy>,
+    /// Snapshots of the VM, the oldest first
+    pub snapshots: Vec<VMSnapshot>,
+    /// What to do when the VM exits on its own
+    pub restart_policy: Option<RestartPolicy>,
+    /// Times the VM was restarted in a row
+    pub restarts: u32,
+    /// When the VM, which exited, will be booted again (in ms since epoch)
+    pub restart_at: Option<u64>,
+    /// Delay before that restart, in seconds
+    pub restart_backoff_seconds: Option<u64>,
+    /// Traffic of the VM, counted with network accounting
+    pub network_usage: NetworkUsage,
+    /// Last readings of the counters of the tap of the VM
+    pub tap_counters: Option<TapCounters>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct VMImages {
+    pub kernel: Option<String>,
+    pub initrd: Option<String>,
+    pub disks: Vec<String>,
+}
+
+/// Read-only view of a VM, as returned by the API
+#[derive(Debug, Clone, Serialize)]
+pub struct VMDetails {
+    pub id: String,
+    pub status: VMStatus,
+    pub ip: Option<String>,
+    pub port_mapping: Vec<(u16, u16)>,
+    pub started_at: Option<u64>,
+    pub destroy_at: Option<u64>,
+    pub expires_at: Option<u64>,
+    pub adopted: bool,
+    pub tenant: Option<String>,
+    pub namespace: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stack: Option<StackMember>,
+    pub egress_profile: Option<String>,
+    pub images: VMImages,
+    pub vcpu_count: Option<u8>,
+    pub mem_size_mib: Option<u32>,
+    pub machine_class: Option<String>,
+    pub snapshots: Vec<VMSnapshot>,
+    pub restarts: u32,
+    pub restart_at: Option<u64>,
+    pub restart_backoff_seconds: Option<u64>,
+    pub network_usage: NetworkUsage,
+    pub boot_args: Option<String>,
+    pub tap: Option<String>,
+}
+
+impl From<&VMState> for VMDetails {
+    fn from(vm: &VMState) -> Self {
+        let mut port_mapping: Vec<(u16, u16)> =
+            vm.port_mapping.iter().map(|(h, g)| (*h, *g)).collect();
+        port_mapping.sort();
+
+        VMDetails {
+            id: vm.get_id(),
+            status: vm.status,
+            ip: vm.ip.map(|ip| ip.to_string()),
+            port_mapping,
+            started_at: vm.started_at,
+            destroy_at: vm.destroy_at,
+            expires_at: vm.expires_at,
+            adopted: vm.adopted,
+            tenant: vm.tenant.clone(),
+            namespace: vm.namespace.clone(),
+            stack: vm.stack.clone(),
+            egress_profile: vm.egress_profile.clone(),
+            images: vm.images.clone(),
+            vcpu_count: vm.vcpu_count,
+            mem_size_mib: vm.mem_size_mib,
+            machine_class: vm.machine_class.clone(),
+            snapshots: vm.snapshots.clone(),
+            restarts: vm.restarts,
+            restart_at: vm.restart_at,
+            restart_backoff_seconds: vm.restart_backoff_seconds,
+            network_usage: vm.network_usage.clone(),
+            boot_args: vm
+                .configuration
+                .kernel
+                .as_ref()
+                .and_then(|kernel| kernel.boot_args.clone()),
+            tap: vm
+                .configuration
+                .interfaces
+                .first()
+                .map(|interface| interface.host_dev_name.clone()),
+        }
+    }
 }
 
 impl VMState {
@@ -39,9 +398,35 @@ impl VMState {
             status: VMStatus::Pending,
             ip: None,
             port_mapping: HashMap::new(),
+            adopted: false,
+            egress_profile: None,
+            firewall_rules: Vec::new(),
+            destroy_at: None,
+            expires_at: None,
+            tenant: None,
+            namespace: DEFAULT_NAMESPACE.to_string(),
+            stack: None,
+            started_at: None,
+            images: VMImages::default(),
+            vcpu_count: None,
+            mem_size_mib: None,
+            machine_class: None,
+            snapshot_policy: None,
+            snapshots: Vec::new(),
+            restart_policy: None,
+            restarts: 0,
+            restart_at: None,
+            restart_backoff_seconds: None,
+            network_usage: NetworkUsage::default(),
+            tap_counters: None,
         }
     }
 
+    /// Whether the VM is alive, or will be once restarted
+    pub fn holds_resources(&self) -> bool {
+        !matches!(self.status, VMStatus::Exited | VMStatus::Terminated) || self.restart_at.is_some()
+    }
+
     pub fn get_state(&self) -> VMStatus {
         self.status
     }
@@ -54,12 +439,12 @@ impl VMState {
         self.machine
             .as_mut()
             .unwrap()
-            .start()
+            .send_action(Action::InstanceStart)
             .await
-            .map_err(|e| vm_manager::Error::VmmRun(e).into())
+            .map_err(|e| vm_manager::Error::VmmRun(e.into()).into())
     }
 
-    fn set_state(&mut self, state: VMStatus) {
+    pub fn set_state(&mut self, state: VMStatus) {
         match state {
             VMStatus::Pending => {
                 debug!("VM {} is pending", self.configuration.vm_id);
@@ -69,6 +454,14 @@ impl VMState {
                 debug!("VM {} is running", self.configuration.vm_id);
                 self.status = state;
             }
+            VMStatus::Paused => {
+                debug!("VM {} is paused", self.configuration.vm_id);
+                self.status = state;
+            }
+            VMStatus::CrashLoopBackOff => {
+                debug!("VM {} is crashing in a loop", self.configuration.vm_id);
+                self.status = state;
+            }
             VMStatus::Exited => {
                 debug!("VM {} has exited", self.configuration.vm_id);
                 // TODO: Find a way to kill the VM
@@ -85,10 +478,15 @@ impl VMState {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum VMStatus {
     Pending,
     Running,
+    Paused,
+    /// Exited again shortly after being restarted, and waiting for a longer
+    /// delay each time before being restarted again
+    CrashLoopBackOff,
     Exited,
     Terminated,
 }
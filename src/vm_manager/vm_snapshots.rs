@@ -0,0 +1,135 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error};
+
+use super::volume_manager::file_driver::copy_file;
+use crate::config::VmSnapshotsConfig;
+
+/// How often a VM is snapshotted, and how many snapshots it keeps
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SnapshotPolicy {
+    pub interval_seconds: u64,
+    pub retention: usize,
+}
+
+impl SnapshotPolicy {
+    /// Check the policy against the limits of the configuration.
+    pub fn check(&self, config: &VmSnapshotsConfig) -> Result<(), String> {
+        if self.interval_seconds < config.min_interval {
+            return Err(format!(
+                "snapshots are taken every {} seconds at most",
+                config.min_interval
+            ));
+        }
+        if self.retention == 0 || self.retention > config.max_retention {
+            return Err(format!(
+                "a VM keeps between 1 and {} snapshots",
+                config.max_retention
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Copy of the writable disks of a VM, all taken while it was paused
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VMSnapshot {
+    pub name: String,
+    /// When the snapshot was taken, in ms since epoch
+    pub created_at: u64,
+    /// Ids of the drives in the snapshot
+    pub drives: Vec<String>,
+}
+
+/// Snapshot `disks`, the drive ids and paths of the writable disks of a VM.
+///
+/// The VM is expected to be paused, so that the disks are consistent with
+/// each other, as if the VM had crashed.
+pub async fn take(
+    config: &VmSnapshotsConfig,
+    vm_id: &str,
+    created_at: u64,
+    disks: &[(String, PathBuf)],
+) -> Result<VMSnapshot> {
+    let snapshot = VMSnapshot {
+        name: created_at.to_string(),
+        created_at,
+        drives: disks.iter().map(|(drive, _)| drive.clone()).collect(),
+    };
+    let folder = snapshot_folder(config, vm_id, &snapshot.name);
+    tokio::fs::create_dir_all(&folder).await?;
+
+    for (drive, path) in disks {
+        if let Err(e) = copy_file(path, &folder.join(drive)).await {
+            remove(config, vm_id, &snapshot);
+            return Err(e);
+        }
+    }
+    debug!("took snapshot {} of VM {}", snapshot.name, vm_id);
+
+    Ok(snapshot)
+}
+
+/// Put the disks of `snapshot` back in place of `disks`.
+///
+/// Each disk is copied next to the one it replaces first, so that a failed
+/// copy leaves the disk as it was.
+pub async fn restore(
+    config: &VmSnapshotsConfig,
+    vm_id: &str,
+    snapshot: &VMSnapshot,
+    disks: &[(String, PathBuf)],
+) -> Result<()> {
+    let folder = snapshot_folder(config, vm_id, &snapshot.name);
+    for drive in &snapshot.drives {
+        let (_, path) = disks
+            .iter()
+            .find(|(id, _)| id == drive)
+            .ok_or_else(|| anyhow!("VM {} has no drive {} anymore", vm_id, drive))?;
+        let tmp = path.with_extension("rollback");
+        copy_file(&folder.join(drive), &tmp).await?;
+        tokio::fs::rename(&tmp, path).await?;
+    }
+    Ok(())
+}
+
+/// Delete the oldest snapshots beyond `retention`.
+pub fn prune(
+    config: &VmSnapshotsConfig,
+    vm_id: &str,
+    snapshots: &mut Vec<VMSnapshot>,
+    retention: usize,
+) {
+    let excess = snapshots.len().saturating_sub(retention);
+    for snapshot in snapshots.drain(..excess) {
+        debug!("pruning snapshot {} of VM {}", snapshot.name, vm_id);
+        remove(config, vm_id, &snapshot);
+    }
+}
+
+/// Delete a snapshot of a VM.
+pub fn remove(config: &VmSnapshotsConfig, vm_id: &str, snapshot: &VMSnapshot) {
+    remove_folder(&snapshot_folder(config, vm_id, &snapshot.name));
+}
+
+/// Delete every snapshot of a VM.
+pub fn remove_all(config: &VmSnapshotsConfig, vm_id: &str) {
+    remove_folder(&Path::new(&config.folder).join(vm_id));
+}
+
+fn remove_folder(folder: &Path) {
+    if !folder.exists() {
+        return;
+    }
+    if let Err(e) = std::fs::remove_dir_all(folder) {
+        error!("Error while removing {}: {:?}", folder.display(), e);
+    }
+}
+
+fn snapshot_folder(config: &VmSnapshotsConfig, vm_id: &str, name: &str) -> PathBuf {
+    Path::new(&config.folder).join(vm_id).join(name)
+}
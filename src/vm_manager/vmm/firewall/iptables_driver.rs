@@ -0,0 +1,92 @@
+use anyhow::{anyhow, Result};
+use iptables::IPTables;
+
+use super::{FirewallDriver, FirewallRule, ListedRule};
+
+/// Applies the rules with iptables, and ip6tables for the IPv6 ones.
+pub struct IptablesDriver {
+    iptables: IPTables,
+    /// Unavailable on hosts without ip6tables, which only matters once a
+    /// bridge has an IPv6 subnet
+    ip6tables: Option<IPTables>,
+}
+
+impl IptablesDriver {
+    pub fn new() -> Result<Self> {
+        let iptables =
+            iptables::new(false).map_err(|e| anyhow!("error when opening iptables: {}", e))?;
+        let ip6tables = iptables::new(true).ok();
+        Ok(IptablesDriver {
+            iptables,
+            ip6tables,
+        })
+    }
+
+    fn tables(&self, rule: &FirewallRule) -> Result<&IPTables> {
+        self.tables_of(rule.ipv6)
+    }
+
+    fn tables_of(&self, ipv6: bool) -> Result<&IPTables> {
+        if ipv6 {
+            self.ip6tables
+                .as_ref()
+                .ok_or_else(|| anyhow!("ip6tables is not available"))
+        } else {
+            Ok(&self.iptables)
+        }
+    }
+}
+
+impl FirewallDriver for IptablesDriver {
+    fn name(&self) -> &'static str {
+        "iptables"
+    }
+
+    fn append(&self, rule: &FirewallRule) -> Result<()> {
+        self.tables(rule)?
+            .append(&rule.table, &rule.chain, &rule.rule)
+            .map_err(|e| anyhow!("{}", e))
+    }
+
+    fn insert(&self, rule: &FirewallRule, position: i32) -> Result<()> {
+        self.tables(rule)?
+            .insert(&rule.table, &rule.chain, &rule.rule, position)
+            .map_err(|e| anyhow!("{}", e))
+    }
+
+    fn delete(&self, rule: &FirewallRule) -> Result<()> {
+        self.tables(rule)?
+            .delete(&rule.table, &rule.chain, &rule.rule)
+            .map_err(|e| anyhow!("{}", e))
+    }
+
+    fn exists(&self, rule: &FirewallRule) -> Result<bool> {
+        self.tables(rule)?
+            .exists(&rule.table, &rule.chain, &rule.rule)
+            .map_err(|e| anyhow!("{}", e))
+    }
+
+    fn list(&self, table: &str, chain: &str, ipv6: bool) -> Result<Vec<ListedRule>> {
+        let prefix = format!("-A {} ", chain);
+        Ok(self
+            .tables_of(ipv6)?
+            .list(table, chain)
+            .map_err(|e| anyhow!("{}", e))?
+            .iter()
+            .filter_map(|line| line.strip_prefix(&prefix))
+            .map(|rule| ListedRule {
+                table: table.to_string(),
+                chain: chain.to_string(),
+                ipv6,
+                rule: rule.to_string(),
+                handle: None,
+            })
+            .collect())
+    }
+
+    fn delete_listed(&self, rule: &ListedRule) -> Result<()> {
+        self.tables_of(rule.ipv6)?
+            .delete(&rule.table, &rule.chain, &rule.rule)
+            .map_err(|e| anyhow!("{}", e))
+    }
+}
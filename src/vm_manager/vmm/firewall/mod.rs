@@ -0,0 +1,244 @@
+use std::net::IpAddr;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, trace};
+
+use crate::config::FirewallBackend;
+
+pub mod iptables_driver;
+pub mod nftables_driver;
+
+use iptables_driver::IptablesDriver;
+use nftables_driver::NftablesDriver;
+
+/// What a rule does, as described by the API
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RuleKind {
+    /// Forwards a host port to a VM
+    Dnat,
+    /// Rewrites the source address of traffic
+    Snat,
+    /// Lets traffic through the host
+    Forward,
+    /// Restricts where a VM may connect to
+    EgressPolicy,
+    /// Caps the connections of a VM
+    ConnectionLimit,
+    #[default]
+    Other,
+}
+
+/// A single firewall rule, as applied by lambdo, in the iptables syntax
+/// whatever the backend.
+#[derive(Debug, Clone, Eq, Deserialize, Serialize)]
+pub struct FirewallRule {
+    pub table: String,
+    pub chain: String,
+    pub rule: String,
+    /// Unknown for the rules saved by older versions
+    #[serde(default)]
+    pub kind: RuleKind,
+    /// Whether the rule is applied with ip6tables
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub ipv6: bool,
+}
+
+impl FirewallRule {
+    pub fn new(table: &str, chain: &str, rule: String) -> Self {
+        FirewallRule {
+            table: table.to_string(),
+            chain: chain.to_string(),
+            rule,
+            kind: RuleKind::Other,
+            ipv6: false,
+        }
+    }
+
+    pub fn with_kind(self, kind: RuleKind) -> Self {
+        FirewallRule { kind, ..self }
+    }
+
+    /// Same rule, applied with ip6tables
+    pub fn v6(self) -> Self {
+        FirewallRule { ipv6: true, ..self }
+    }
+}
+
+/// Rules are the same if iptables sees them the same, whatever their kind
+impl PartialEq for FirewallRule {
+    fn eq(&self, other: &Self) -> bool {
+        self.table == other.table
+            && self.chain == other.chain
+            && self.rule == other.rule
+            && self.ipv6 == other.ipv6
+    }
+}
+
+/// Backend applying the rules to the kernel.
+pub trait FirewallDriver: Sync + Send {
+    /// Name the backend is known by in the logs
+    fn name(&self) -> &'static str;
+    fn append(&self, rule: &FirewallRule) -> Result<()>;
+    /// Insert `rule` at `position` of its chain, starting at 1.
+    fn insert(&self, rule: &FirewallRule, position: i32) -> Result<()>;
+    fn delete(&self, rule: &FirewallRule) -> Result<()>;
+    fn exists(&self, rule: &FirewallRule) -> Result<bool>;
+    /// Rules of a chain, including the ones lambdo did not keep track of
+    fn list(&self, table: &str, chain: &str, ipv6: bool) -> Result<Vec<ListedRule>>;
+    fn delete_listed(&self, rule: &ListedRule) -> Result<()>;
+}
+
+/// Rule as a backend lists it
+#[derive(Debug, Clone)]
+pub struct ListedRule {
+    pub table: String,
+    pub chain: String,
+    pub ipv6: bool,
+    /// Rule in the syntax of the backend
+    pub rule: String,
+    /// Handle the backend deletes the rule by, instead of the rule itself
+    pub handle: Option<u64>,
+}
+
+impl ListedRule {
+    /// Addresses of single hosts the rule refers to, leaving out subnets
+    pub fn host_addresses(&self) -> Vec<IpAddr> {
+        self.rule
+            .split_whitespace()
+            .filter_map(|token| {
+                let token = token.trim_matches(|c| c == '"' || c == ',');
+                let token = token
+                    .strip_suffix("/32")
+                    .or_else(|| token.strip_suffix("/128"))
+                    .unwrap_or(token);
+                // Either an address or an address and a port, as in DNAT
+                // targets
+                if let Ok(address) = token.parse() {
+                    return Some(address);
+                }
+                match token.strip_prefix('[') {
+                    Some(rest) => rest.split_once(']').and_then(|(a, _)| a.parse().ok()),
+                    None => token.split_once(':').and_then(|(a, _)| a.parse().ok()),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Wrapper around the firewall backend checking that every mutation took
+/// effect, since a rule silently failing to apply leaves an unreachable VM
+/// behind.
+pub struct Firewall {
+    driver: Box<dyn FirewallDriver>,
+}
+
+impl Firewall {
+    pub fn new(backend: FirewallBackend) -> Result<Self> {
+        let driver: Box<dyn FirewallDriver> = match backend {
+            FirewallBackend::Iptables => Box::new(IptablesDriver::new()?),
+            FirewallBackend::Nftables => Box::new(NftablesDriver::new()?),
+        };
+        Ok(Firewall { driver })
+    }
+
+    pub fn append(&self, rule: FirewallRule) -> Result<FirewallRule> {
+        debug!(
+            "{}: -t {} -A {} {}{}",
+            self.driver.name(),
+            rule.table,
+            rule.chain,
+            rule.rule,
+            if rule.ipv6 { " (IPv6)" } else { "" }
+        );
+        self.driver
+            .append(&rule)
+            .map_err(|e| anyhow!("error when appending rule {:?}: {}", rule, e))?;
+        self.verify(&rule, true)?;
+        Ok(rule)
+    }
+
+    pub fn insert(&self, rule: FirewallRule, position: i32) -> Result<FirewallRule> {
+        debug!(
+            "{}: -t {} -I {} {} {}{}",
+            self.driver.name(),
+            rule.table,
+            rule.chain,
+            position,
+            rule.rule,
+            if rule.ipv6 { " (IPv6)" } else { "" }
+        );
+        self.driver
+            .insert(&rule, position)
+            .map_err(|e| anyhow!("error when inserting rule {:?}: {}", rule, e))?;
+        self.verify(&rule, true)?;
+        Ok(rule)
+    }
+
+    pub fn list(&self, table: &str, chain: &str, ipv6: bool) -> Result<Vec<ListedRule>> {
+        self.driver
+            .list(table, chain, ipv6)
+            .map_err(|e| anyhow!("error when listing rules of {} {}: {}", table, chain, e))
+    }
+
+    pub fn delete_listed(&self, rule: &ListedRule) -> Result<()> {
+        debug!(
+            "{}: -t {} -D {} {}{}",
+            self.driver.name(),
+            rule.table,
+            rule.chain,
+            rule.rule,
+            if rule.ipv6 { " (IPv6)" } else { "" }
+        );
+        self.driver
+            .delete_listed(rule)
+            .map_err(|e| anyhow!("error when deleting rule {:?}: {}", rule, e))
+    }
+
+    pub fn delete(&self, rule: &FirewallRule) -> Result<()> {
+        debug!(
+            "{}: -t {} -D {} {}{}",
+            self.driver.name(),
+            rule.table,
+            rule.chain,
+            rule.rule,
+            if rule.ipv6 { " (IPv6)" } else { "" }
+        );
+        self.driver
+            .delete(rule)
+            .map_err(|e| anyhow!("error when deleting rule {:?}: {}", rule, e))?;
+        self.verify(rule, false)
+    }
+
+    pub fn exists(&self, rule: &FirewallRule) -> Result<bool> {
+        self.driver
+            .exists(rule)
+            .map_err(|e| anyhow!("error when checking rule {:?}: {}", rule, e))
+    }
+
+    fn verify(&self, rule: &FirewallRule, expected: bool) -> Result<()> {
+        let exists = self.exists(rule)?;
+        trace!("rule {:?} exists: {}", rule, exists);
+
+        if exists != expected {
+            error!(
+                "{} rule {:?} should {}exist but does {}",
+                self.driver.name(),
+                rule,
+                if expected { "" } else { "not " },
+                if exists { "" } else { "not" }
+            );
+            return Err(anyhow!(
+                "{} rule in {} {} was not {}: {}",
+                self.driver.name(),
+                rule.table,
+                rule.chain,
+                if expected { "applied" } else { "removed" },
+                rule.rule
+            ));
+        }
+
+        Ok(())
+    }
+}
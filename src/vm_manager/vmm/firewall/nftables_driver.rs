@@ -0,0 +1,423 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+use tracing::trace;
+
+use super::{FirewallDriver, FirewallRule, ListedRule};
+
+/// Table lambdo keeps its rules in, in both the ip and ip6 families
+const TABLE: &str = "lambdo";
+
+/// Applies the rules with nft, in tables of lambdo's own, for hosts without
+/// iptables.
+///
+/// The rules are kept in the iptables syntax and translated when applied.
+/// Each is tagged with a comment derived from that syntax, by which it is
+/// found again, nft listing rules as it normalized them.
+pub struct NftablesDriver;
+
+impl NftablesDriver {
+    pub fn new() -> Result<Self> {
+        nft(&["--version"], None).map_err(|e| anyhow!("error when opening nftables: {}", e))?;
+        Ok(NftablesDriver)
+    }
+}
+
+impl FirewallDriver for NftablesDriver {
+    fn name(&self) -> &'static str {
+        "nftables"
+    }
+
+    fn append(&self, rule: &FirewallRule) -> Result<()> {
+        add(rule, None)
+    }
+
+    fn insert(&self, rule: &FirewallRule, position: i32) -> Result<()> {
+        // nft inserts ahead of a rule given by its handle
+        let rules = list(rule)?;
+        let before = usize::try_from(position - 1)
+            .ok()
+            .and_then(|index| rules.get(index));
+        match before {
+            Some((handle, _)) => add(rule, Some(*handle)),
+            None => add(rule, None),
+        }
+    }
+
+    fn delete(&self, rule: &FirewallRule) -> Result<()> {
+        let tag = tag(rule);
+        let (handle, _) = list(rule)?
+            .into_iter()
+            .find(|(_, comment)| comment.as_deref() == Some(tag.as_str()))
+            .ok_or_else(|| anyhow!("rule does not exist"))?;
+        let (chain, _) = chain(rule)?;
+        nft(
+            &[
+                "delete",
+                "rule",
+                family(rule),
+                TABLE,
+                &chain,
+                "handle",
+                &handle.to_string(),
+            ],
+            None,
+        )?;
+        Ok(())
+    }
+
+    fn exists(&self, rule: &FirewallRule) -> Result<bool> {
+        let tag = tag(rule);
+        Ok(list(rule)?
+            .iter()
+            .any(|(_, comment)| comment.as_deref() == Some(tag.as_str())))
+    }
+
+    fn list(&self, table: &str, chain: &str, ipv6: bool) -> Result<Vec<ListedRule>> {
+        let mut template = FirewallRule::new(table, chain, String::new());
+        template.ipv6 = ipv6;
+        let (name, hook) = self::chain(&template)?;
+        nft(&["-f", "-"], Some(&declare(&template, &name, hook)))?;
+
+        // Listed as text, in which the addresses read as in iptables rules
+        let output = nft(
+            &["-a", "list", "chain", family(&template), TABLE, &name],
+            None,
+        )?;
+        Ok(output
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.starts_with("chain ") && !line.starts_with("table "))
+            .filter_map(|line| line.rsplit_once(" # handle "))
+            .filter_map(|(rule, handle)| {
+                Some(ListedRule {
+                    table: table.to_string(),
+                    chain: chain.to_string(),
+                    ipv6,
+                    rule: rule.to_string(),
+                    handle: Some(handle.trim().parse().ok()?),
+                })
+            })
+            .collect())
+    }
+
+    fn delete_listed(&self, rule: &ListedRule) -> Result<()> {
+        let handle = rule
+            .handle
+            .ok_or_else(|| anyhow!("no handle for rule {}", rule.rule))?;
+        let mut template = FirewallRule::new(&rule.table, &rule.chain, String::new());
+        template.ipv6 = rule.ipv6;
+        let (chain, _) = chain(&template)?;
+        nft(
+            &[
+                "delete",
+                "rule",
+                family(&template),
+                TABLE,
+                &chain,
+                "handle",
+                &handle.to_string(),
+            ],
+            None,
+        )?;
+        Ok(())
+    }
+}
+
+/// Add `rule` at the end of its chain, or ahead of the rule `before`,
+/// creating the table and the chain along if needed.
+fn add(rule: &FirewallRule, before: Option<u64>) -> Result<()> {
+    let (chain, hook) = chain(rule)?;
+    let verb = match before {
+        Some(handle) => format!(
+            "insert rule {} {} {} position {}",
+            family(rule),
+            TABLE,
+            chain,
+            handle
+        ),
+        None => format!("add rule {} {} {}", family(rule), TABLE, chain),
+    };
+    let script = format!(
+        "{}\n{} {} comment \"{}\"\n",
+        declare(rule, &chain, hook),
+        verb,
+        translate(rule)?,
+        tag(rule)
+    );
+    trace!("nft script: {}", script);
+    nft(&["-f", "-"], Some(&script))?;
+    Ok(())
+}
+
+/// Handles and comments of the rules of the chain of `rule`, in order.
+fn list(rule: &FirewallRule) -> Result<Vec<(u64, Option<String>)>> {
+    let (chain, hook) = chain(rule)?;
+    nft(&["-f", "-"], Some(&declare(rule, &chain, hook)))?;
+
+    let output = nft(&["-j", "list", "chain", family(rule), TABLE, &chain], None)?;
+    let listing: Value =
+        serde_json::from_str(&output).map_err(|e| anyhow!("unexpected output of nft: {}", e))?;
+    Ok(listing["nftables"]
+        .as_array()
+        .map(|objects| {
+            objects
+                .iter()
+                .filter_map(|object| object.get("rule"))
+                .filter_map(|rule| {
+                    let handle = rule["handle"].as_u64()?;
+                    let comment = rule["comment"].as_str().map(str::to_string);
+                    Some((handle, comment))
+                })
+                .collect()
+        })
+        .unwrap_or_default())
+}
+
+/// Commands creating the table and the chain of `rule`, which do nothing
+/// if they exist.
+fn declare(rule: &FirewallRule, chain: &str, hook: &str) -> String {
+    format!(
+        "add table {family} {table}\nadd chain {family} {table} {chain} {{ {hook}; }}",
+        family = family(rule),
+        table = TABLE,
+        chain = chain,
+        hook = hook
+    )
+}
+
+fn family(rule: &FirewallRule) -> &'static str {
+    if rule.ipv6 {
+        "ip6"
+    } else {
+        "ip"
+    }
+}
+
+/// Name of the chain standing for the iptables chain of `rule`, along with
+/// the hook it is attached to.
+fn chain(rule: &FirewallRule) -> Result<(String, &'static str)> {
+    let hook = match (rule.table.as_str(), rule.chain.as_str()) {
+        ("nat", "PREROUTING") => "type nat hook prerouting priority dstnat",
+        ("nat", "POSTROUTING") => "type nat hook postrouting priority srcnat",
+        ("filter", "FORWARD") => "type filter hook forward priority filter",
+        ("filter", "INPUT") => "type filter hook input priority filter",
+        (table, chain) => return Err(anyhow!("no nftables chain for {} {}", table, chain)),
+    };
+    Ok((
+        format!("{}-{}", rule.table, rule.chain.to_lowercase()),
+        hook,
+    ))
+}
+
+/// Comment identifying `rule`, short enough for nft whatever the length of
+/// the rule
+fn tag(rule: &FirewallRule) -> String {
+    let digest =
+        openssl::sha::sha256(format!("{} {} {}", rule.table, rule.chain, rule.rule).as_bytes());
+    format!("lambdo-{}", &hex::encode(digest)[..16])
+}
+
+/// `rule` as an nft statement, for the options of iptables lambdo uses.
+///
+/// The limits are kept by the rule itself, which is about a single address.
+fn translate(rule: &FirewallRule) -> Result<String> {
+    let ip = family(rule);
+    let mut args = rule.rule.split_whitespace();
+    let mut statement = Vec::new();
+    let mut protocol = None;
+    let mut rate = None;
+    let mut burst = None;
+    let mut verdict = None;
+
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| anyhow!("no value for {} in rule {}", arg, rule.rule))
+        };
+        match arg {
+            "-p" => {
+                let name = value()?;
+                protocol = Some(name);
+                statement.push(format!("meta l4proto {}", name));
+            }
+            "-s" => statement.push(format!("{} saddr {}", ip, value()?)),
+            "-d" => statement.push(format!("{} daddr {}", ip, value()?)),
+            "-i" => statement.push(format!("iifname \"{}\"", value()?)),
+            "-o" => statement.push(format!("oifname \"{}\"", value()?)),
+            "--dport" => {
+                let protocol =
+                    protocol.ok_or_else(|| anyhow!("no protocol for --dport in {}", rule.rule))?;
+                statement.push(format!("{} dport {}", protocol, value()?));
+            }
+            // Modules are implied by their options
+            "-m" => {
+                value()?;
+            }
+            "--state" => statement.push(format!("ct state {}", value()?.to_lowercase())),
+            "--connlimit-above" => statement.push(format!("ct count over {}", value()?)),
+            "--hashlimit-above" => rate = Some(value()?.replace("/sec", "/second")),
+            "--hashlimit-burst" => burst = Some(value()?),
+            "--connlimit-mask" | "--hashlimit-mode" | "--hashlimit-name" => {
+                value()?;
+            }
+            "--connlimit-saddr" | "--connlimit-daddr" => {}
+            "-j" => match value()? {
+                "ACCEPT" => verdict = Some("accept".to_string()),
+                "DROP" => verdict = Some("drop".to_string()),
+                "MASQUERADE" => verdict = Some("masquerade".to_string()),
+                // Given by --to-source and --to-destination
+                "SNAT" | "DNAT" => {}
+                target => return Err(anyhow!("cannot translate target {} to nftables", target)),
+            },
+            "--to-source" => verdict = Some(format!("snat to {}", value()?)),
+            "--to-destination" => verdict = Some(format!("dnat to {}", value()?)),
+            option => return Err(anyhow!("cannot translate {} to nftables", option)),
+        }
+    }
+
+    if let Some(rate) = rate {
+        let burst = burst.map(|burst| format!(" burst {} packets", burst));
+        statement.push(format!(
+            "limit rate over {}{}",
+            rate,
+            burst.unwrap_or_default()
+        ));
+    }
+    statement.push(verdict.ok_or_else(|| anyhow!("no target in rule {}", rule.rule))?);
+    Ok(statement.join(" "))
+}
+
+/// Run nft with `args`, feeding it `input`, and return what it printed.
+fn nft(args: &[&str], input: Option<&str>) -> Result<String> {
+    let mut child = Command::new("nft")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| anyhow!("error when running nft: {}", e))?;
+    if let Some(input) = input {
+        child
+            .stdin
+            .take()
+            .ok_or_else(|| anyhow!("no stdin for nft"))?
+            .write_all(input.as_bytes())?;
+    }
+    // Closes stdin, for nft to read to the end
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "nft {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn translated(table: &str, chain: &str, rule: &str) -> String {
+        translate(&FirewallRule::new(table, chain, rule.to_string())).unwrap()
+    }
+
+    #[test]
+    fn translates_port_mapping() {
+        assert_eq!(
+            translated(
+                "nat",
+                "PREROUTING",
+                "-p tcp --dport 8080 -j DNAT --to-destination 10.0.0.2:80"
+            ),
+            "meta l4proto tcp tcp dport 8080 dnat to 10.0.0.2:80"
+        );
+        assert_eq!(
+            translated(
+                "nat",
+                "POSTROUTING",
+                "-p tcp -d 10.0.0.2 --dport 80 -j MASQUERADE"
+            ),
+            "meta l4proto tcp ip daddr 10.0.0.2 tcp dport 80 masquerade"
+        );
+        assert_eq!(
+            translated(
+                "filter",
+                "FORWARD",
+                "-p tcp -d 10.0.0.2 --dport 80 -m state --state NEW,ESTABLISHED,RELATED -j ACCEPT"
+            ),
+            "meta l4proto tcp ip daddr 10.0.0.2 tcp dport 80 ct state new,established,related accept"
+        );
+    }
+
+    #[test]
+    fn translates_ipv6_port_mapping() {
+        let rule = FirewallRule::new(
+            "nat",
+            "PREROUTING",
+            "-p tcp --dport 8080 -j DNAT --to-destination [fd00::2]:80".to_string(),
+        )
+        .v6();
+        assert_eq!(family(&rule), "ip6");
+        assert_eq!(
+            translate(&rule).unwrap(),
+            "meta l4proto tcp tcp dport 8080 dnat to [fd00::2]:80"
+        );
+    }
+
+    #[test]
+    fn translates_connection_limits() {
+        assert_eq!(
+            translated(
+                "filter",
+                "FORWARD",
+                "-s 10.0.0.2 -m state --state NEW -m connlimit --connlimit-above 100 --connlimit-mask 32 --connlimit-saddr -j DROP"
+            ),
+            "ip saddr 10.0.0.2 ct state new ct count over 100 drop"
+        );
+        assert_eq!(
+            translated(
+                "filter",
+                "FORWARD",
+                "-s 10.0.0.2 -m state --state NEW -m hashlimit --hashlimit-above 50/sec --hashlimit-burst 50 --hashlimit-mode srcip --hashlimit-name lambdo-1234567 -j DROP"
+            ),
+            "ip saddr 10.0.0.2 ct state new limit rate over 50/second burst 50 packets drop"
+        );
+    }
+
+    #[test]
+    fn translates_snat() {
+        assert_eq!(
+            translated(
+                "nat",
+                "POSTROUTING",
+                "-s 10.0.0.0/24 -o eth0 -j SNAT --to-source 192.168.1.10"
+            ),
+            "ip saddr 10.0.0.0/24 oifname \"eth0\" snat to 192.168.1.10"
+        );
+    }
+
+    #[test]
+    fn translates_new_connection_drop() {
+        assert_eq!(
+            translated(
+                "filter",
+                "FORWARD",
+                "-i lambdo0 -s 10.0.0.2 -m state --state NEW -j DROP"
+            ),
+            "iifname \"lambdo0\" ip saddr 10.0.0.2 ct state new drop"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_options() {
+        let rule = FirewallRule::new("filter", "FORWARD", "-m mark --mark 1 -j DROP".to_string());
+        assert!(translate(&rule).is_err());
+        let rule = FirewallRule::new("filter", "FORWARD", "-s 10.0.0.2 -j LOG".to_string());
+        assert!(translate(&rule).is_err());
+    }
+}
@@ -0,0 +1,139 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use firepilot::executor::Executor;
+use firepilot_models::models::{BootSource, Drive};
+use tokio::net::UnixStream;
+use tokio::process::Command;
+use tracing::debug;
+
+use crate::config::JailerConfig;
+
+/// Folder of the jail of a VM the jailer chroots into, within its workspace
+const JAIL_ROOT: &str = "root";
+/// Path of the API socket of firecracker, within the jail
+const JAILED_SOCKET: &str = "/firecracker.socket";
+/// How long the jailer has to get firecracker listening on its socket
+const SPAWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Folder the workspaces of jailed VMs are in, named after the firecracker
+/// binary as the jailer does.
+pub(super) fn chroot(config: &JailerConfig, exec_file: &Path) -> String {
+    let exec_name = exec_file
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "firecracker".to_string());
+    Path::new(&config.chroot_base)
+        .join(exec_name)
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Folder firecracker sees as `/`, where the images of a VM are placed.
+pub(super) fn jail_root(executor: &Executor) -> PathBuf {
+    executor.chroot().join(JAIL_ROOT)
+}
+
+/// Give the files of the jail of a VM to the user firecracker runs as.
+pub(super) fn give_jail(config: &JailerConfig, executor: &Executor) -> Result<()> {
+    let root = jail_root(executor);
+    std::os::unix::fs::chown(&root, Some(config.uid), Some(config.gid))?;
+    for entry in std::fs::read_dir(&root)? {
+        let path = entry?.path();
+        std::os::unix::fs::chown(&path, Some(config.uid), Some(config.gid))
+            .map_err(|e| anyhow!("cannot give {} to the jail: {}", path.display(), e))?;
+    }
+    Ok(())
+}
+
+/// Start firecracker through the jailer, in the workspace of `executor`
+/// whose jail is already populated.
+///
+/// The jailer daemonizes firecracker, which is not a child of lambdo then.
+/// Its socket is linked from the workspace, where the executor expects it.
+/// What a previous firecracker left in the jail is removed first, the jailer
+/// refusing to create device nodes that exist.
+pub(super) async fn spawn(
+    config: &JailerConfig,
+    executor: &Executor,
+    exec_file: &Path,
+    vm_id: &str,
+) -> Result<()> {
+    let root = jail_root(executor);
+    let _ = std::fs::remove_dir_all(root.join("dev"));
+    let _ = std::fs::remove_file(root.join(JAILED_SOCKET.trim_start_matches('/')));
+
+    let mut command = Command::new(&config.binary);
+    command
+        .arg("--id")
+        .arg(vm_id)
+        .arg("--exec-file")
+        .arg(exec_file)
+        .arg("--uid")
+        .arg(config.uid.to_string())
+        .arg("--gid")
+        .arg(config.gid.to_string())
+        .arg("--chroot-base-dir")
+        .arg(&config.chroot_base);
+    if let Some(version) = config.cgroup_version {
+        command.arg("--cgroup-version").arg(version.to_string());
+    }
+    if let Some(parent) = &config.parent_cgroup {
+        command.arg("--parent-cgroup").arg(parent);
+    }
+    for cgroup in &config.cgroups {
+        command.arg("--cgroup").arg(cgroup);
+    }
+    command
+        .arg("--daemonize")
+        .arg("--")
+        .arg("--api-sock")
+        .arg(JAILED_SOCKET);
+
+    debug!("running {:?}", command);
+    let output = command
+        .output()
+        .await
+        .map_err(|e| anyhow!("error when running the jailer: {}", e))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "error when jailing firecracker: {}",
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+
+    let socket = executor.chroot().join("firecracker.socket");
+    let _ = std::fs::remove_file(&socket);
+    std::os::unix::fs::symlink(root.join(JAILED_SOCKET.trim_start_matches('/')), &socket)?;
+
+    let deadline = tokio::time::Instant::now() + SPAWN_TIMEOUT;
+    while UnixStream::connect(&socket).await.is_err() {
+        if tokio::time::Instant::now() > deadline {
+            return Err(anyhow!("jailed firecracker of VM {} did not start", vm_id));
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    Ok(())
+}
+
+/// `drives` as seen by a jailed firecracker, each in the root of the jail.
+pub(super) fn jailed_drives(drives: &[Drive]) -> Vec<Drive> {
+    drives
+        .iter()
+        .map(|drive| Drive {
+            path_on_host: format!("/{}", drive.drive_id),
+            ..drive.clone()
+        })
+        .collect()
+}
+
+/// `kernel` as seen by a jailed firecracker.
+pub(super) fn jailed_kernel(kernel: &BootSource) -> BootSource {
+    BootSource {
+        kernel_image_path: "/vmlinux".to_string(),
+        initrd_path: kernel.initrd_path.as_ref().map(|_| "/initrd".to_string()),
+        ..kernel.clone()
+    }
+}
@@ -1,24 +1,57 @@
+pub mod firewall;
+mod jailer;
 mod net;
 
-use std::path::PathBuf;
+pub(super) use net::{ipv6_of, remove_leaked};
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
 use std::{error::Error as STDError, fmt::Display};
 
 use firepilot::builder::drive::DriveBuilder;
 use firepilot::builder::executor::FirecrackerExecutorBuilder;
 use firepilot::builder::kernel::KernelBuilder;
 use firepilot::builder::network_interface::NetworkInterfaceBuilder;
-use firepilot::machine::Machine;
+use firepilot::executor::{Action, Executor};
+use firepilot_models::models::vm::{State, Vm};
+use firepilot_models::models::{MachineConfiguration, Vsock};
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
 use tracing::{debug, error, info, trace};
 use uuid::Uuid;
 
+use crate::config::{ExecutorConfig, JailerConfig, ReadOnlyRootfsConfig};
 use crate::vm_manager::state::VMState;
 
-use super::state::LambdoState;
-use super::VMOptions;
+use super::agent;
+use super::boot_watchdog::BootFailure;
+use super::cpu_accounting;
+use super::image_manager::profile;
+use super::leases::Lease;
+use super::now_ms;
+use super::persistence::PersistedVM;
+use super::restart::RestartMode;
+use super::ssh;
+use super::state::{LambdoState, QuotaViolation, VMImages, VMStatus};
+use super::vm_snapshots;
+use super::volume_manager::file_driver::{copy_file, link_file};
+use super::volume_manager::validate_name;
+use super::{AdoptOptions, Check, PortsUpdateDTO, StartPlan, VMOptions};
 use firepilot::builder::{Builder, Configuration};
 use firepilot::{builder, machine};
 
-const DEFAULT_BOOT_ARGS: &str = "console=ttyS0 reboot=k panic=1 pci=off nomodule";
+/// Kernel command line of the VMs started without boot arguments
+pub const DEFAULT_BOOT_ARGS: &str = "console=ttyS0 reboot=k panic=1 pci=off nomodule";
+/// Machine size used by firecracker when none is given
+pub const DEFAULT_VCPU_COUNT: u8 = 1;
+pub const DEFAULT_MEM_SIZE_MIB: u32 = 128;
+/// Most vCPUs firecracker gives a VM
+const MAX_VCPU_COUNT: u8 = 32;
+/// How long a guest asked to reboot has to make firecracker exit
+const HALT_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[derive(Clone, Debug)]
 struct VMOptionsWrapper(VMOptions);
@@ -58,12 +91,7 @@ impl TryInto<Configuration> for VMOptionsWrapper {
 
         let mut kernel = KernelBuilder::new();
 
-        kernel.boot_args = Some(
-            opts.boot
-                .boot_args
-                .clone()
-                .unwrap_or(DEFAULT_BOOT_ARGS.to_string()),
-        );
+        kernel.boot_args = Some(boot_args(opts));
         kernel.initrd_path = if let Some(initrd) = opts.boot.initrd.clone() {
             Some(initrd.path.into_os_string().into_string().map_err(|e| {
                 Error::ImageError(anyhow::anyhow!(
@@ -98,15 +126,10 @@ impl TryInto<Configuration> for VMOptionsWrapper {
             .try_build()
             .map_err(Error::VmmNew)?;
 
-        let executor = FirecrackerExecutorBuilder::new()
-            .with_chroot("/tmp".to_string())
-            .with_exec_binary(PathBuf::from("/usr/bin/firecracker"))
-            .try_build()
-            .map_err(Error::VmmNew)?;
-
+        // The executor depends on the configuration of lambdo, it is set
+        // when the VM is created
         configuration = configuration
             .with_kernel(kernel.try_build().unwrap())
-            .with_executor(executor)
             .with_interface(network);
 
         Ok(configuration)
@@ -124,6 +147,47 @@ pub enum Error {
     NoIPAvailable,
     VmNotFound,
     VmAlreadyEnded,
+    VmAlreadyExists,
+    VolumeError(anyhow::Error),
+    VolumeNotFound,
+    VolumeAlreadyExists,
+    VolumeInUse(String),
+    VolumeBusy(String),
+    SnapshotNotFound,
+    ShareLinkNotFound,
+    StackNotFound,
+    StackAlreadyExists,
+    ServiceNotFound,
+    /// A request with the same idempotency key is still being handled
+    IdempotencyKeyInUse,
+    /// The idempotency key was first sent with a different request
+    IdempotencyKeyReused,
+    /// A service of a stack did not pass its readiness probe in time
+    ServiceUnhealthy(String, BootFailure),
+    SnapshotAlreadyExists,
+    PortInUse(u16),
+    PortNotMapped(u16),
+    DestroyNotScheduled,
+    DestroyScheduled,
+    VmNotRunning,
+    VmNotPaused,
+    TenantNotFound,
+    FeatureDisabled(String),
+    InvalidOptions(String),
+    InsufficientCapacity(String),
+    BudgetExceeded(String),
+    QuotaExceeded(QuotaViolation),
+    BootTimeout(BootFailure),
+    PromotionRejected(String),
+    ShuttingDown,
+    Cordoned,
+    AgentUnavailable(String),
+    FunctionNotFound,
+    JobNotFound,
+    FunctionAlreadyExists,
+    HookFailed(String),
+    AdmissionDenied(String),
+    PolicyUnavailable(String),
 }
 
 impl STDError for Error {}
@@ -140,28 +204,312 @@ impl Display for Error {
             Error::NoIPAvailable => write!(f, "No IP address available"),
             Error::VmNotFound => write!(f, "VM not found"),
             Error::VmAlreadyEnded => write!(f, "VM already ended"),
+            Error::VmAlreadyExists => write!(f, "VM already exists"),
+            Error::VolumeError(e) => write!(f, "Error with volumes: {:?}", e),
+            Error::VolumeNotFound => write!(f, "Volume not found"),
+            Error::VolumeAlreadyExists => write!(f, "Volume already exists"),
+            Error::VolumeInUse(id) => write!(f, "Volume is attached to VM {}", id),
+            Error::VolumeBusy(name) => {
+                write!(f, "Volume {} is being created, copied or deleted", name)
+            }
+            Error::SnapshotNotFound => write!(f, "Snapshot not found"),
+            Error::ShareLinkNotFound => write!(f, "Share link not found"),
+            Error::StackNotFound => write!(f, "Stack not found"),
+            Error::StackAlreadyExists => write!(f, "Stack already exists"),
+            Error::ServiceNotFound => write!(f, "Service not found"),
+            Error::IdempotencyKeyInUse => {
+                write!(f, "A request with this idempotency key is in progress")
+            }
+            Error::IdempotencyKeyReused => {
+                write!(f, "Idempotency key was used for a different request")
+            }
+            Error::ServiceUnhealthy(service, failure) => {
+                write!(f, "Service {} did not become healthy: {}", service, failure)
+            }
+            Error::SnapshotAlreadyExists => write!(f, "Snapshot already exists"),
+            Error::PortInUse(port) => write!(f, "Host port {} is already mapped", port),
+            Error::PortNotMapped(port) => write!(f, "Host port {} is not mapped", port),
+            Error::DestroyNotScheduled => write!(f, "VM destruction is not scheduled"),
+            Error::DestroyScheduled => write!(f, "VM destruction is scheduled"),
+            Error::VmNotRunning => write!(f, "VM is not running"),
+            Error::VmNotPaused => write!(f, "VM is not paused"),
+            Error::TenantNotFound => write!(f, "Tenant not found"),
+            Error::FeatureDisabled(feature) => write!(f, "Feature {} is disabled", feature),
+            Error::InvalidOptions(reason) => write!(f, "Invalid VM options: {}", reason),
+            Error::InsufficientCapacity(reason) => write!(f, "Insufficient capacity: {}", reason),
+            Error::BudgetExceeded(reason) => write!(f, "CPU budget exceeded: {}", reason),
+            Error::QuotaExceeded(violation) => write!(
+                f,
+                "Namespace quota exceeded: namespace {} would use {} {}, more than {}",
+                violation.namespace, violation.used, violation.limit, violation.max
+            ),
+            Error::BootTimeout(failure) => write!(f, "VM did not boot in time: {}", failure),
+            Error::PromotionRejected(reason) => write!(f, "Image promotion rejected: {}", reason),
+            Error::ShuttingDown => write!(f, "lambdo is shutting down"),
+            Error::Cordoned => write!(f, "Node is cordoned"),
+            Error::AgentUnavailable(reason) => write!(f, "Guest agent unavailable: {}", reason),
+            Error::FunctionNotFound => write!(f, "Function not found"),
+            Error::JobNotFound => write!(f, "Job not found"),
+            Error::FunctionAlreadyExists => write!(f, "Function already exists"),
+            Error::HookFailed(reason) => write!(f, "Hook failed: {}", reason),
+            Error::AdmissionDenied(reason) => write!(f, "Request denied: {}", reason),
+            Error::PolicyUnavailable(reason) => write!(f, "Policy engine unavailable: {}", reason),
         }
     }
 }
 
-pub async fn start(state: &mut LambdoState, vm_options: VMOptions) -> Result<String, Error> {
+/// Kernel command line of a VM started with `opts`
+fn boot_args(opts: &VMOptions) -> String {
+    let mut boot_args = opts
+        .boot
+        .boot_args
+        .clone()
+        .unwrap_or(DEFAULT_BOOT_ARGS.to_string());
+    // Applied by the guest kernel and init, without changing the rootfs
+    let mut sysctls: Vec<_> = opts.boot.sysctls.iter().collect();
+    sysctls.sort();
+    for (name, value) in sysctls {
+        boot_args.push_str(&format!(" sysctl.{}={}", name, value));
+    }
+    if !opts.boot.kernel_modules.is_empty() {
+        boot_args.push_str(&format!(
+            " modules-load={}",
+            opts.boot.kernel_modules.join(",")
+        ));
+    }
+    boot_args
+}
+
+/// Apply the machine class, the boot profiles of the images and the
+/// read-only rootfs mode to `vm_options`.
+fn prepare(state: &LambdoState, vm_options: &mut VMOptions) -> Result<(), Error> {
+    apply_machine_class(state, vm_options)?;
+    profile::apply(
+        vm_options,
+        state.config.api.image_manager.enforce_boot_profiles,
+    )
+    .map_err(Error::InvalidOptions)?;
+    if vm_options
+        .tenant
+        .as_ref()
+        .and_then(|tenant| state.config.api.tenants.get(tenant))
+        .is_some_and(|tenant| tenant.read_only_rootfs)
+    {
+        vm_options.boot.read_only_rootfs = true;
+    }
+    if vm_options.boot.read_only_rootfs {
+        read_only_rootfs(vm_options, &state.config.api.read_only_rootfs);
+    }
+    Ok(())
+}
+
+/// Size `vm_options` by its machine class, if it has one.
+///
+/// A size given along with the class must be the one of the class.
+fn apply_machine_class(state: &LambdoState, vm_options: &mut VMOptions) -> Result<(), Error> {
+    let Some(name) = &vm_options.machine_class else {
+        return Ok(());
+    };
+    let class = state
+        .config
+        .api
+        .admission
+        .classes
+        .get(name)
+        .ok_or_else(|| Error::InvalidOptions(format!("unknown machine class {}", name)))?;
+
+    if vm_options
+        .vcpu_count
+        .is_some_and(|vcpus| vcpus != class.vcpu_count)
+        || vm_options
+            .mem_size_mib
+            .is_some_and(|mem| mem != class.mem_size_mib)
+    {
+        return Err(Error::InvalidOptions(format!(
+            "machine class {} is {} vCPUs and {} MiB, the size cannot be changed",
+            name, class.vcpu_count, class.mem_size_mib
+        )));
+    }
+    vm_options.vcpu_count = Some(class.vcpu_count);
+    vm_options.mem_size_mib = Some(class.mem_size_mib);
+    Ok(())
+}
+
+/// What starting a VM with `vm_options` would do, checking everything
+/// [`start`] checks before creating anything.
+pub fn plan(state: &LambdoState, mut vm_options: VMOptions) -> StartPlan {
+    let mut checks = vec![Check::new(
+        "cordon",
+        if state.shutting_down {
+            Err(Error::ShuttingDown)
+        } else if state.cordon.is_some() {
+            Err(Error::Cordoned)
+        } else {
+            Ok("the node accepts new VMs".to_string())
+        },
+    )];
+    if let Some(class) = vm_options.machine_class.clone() {
+        checks.push(Check::new(
+            "machine class",
+            apply_machine_class(state, &mut vm_options)
+                .map(|_| format!("sized by machine class {}", class)),
+        ));
+    }
+    checks.push(Check::new(
+        "boot profile",
+        prepare(state, &mut vm_options).map(|_| "the images' minimums are met".to_string()),
+    ));
+
+    let tenant = vm_options.tenant.as_deref();
+    if let Some(tenant) = tenant {
+        checks.push(Check::new(
+            "tenant",
+            if state.config.api.tenants.contains_key(tenant) {
+                Ok(format!("tenant {} exists", tenant))
+            } else {
+                Err(Error::TenantNotFound)
+            },
+        ));
+        checks.push(Check::new(
+            "cpu budget",
+            cpu_accounting::check_budget(state, tenant).map(|_| "within budget".to_string()),
+        ));
+    }
+
+    let machine = machine_configuration(&vm_options);
+    checks.push(Check::new(
+        "machine size",
+        machine
+            .as_ref()
+            .map(|m| format!("{} vCPUs, {} MiB", m.vcpu_count, m.mem_size_mib))
+            .map_err(|e| e.to_string()),
+    ));
+    if let Ok(machine) = &machine {
+        checks.push(Check::new(
+            "capacity",
+            admit(state, machine, vm_options.machine_class.as_deref())
+                .map(|_| "the host has room for the VM".to_string()),
+        ));
+    }
+
+    if let Some(profile) = &vm_options.network.egress_profile {
+        let proxy = &state.config.api.egress_proxy;
+        checks.push(Check::new(
+            "egress profile",
+            if proxy.enabled && proxy.profiles.contains_key(profile) {
+                Ok(format!("egress through profile {}", profile))
+            } else {
+                Err(format!("egress profile {} is not available", profile))
+            },
+        ));
+    }
+    if let Some(policy) = &vm_options.snapshot_policy {
+        checks.push(Check::new(
+            "snapshot policy",
+            policy.check(&state.config.api.vm_snapshots).map(|_| {
+                format!(
+                    "a snapshot every {} seconds, {} kept",
+                    policy.interval_seconds, policy.retention
+                )
+            }),
+        ));
+    }
+
+    StartPlan {
+        bridge: state.config.api.bridge_for(tenant).bridge,
+        boot_args: boot_args(&vm_options),
+        vcpu_count: vm_options.vcpu_count.unwrap_or(DEFAULT_VCPU_COUNT),
+        mem_size_mib: vm_options.mem_size_mib.unwrap_or(DEFAULT_MEM_SIZE_MIB),
+        read_only_rootfs: vm_options.boot.read_only_rootfs,
+        checks,
+    }
+}
+
+/// Attach the root device read-only, and boot through the init setting up a
+/// tmpfs overlay over it in the guest.
+fn read_only_rootfs(options: &mut VMOptions, config: &ReadOnlyRootfsConfig) {
+    for disk in options.disks.iter_mut().filter(|disk| disk.is_root_device) {
+        disk.is_readonly = true;
+    }
+
+    let mut boot_args = options
+        .boot
+        .boot_args
+        .clone()
+        .unwrap_or(DEFAULT_BOOT_ARGS.to_string());
+    boot_args.push_str(&format!(
+        " ro init={} overlay_root=ram",
+        config.overlay_init
+    ));
+    if let Some(size) = &config.overlay_size {
+        boot_args.push_str(&format!(" overlay_size={}", size));
+    }
+    options.boot.boot_args = Some(boot_args);
+}
+
+pub async fn start(state: &mut LambdoState, mut vm_options: VMOptions) -> Result<String, Error> {
+    if state.shutting_down {
+        return Err(Error::ShuttingDown);
+    }
+    if state.cordon.is_some() {
+        return Err(Error::Cordoned);
+    }
+    prepare(state, &mut vm_options)?;
+
     trace!("Creating VMState");
     let mut configuration: Configuration = VMOptionsWrapper::from(vm_options.clone()).try_into()?;
     let mut configuration_cloned: Configuration =
         VMOptionsWrapper::from(vm_options.clone()).try_into()?;
 
     let id = configuration.vm_id.clone();
+    configuration_cloned.vm_id = id.clone();
+    configuration_cloned.executor = Some(executor(state, &id)?);
+    let machine_configuration = machine_configuration(&vm_options)?;
+    admit(
+        state,
+        &machine_configuration,
+        vm_options.machine_class.as_deref(),
+    )?;
+    if let Some(policy) = &vm_options.snapshot_policy {
+        policy
+            .check(&state.config.api.vm_snapshots)
+            .map_err(Error::InvalidOptions)?;
+    }
+
+    let ttl_seconds = vm_options.ttl_seconds;
+    let tenant = vm_options.tenant.clone();
+    if let Some(tenant) = &tenant {
+        if !state.config.api.tenants.contains_key(tenant) {
+            return Err(Error::TenantNotFound);
+        }
+        cpu_accounting::check_budget(state, tenant)?;
+    }
+    let egress_profile = vm_options.network.egress_profile.clone();
+    if let Some(profile) = &egress_profile {
+        let proxy = &state.config.api.egress_proxy;
+        if !proxy.enabled || !proxy.profiles.contains_key(profile) {
+            return Err(Error::InvalidOptions(format!(
+                "egress profile {} is not available",
+                profile
+            )));
+        }
+    }
+    let bridge = state.config.api.bridge_for(tenant.as_deref());
 
-    let ip = net::find_available_ip(state).await.map_err(|e| {
+    let ip = net::find_available_ip(state, &id, &bridge).map_err(|e| {
         error!("Error while finding available IP address: {:?}", e);
         Error::NoIPAvailable
     })?;
 
     info!("Creating tap device");
-    let tap_name = net::create_tap_device(&id).await.map_err(|e| {
-        error!("Error while creating tap device: {:?}", e);
-        Error::NetSetupError(e)
-    })?;
+    let tap_name = match net::create_tap_device(&id).await {
+        Ok(tap_name) => tap_name,
+        Err(e) => {
+            error!("Error while creating tap device: {:?}", e);
+            state.ipam.release(&id);
+            return Err(Error::NetSetupError(e));
+        }
+    };
 
     configuration.interfaces[0]
         .host_dev_name
@@ -169,49 +517,530 @@ pub async fn start(state: &mut LambdoState, vm_options: VMOptions) -> Result<Str
 
     let mut vm_state = VMState::new(configuration);
     vm_state.port_mapping = vm_options.network.port_mapping.into_iter().collect();
+    vm_state.egress_profile = egress_profile;
+    vm_state.tenant = tenant;
+    if let Some(namespace) = &vm_options.namespace {
+        vm_state.namespace.clone_from(namespace);
+    }
+    vm_state.stack = vm_options.stack.clone();
+    vm_state.vcpu_count = Some(machine_configuration.vcpu_count as u8);
+    vm_state.mem_size_mib = Some(machine_configuration.mem_size_mib as u32);
+    vm_state.machine_class = vm_options.machine_class.clone();
+    vm_state.snapshot_policy = vm_options.snapshot_policy.clone();
+    vm_state.restart_policy = vm_options.restart_policy.clone();
+    vm_state.images = VMImages {
+        kernel: Some(vm_options.boot.kernel.id.clone()),
+        initrd: vm_options.boot.initrd.as_ref().map(|i| i.id.clone()),
+        disks: vm_options
+            .disks
+            .iter()
+            .map(|d| d.image.id.clone())
+            .collect(),
+    };
 
     vm_state.ip = Some(ip);
 
-    debug!("Adding interface to bridge");
+    // The tap device and the firewall rules are undone if the VM does not
+    // start, not to be left behind
+    let started = async {
+        debug!("Adding interface to bridge");
 
-    net::add_interface_to_bridge(&tap_name, &*state).map_err(|e| {
-        error!("Error while adding interface to bridge: {:?}", e);
-        Error::NoIPAvailable
-    })?;
+        net::add_interface_to_bridge(&tap_name, &bridge.bridge).map_err(|e| {
+            error!("Error while adding interface to bridge: {:?}", e);
+            Error::NoIPAvailable
+        })?;
 
-    net::add_boot_option(&mut vm_state, state).map_err(|e| {
-        error!("Error while adding boot option: {:?}", e);
-        Error::NetSetupError(e)
-    })?;
+        net::add_boot_option(&mut vm_state, &bridge).map_err(|e| {
+            error!("Error while adding boot option: {:?}", e);
+            Error::NetSetupError(e)
+        })?;
 
-    debug!("Adding port mapping");
-    trace!("Port mapping: {:?}", vm_state.port_mapping);
-    net::create_port_mapping(&mut vm_state, state).map_err(|e| {
-        error!("Error while adding port mapping: {:?}", e);
-        Error::NetSetupError(e)
-    })?;
+        debug!("Adding port mapping");
+        trace!("Port mapping: {:?}", vm_state.port_mapping);
+        net::create_port_mapping(&mut vm_state, state).map_err(|e| {
+            error!("Error while adding port mapping: {:?}", e);
+            Error::NetSetupError(e)
+        })?;
+        net::limit_connections(
+            &mut vm_state,
+            &bridge,
+            &state.config.api.network.connection_limits,
+            state.config.api.network.firewall,
+        )
+        .map_err(|e| {
+            error!("Error while limiting connections: {:?}", e);
+            Error::NetSetupError(e)
+        })?;
 
-    configuration_cloned.interfaces[0] = vm_state.configuration.interfaces[0].clone();
-    configuration_cloned
-        .kernel
-        .clone_from(&vm_state.configuration.kernel);
+        if vm_state.egress_profile.is_some() {
+            debug!("Restricting egress to the proxy");
+            net::block_direct_egress(&mut vm_state, &bridge, state.config.api.network.firewall)
+                .and_then(|_| net::add_proxy_boot_option(&mut vm_state, &bridge, state))
+                .map_err(|e| {
+                    error!("Error while restricting egress: {:?}", e);
+                    Error::NetSetupError(e)
+                })?;
+        }
+
+        configuration_cloned.interfaces[0] = vm_state.configuration.interfaces[0].clone();
+        configuration_cloned
+            .kernel
+            .clone_from(&vm_state.configuration.kernel);
+
+        let mut persistent_drives: Vec<String> = vm_options
+            .disks
+            .iter()
+            .filter(|d| d.is_persistent)
+            .map(|d| keep_only_alphanumerics(&d.image.id))
+            .collect();
+
+        if state.config.feature_enabled("ssh") {
+            let seed = ssh::create_seed(&state.config.api.ssh, &id)
+                .await
+                .map_err(Error::Other)?;
+            let mut drive = DriveBuilder::new();
+            drive.path_on_host = Some(seed);
+            drive.drive_id = Some(ssh::SEED_DRIVE_ID.to_string());
+            drive.is_read_only = true;
+            drive.is_root_device = false;
+            configuration_cloned =
+                configuration_cloned.with_drive(drive.try_build().map_err(Error::VmmNew)?);
+            // Already the VM's own
+            persistent_drives.push(ssh::SEED_DRIVE_ID.to_string());
+        }
+
+        let machine = create_machine(
+            &mut configuration_cloned,
+            &persistent_drives,
+            &machine_configuration,
+            &state.config.api.executor,
+            state.config.api.jailer.as_ref(),
+        )
+        .await
+        .map_err(|e| {
+            error!("Error while creating VMM: {:?}", e);
+            Error::VmmConfigure(e)
+        })?;
+        vm_state.configuration.storage = configuration_cloned.storage;
+
+        info!("Starting execution for {:?}", vm_state);
+
+        // Held by the VM from now on, for its workspace to be removed if it
+        // does not start
+        vm_state
+            .machine
+            .insert(machine)
+            .send_action(Action::InstanceStart)
+            .await
+            .map_err(|e| Error::VmmRun(e.into()))
+    }
+    .await;
+    if let Err(e) = started {
+        // Firecracker processes are not killed when dropped
+        if let Some(machine) = vm_state.machine.as_mut() {
+            let _ = machine.destroy_socket().await;
+        }
+        undo_network(state, &vm_state, &tap_name).await;
+        remove_workspace(&vm_state).await;
+        state.ipam.release(&id);
+        ssh::remove(&state.config.api.ssh, &id);
+        return Err(e);
+    }
+    if let Some(tenant) = vm_state.tenant.as_deref() {
+        let accounting = &state.config.api.cpu_accounting;
+        if let (true, Some(machine)) = (accounting.enabled, vm_state.machine.as_ref()) {
+            let socket = machine.chroot().join("firecracker.socket");
+            if let Err(e) = cpu_accounting::attach(accounting, tenant, &socket) {
+                error!(
+                    "Error while accounting for the CPU time of VM {}: {:?}",
+                    id, e
+                );
+            }
+        }
+    }
+    vm_state.set_state(VMStatus::Running);
+    vm_state.started_at = Some(now_ms());
+    vm_state.expires_at = ttl_seconds.and_then(|ttl| {
+        vm_state
+            .started_at
+            .map(|started_at| started_at + ttl * 1000)
+    });
 
-    let mut machine = Machine::new();
-    machine.create(configuration_cloned).await.map_err(|e| {
-        error!("Error while creating VMM: {:?}", e);
-        Error::VmmConfigure(e)
+    state.events.record(
+        "vm.created",
+        Some(&id),
+        serde_json::json!({
+            "ip": ip.address(),
+            "port_mapping": vm_state.port_mapping,
+            "tenant": vm_state.tenant,
+            "namespace": vm_state.namespace,
+            "stack": vm_state.stack,
+        }),
+    );
+    state
+        .events
+        .record("vm.running", Some(&id), serde_json::Value::Null);
+    state.leases.acquire(&id, lease(&vm_state, &bridge.bridge));
+    state.vms.push(vm_state);
+
+    Ok(id)
+}
+
+/// Remove the firewall rules and the tap device of a VM that failed to
+/// start.
+async fn undo_network(state: &LambdoState, vm: &VMState, tap_name: &str) {
+    if let Err(e) =
+        net::remove_firewall_rules(&vm.firewall_rules, state.config.api.network.firewall)
+    {
+        error!("Error while removing firewall rules: {:?}", e);
+    }
+    if let Err(e) = net::remove_tap_device(tap_name).await {
+        error!("Error while removing tap device {}: {:?}", tap_name, e);
+    }
+}
+
+/// Register a firecracker VM started outside of lambdo, reaching its API
+/// through a symlink to its socket in the lambdo workspace.
+pub async fn adopt(state: &mut LambdoState, options: AdoptOptions) -> Result<String, Error> {
+    // The workspace of the VM is named after its id
+    let id = match options.id {
+        Some(id) => {
+            validate_name(&id)?;
+            id
+        }
+        None => Uuid::new_v4().to_string(),
+    };
+    debug!("Adopting VM {} from {}", id, options.socket_path.display());
+
+    if state.vms.iter().any(|vm| vm.configuration.vm_id == id) {
+        return Err(Error::VmAlreadyExists);
+    }
+
+    let socket_path = options.socket_path.canonicalize().map_err(|e| {
+        Error::Other(anyhow::anyhow!(
+            "cannot resolve firecracker socket {}: {}",
+            options.socket_path.display(),
+            e
+        ))
     })?;
+    check_adoptable(state, &socket_path)?;
+    UnixStream::connect(&socket_path).await.map_err(|e| {
+        Error::Other(anyhow::anyhow!(
+            "cannot connect to firecracker socket {}: {}",
+            socket_path.display(),
+            e
+        ))
+    })?;
+
+    let ip = options
+        .ip
+        .as_deref()
+        .map(cidr::Ipv4Inet::from_str)
+        .transpose()
+        .map_err(|e| Error::NetSetupError(anyhow::anyhow!("invalid IP address: {}", e)))?;
+    if let Some(ip) = ip {
+        if state
+            .vms
+            .iter()
+            .any(|vm| vm.ip.map(|used| used.address()) == Some(ip.address()))
+        {
+            return Err(Error::NetSetupError(anyhow::anyhow!(
+                "IP address {} is already used",
+                ip.address()
+            )));
+        }
+    }
+
+    let port_mapping: HashMap<u16, u16> = options.port_mapping.into_iter().collect();
+    for host_port in port_mapping.keys() {
+        if state
+            .vms
+            .iter()
+            .any(|vm| vm.port_mapping.contains_key(host_port))
+        {
+            return Err(Error::NetSetupError(anyhow::anyhow!(
+                "Port mapping already exists for {}",
+                host_port
+            )));
+        }
+    }
+
+    let executor = build_executor(
+        &state.config.api.executor,
+        state.config.api.executor.chroot_base.clone(),
+        &id,
+    )?;
 
-    info!("Starting execution for {:?}", vm_state);
+    executor
+        .create_workspace()
+        .map_err(|e| Error::VmmConfigure(e.into()))?;
+    let socket = executor.chroot().join("firecracker.socket");
+    if socket.exists() {
+        std::fs::remove_file(&socket).map_err(|e| Error::Other(e.into()))?;
+    }
+    std::os::unix::fs::symlink(&socket_path, &socket)
+        .map_err(|e| Error::Other(anyhow::anyhow!("cannot link firecracker socket: {}", e)))?;
 
-    machine.start().await.unwrap();
-    vm_state.machine = Some(machine);
+    let mut configuration = Configuration::new(id.clone());
+    if let Some(tap) = options.tap {
+        configuration = configuration.with_interface(
+            NetworkInterfaceBuilder::new()
+                .with_host_dev_name(tap)
+                .with_iface_id("tap0".to_string())
+                .try_build()
+                .map_err(Error::VmmNew)?,
+        );
+    }
 
+    let mut vm_state = VMState::new(configuration);
+    vm_state.machine = Some(executor);
+    vm_state.ip = ip;
+    vm_state.port_mapping = port_mapping;
+    vm_state.adopted = true;
+    if let Some(namespace) = &options.namespace {
+        vm_state.namespace.clone_from(namespace);
+    }
+    vm_state.set_state(VMStatus::Running);
+
+    info!("VM {} adopted", id);
+    let bridge = state.config.api.bridge_for(None).bridge;
+    state.leases.acquire(&id, lease(&vm_state, &bridge));
+    state.events.record(
+        "vm.adopted",
+        Some(&id),
+        serde_json::json!({ "socket_path": socket_path }),
+    );
     state.vms.push(vm_state);
 
     Ok(id)
 }
 
+/// Refuse to adopt the firecracker process behind `socket_path` if it is one
+/// lambdo started or already adopted, which would hand it to the caller.
+fn check_adoptable(state: &LambdoState, socket_path: &Path) -> Result<(), Error> {
+    let config = &state.config.api;
+    let workspaces = std::iter::once(&config.executor.chroot_base)
+        .chain(config.jailer.as_ref().map(|jailer| &jailer.chroot_base))
+        .filter_map(|base| Path::new(base).canonicalize().ok());
+    let tracked = state
+        .vms
+        .iter()
+        .filter_map(|vm| vm.machine.as_ref())
+        .filter_map(|machine| {
+            machine
+                .chroot()
+                .join("firecracker.socket")
+                .canonicalize()
+                .ok()
+        });
+
+    if workspaces
+        .into_iter()
+        .any(|base| socket_path.starts_with(base))
+        || tracked.into_iter().any(|socket| socket == socket_path)
+    {
+        return Err(Error::InvalidOptions(format!(
+            "firecracker socket {} belongs to a VM of lambdo",
+            socket_path.display()
+        )));
+    }
+
+    Ok(())
+}
+
+/// Rebuild a VM saved before a restart, reattaching to its firecracker
+/// process if it is still running and cleaning up its network otherwise.
+///
+/// Returns whether the VM was reattached.
+pub async fn reattach(state: &mut LambdoState, persisted: PersistedVM) -> Result<bool, Error> {
+    let id = persisted.id;
+    debug!("Reattaching VM {}", id);
+
+    let executor = executor(state, &id)?;
+    let socket = executor.chroot().join("firecracker.socket");
+
+    let mut configuration = Configuration::new(id.clone());
+    configuration.kernel = persisted.kernel;
+    configuration.storage = persisted.drives;
+    configuration.interfaces = persisted.interfaces;
+
+    let mut vm_state = VMState::new(configuration);
+    vm_state.ip = persisted
+        .ip
+        .as_deref()
+        .map(cidr::Ipv4Inet::from_str)
+        .transpose()
+        .map_err(|e| Error::NetSetupError(anyhow::anyhow!("invalid IP address: {}", e)))?;
+    vm_state.port_mapping = persisted.port_mapping;
+    vm_state.adopted = persisted.adopted;
+    vm_state.egress_profile = persisted.egress_profile;
+    vm_state.firewall_rules = persisted.firewall_rules;
+    vm_state.destroy_at = persisted.destroy_at;
+    vm_state.expires_at = persisted.expires_at;
+    vm_state.tenant = persisted.tenant;
+    vm_state.namespace = persisted.namespace;
+    vm_state.stack = persisted.stack;
+    vm_state.started_at = persisted.started_at;
+    vm_state.images = persisted.images;
+    vm_state.vcpu_count = persisted.vcpu_count;
+    vm_state.mem_size_mib = persisted.mem_size_mib;
+    vm_state.machine_class = persisted.machine_class;
+    vm_state.snapshot_policy = persisted.snapshot_policy;
+    vm_state.snapshots = persisted.snapshots;
+    vm_state.restart_policy = persisted.restart_policy;
+    vm_state.restarts = persisted.restarts;
+    vm_state.network_usage = persisted.network_usage;
+    vm_state.tap_counters = persisted.tap_counters;
+    vm_state.set_state(persisted.status);
+
+    let was_alive = matches!(vm_state.status, VMStatus::Running | VMStatus::Paused);
+    if was_alive && UnixStream::connect(&socket).await.is_ok() {
+        vm_state.machine = Some(executor);
+        info!("VM {} reattached", id);
+        let bridge = state
+            .config
+            .api
+            .bridge_for(vm_state.tenant.as_deref())
+            .bridge;
+        state.leases.acquire(&id, lease(&vm_state, &bridge));
+        state.events.record(
+            "vm.reattached",
+            Some(&id),
+            serde_json::json!({ "status": vm_state.status }),
+        );
+        state.vms.push(vm_state);
+        return Ok(true);
+    }
+
+    info!("VM {} is gone, cleaning up after it", id);
+    if was_alive {
+        state
+            .events
+            .record("vm.exited", Some(&id), serde_json::Value::Null);
+    }
+    state
+        .events
+        .record("vm.destroyed", Some(&id), serde_json::Value::Null);
+
+    if vm_state.adopted {
+        let _ = std::fs::remove_file(&socket);
+    } else {
+        vm_state.machine = Some(executor);
+        remove_workspace(&vm_state).await;
+        ssh::remove(&state.config.api.ssh, &id);
+        vm_snapshots::remove_all(&state.config.api.vm_snapshots, &id);
+        cleanup_network(state, &mut vm_state).await?;
+    }
+    state.leases.release(&id);
+    state.ipam.release(&id);
+
+    Ok(false)
+}
+
+/// Delete the workspace of a VM, with its drive clones and kernel copy.
+///
+/// Drives used in place, such as volumes, live outside of it. A firecracker
+/// process still shutting down keeps its open files until it exits.
+async fn remove_workspace(vm: &VMState) {
+    let Some(machine) = vm.machine.as_ref() else {
+        return;
+    };
+    debug!("Removing workspace {}", machine.chroot().display());
+    if let Err(e) = tokio::fs::remove_dir_all(machine.chroot()).await {
+        error!(
+            "Error while removing workspace of VM {}: {:?}",
+            vm.configuration.vm_id, e
+        );
+    }
+}
+
+/// Lease of the address and host ports of a VM attached to `bridge`
+fn lease(vm: &VMState, bridge: &str) -> Lease {
+    Lease {
+        bridge: bridge.to_string(),
+        ip: vm.ip.map(|ip| ip.address()),
+        host_ports: vm.port_mapping.keys().copied().collect(),
+        socket: vm
+            .machine
+            .as_ref()
+            .map(|machine| machine.chroot().join("firecracker.socket"))
+            .unwrap_or_default(),
+    }
+}
+
+/// Add and remove port mappings of a VM without restarting it.
+pub fn update_ports(
+    state: &mut LambdoState,
+    id: &str,
+    update: PortsUpdateDTO,
+) -> Result<HashMap<u16, u16>, Error> {
+    let vm = state
+        .vms
+        .iter()
+        .find(|vm| vm.configuration.vm_id == id)
+        .ok_or(Error::VmNotFound)?;
+
+    if vm.adopted {
+        return Err(Error::Other(anyhow::anyhow!(
+            "ports of adopted VM {} are managed externally",
+            id
+        )));
+    }
+
+    let remove: Vec<u16> = update
+        .remove
+        .into_iter()
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+    if let Some(port) = remove
+        .iter()
+        .find(|port| !vm.port_mapping.contains_key(port))
+    {
+        return Err(Error::PortNotMapped(*port));
+    }
+
+    let add: HashMap<u16, u16> = update.add.into_iter().collect();
+    for host_port in add.keys() {
+        let in_use = state.vms.iter().any(|other| {
+            other.port_mapping.contains_key(host_port)
+                && (other.configuration.vm_id != id || !remove.contains(host_port))
+        });
+        if in_use {
+            return Err(Error::PortInUse(*host_port));
+        }
+    }
+
+    let mode = state.config.api.network.mode;
+    let backend = state.config.api.network.firewall;
+    let vm = state
+        .vms
+        .iter_mut()
+        .find(|vm| vm.configuration.vm_id == id)
+        .ok_or(Error::VmNotFound)?;
+    let bridge = state.config.api.bridge_for(vm.tenant.as_deref());
+    net::update_port_mapping(vm, &add, &remove, &bridge, mode, backend).map_err(|e| {
+        error!("Error while updating port mapping: {:?}", e);
+        Error::NetSetupError(e)
+    })?;
+    let port_mapping = vm.port_mapping.clone();
+    state
+        .leases
+        .update_ports(id, port_mapping.keys().copied().collect());
+
+    state.events.record(
+        "vm.ports_updated",
+        Some(id),
+        serde_json::json!({
+            "added": add,
+            "removed": remove,
+            "port_mapping": port_mapping,
+        }),
+    );
+
+    Ok(port_mapping)
+}
+
 pub async fn stop(state: &mut LambdoState, id: &str) -> Result<(), Error> {
     debug!("Stopping VM {}", id);
 
@@ -222,17 +1051,36 @@ pub async fn stop(state: &mut LambdoState, id: &str) -> Result<(), Error> {
         .ok_or(Error::VmNotFound)?;
 
     let mut vm = state.vms.remove(vm_index);
+    state.leases.release(id);
+    state.ipam.release(id);
+    state.share_links.retain(|_, link| link.vm_id != id);
+    for ids in state.warm_vms.values_mut() {
+        ids.retain(|warm| warm != id);
+    }
+    state
+        .events
+        .record("vm.destroyed", Some(id), serde_json::Value::Null);
 
-    let res = vm
-        .machine
-        .as_mut()
-        .ok_or(Error::Other(anyhow::anyhow!("VM is not running")))?
-        .stop()
-        .await
-        .map_err(|e| {
+    let res = if matches!(vm.status, VMStatus::Exited | VMStatus::CrashLoopBackOff) {
+        debug!("VM {} has already exited", id);
+        Ok(())
+    } else {
+        shut_down(&mut vm).await.map_err(|e| {
             error!("Error while stopping VM: {:?}", e);
             Error::Other(anyhow::anyhow!("Error while stopping VM: {:?}", e))
-        });
+        })
+    };
+
+    if vm.adopted {
+        debug!("VM {} was adopted, leaving its network untouched", id);
+        // Only holds the link to the socket of the VM
+        remove_workspace(&vm).await;
+        return res;
+    }
+
+    remove_workspace(&vm).await;
+    ssh::remove(&state.config.api.ssh, &vm.configuration.vm_id);
+    vm_snapshots::remove_all(&state.config.api.vm_snapshots, &vm.configuration.vm_id);
 
     match cleanup_network(state, &mut vm).await {
         Ok(()) => res,
@@ -247,36 +1095,955 @@ pub async fn stop(state: &mut LambdoState, id: &str) -> Result<(), Error> {
     }
 }
 
-pub async fn cleanup_network(state: &mut LambdoState, vm: &mut VMState) -> Result<(), Error> {
-    debug!(
-        "Cleaning up VM Network configuration for {} ",
-        vm.configuration.vm_id
-    );
+/// Make the firecracker process of a VM exit, by asking its guest to reboot.
+///
+/// A paused guest never handles the request, so its process is killed if
+/// lambdo owns it, or it is resumed first otherwise.
+async fn shut_down(vm: &mut VMState) -> Result<(), Error> {
+    let machine = vm
+        .machine
+        .as_mut()
+        .ok_or(Error::Other(anyhow::anyhow!("VM is not running")))?;
+    if vm.status == VMStatus::Paused {
+        if machine.is_running() {
+            return machine
+                .destroy_socket()
+                .await
+                .map_err(|e| Error::VmmRun(e.into()));
+        }
+        resume(vm).await?;
+    }
 
-    let ip = vm
-        .ip
+    vm.machine
         .as_ref()
-        .ok_or(Error::Other(anyhow::anyhow!("VM has no IP address")))?;
+        .ok_or(Error::Other(anyhow::anyhow!("VM is not running")))?
+        .send_action(Action::SendCtrlAltDel)
+        .await
+        .map_err(|e| Error::VmmRun(e.into()))
+}
 
-    net::remove_port_mapping(&vm.port_mapping, ip).map_err(|e| {
-        error!("Error while removing port mapping: {:?}", e);
-        Error::NetSetupError(e)
+/// Release the network of a VM whose firecracker process exited: its
+/// firewall rules, tap device, address and host ports.
+///
+/// The VM is kept, as exited, until it is destroyed, and so is its
+/// workspace for its disks to be exported.
+pub async fn reap(state: &mut LambdoState, id: &str) -> Result<(), Error> {
+    let vm_index = state
+        .vms
+        .iter()
+        .position(|vm| vm.configuration.vm_id == id)
+        .ok_or(Error::VmNotFound)?;
+    if state.vms[vm_index].adopted {
+        debug!("VM {} was adopted, leaving its network untouched", id);
+        return Ok(());
+    }
+
+    debug!("Reaping VM {}", id);
+    let mut vm = state.vms.remove(vm_index);
+    let result = cleanup_network(state, &mut vm).await;
+    vm.ip = None;
+    vm.firewall_rules.clear();
+    vm.port_mapping.clear();
+    state.leases.release(id);
+    state.ipam.release(id);
+    state.vms.insert(vm_index, vm);
+
+    result
+}
+
+/// Size of the machine requested in `options`, with firecracker defaults for
+/// what is not set.
+fn machine_configuration(options: &VMOptions) -> Result<MachineConfiguration, Error> {
+    let vcpu_count = options.vcpu_count.unwrap_or(DEFAULT_VCPU_COUNT);
+    if vcpu_count == 0
+        || vcpu_count > MAX_VCPU_COUNT
+        || (vcpu_count > 1 && !vcpu_count.is_multiple_of(2))
+    {
+        return Err(Error::InvalidOptions(format!(
+            "vcpu_count must be 1 or an even number up to {}",
+            MAX_VCPU_COUNT
+        )));
+    }
+
+    let mem_size_mib = options.mem_size_mib.unwrap_or(DEFAULT_MEM_SIZE_MIB);
+    if mem_size_mib == 0 || mem_size_mib > i32::MAX as u32 {
+        return Err(Error::InvalidOptions(
+            "mem_size_mib must be a positive number".to_string(),
+        ));
+    }
+
+    Ok(MachineConfiguration::new(
+        mem_size_mib as i32,
+        vcpu_count as i32,
+    ))
+}
+
+/// Check that the host has room for a VM of the given size, within the
+/// overcommit limits of the configuration and the slots of its machine class.
+fn admit(
+    state: &LambdoState,
+    machine_configuration: &MachineConfiguration,
+    machine_class: Option<&str>,
+) -> Result<(), Error> {
+    let admission = &state.config.api.admission;
+    let slots = machine_class.and_then(|class| {
+        admission
+            .classes
+            .get(class)
+            .and_then(|config| config.slots)
+            .map(|slots| (class, slots))
+    });
+    if let Some((class, slots)) = slots {
+        let used = state.class_usage(class);
+        if used >= slots {
+            return Err(Error::InsufficientCapacity(format!(
+                "the {} slots of machine class {} are used",
+                slots, class
+            )));
+        }
+    }
+
+    let (vcpus, memory) = state.committed_resources();
+
+    if let Some(ratio) = admission.cpu_overcommit {
+        let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let limit = (cpus as f64 * ratio) as u64;
+        let requested = vcpus + machine_configuration.vcpu_count as u64;
+        if requested > limit {
+            return Err(Error::InsufficientCapacity(format!(
+                "{} vCPUs would be committed, the limit is {}",
+                requested, limit
+            )));
+        }
+    }
+
+    if let Some(ratio) = admission.memory_overcommit {
+        let host_memory = host_memory_mib().map_err(Error::Other)?;
+        let limit = (host_memory as f64 * ratio) as u64;
+        let requested = memory + machine_configuration.mem_size_mib as u64;
+        if requested > limit {
+            return Err(Error::InsufficientCapacity(format!(
+                "{} MiB of memory would be committed, the limit is {} MiB",
+                requested, limit
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Total memory of the host, in MiB
+pub fn host_memory_mib() -> anyhow::Result<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo")?;
+    let kib = meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemTotal:"))
+        .and_then(|value| value.split_whitespace().next())
+        .and_then(|value| value.parse::<u64>().ok())
+        .ok_or(anyhow::anyhow!("cannot read the host memory size"))?;
+
+    Ok(kib / 1024)
+}
+
+/// Apply the machine size of a VM whose API socket is running.
+///
+/// firepilot has no call for the machine configuration, so the request is
+/// sent on the socket directly.
+async fn configure_machine(
+    executor: &Executor,
+    machine_configuration: &MachineConfiguration,
+) -> Result<(), machine::FirepilotError> {
+    put(executor, "/machine-config", machine_configuration)
+        .await
+        .map_err(|e| {
+            machine::FirepilotError::Configure(format!("Failed to configure machine: {}", e))
+        })
+}
+
+/// Add the vsock device the guest agent is reached through, listening on
+/// `socket` on the host side, `uds_path` being the same socket as seen by
+/// firecracker.
+///
+/// firepilot has no call for vsock devices either.
+async fn configure_vsock(
+    executor: &Executor,
+    socket: &Path,
+    uds_path: &str,
+) -> Result<(), machine::FirepilotError> {
+    // Left by a previous firecracker process of the VM, it would fail to bind
+    let _ = std::fs::remove_file(socket);
+    let vsock = Vsock::new(agent::GUEST_CID, uds_path.to_string());
+    put(executor, "/vsock", &vsock).await.map_err(|e| {
+        machine::FirepilotError::Configure(format!("Failed to configure vsock: {}", e))
+    })
+}
+
+/// Send a configuration request to the API socket of firecracker.
+async fn put(executor: &Executor, path: &str, body: &impl Serialize) -> anyhow::Result<()> {
+    let body = serde_json::to_string(body)?;
+    let request = format!(
+        "PUT {} HTTP/1.1\r\n\
+         Host: localhost\r\n\
+         Content-Type: application/json\r\n\
+         Accept: application/json\r\n\
+         Content-Length: {}\r\n\r\n{}",
+        path,
+        body.len(),
+        body
+    );
+
+    let mut stream = UnixStream::connect(executor.chroot().join("firecracker.socket")).await?;
+    stream.write_all(request.as_bytes()).await?;
+
+    // Replies to configuration requests are small enough to come in one read
+    let mut response = vec![0; 4096];
+    let read = stream.read(&mut response).await?;
+    let response = String::from_utf8_lossy(&response[..read]);
+
+    let success = response
+        .split_whitespace()
+        .nth(1)
+        .is_some_and(|status| status.starts_with('2'));
+    if !success {
+        let reason = response.lines().last().unwrap_or_default();
+        return Err(anyhow::anyhow!("{}", reason));
+    }
+
+    Ok(())
+}
+
+/// Folder firecracker sees as `/` for `executor`, where the images of the
+/// VM are placed.
+fn workspace(executor: &Executor, jailer: Option<&JailerConfig>) -> PathBuf {
+    match jailer {
+        Some(_) => jailer::jail_root(executor),
+        None => executor.chroot(),
+    }
+}
+
+/// Socket of the vsock device of a VM, through which its guest agent is
+/// reached, if lambdo created it.
+pub fn agent_socket(state: &LambdoState, vm: &VMState) -> Option<PathBuf> {
+    if vm.adopted {
+        return None;
+    }
+    let machine = vm.machine.as_ref()?;
+    Some(workspace(machine, state.config.api.jailer.as_ref()).join(agent::VSOCK_SOCKET))
+}
+
+/// Path of the vsock socket of a VM, as seen by its firecracker process
+fn vsock_uds_path(workspace: &Path, jailer: Option<&JailerConfig>) -> String {
+    match jailer {
+        Some(_) => format!("/{}", agent::VSOCK_SOCKET),
+        None => workspace
+            .join(agent::VSOCK_SOCKET)
+            .to_string_lossy()
+            .into_owned(),
+    }
+}
+
+/// Set up the workspace of a VM and configure firecracker, the same way
+/// `firepilot::machine::Machine::create` does, except that the drives listed in
+/// `persistent_drives` are used in place instead of being copied in the
+/// workspace, so that writes to them outlive the VM.
+///
+/// With the jailer, the images are placed in the jail instead, persistent
+/// drives being hard-linked there, and firecracker is started by the jailer.
+///
+/// The drives of `configuration` are updated to point to the files actually
+/// used by the VM.
+async fn create_machine(
+    configuration: &mut Configuration,
+    persistent_drives: &[String],
+    machine_configuration: &MachineConfiguration,
+    executor_config: &ExecutorConfig,
+    jailer: Option<&JailerConfig>,
+) -> Result<Executor, machine::FirepilotError> {
+    let setup_error = |what: &str, e: &dyn Display| {
+        machine::FirepilotError::Setup(format!("Failed to {}: {}", what, e))
+    };
+    let mut executor = configuration.executor.take().ok_or_else(|| {
+        machine::FirepilotError::Setup("No executor was provided in the configuration".into())
+    })?;
+    let kernel = configuration.kernel.take().ok_or_else(|| {
+        machine::FirepilotError::Setup("No kernel was provided in the configuration".into())
     })?;
 
-    let tap_name = vm.configuration.interfaces[0].host_dev_name.clone();
+    executor.create_workspace()?;
+    let placed = async {
+        let workspace = workspace(&executor, jailer);
+        std::fs::create_dir_all(&workspace).map_err(|e| setup_error("create jail", &e))?;
+
+        for drive in configuration.storage.iter_mut() {
+            let persistent = persistent_drives.contains(&drive.drive_id);
+            if persistent && jailer.is_none() {
+                debug!("Using drive {} in place", drive.drive_id);
+                continue;
+            }
+
+            // Each VM writes to its own clone of the image, sharing its blocks
+            // until they are written to when the filesystem supports reflinks.
+            // Read-only images are shared as they are.
+            let path = workspace.join(&drive.drive_id);
+            let placed = if persistent {
+                debug!(
+                    "Linking persistent drive {} to {:?}",
+                    drive.path_on_host, path
+                );
+                tokio::fs::hard_link(&drive.path_on_host, &path)
+                    .await
+                    .map_err(anyhow::Error::from)
+            } else if drive.is_read_only {
+                debug!("Linking drive {} to {:?}", drive.path_on_host, path);
+                link_file(Path::new(&drive.path_on_host), &path).await
+            } else {
+                debug!("Cloning drive {} to {:?}", drive.path_on_host, path);
+                copy_file(Path::new(&drive.path_on_host), &path).await
+            };
+            placed.map_err(|e| setup_error("place drive", &e))?;
+            drive.path_on_host = path.to_string_lossy().into_owned();
+        }
+
+        let kernel_path = workspace.join("vmlinux");
+        debug!("Linking kernel to {:?}", kernel_path);
+        link_file(Path::new(&kernel.kernel_image_path), &kernel_path)
+            .await
+            .map_err(|e| setup_error("place kernel", &e))?;
+        if let Some(initrd) = &kernel.initrd_path {
+            link_file(Path::new(initrd), &workspace.join("initrd"))
+                .await
+                .map_err(|e| setup_error("place initrd", &e))?;
+        }
+
+        launch(&mut executor, executor_config, jailer, &configuration.vm_id)
+            .await
+            .map_err(|e| setup_error("start firecracker", &e))?;
+        configure_machine(&executor, machine_configuration).await?;
+        let (drives, kernel) = match jailer {
+            Some(_) => (
+                jailer::jailed_drives(&configuration.storage),
+                jailer::jailed_kernel(&kernel),
+            ),
+            None => (configuration.storage.clone(), kernel),
+        };
+        executor.configure_drives(drives).await?;
+        executor.configure_boot_source(kernel).await?;
+        executor
+            .configure_network(configuration.interfaces.clone())
+            .await?;
+        configure_vsock(
+            &executor,
+            &workspace.join(agent::VSOCK_SOCKET),
+            &vsock_uds_path(&workspace, jailer),
+        )
+        .await?;
+
+        Ok(())
+    }
+    .await;
+    if let Err(e) = placed {
+        if executor.is_running() {
+            let _ = executor.destroy_socket().await;
+        }
+        // The clones of the drives of a VM that never started are of no use
+        let _ = tokio::fs::remove_dir_all(executor.chroot()).await;
+        return Err(e);
+    }
+
+    Ok(executor)
+}
+
+/// Start the firecracker process of `executor`, through the jailer if it is
+/// configured.
+async fn launch(
+    executor: &mut Executor,
+    executor_config: &ExecutorConfig,
+    jailer: Option<&JailerConfig>,
+    vm_id: &str,
+) -> anyhow::Result<()> {
+    match jailer {
+        Some(config) => {
+            jailer::give_jail(config, executor)?;
+            let firecracker = Path::new(&executor_config.firecracker_path);
+            jailer::spawn(config, executor, firecracker, vm_id).await
+        }
+        None => executor.run_socket().map_err(anyhow::Error::from),
+    }
+}
+
+/// Executor of the VM `id`, whose workspace is in the folder of the jails
+/// when the jailer is configured.
+fn executor(state: &LambdoState, id: &str) -> Result<Executor, Error> {
+    let config = &state.config.api;
+    let chroot = match &config.jailer {
+        Some(jailer) => jailer::chroot(jailer, Path::new(&config.executor.firecracker_path)),
+        None => config.executor.chroot_base.clone(),
+    };
+    build_executor(&config.executor, chroot, id)
+}
+
+fn build_executor(config: &ExecutorConfig, chroot: String, id: &str) -> Result<Executor, Error> {
+    Ok(FirecrackerExecutorBuilder::new()
+        .with_chroot(chroot)
+        .with_exec_binary(PathBuf::from(&config.firecracker_path))
+        .try_build()
+        .map_err(Error::VmmNew)?
+        .with_id(id.to_string()))
+}
+
+/// Check that the firecracker binary of `config` exists and can be run,
+/// returning the version it reports.
+pub async fn firecracker_version(config: &ExecutorConfig) -> anyhow::Result<String> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = &config.firecracker_path;
+    let metadata = std::fs::metadata(path)
+        .map_err(|e| anyhow::anyhow!("cannot find firecracker at {}: {}", path, e))?;
+    if !metadata.is_file() || metadata.permissions().mode() & 0o111 == 0 {
+        return Err(anyhow::anyhow!("{} is not executable", path));
+    }
+
+    let output = tokio::process::Command::new(path)
+        .arg("--version")
+        .output()
+        .await
+        .map_err(|e| anyhow::anyhow!("cannot run {}: {}", path, e))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    // Such as "Firecracker v1.5.0"
+    match stdout
+        .lines()
+        .next()
+        .and_then(|line| line.strip_prefix("Firecracker "))
+    {
+        Some(version) if output.status.success() => Ok(version.trim().to_string()),
+        _ => Err(anyhow::anyhow!(
+            "cannot detect the version of {}: {}",
+            path,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )),
+    }
+}
+
+/// Boot a VM without network from `kernel` and `rootfs`, check that it is
+/// still alive after `delay`, and tear it down.
+///
+/// The boot arguments make the guest reboot, and so firecracker exit, on
+/// kernel panic, so a VM still answering after the delay did boot.
+pub async fn boot_test(
+    config: &ExecutorConfig,
+    kernel: &Path,
+    rootfs: &Path,
+    delay: Duration,
+) -> Result<(), Error> {
+    let id = format!("doctor-{}", Uuid::new_v4());
+    let path = |p: &Path| {
+        p.canonicalize()
+            .map_err(|e| Error::ImageError(anyhow::anyhow!("{}: {}", p.display(), e)))
+    };
+
+    let mut drive = DriveBuilder::new();
+    drive.path_on_host = Some(path(rootfs)?);
+    drive.drive_id = Some("rootfs".to_string());
+    drive.is_root_device = true;
+
+    let mut kernel_builder = KernelBuilder::new();
+    kernel_builder.boot_args = Some(DEFAULT_BOOT_ARGS.to_string());
+    kernel_builder.kernel_image_path = Some(path(kernel)?.to_string_lossy().into_owned());
+
+    let executor = build_executor(config, config.chroot_base.clone(), &id)?;
+
+    let mut configuration = Configuration::new(id)
+        .with_drive(drive.try_build().map_err(Error::VmmNew)?)
+        .with_kernel(kernel_builder.try_build().map_err(Error::VmmNew)?)
+        .with_executor(executor);
+
+    let mut machine = create_machine(
+        &mut configuration,
+        &[],
+        &MachineConfiguration::new(DEFAULT_MEM_SIZE_MIB as i32, DEFAULT_VCPU_COUNT as i32),
+        config,
+        None,
+    )
+    .await
+    .map_err(Error::VmmConfigure)?;
+
+    let result = async {
+        machine
+            .send_action(Action::InstanceStart)
+            .await
+            .map_err(|e| Error::VmmRun(e.into()))?;
+        tokio::time::sleep(delay).await;
+        machine
+            .set_vm_state(Vm::new(State::Paused))
+            .await
+            .map_err(|e| {
+                Error::Other(anyhow::anyhow!(
+                    "VM did not survive its boot (kernel panic?): {}",
+                    e
+                ))
+            })
+    }
+    .await;
+
+    let _ = machine.destroy_socket().await;
+    let _ = tokio::fs::remove_dir_all(machine.chroot()).await;
+
+    result
+}
+
+/// Pause a VM until `destroy_at`, when it is expected to be destroyed unless
+/// the destruction is cancelled in the meantime.
+///
+/// Returns false if the destruction of the VM was already scheduled.
+pub async fn schedule_destroy(
+    state: &mut LambdoState,
+    id: &str,
+    destroy_at: u64,
+) -> Result<bool, Error> {
+    let vm = state
+        .vms
+        .iter_mut()
+        .find(|vm| vm.configuration.vm_id == id)
+        .ok_or(Error::VmNotFound)?;
+
+    if vm.destroy_at.is_some() {
+        debug!("Destruction of VM {} is already scheduled", id);
+        return Ok(false);
+    }
+
+    if vm.status != VMStatus::Paused {
+        pause(vm).await?;
+        vm.set_state(VMStatus::Paused);
+    }
+    vm.destroy_at = Some(destroy_at);
+    state.events.record(
+        "vm.destroy_scheduled",
+        Some(id),
+        serde_json::json!({ "destroy_at": destroy_at }),
+    );
+
+    Ok(true)
+}
 
+/// Roll the disks of a VM back to one of its snapshots, and boot it again
+/// from them.
+///
+/// The VM keeps its address, ports and firewall rules, but the guest loses
+/// everything it did not write to its disks before the snapshot.
+pub async fn rollback(state: &mut LambdoState, id: &str, snapshot: &str) -> Result<(), Error> {
+    let vm_index = state
+        .vms
+        .iter()
+        .position(|vm| vm.configuration.vm_id == id)
+        .ok_or(Error::VmNotFound)?;
+    let vm = &state.vms[vm_index];
+    let snapshot = vm
+        .snapshots
+        .iter()
+        .find(|s| s.name == snapshot)
+        .cloned()
+        .ok_or(Error::SnapshotNotFound)?;
+    if vm.adopted || vm.ip.is_none() {
+        return Err(Error::InvalidOptions(format!(
+            "VM {} cannot be booted again by lambdo",
+            id
+        )));
+    }
+
+    info!("Rolling VM {} back to snapshot {}", id, snapshot.name);
+    let mut vm = state.vms.remove(vm_index);
+    let result = async {
+        halt(&mut vm).await?;
+        let disks: Vec<(String, PathBuf)> = vm
+            .configuration
+            .storage
+            .iter()
+            .map(|drive| (drive.drive_id.clone(), PathBuf::from(&drive.path_on_host)))
+            .collect();
+        vm_snapshots::restore(&state.config.api.vm_snapshots, id, &snapshot, &disks)
+            .await
+            .map_err(Error::Other)?;
+        reboot(state, &mut vm).await
+    }
+    .await;
+
+    // A VM that failed to boot again is left exited, for its owner to
+    // destroy it or roll it back again
+    if result.is_ok() {
+        state.events.record(
+            "vm.rolled_back",
+            Some(id),
+            serde_json::json!({ "snapshot": snapshot.name }),
+        );
+    } else {
+        vm.set_state(VMStatus::Exited);
+    }
+    state.vms.insert(vm_index, vm);
+
+    result
+}
+
+/// Stop the firecracker process of a VM, leaving its workspace and its
+/// network in place.
+async fn halt(vm: &mut VMState) -> Result<(), Error> {
+    if !matches!(vm.status, VMStatus::Running | VMStatus::Paused) {
+        return Ok(());
+    }
+    let Some(machine) = vm.machine.as_mut() else {
+        return Ok(());
+    };
+
+    if machine.is_running() {
+        machine
+            .destroy_socket()
+            .await
+            .map_err(|e| Error::VmmRun(e.into()))?;
+    } else {
+        // Firecracker processes lambdo reattached to are not its children,
+        // the guest is asked to reboot instead, which makes firecracker exit
+        let socket = machine.chroot().join("firecracker.socket");
+        if vm.status == VMStatus::Paused {
+            resume(vm).await?;
+        }
+        vm.machine
+            .as_ref()
+            .ok_or(Error::Other(anyhow::anyhow!("VM is not running")))?
+            .send_action(Action::SendCtrlAltDel)
+            .await
+            .map_err(|e| Error::VmmRun(e.into()))?;
+
+        let deadline = tokio::time::Instant::now() + HALT_TIMEOUT;
+        while UnixStream::connect(&socket).await.is_ok() {
+            if tokio::time::Instant::now() > deadline {
+                return Err(Error::Other(anyhow::anyhow!(
+                    "VM {} did not stop in time",
+                    vm.configuration.vm_id
+                )));
+            }
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+        let _ = std::fs::remove_file(&socket);
+    }
+    vm.set_state(VMStatus::Exited);
+
+    Ok(())
+}
+
+/// Boot a VM whose firecracker process is gone again, in a new process
+/// configured like the previous one.
+async fn reboot(state: &mut LambdoState, vm: &mut VMState) -> Result<(), Error> {
+    let id = vm.get_id();
+    debug!("Booting VM {} again", id);
+
+    let kernel = vm
+        .configuration
+        .kernel
+        .clone()
+        .ok_or(Error::Other(anyhow::anyhow!("VM {} has no kernel", id)))?;
+    let machine_configuration = MachineConfiguration::new(
+        vm.mem_size_mib.unwrap_or(DEFAULT_MEM_SIZE_MIB) as i32,
+        vm.vcpu_count.unwrap_or(DEFAULT_VCPU_COUNT) as i32,
+    );
+
+    let jailer = state.config.api.jailer.as_ref();
+    let (drives, kernel) = match jailer {
+        Some(_) => (
+            jailer::jailed_drives(&vm.configuration.storage),
+            jailer::jailed_kernel(&kernel),
+        ),
+        None => (vm.configuration.storage.clone(), kernel),
+    };
+
+    let mut machine = executor(state, &id)?;
+    launch(&mut machine, &state.config.api.executor, jailer, &id)
+        .await
+        .map_err(|e| Error::VmmConfigure(machine::FirepilotError::Setup(e.to_string())))?;
+    let booted = async {
+        configure_machine(&machine, &machine_configuration)
+            .await
+            .map_err(Error::VmmConfigure)?;
+        machine
+            .configure_drives(drives)
+            .await
+            .map_err(|e| Error::VmmConfigure(e.into()))?;
+        machine
+            .configure_boot_source(kernel)
+            .await
+            .map_err(|e| Error::VmmConfigure(e.into()))?;
+        machine
+            .configure_network(vm.configuration.interfaces.clone())
+            .await
+            .map_err(|e| Error::VmmConfigure(e.into()))?;
+        let workspace = workspace(&machine, jailer);
+        configure_vsock(
+            &machine,
+            &workspace.join(agent::VSOCK_SOCKET),
+            &vsock_uds_path(&workspace, jailer),
+        )
+        .await
+        .map_err(Error::VmmConfigure)?;
+        machine
+            .send_action(Action::InstanceStart)
+            .await
+            .map_err(|e| Error::VmmRun(e.into()))
+    }
+    .await;
+    if let Err(e) = booted {
+        let _ = machine.destroy_socket().await;
+        return Err(e);
+    }
+
+    if let Some(tenant) = vm.tenant.as_deref() {
+        let accounting = &state.config.api.cpu_accounting;
+        if accounting.enabled {
+            let socket = machine.chroot().join("firecracker.socket");
+            if let Err(e) = cpu_accounting::attach(accounting, tenant, &socket) {
+                error!(
+                    "Error while accounting for the CPU time of VM {}: {:?}",
+                    id, e
+                );
+            }
+        }
+    }
+    vm.machine = Some(machine);
+    vm.set_state(VMStatus::Running);
+    vm.restart_at = None;
+    vm.restart_backoff_seconds = None;
+    vm.started_at = Some(now_ms());
+    let bridge = state.config.api.bridge_for(vm.tenant.as_deref()).bridge;
+    state.leases.acquire(&id, lease(vm, &bridge));
+
+    Ok(())
+}
+
+/// Schedule the restart of a VM that exited after running for `uptime`, as
+/// its restart policy says, returning whether it will be restarted.
+///
+/// The network of a VM waiting for its restart is kept.
+pub fn schedule_restart(state: &mut LambdoState, id: &str, uptime: Duration) -> bool {
+    let Some(vm) = state.vms.iter_mut().find(|vm| vm.configuration.vm_id == id) else {
+        return false;
+    };
+    let Some(policy) = vm.restart_policy.as_ref().filter(|_| !vm.adopted) else {
+        return false;
+    };
+
+    let Some((restarts, backoff)) = policy.next_restart(vm.restarts, uptime) else {
+        if policy.policy != RestartMode::Never {
+            info!("Giving up on VM {} after {} restarts", id, vm.restarts);
+            vm.set_state(VMStatus::Exited);
+            vm.restart_backoff_seconds = None;
+            state.events.record(
+                "vm.restart_abandoned",
+                Some(id),
+                serde_json::json!({ "restarts": vm.restarts }),
+            );
+        }
+        return false;
+    };
+
+    let restart_at = now_ms() + backoff.as_millis() as u64;
+    info!("Restarting VM {} in {:?}", id, backoff);
+    vm.restarts = restarts;
+    vm.restart_at = Some(restart_at);
+    vm.restart_backoff_seconds = Some(backoff.as_secs());
+    // A VM exiting again before it ran long enough is crashing in a loop
+    if restarts > 0 {
+        vm.set_state(VMStatus::CrashLoopBackOff);
+    }
+    state.events.record(
+        "vm.restart_scheduled",
+        Some(id),
+        serde_json::json!({
+            "restart_at": restart_at,
+            "restarts": restarts,
+            "backoff_seconds": backoff.as_secs(),
+        }),
+    );
+
+    true
+}
+
+/// Boot the VMs whose restart is due again, giving up on the ones that fail
+/// to boot once their restart policy says so.
+pub async fn restart_due(state: &mut LambdoState) {
+    let now = now_ms();
+    let due: Vec<String> = state
+        .vms
+        .iter()
+        .filter(|vm| vm.restart_at.is_some_and(|at| at <= now))
+        .map(|vm| vm.get_id())
+        .collect();
+
+    for id in due {
+        let Some(vm_index) = state.vms.iter().position(|vm| vm.get_id() == id) else {
+            continue;
+        };
+        let mut vm = state.vms.remove(vm_index);
+        vm.restart_at = None;
+        let result = reboot(state, &mut vm).await;
+        vm.restarts += 1;
+        let restarts = vm.restarts;
+        state.vms.insert(vm_index, vm);
+
+        match result {
+            Ok(()) => {
+                info!("VM {} restarted", id);
+                state.events.record(
+                    "vm.restarted",
+                    Some(&id),
+                    serde_json::json!({ "restarts": restarts }),
+                );
+            }
+            Err(e) => {
+                error!("Error while restarting VM {}: {:?}", id, e);
+                if !schedule_restart(state, &id, Duration::ZERO) {
+                    if let Err(e) = reap(state, &id).await {
+                        error!("Error while reaping VM {}: {:?}", id, e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Make a VM expire `ttl_seconds` from now, returning when (in ms since
+/// epoch).
+pub fn set_ttl(state: &mut LambdoState, id: &str, ttl_seconds: u64) -> Result<u64, Error> {
+    let vm = state
+        .vms
+        .iter_mut()
+        .find(|vm| vm.configuration.vm_id == id)
+        .ok_or(Error::VmNotFound)?;
+
+    let expires_at = now_ms() + ttl_seconds * 1000;
+    vm.expires_at = Some(expires_at);
+    debug!("VM {} expires at {}", id, expires_at);
+
+    Ok(expires_at)
+}
+
+/// Cancel the scheduled destruction of a VM, resuming it.
+pub async fn cancel_destroy(state: &mut LambdoState, id: &str) -> Result<(), Error> {
+    let vm = state
+        .vms
+        .iter_mut()
+        .find(|vm| vm.configuration.vm_id == id)
+        .ok_or(Error::VmNotFound)?;
+
+    if vm.destroy_at.is_none() {
+        return Err(Error::DestroyNotScheduled);
+    }
+
+    resume(vm).await?;
+    vm.set_state(VMStatus::Running);
+    vm.destroy_at = None;
+    state
+        .events
+        .record("vm.destroy_cancelled", Some(id), serde_json::Value::Null);
+
+    Ok(())
+}
+
+/// Freeze a running VM, keeping its memory and devices as they are.
+pub async fn pause_vm(state: &mut LambdoState, id: &str) -> Result<(), Error> {
+    let vm = state
+        .vms
+        .iter_mut()
+        .find(|vm| vm.configuration.vm_id == id)
+        .ok_or(Error::VmNotFound)?;
+
+    if vm.status != VMStatus::Running {
+        return Err(Error::VmNotRunning);
+    }
+
+    pause(vm).await?;
+    vm.set_state(VMStatus::Paused);
+    state
+        .events
+        .record("vm.paused", Some(id), serde_json::Value::Null);
+
+    Ok(())
+}
+
+/// Resume a VM paused with [`pause_vm`].
+///
+/// VMs waiting for their destruction are resumed by cancelling it instead.
+pub async fn resume_vm(state: &mut LambdoState, id: &str) -> Result<(), Error> {
+    let vm = state
+        .vms
+        .iter_mut()
+        .find(|vm| vm.configuration.vm_id == id)
+        .ok_or(Error::VmNotFound)?;
+
+    if vm.destroy_at.is_some() {
+        return Err(Error::DestroyScheduled);
+    }
+    if vm.status != VMStatus::Paused {
+        return Err(Error::VmNotPaused);
+    }
+
+    resume(vm).await?;
+    vm.set_state(VMStatus::Running);
+    state
+        .events
+        .record("vm.resumed", Some(id), serde_json::Value::Null);
+
+    Ok(())
+}
+
+pub async fn pause(vm: &VMState) -> Result<(), Error> {
+    debug!("Pausing VM {}", vm.configuration.vm_id);
+
+    vm.machine
+        .as_ref()
+        .ok_or(Error::Other(anyhow::anyhow!("VM is not running")))?
+        .set_vm_state(Vm::new(State::Paused))
+        .await
+        .map_err(|e| Error::VmmRun(e.into()))
+}
+
+pub async fn resume(vm: &VMState) -> Result<(), Error> {
+    debug!("Resuming VM {}", vm.configuration.vm_id);
+
+    vm.machine
+        .as_ref()
+        .ok_or(Error::Other(anyhow::anyhow!("VM is not running")))?
+        .set_vm_state(Vm::new(State::Resumed))
+        .await
+        .map_err(|e| Error::VmmRun(e.into()))
+}
+
+pub async fn cleanup_network(state: &mut LambdoState, vm: &mut VMState) -> Result<(), Error> {
     debug!(
-        "Removing interface {} from bridge {}",
-        tap_name, state.config.api.network.bridge
+        "Cleaning up VM Network configuration for {} ",
+        vm.configuration.vm_id
     );
 
-    net::remove_interface_from_bridge(&tap_name, &state.config.api.network.bridge).map_err(
+    // The network of a VM is released as soon as it exits
+    let Some(ip) = vm.ip.as_ref() else {
+        debug!("VM {} has no network left", vm.configuration.vm_id);
+        return Ok(());
+    };
+
+    trace!("VM {} had IP {}", vm.configuration.vm_id, ip);
+    net::remove_firewall_rules(&vm.firewall_rules, state.config.api.network.firewall).map_err(
         |e| {
-            error!("Error while removing tap device: {:?}", e);
+            error!("Error while removing firewall rules: {:?}", e);
             Error::NetSetupError(e)
         },
     )?;
 
+    let tap_name = vm.configuration.interfaces[0].host_dev_name.clone();
+    let bridge = state.config.api.bridge_for(vm.tenant.as_deref()).bridge;
+
+    debug!("Removing interface {} from bridge {}", tap_name, bridge);
+
+    net::remove_interface_from_bridge(&tap_name, &bridge).map_err(|e| {
+        error!("Error while removing tap device: {:?}", e);
+        Error::NetSetupError(e)
+    })?;
+
     debug!("Removing tap device {}", tap_name);
 
     net::remove_tap_device(&tap_name).await.map_err(|e| {
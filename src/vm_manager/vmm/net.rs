@@ -1,18 +1,20 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::process::Command;
 use std::str::FromStr;
 
 use anyhow::anyhow;
 use anyhow::Result;
-use cidr::Ipv4Inet;
-use tracing::{debug, info, trace};
+use cidr::{Ipv4Inet, Ipv6Inet};
+use tracing::{debug, info, trace, warn};
 
+use super::firewall::{Firewall, FirewallRule, RuleKind};
+use crate::config::{BridgeConfig, ConnectionLimitsConfig, FirewallBackend, NetworkMode};
 use crate::vm_manager::state::LambdoState;
 use crate::vm_manager::state::VMState;
 use crate::vm_manager::state::VMStatus;
 
-pub(super) fn add_interface_to_bridge(interface_name: &String, state: &LambdoState) -> Result<()> {
-    let bridge_name = &state.config.api.network.bridge;
+pub(super) fn add_interface_to_bridge(interface_name: &str, bridge_name: &str) -> Result<()> {
     debug!(
         "adding interface {} to bridge {}",
         interface_name, bridge_name
@@ -54,45 +56,79 @@ pub(super) async fn create_tap_device(id: &str) -> Result<String> {
     Ok(tap_name)
 }
 
-pub(super) async fn find_available_ip(state: &LambdoState) -> Result<Ipv4Inet> {
-    let config = &state.config;
-    // Safe since we checked the validity of the address before
-    let host_ip = Ipv4Inet::from_str(&config.api.network.bridge_address).unwrap();
-
-    let used_ip: &Vec<_> = &state
+/// Allocate an address of `bridge` to the VM `id`, skipping the addresses
+/// still used by VMs or held by leases.
+pub(super) fn find_available_ip(
+    state: &mut LambdoState,
+    id: &str,
+    bridge: &BridgeConfig,
+) -> Result<Ipv4Inet> {
+    let mut held: HashSet<Ipv4Addr> = state
         .vms
         .iter()
-        .filter_map(|vm| {
-            debug!("VM {:?} has ip {:?}", vm.configuration.vm_id, vm.ip);
-            match vm.ip {
-                Some(ip)
-                    if vm.get_state() != VMStatus::Exited
-                        || vm.get_state() != VMStatus::Terminated =>
-                {
-                    Some(ip.address())
-                }
-                _ => None,
-            }
+        .filter(|vm| state.config.api.bridge_for(vm.tenant.as_deref()).bridge == bridge.bridge)
+        // Exited VMs keep their address until they are reaped or restarted
+        .filter(|vm| {
+            !matches!(vm.get_state(), VMStatus::Exited | VMStatus::Terminated)
+                || vm.restart_at.is_some()
         })
+        .filter_map(|vm| vm.ip.map(|ip| ip.address()))
         .collect();
+    // Addresses of VMs lost after a restart may still be held by their
+    // firecracker process
+    held.extend(state.leases.ips(&bridge.bridge));
 
-    debug!("looking for available ip in {}", host_ip);
-    trace!("used ip: {:?}", used_ip);
-    let mut ip = host_ip;
-    ip.increment();
+    let ip = state.ipam.allocate(id, bridge, &held)?;
+    info!("found available ip: {}", ip);
+    Ok(ip)
+}
 
-    while used_ip.contains(&ip.address()) {
-        trace!("ip {} is already used, trying next one", ip);
-        if ip.increment() {
-            // return Err(anyhow!("no available ip"));
-        }
+/// IPv6 address of the VM with the IPv4 address `ip` on `bridge`, if the
+/// bridge has an IPv6 subnet.
+///
+/// It has the same host part as the IPv4 address, so it is unique as long as
+/// the IPv4 one is, without being leased.
+pub fn ipv6_of(bridge: &BridgeConfig, ip: Ipv4Inet) -> Result<Option<Ipv6Inet>> {
+    let Some(address) = &bridge.bridge_address_v6 else {
+        return Ok(None);
+    };
+    let subnet =
+        Ipv6Inet::from_str(address).map_err(|e| anyhow!("invalid bridge IPv6 address: {}", e))?;
+
+    let host = u32::from(ip.address()) - u32::from(ip.first_address());
+    let host_bits = 128 - subnet.network_length() as u32;
+    if host_bits < 32 && host >> host_bits != 0 {
+        return Err(anyhow!(
+            "IPv6 subnet {} is smaller than the IPv4 one",
+            subnet.network()
+        ));
     }
+    let address = Ipv6Addr::from(u128::from(subnet.first_address()) + host as u128);
+    Ipv6Inet::new(address, subnet.network_length())
+        .map(Some)
+        .map_err(|e| anyhow!("invalid IPv6 address {}: {}", address, e))
+}
 
-    info!("found available ip: {}", ip);
-    Ok(ip)
+/// Addresses of a VM, along with its IPv6 one if its bridge has an IPv6
+/// subnet
+fn addresses(vm: &VMState, bridge: &BridgeConfig) -> Result<Vec<IpAddr>> {
+    let ip = vm.ip.ok_or(anyhow!("IP not set"))?;
+    let mut addresses = vec![IpAddr::V4(ip.address())];
+    if let Some(ip6) = ipv6_of(bridge, ip)? {
+        addresses.push(IpAddr::V6(ip6.address()));
+    }
+    Ok(addresses)
 }
 
-pub(super) fn add_boot_option(vm: &mut VMState, state: &LambdoState) -> Result<()> {
+/// `rule` applied with ip6tables if it is about an IPv6 address
+fn for_address(rule: FirewallRule, address: &IpAddr) -> FirewallRule {
+    match address {
+        IpAddr::V4(_) => rule,
+        IpAddr::V6(_) => rule.v6(),
+    }
+}
+
+pub(super) fn add_boot_option(vm: &mut VMState, bridge: &BridgeConfig) -> Result<()> {
     debug!("adding network boot option to kernel");
     let mut boot_args = vm
         .configuration
@@ -105,14 +141,7 @@ pub(super) fn add_boot_option(vm: &mut VMState, state: &LambdoState) -> Result<(
 
     let guest_ip = vm.ip.ok_or(anyhow!("IP not set"))?;
     let netmask = guest_ip.mask();
-    let gateway = state
-        .config
-        .api
-        .network
-        .bridge_address
-        .split('/')
-        .next()
-        .unwrap_or_default();
+    let gateway = bridge.bridge_address.split('/').next().unwrap_or_default();
 
     debug!("guest ip: {}", guest_ip);
     debug!("gateway: {}", gateway);
@@ -127,6 +156,16 @@ pub(super) fn add_boot_option(vm: &mut VMState, state: &LambdoState) -> Result<(
         )
         .as_str(),
     );
+    // The kernel only configures IPv4, parameters unknown to it are passed
+    // to init as environment
+    if let Some(ip6) = ipv6_of(bridge, guest_ip)? {
+        let gateway = bridge
+            .bridge_address_v6
+            .as_deref()
+            .and_then(|address| address.split('/').next())
+            .unwrap_or_default();
+        boot_args.push_str(&format!(" ipv6={} ipv6_gateway={}", ip6, gateway));
+    }
 
     debug!("boot args: {}", boot_args);
 
@@ -139,117 +178,393 @@ pub(super) fn create_port_mapping(
     vm_state: &mut VMState,
     lambdo_state: &LambdoState,
 ) -> Result<()> {
-    for (host_port, guest_port) in vm_state.port_mapping.iter() {
+    let firewall = Firewall::new(lambdo_state.config.api.network.firewall)?;
+    let bridge = lambdo_state
+        .config
+        .api
+        .bridge_for(vm_state.tenant.as_deref());
+    let addresses = addresses(vm_state, &bridge)?;
+    let mode = lambdo_state.config.api.network.mode;
+
+    for (host_port, guest_port) in vm_state.port_mapping.clone() {
         for vm in &lambdo_state.vms {
-            if vm.get_state() == VMStatus::Running
-                || vm.get_state() == VMStatus::Pending && vm.port_mapping.contains_key(host_port)
+            if matches!(
+                vm.get_state(),
+                VMStatus::Pending
+                    | VMStatus::Running
+                    | VMStatus::Paused
+                    | VMStatus::CrashLoopBackOff
+            ) && vm.port_mapping.contains_key(&host_port)
             {
                 return Err(anyhow!("Port mapping already exists for {}", host_port));
             }
         }
-
-        let ip_table =
-            iptables::new(false).map_err(|e| anyhow!("error when creating nat table: {}", e))?;
+        if lambdo_state
+            .leases
+            .host_ports()
+            .any(|port| port == host_port)
+        {
+            return Err(anyhow!("Port {} is still leased", host_port));
+        }
 
         debug!("adding port mapping for {} to {}", host_port, guest_port);
+        for address in &addresses {
+            for rule in port_mapping_rules(address, host_port, guest_port, mode) {
+                let rule = firewall.append(rule)?;
+                vm_state.firewall_rules.push(rule);
+            }
+        }
+    }
 
-        // PORT MAPPING
-        ip_table
-            .append(
-                "nat",
-                "PREROUTING",
-                format!(
-                    "-p tcp --dport {} -j DNAT --to-destination {}:{}",
-                    host_port,
-                    vm_state.ip.ok_or(anyhow!("IP not set"))?.address(),
-                    guest_port
-                )
-                .as_str(),
-            )
-            .map_err(|e| anyhow!("error when adding port mapping: {}", e))?;
+    Ok(())
+}
 
-        //MASQUERADE
-        ip_table
-            .append(
-                "nat",
-                "POSTROUTING",
-                format!(
-                    "-p tcp -d {} --dport {} -j MASQUERADE",
-                    vm_state.ip.ok_or(anyhow!("IP not set"))?.address(),
-                    guest_port
-                )
-                .as_str(),
-            )
-            .map_err(|e| anyhow!("error when adding port mapping: {}", e))?;
+/// Rules of a port mapping. In routed mode, the guest port is only opened,
+/// the VM being reached on its own address.
+fn port_mapping_rules(
+    address: &IpAddr,
+    host_port: u16,
+    guest_port: u16,
+    mode: NetworkMode,
+) -> Vec<FirewallRule> {
+    let destination = match address {
+        IpAddr::V4(address) => format!("{}:{}", address, guest_port),
+        IpAddr::V6(address) => format!("[{}]:{}", address, guest_port),
+    };
+    let forward = FirewallRule::new(
+        "filter",
+        "FORWARD",
+        format!(
+            "-p tcp -d {} --dport {} -m state --state NEW,ESTABLISHED,RELATED -j ACCEPT",
+            address, guest_port
+        ),
+    )
+    .with_kind(RuleKind::Forward);
+    if mode == NetworkMode::Routed {
+        return vec![for_address(forward, address)];
+    }
 
+    [
+        // PORT MAPPING
+        FirewallRule::new(
+            "nat",
+            "PREROUTING",
+            format!(
+                "-p tcp --dport {} -j DNAT --to-destination {}",
+                host_port, destination
+            ),
+        )
+        .with_kind(RuleKind::Dnat),
+        //MASQUERADE
+        FirewallRule::new(
+            "nat",
+            "POSTROUTING",
+            format!("-p tcp -d {} --dport {} -j MASQUERADE", address, guest_port),
+        )
+        .with_kind(RuleKind::Snat),
         //ACCEPT FORWARD
-        ip_table
-            .append(
-                "filter",
-                "FORWARD",
-                format!(
-                    "-p tcp -d {} --dport {} -m state --state NEW,ESTABLISHED,RELATED -j ACCEPT",
-                    vm_state.ip.ok_or(anyhow!("IP not set"))?.address(),
-                    guest_port
-                )
-                .as_str(),
-            )
-            .map_err(|e| anyhow!("error when adding port mapping: {}", e))?;
+        forward,
+    ]
+    .into_iter()
+    .map(|rule| for_address(rule, address))
+    .collect()
+}
+
+/// Drop the new connections of a VM beyond the limits, from it as well as
+/// to it through its port mappings.
+///
+/// The rules go first in FORWARD, ahead of the ones accepting the traffic
+/// of the port mappings.
+pub(super) fn limit_connections(
+    vm_state: &mut VMState,
+    bridge: &BridgeConfig,
+    limits: &ConnectionLimitsConfig,
+    backend: FirewallBackend,
+) -> Result<()> {
+    let firewall = Firewall::new(backend)?;
+    for address in addresses(vm_state, bridge)? {
+        let mask = if address.is_ipv6() { 128 } else { 32 };
+        let mut rules = Vec::new();
+        if let Some(max) = limits.max_connections {
+            debug!("limiting {} to {} connections", address, max);
+            rules.push(format!(
+                "-s {} -m state --state NEW -m connlimit --connlimit-above {} --connlimit-mask {} --connlimit-saddr -j DROP",
+                address, max, mask
+            ));
+            rules.push(format!(
+                "-d {} -m state --state NEW -m connlimit --connlimit-above {} --connlimit-mask {} --connlimit-daddr -j DROP",
+                address, max, mask
+            ));
+        }
+        if let Some(rate) = limits.max_new_per_second {
+            debug!(
+                "limiting {} to {} new connections per second",
+                address, rate
+            );
+            // Names of hashlimit tables are limited to 15 characters
+            let name = format!(
+                "lambdo{}-{}",
+                if address.is_ipv6() { "6" } else { "" },
+                &vm_state.configuration.vm_id[..7]
+            );
+            rules.push(format!(
+                "-s {} -m state --state NEW -m hashlimit --hashlimit-above {}/sec --hashlimit-burst {} --hashlimit-mode srcip --hashlimit-name {} -j DROP",
+                address, rate, rate, name
+            ));
+        }
+
+        for rule in rules {
+            let rule =
+                FirewallRule::new("filter", "FORWARD", rule).with_kind(RuleKind::ConnectionLimit);
+            let rule = firewall.insert(for_address(rule, &address), 1)?;
+            vm_state.firewall_rules.push(rule);
+        }
     }
 
     Ok(())
 }
 
-pub(super) fn remove_port_mapping(
-    port_mapping: &HashMap<u16, u16>,
-    vm_ip: &Ipv4Inet,
+/// Remove and add port mappings of a running VM. Either every change is
+/// applied, or the firewall is rolled back to its previous rules.
+pub(super) fn update_port_mapping(
+    vm_state: &mut VMState,
+    add: &HashMap<u16, u16>,
+    remove: &[u16],
+    bridge: &BridgeConfig,
+    mode: NetworkMode,
+    backend: FirewallBackend,
 ) -> Result<()> {
-    debug!("removing port mapping");
-    trace!("port mapping: {:?}", port_mapping);
-    trace!("vm ip: {}", vm_ip);
-
-    let ip_table =
-        iptables::new(false).map_err(|e| anyhow!("error when creating nat table: {}", e))?;
-
-    let address = vm_ip.address();
-
-    for (host_port, guest_port) in port_mapping {
-        ip_table
-            .delete(
-                "nat",
-                "PREROUTING",
-                format!(
-                    "-p tcp --dport {} -j DNAT --to-destination {}:{}",
-                    host_port, address, guest_port
-                )
-                .as_str(),
-            )
-            .map_err(|e| anyhow!("error when removing port mapping: {}", e))?;
-
-        ip_table
-            .delete(
-                "nat",
-                "POSTROUTING",
-                format!("-p tcp -d {} --dport {} -j MASQUERADE", address, guest_port).as_str(),
-            )
-            .map_err(|e| anyhow!("error when removing port mapping: {}", e))?;
-
-        ip_table
-            .delete(
-                "filter",
-                "FORWARD",
-                format!(
-                    "-p tcp -d {} --dport {} -m state --state NEW,ESTABLISHED,RELATED -j ACCEPT",
-                    address, guest_port
-                )
-                .as_str(),
-            )
-            .map_err(|e| anyhow!("error when removing port mapping: {}", e))?;
+    let firewall = Firewall::new(backend)?;
+    let addresses = addresses(vm_state, bridge)?;
+
+    let mut removed = Vec::new();
+    let mut added = Vec::new();
+    let result = (|| -> Result<()> {
+        for host_port in remove {
+            let guest_port = vm_state.port_mapping[host_port];
+            debug!("removing port mapping for {} to {}", host_port, guest_port);
+            for address in addresses.iter().rev() {
+                for rule in port_mapping_rules(address, *host_port, guest_port, mode)
+                    .into_iter()
+                    .rev()
+                {
+                    firewall.delete(&rule)?;
+                    removed.push(rule);
+                }
+            }
+        }
+
+        for (host_port, guest_port) in add {
+            debug!("adding port mapping for {} to {}", host_port, guest_port);
+            for address in &addresses {
+                for rule in port_mapping_rules(address, *host_port, *guest_port, mode) {
+                    added.push(firewall.append(rule)?);
+                }
+            }
+        }
+
+        Ok(())
+    })();
+
+    if let Err(e) = result {
+        warn!("failed to update port mapping, rolling back: {}", e);
+        for rule in added.iter().rev() {
+            firewall.delete(rule)?;
+        }
+        for rule in removed.into_iter().rev() {
+            firewall.append(rule)?;
+        }
+        return Err(e);
+    }
+
+    for host_port in remove {
+        vm_state.port_mapping.remove(host_port);
+    }
+    vm_state.port_mapping.extend(add);
+    vm_state
+        .firewall_rules
+        .retain(|rule| !removed.contains(rule));
+    vm_state.firewall_rules.extend(added);
+
+    Ok(())
+}
+
+/// Prevent the VM from opening connections outside of the bridge, so it can
+/// only reach the internet through the egress proxy.
+pub(super) fn block_direct_egress(
+    vm_state: &mut VMState,
+    bridge: &BridgeConfig,
+    backend: FirewallBackend,
+) -> Result<()> {
+    let firewall = Firewall::new(backend)?;
+    for address in addresses(vm_state, bridge)? {
+        debug!("blocking direct egress for {}", address);
+        let rule = FirewallRule::new(
+            "filter",
+            "FORWARD",
+            format!(
+                "-i {} -s {} -m state --state NEW -j DROP",
+                bridge.bridge, address
+            ),
+        )
+        .with_kind(RuleKind::EgressPolicy);
+        let rule = firewall.insert(for_address(rule, &address), 1)?;
+        vm_state.firewall_rules.push(rule);
     }
 
     Ok(())
 }
 
+pub(super) fn add_proxy_boot_option(
+    vm: &mut VMState,
+    bridge: &BridgeConfig,
+    state: &LambdoState,
+) -> Result<()> {
+    let gateway = bridge.bridge_address.split('/').next().unwrap_or_default();
+    let proxy = format!("http://{}:{}", gateway, state.config.api.egress_proxy.port);
+
+    let kernel = vm
+        .configuration
+        .kernel
+        .as_mut()
+        .ok_or(anyhow!("Boot source not configured"))?;
+    let mut boot_args = kernel.boot_args.clone().unwrap_or_default();
+
+    // Parameters unknown to the kernel are passed to init as environment
+    boot_args.push_str(&format!(" http_proxy={} https_proxy={}", proxy, proxy));
+    debug!("boot args: {}", boot_args);
+    kernel.boot_args = Some(boot_args);
+
+    Ok(())
+}
+
+/// Remove the rules recorded for a VM, newest first.
+pub(super) fn remove_firewall_rules(
+    rules: &[FirewallRule],
+    backend: FirewallBackend,
+) -> Result<()> {
+    debug!("removing firewall rules");
+    trace!("rules: {:?}", rules);
+
+    let firewall = Firewall::new(backend)?;
+    for rule in rules.iter().rev() {
+        firewall.delete(rule)?;
+    }
+
+    Ok(())
+}
+
+/// Remove the taps on the bridges of lambdo and the per-VM firewall rules
+/// that belong to no tracked VM nor lease, as left behind by VMs lambdo lost
+/// track of, returning a description of every removal.
+///
+/// A rule is per-VM if it refers to a single address of the subnet of a
+/// bridge other than the bridge itself; the rules of the bridges only refer
+/// to their subnets.
+pub async fn remove_leaked(state: &LambdoState) -> Result<Vec<String>> {
+    let bridges = state.config.api.bridges();
+    let mut removed = Vec::new();
+
+    // Taps are named after the VM the lease is held for
+    let mut taps: HashSet<String> = state
+        .vms
+        .iter()
+        .flat_map(|vm| vm.configuration.interfaces.iter())
+        .map(|interface| interface.host_dev_name.clone())
+        .collect();
+    taps.extend(
+        state
+            .leases
+            .all()
+            .keys()
+            .filter_map(|id| id.get(..8))
+            .map(|id| format!("tap-{}", id)),
+    );
+
+    let bridge_names: Vec<&str> = bridges.iter().map(|b| b.bridge.as_str()).collect();
+    for entry in std::fs::read_dir("/sys/class/net")? {
+        let name = entry?.file_name().to_string_lossy().to_string();
+        if !name.starts_with("tap-") || taps.contains(&name) {
+            continue;
+        }
+        let master = std::fs::read_link(format!("/sys/class/net/{}/master", name))
+            .ok()
+            .and_then(|link| link.file_name().map(|m| m.to_string_lossy().to_string()));
+        if !master.is_some_and(|master| bridge_names.contains(&master.as_str())) {
+            continue;
+        }
+        remove_tap_device(&name).await?;
+        removed.push(format!("removed leaked tap {}", name));
+    }
+
+    let mut in_use = HashSet::new();
+    for bridge in &bridges {
+        let gateway = Ipv4Inet::from_str(&bridge.bridge_address)
+            .map_err(|e| anyhow!("invalid bridge address: {}", e))?;
+        in_use.insert(IpAddr::V4(gateway.address()));
+        if let Some(address) = &bridge.bridge_address_v6 {
+            let gateway = Ipv6Inet::from_str(address)
+                .map_err(|e| anyhow!("invalid bridge IPv6 address: {}", e))?;
+            in_use.insert(IpAddr::V6(gateway.address()));
+        }
+
+        let leased = state
+            .vms
+            .iter()
+            .filter(|vm| state.config.api.bridge_for(vm.tenant.as_deref()).bridge == bridge.bridge)
+            .filter_map(|vm| vm.ip)
+            .chain(
+                state
+                    .leases
+                    .ips(&bridge.bridge)
+                    .filter_map(|ip| Ipv4Inet::new(ip, gateway.network_length()).ok()),
+            );
+        for ip in leased {
+            in_use.insert(IpAddr::V4(ip.address()));
+            if let Some(ip6) = ipv6_of(bridge, ip)? {
+                in_use.insert(IpAddr::V6(ip6.address()));
+            }
+        }
+    }
+    let in_bridge_subnet = |address: &IpAddr| {
+        bridges.iter().any(|bridge| match address {
+            IpAddr::V4(address) => Ipv4Inet::from_str(&bridge.bridge_address)
+                .is_ok_and(|gateway| gateway.network().contains(address)),
+            IpAddr::V6(address) => bridge
+                .bridge_address_v6
+                .as_deref()
+                .and_then(|gateway| Ipv6Inet::from_str(gateway).ok())
+                .is_some_and(|gateway| gateway.network().contains(address)),
+        })
+    };
+
+    let firewall = Firewall::new(state.config.api.network.firewall)?;
+    for (table, chain) in [
+        ("nat", "PREROUTING"),
+        ("nat", "POSTROUTING"),
+        ("filter", "FORWARD"),
+    ] {
+        for ipv6 in [false, true] {
+            for rule in firewall.list(table, chain, ipv6)? {
+                let leaked = rule
+                    .host_addresses()
+                    .iter()
+                    .any(|address| in_bridge_subnet(address) && !in_use.contains(address));
+                if !leaked {
+                    continue;
+                }
+                firewall.delete_listed(&rule)?;
+                removed.push(format!(
+                    "removed leaked rule -t {} -A {} {}",
+                    table, chain, rule.rule
+                ));
+            }
+        }
+    }
+
+    Ok(removed)
+}
+
 pub(super) fn remove_interface_from_bridge(interface_name: &str, bridge_name: &str) -> Result<()> {
     let interface_id = network_bridge::interface_id(interface_name)
         .map_err(|e| anyhow!("error when fetching interface id: {}", e))?;
@@ -260,7 +575,7 @@ pub(super) fn remove_interface_from_bridge(interface_name: &str, bridge_name: &s
     Ok(())
 }
 
-pub(super) async fn remove_tap_device(tap_name: &String) -> Result<()> {
+pub(super) async fn remove_tap_device(tap_name: &str) -> Result<()> {
     tokio::process::Command::new("ip")
         .args(["link", "delete", tap_name])
         .output()
@@ -0,0 +1,139 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Error};
+use tokio::process::Command;
+use tracing::{debug, trace};
+
+use super::StorageDriver;
+
+/// Stores every volume as a raw ext4 image file in a folder.
+pub struct FileStorageDriver {
+    pub path: PathBuf,
+}
+
+impl FileStorageDriver {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+}
+
+#[async_trait::async_trait]
+impl StorageDriver for FileStorageDriver {
+    async fn create(
+        &self,
+        name: &str,
+        size_mib: u64,
+        source: Option<&Path>,
+    ) -> Result<PathBuf, Error> {
+        let path = self.path.join(format!("{}.ext4", name));
+        let size = size_mib * 1024 * 1024;
+
+        if let Some(source) = source {
+            copy_file(source, &path).await?;
+
+            let file = tokio::fs::OpenOptions::new()
+                .write(true)
+                .open(&path)
+                .await?;
+            let current_size = file.metadata().await?.len();
+            if current_size > size {
+                drop(file);
+                tokio::fs::remove_file(&path).await?;
+                return Err(anyhow!(
+                    "volume size ({} MiB) is smaller than its source image",
+                    size_mib
+                ));
+            }
+            file.set_len(size).await?;
+        } else {
+            trace!("creating sparse file {}", path.display());
+            let file = tokio::fs::File::create(&path).await?;
+            file.set_len(size).await?;
+
+            debug!("formatting {}", path.display());
+            let output = Command::new("mkfs.ext4")
+                .args(["-F", "-q"])
+                .arg(&path)
+                .output()
+                .await
+                .map_err(|e| anyhow!("error when running mkfs.ext4: {}", e))?;
+
+            if !output.status.success() {
+                tokio::fs::remove_file(&path).await?;
+                return Err(anyhow!(
+                    "error when formatting volume: {}",
+                    String::from_utf8_lossy(&output.stderr)
+                ));
+            }
+        }
+
+        Ok(path)
+    }
+
+    async fn snapshot(&self, volume: &str, name: &str, source: &Path) -> Result<PathBuf, Error> {
+        let folder = self.path.join("snapshots");
+        tokio::fs::create_dir_all(&folder).await?;
+
+        let path = folder.join(format!("{}@{}.ext4", volume, name));
+        copy_file(source, &path).await?;
+        Ok(path)
+    }
+
+    async fn clone(&self, name: &str, source: &Path) -> Result<PathBuf, Error> {
+        let path = self.path.join(format!("{}.ext4", name));
+        copy_file(source, &path).await?;
+        Ok(path)
+    }
+
+    async fn delete(&self, path: &Path) -> Result<(), Error> {
+        debug!("removing volume file {}", path.display());
+        tokio::fs::remove_file(path).await?;
+        Ok(())
+    }
+}
+
+/// Copy `source` to `destination`, sharing blocks with the source when the
+/// filesystem supports reflinks and keeping holes otherwise.
+pub async fn copy_file(source: &Path, destination: &Path) -> Result<(), Error> {
+    debug!("copying {} to {}", source.display(), destination.display());
+    let output = Command::new("cp")
+        .args(["--reflink=auto", "--sparse=always"])
+        .arg(source)
+        .arg(destination)
+        .output()
+        .await
+        .map_err(|e| anyhow!("error when running cp: {}", e))?;
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "error when copying {}: {}",
+            source.display(),
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+
+    Ok(())
+}
+
+/// Make `source` available at `destination` without copying it, as a hard
+/// link, falling back to a copy when both are on different filesystems or
+/// the filesystem does not support hard links.
+///
+/// Writes through the link change the source, only link what is not written.
+pub async fn link_file(source: &Path, destination: &Path) -> Result<(), Error> {
+    match tokio::fs::hard_link(source, destination).await {
+        Ok(()) => {
+            trace!("linked {} to {}", source.display(), destination.display());
+            Ok(())
+        }
+        Err(e) => {
+            debug!(
+                "cannot link {} to {} ({}), copying it",
+                source.display(),
+                destination.display(),
+                e
+            );
+            copy_file(source, destination).await
+        }
+    }
+}
@@ -0,0 +1,495 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+
+use super::{
+    image_manager::{Image, ImageManifest},
+    state::DEFAULT_NAMESPACE,
+    Error,
+};
+
+pub mod file_driver;
+
+const INDEX_FILE: &str = "volumes.json";
+
+/// Backend in charge of allocating the storage of a volume.
+#[async_trait::async_trait]
+pub trait StorageDriver: Sync + Send {
+    /// Create the storage for volume `name`, optionally seeded from `source`,
+    /// and return the host level path of the resulting disk.
+    async fn create(
+        &self,
+        name: &str,
+        size_mib: u64,
+        source: Option<&Path>,
+    ) -> Result<PathBuf, anyhow::Error>;
+    /// Take a point-in-time copy named `name` of the disk of `volume`.
+    async fn snapshot(
+        &self,
+        volume: &str,
+        name: &str,
+        source: &Path,
+    ) -> Result<PathBuf, anyhow::Error>;
+    /// Create the storage for volume `name` as a copy of `source`.
+    async fn clone(&self, name: &str, source: &Path) -> Result<PathBuf, anyhow::Error>;
+    async fn delete(&self, path: &Path) -> Result<(), anyhow::Error>;
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CreateVolumeDTO {
+    pub name: String,
+    pub size_mib: u64,
+    /// Image used as the initial content of the volume
+    #[serde(default)]
+    pub image: Option<ImageManifest>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CreateSnapshotDTO {
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CloneVolumeDTO {
+    /// Name of the new volume
+    pub name: String,
+    /// Snapshot to clone instead of the current content of the volume
+    #[serde(default)]
+    pub snapshot: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Snapshot {
+    pub name: String,
+    pub path: PathBuf,
+    /// Creation date, in seconds since the epoch
+    pub created_at: u64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Volume {
+    pub name: String,
+    pub size_mib: u64,
+    pub path: PathBuf,
+    /// Id of the image the volume was created from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// Id of the VM currently using the volume
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attached_to: Option<String>,
+    #[serde(default)]
+    pub snapshots: Vec<Snapshot>,
+    /// Namespace of the token the volume was created with, only VMs of
+    /// that namespace attaching it
+    #[serde(default = "default_namespace")]
+    pub namespace: String,
+}
+
+fn default_namespace() -> String {
+    DEFAULT_NAMESPACE.to_string()
+}
+
+impl Volume {
+    pub fn image(&self) -> Image {
+        Image {
+            id: self.name.clone(),
+            path: self.path.clone(),
+        }
+    }
+}
+
+/// Keeps track of the volumes and of the VMs they are attached to.
+///
+/// The volume list is stored in `volumes.json` inside the volumes folder so
+/// it survives daemon restarts.
+pub struct VolumeManager {
+    pub path: PathBuf,
+    driver: Box<dyn StorageDriver>,
+    volumes: Mutex<HashMap<String, Volume>>,
+    /// Names of the volumes whose storage is being allocated, copied or
+    /// freed, which the lock on `volumes` is not held for
+    busy: std::sync::Mutex<HashSet<String>>,
+}
+
+/// Volumes marked busy until dropped
+struct Reservation<'a> {
+    busy: &'a std::sync::Mutex<HashSet<String>>,
+    names: Vec<String>,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        let mut busy = self.busy.lock().unwrap_or_else(|e| e.into_inner());
+        for name in &self.names {
+            busy.remove(name);
+        }
+    }
+}
+
+impl VolumeManager {
+    pub async fn new(path: String, driver: Box<dyn StorageDriver>) -> Result<Self, Error> {
+        let path = PathBuf::from(path);
+        tokio::fs::create_dir_all(&path)
+            .await
+            .map_err(|e| Error::VolumeError(anyhow!("cannot create volumes folder: {}", e)))?;
+
+        let index = path.join(INDEX_FILE);
+        let volumes: HashMap<String, Volume> = if index.exists() {
+            let content = tokio::fs::read(&index)
+                .await
+                .map_err(|e| Error::VolumeError(anyhow!("cannot read volume index: {}", e)))?;
+            serde_json::from_slice(&content)
+                .map_err(|e| Error::VolumeError(anyhow!("cannot parse volume index: {}", e)))?
+        } else {
+            HashMap::new()
+        };
+
+        info!("loaded {} volumes from {}", volumes.len(), path.display());
+
+        let manager = VolumeManager {
+            path,
+            driver,
+            volumes: Mutex::new(volumes),
+            busy: std::sync::Mutex::new(HashSet::new()),
+        };
+        manager.save(&*manager.volumes.lock().await).await?;
+        Ok(manager)
+    }
+
+    pub async fn list(&self) -> Vec<Volume> {
+        let mut volumes: Vec<Volume> = self.volumes.lock().await.values().cloned().collect();
+        volumes.sort_by(|a, b| a.name.cmp(&b.name));
+        volumes
+    }
+
+    pub async fn get(&self, name: &str) -> Result<Volume, Error> {
+        self.volumes
+            .lock()
+            .await
+            .get(name)
+            .cloned()
+            .ok_or(Error::VolumeNotFound)
+    }
+
+    pub async fn create(
+        &self,
+        name: &str,
+        size_mib: u64,
+        source: Option<&Image>,
+        namespace: &str,
+    ) -> Result<Volume, Error> {
+        validate_name(name)?;
+
+        let _reservation = {
+            let volumes = self.volumes.lock().await;
+            if volumes.contains_key(name) {
+                return Err(Error::VolumeAlreadyExists);
+            }
+            self.reserve(&[name])?
+        };
+
+        debug!("creating volume {} of {} MiB", name, size_mib);
+        let path = self
+            .driver
+            .create(name, size_mib, source.map(|image| image.path.as_path()))
+            .await
+            .map_err(Error::VolumeError)?;
+
+        let volume = Volume {
+            name: name.to_string(),
+            size_mib,
+            path,
+            source: source.map(|image| image.id.clone()),
+            attached_to: None,
+            snapshots: Vec::new(),
+            namespace: namespace.to_string(),
+        };
+        let mut volumes = self.volumes.lock().await;
+        volumes.insert(name.to_string(), volume.clone());
+        self.save(&volumes).await?;
+
+        info!("volume {} created", name);
+        Ok(volume)
+    }
+
+    /// Forget volume `name`, then free its storage.
+    pub async fn delete(&self, name: &str) -> Result<(), Error> {
+        let (volume, _reservation) = {
+            let mut volumes = self.volumes.lock().await;
+            let volume = volumes.get(name).ok_or(Error::VolumeNotFound)?;
+            if let Some(vm_id) = &volume.attached_to {
+                return Err(Error::VolumeInUse(vm_id.clone()));
+            }
+            let reservation = self.reserve(&[name])?;
+            let volume = volumes.remove(name).ok_or(Error::VolumeNotFound)?;
+            self.save(&volumes).await?;
+            (volume, reservation)
+        };
+
+        for snapshot in &volume.snapshots {
+            self.driver
+                .delete(&snapshot.path)
+                .await
+                .map_err(Error::VolumeError)?;
+        }
+        self.driver
+            .delete(&volume.path)
+            .await
+            .map_err(Error::VolumeError)?;
+
+        info!("volume {} deleted", name);
+        Ok(())
+    }
+
+    pub async fn create_snapshot(&self, volume: &str, name: &str) -> Result<Snapshot, Error> {
+        validate_name(name)?;
+
+        let (source, _reservation) = {
+            let volumes = self.volumes.lock().await;
+            let volume = volumes.get(volume).ok_or(Error::VolumeNotFound)?;
+            if volume
+                .snapshots
+                .iter()
+                .any(|snapshot| snapshot.name == name)
+            {
+                return Err(Error::SnapshotAlreadyExists);
+            }
+            if let Some(vm_id) = &volume.attached_to {
+                warn!(
+                    "snapshotting volume {} while attached to VM {}, the snapshot is only crash consistent",
+                    volume.name, vm_id
+                );
+            }
+            (volume.path.clone(), self.reserve(&[&volume.name])?)
+        };
+
+        let path = self
+            .driver
+            .snapshot(volume, name, &source)
+            .await
+            .map_err(Error::VolumeError)?;
+        let mut volumes = self.volumes.lock().await;
+        let volume = volumes.get_mut(volume).ok_or(Error::VolumeNotFound)?;
+        let snapshot = Snapshot {
+            name: name.to_string(),
+            path,
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+        };
+        volume.snapshots.push(snapshot.clone());
+        info!("snapshot {} of volume {} created", name, volume.name);
+        self.save(&volumes).await?;
+
+        Ok(snapshot)
+    }
+
+    pub async fn delete_snapshot(&self, volume: &str, name: &str) -> Result<(), Error> {
+        let (snapshot, _reservation) = {
+            let mut volumes = self.volumes.lock().await;
+            let volume = volumes.get_mut(volume).ok_or(Error::VolumeNotFound)?;
+            let index = volume
+                .snapshots
+                .iter()
+                .position(|snapshot| snapshot.name == name)
+                .ok_or(Error::SnapshotNotFound)?;
+            let reservation = self.reserve(&[&volume.name])?;
+            let snapshot = volume.snapshots.remove(index);
+            self.save(&volumes).await?;
+            (snapshot, reservation)
+        };
+
+        self.driver
+            .delete(&snapshot.path)
+            .await
+            .map_err(Error::VolumeError)?;
+
+        info!("snapshot {} deleted", name);
+        Ok(())
+    }
+
+    /// Create volume `name` from the content of `source`, or from one of its
+    /// snapshots, in the namespace of `source`.
+    pub async fn clone_volume(
+        &self,
+        source: &str,
+        snapshot: Option<&str>,
+        name: &str,
+    ) -> Result<Volume, Error> {
+        validate_name(name)?;
+
+        let (source, source_path, _reservation) = {
+            let volumes = self.volumes.lock().await;
+            if volumes.contains_key(name) {
+                return Err(Error::VolumeAlreadyExists);
+            }
+
+            let source = volumes.get(source).ok_or(Error::VolumeNotFound)?;
+            let source_path = match snapshot {
+                Some(snapshot) => source
+                    .snapshots
+                    .iter()
+                    .find(|s| s.name == snapshot)
+                    .map(|s| s.path.clone())
+                    .ok_or(Error::SnapshotNotFound)?,
+                None => source.path.clone(),
+            };
+            let reservation = self.reserve(&[&source.name, name])?;
+            (source.clone(), source_path, reservation)
+        };
+
+        debug!("cloning volume {} into {}", source.name, name);
+        let path = self
+            .driver
+            .clone(name, &source_path)
+            .await
+            .map_err(Error::VolumeError)?;
+
+        let volume = Volume {
+            name: name.to_string(),
+            size_mib: source.size_mib,
+            path,
+            source: source.source.clone(),
+            attached_to: None,
+            snapshots: Vec::new(),
+            namespace: source.namespace.clone(),
+        };
+        let mut volumes = self.volumes.lock().await;
+        volumes.insert(name.to_string(), volume.clone());
+        self.save(&volumes).await?;
+
+        info!("volume {} created", name);
+        Ok(volume)
+    }
+
+    /// Mark every volume in `names` as used by `owner`, a VM of `namespace`,
+    /// failing without attaching anything if one of them is missing, in
+    /// another namespace, already in use or named twice.
+    pub async fn attach(
+        &self,
+        names: &[String],
+        namespace: &str,
+        owner: &str,
+    ) -> Result<Vec<Volume>, Error> {
+        let mut volumes = self.volumes.lock().await;
+
+        for (index, name) in names.iter().enumerate() {
+            if names[..index].contains(name) {
+                return Err(Error::InvalidOptions(format!(
+                    "volume {} is attached twice",
+                    name
+                )));
+            }
+            let volume = volumes
+                .get(name)
+                .filter(|volume| volume.namespace == namespace)
+                .ok_or(Error::VolumeNotFound)?;
+            if let Some(vm_id) = &volume.attached_to {
+                return Err(Error::VolumeInUse(vm_id.clone()));
+            }
+        }
+
+        let mut attached = Vec::with_capacity(names.len());
+        for name in names {
+            if let Some(volume) = volumes.get_mut(name) {
+                volume.attached_to = Some(owner.to_string());
+                attached.push(volume.clone());
+            }
+        }
+        self.save(&volumes).await?;
+
+        Ok(attached)
+    }
+
+    /// Move the attachments of `from` to `to`.
+    pub async fn transfer(&self, from: &str, to: &str) -> Result<(), Error> {
+        let mut volumes = self.volumes.lock().await;
+        volumes
+            .values_mut()
+            .filter(|volume| volume.attached_to.as_deref() == Some(from))
+            .for_each(|volume| volume.attached_to = Some(to.to_string()));
+        self.save(&volumes).await
+    }
+
+    /// Detach every volume used by `owner`.
+    pub async fn detach_all(&self, owner: &str) -> Result<(), Error> {
+        let mut volumes = self.volumes.lock().await;
+        volumes
+            .values_mut()
+            .filter(|volume| volume.attached_to.as_deref() == Some(owner))
+            .for_each(|volume| {
+                debug!("detaching volume {} from {}", volume.name, owner);
+                volume.attached_to = None;
+            });
+        self.save(&volumes).await
+    }
+
+    /// Detach the volumes used by VMs other than `vm_ids`, such as the VMs
+    /// that did not survive a daemon restart.
+    pub async fn release_unknown(&self, vm_ids: &[String]) -> Result<(), Error> {
+        let mut volumes = self.volumes.lock().await;
+        for volume in volumes.values_mut() {
+            if let Some(vm_id) = volume.attached_to.take_if(|vm_id| !vm_ids.contains(vm_id)) {
+                warn!(
+                    "releasing volume {} still attached to unknown VM {}",
+                    volume.name, vm_id
+                );
+            }
+        }
+        self.save(&volumes).await
+    }
+
+    /// Mark `names` busy, failing if one of them already is. Expected to be
+    /// called with the lock on `volumes` held.
+    fn reserve(&self, names: &[&str]) -> Result<Reservation<'_>, Error> {
+        let mut busy = self.busy.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(name) = names.iter().find(|name| busy.contains(**name)) {
+            return Err(Error::VolumeBusy(name.to_string()));
+        }
+        busy.extend(names.iter().map(|name| name.to_string()));
+        Ok(Reservation {
+            busy: &self.busy,
+            names: names.iter().map(|name| name.to_string()).collect(),
+        })
+    }
+
+    async fn save(&self, volumes: &HashMap<String, Volume>) -> Result<(), Error> {
+        let content = serde_json::to_vec_pretty(volumes)
+            .map_err(|e| Error::VolumeError(anyhow!("cannot serialize volume index: {}", e)))?;
+        let index = self.path.join(INDEX_FILE);
+        let tmp = index.with_extension("json.tmp");
+
+        tokio::fs::write(&tmp, content)
+            .await
+            .map_err(|e| Error::VolumeError(anyhow!("cannot write volume index: {}", e)))?;
+        tokio::fs::rename(&tmp, &index)
+            .await
+            .map_err(|e| Error::VolumeError(anyhow!("cannot write volume index: {}", e)))?;
+
+        Ok(())
+    }
+}
+
+/// Check that `name` is safe to use as a file name, of a volume or of
+/// anything an API caller names.
+pub fn validate_name(name: &str) -> Result<(), Error> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(Error::InvalidOptions(
+            "name must only contain alphanumerics, '-' and '_'".to_string(),
+        ));
+    }
+
+    Ok(())
+}
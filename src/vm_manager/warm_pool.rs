@@ -0,0 +1,91 @@
+use std::{sync::Arc, time::Duration};
+
+use tracing::{debug, warn};
+
+use super::{image_manager::Image, VMManagerTrait, VMOptions};
+
+/// Name of the warm pool holding VMs booted from `kernel` and `rootfs`
+pub fn pool_key(kernel: &Image, rootfs: &Image) -> String {
+    format!("{}+{}", kernel.id, rootfs.id)
+}
+
+/// Warm pool able to serve a VM started with `options`, if they are the
+/// plain options the pooled VMs were booted with
+pub fn pool_for(options: &VMOptions) -> Option<String> {
+    match not_poolable(options) {
+        None => options
+            .disks
+            .first()
+            .map(|disk| pool_key(&options.boot.kernel, &disk.image)),
+        Some(_) => None,
+    }
+}
+
+/// Why a VM started with `options` cannot come from a warm pool
+pub fn not_poolable(options: &VMOptions) -> Option<&'static str> {
+    let [disk] = options.disks.as_slice() else {
+        return Some("pooled VMs have a single disk");
+    };
+
+    if options.tenant.is_some() {
+        Some("tenant VMs are not pooled")
+    } else if options.vcpu_count.is_some()
+        || options.mem_size_mib.is_some()
+        || options.machine_class.is_some()
+    {
+        Some("pooled VMs have the default machine size")
+    } else if options.network.egress_profile.is_some() {
+        Some("pooled VMs have no egress profile")
+    } else if options.snapshot_policy.is_some() {
+        Some("pooled VMs are not snapshotted")
+    } else if options.restart_policy.is_some() {
+        Some("pooled VMs are not restarted")
+    } else if options.boot.boot_args.is_some()
+        || options.boot.initrd.is_some()
+        || !options.boot.sysctls.is_empty()
+        || !options.boot.kernel_modules.is_empty()
+        || options.boot.read_only_rootfs
+    {
+        Some("pooled VMs boot with the default boot options")
+    } else if !disk.is_root_device || disk.is_readonly || disk.is_persistent {
+        Some("pooled VMs boot from a writable copy of their rootfs")
+    } else {
+        None
+    }
+}
+
+/// A pool of VMs booted ahead of time with the same options
+#[derive(Debug, Clone)]
+pub struct WarmPool {
+    pub key: String,
+    pub size: usize,
+    pub options: VMOptions,
+}
+
+/// Boot VMs until every pool is full, checking again every `interval` for
+/// the VMs claimed in the meantime.
+pub async fn maintain(
+    vm_manager: Arc<dyn VMManagerTrait>,
+    pools: Vec<WarmPool>,
+    interval: Duration,
+) {
+    loop {
+        for pool in &pools {
+            let idle = vm_manager.count_warm_vms(&pool.key).await;
+            for _ in idle..pool.size {
+                match vm_manager
+                    .start_warm_vm(&pool.key, pool.options.clone())
+                    .await
+                {
+                    Ok(id) => debug!("VM {} added to warm pool {}", id, pool.key),
+                    Err(e) => {
+                        warn!("failed to boot a VM for warm pool {}: {}", pool.key, e);
+                        break;
+                    }
+                }
+            }
+        }
+
+        tokio::time::sleep(interval).await;
+    }
+}
@@ -0,0 +1,172 @@
+use std::{os::unix::fs::PermissionsExt, path::Path, process::Stdio, str::FromStr};
+
+use anyhow::{anyhow, Result};
+use tokio::{io::AsyncWriteExt, process::Command};
+use tracing::{debug, info};
+
+use super::vmm::firewall::{Firewall, FirewallRule};
+use crate::config::{BridgeConfig, FirewallBackend, WireguardConfig};
+
+/// Set up the wireguard interface, its peers and the routes to their
+/// networks, and let the VMs of `bridges` reach them through the firewall
+/// `backend`.
+///
+/// Every step is idempotent, so that restarting lambdo with a modified
+/// configuration updates the tunnel in place.
+pub async fn setup(
+    config: &WireguardConfig,
+    bridges: &[BridgeConfig],
+    backend: FirewallBackend,
+) -> Result<()> {
+    let interface = &config.interface;
+    if interface.len() > 15 {
+        return Err(anyhow!("wireguard interface name is too long"));
+    }
+
+    let private_key = load_or_generate_key(Path::new(&config.private_key_file)).await?;
+    let public_key = wg(&["pubkey"], Some(&private_key)).await?;
+    info!("wireguard public key of this node: {}", public_key.trim());
+
+    if !Path::new(&format!("/sys/class/net/{}", interface)).exists() {
+        debug!("creating wireguard interface {}", interface);
+        ip(&["link", "add", "dev", interface, "type", "wireguard"]).await?;
+    }
+
+    let listen_port = config.listen_port.to_string();
+    wg(
+        &[
+            "set",
+            interface,
+            "listen-port",
+            &listen_port,
+            "private-key",
+            &config.private_key_file,
+        ],
+        None,
+    )
+    .await?;
+
+    for peer in &config.peers {
+        debug!("configuring wireguard peer {}", peer.public_key);
+        let allowed_ips = peer.allowed_ips.join(",");
+        let keepalive = peer.persistent_keepalive.unwrap_or(0).to_string();
+        let mut args = vec![
+            "set",
+            interface,
+            "peer",
+            &peer.public_key,
+            "allowed-ips",
+            &allowed_ips,
+            "persistent-keepalive",
+            &keepalive,
+        ];
+        if let Some(endpoint) = &peer.endpoint {
+            args.extend(["endpoint", endpoint]);
+        }
+        wg(&args, None).await?;
+    }
+
+    ip(&["address", "replace", &config.address, "dev", interface]).await?;
+    ip(&["link", "set", interface, "up"]).await?;
+
+    let tunnel = cidr::Ipv4Inet::from_str(&config.address)
+        .map_err(|e| anyhow!("invalid wireguard address: {}", e))?
+        .network();
+    for peer in &config.peers {
+        for allowed_ip in &peer.allowed_ips {
+            // The tunnel network itself is routed by its address
+            let network = cidr::Ipv4Cidr::from_str(allowed_ip)
+                .map_err(|e| anyhow!("invalid allowed IP {}: {}", allowed_ip, e))?;
+            if tunnel.contains(&network.first_address()) {
+                continue;
+            }
+            ip(&["route", "replace", allowed_ip, "dev", interface]).await?;
+        }
+    }
+
+    let firewall = Firewall::new(backend)?;
+    for bridge in bridges {
+        for (from, to) in [(interface, &bridge.bridge), (&bridge.bridge, interface)] {
+            let rule = FirewallRule::new(
+                "filter",
+                "FORWARD",
+                format!("-i {} -o {} -j ACCEPT", from, to),
+            );
+            if !firewall.exists(&rule)? {
+                firewall.append(rule)?;
+            }
+        }
+    }
+
+    info!("wireguard interface {} is ready", interface);
+    Ok(())
+}
+
+/// Read the private key from `path`, generating it on first use.
+async fn load_or_generate_key(path: &Path) -> Result<String> {
+    if path.exists() {
+        return tokio::fs::read_to_string(path)
+            .await
+            .map(|key| key.trim().to_string())
+            .map_err(|e| anyhow!("cannot read wireguard key {}: {}", path.display(), e));
+    }
+
+    info!("generating wireguard private key in {}", path.display());
+    let key = wg(&["genkey"], None).await?;
+    let key = key.trim().to_string();
+
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    tokio::fs::write(path, format!("{}\n", key)).await?;
+    tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600)).await?;
+
+    Ok(key)
+}
+
+async fn ip(args: &[&str]) -> Result<()> {
+    debug!("ip {}", args.join(" "));
+    let output = Command::new("ip")
+        .args(args)
+        .output()
+        .await
+        .map_err(|e| anyhow!("cannot run ip: {}", e))?;
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "ip {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+/// Run `wg`, feeding it `input` on stdin, and return its output.
+async fn wg(args: &[&str], input: Option<&str>) -> Result<String> {
+    debug!("wg {}", args.first().copied().unwrap_or_default());
+    let mut child = Command::new("wg")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| anyhow!("cannot run wg, are wireguard-tools installed? {}", e))?;
+
+    let mut stdin = child.stdin.take().unwrap();
+    if let Some(input) = input {
+        stdin.write_all(input.as_bytes()).await?;
+    }
+    drop(stdin);
+
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "wg {} failed: {}",
+            args.first().copied().unwrap_or_default(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}